@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use futures_util::FutureExt;
+use uuid::Uuid;
+
+use rstream::PeerMsg;
+use rstream::registry::{Peer, Registry};
+
+const PEERS: usize = 10_000;
+const MESSAGES: usize = 100;
+
+fn offer(sender: Uuid, recipient: Uuid) -> PeerMsg {
+    PeerMsg {
+        signal: common::Signal::Offer { sdp: String::new() },
+        sender,
+        recipient,
+    }
+}
+
+fn drain(peers: &[Arc<Peer>]) {
+    for peer in peers {
+        while let Some(Ok(_)) = peer.queue.pop().now_or_never() {}
+    }
+}
+
+fn message_throughput(c: &mut Criterion) {
+    let registry = Registry::new();
+    let peers = (0..PEERS)
+        .map(|_| Arc::new(Peer::new(Uuid::new_v4(), rstream::queue::DEFAULT_CAPACITY)))
+        .collect::<Vec<_>>();
+    for peer in &peers {
+        registry.register(peer.clone()).unwrap();
+    }
+
+    // The previous design: a single mutex-guarded map cloned for every message.
+    let cloned_map = Arc::new(Mutex::new(
+        peers.iter().map(|peer| (peer.id, peer.clone())).collect::<HashMap<_, _>>(),
+    ));
+
+    let routes = (0..MESSAGES)
+        .map(|i| (peers[i * 7919 % PEERS].id, peers[i * 104_729 % PEERS].id))
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("10k peers");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+
+    group.bench_function("registry", |b| {
+        b.iter_batched(
+            || drain(&peers),
+            |_| {
+                for (sender, recipient) in &routes {
+                    registry.send(offer(*sender, *recipient)).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("cloned map", |b| {
+        b.iter_batched(
+            || drain(&peers),
+            |_| {
+                for (sender, recipient) in &routes {
+                    let snapshot = cloned_map.lock().unwrap().clone();
+                    snapshot[recipient].queue.push(offer(*sender, *recipient)).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, message_throughput);
+criterion_main!(benches);
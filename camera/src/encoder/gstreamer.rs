@@ -0,0 +1,190 @@
+//! H.264 encoding of raw captures by GStreamer, run under `gst-launch-1.0`
+//! as `gst:` sources are, so the client still builds without GStreamer.
+//!
+//! The first encoder element installed is used, in order of preference: the
+//! V4L2 memory-to-memory encoder of SoCs such as the Raspberry Pi, VA-API on
+//! Intel and AMD graphics, then openh264 and x264 in software. Pictures are
+//! written to the child's stdin as I420 and access units read back as GDP.
+
+use std::io::{BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::OnceLock;
+use std::thread;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use super::I420;
+use crate::source::{GdpPacket, GdpReader, GST_LAUNCH};
+
+const GST_INSPECT: &str = "gst-inspect-1.0";
+
+/// An H.264 encoder element and how to set it up for live streaming.
+pub struct Element {
+    pub name: &'static str,
+    pub hardware: bool,
+    /// Properties for a bitrate in kbit/s and a keyframe interval in frames.
+    properties: fn(u32, u32) -> String,
+}
+
+const ELEMENTS: &[Element] = &[
+    Element {
+        name: "v4l2h264enc",
+        hardware: true,
+        properties: |kbps, gop| format!("extra-controls=\"controls,video_bitrate={},h264_i_frame_period={}\"", kbps * 1000, gop),
+    },
+    Element {
+        name: "vah264enc",
+        hardware: true,
+        properties: |kbps, gop| format!("bitrate={} key-int-max={} b-frames=0 rate-control=cbr", kbps, gop),
+    },
+    Element {
+        name: "vaapih264enc",
+        hardware: true,
+        properties: |kbps, gop| format!("bitrate={} keyframe-period={} max-bframes=0 rate-control=cbr", kbps, gop),
+    },
+    Element {
+        name: "openh264enc",
+        hardware: false,
+        properties: |kbps, gop| format!("bitrate={} gop-size={} complexity=low", kbps * 1000, gop),
+    },
+    Element {
+        name: "x264enc",
+        hardware: false,
+        properties: |kbps, gop| format!("bitrate={} key-int-max={} tune=zerolatency speed-preset=ultrafast", kbps, gop),
+    },
+];
+
+fn choose(installed: impl Fn(&str) -> bool) -> Option<&'static Element> {
+    ELEMENTS.iter().find(|element| installed(element.name))
+}
+
+/// The preferred encoder installed on this machine, looked up once. Hardware
+/// elements are only registered where their device is present.
+pub fn available() -> Option<&'static Element> {
+    static AVAILABLE: OnceLock<Option<&'static Element>> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        let element = choose(|name| {
+            Command::new(GST_INSPECT)
+                .args(["--exists", name])
+                .status()
+                .is_ok_and(|status| status.success())
+        });
+        match element {
+            Some(element) => log::info!("Encoding raw captures with {}", element.name),
+            None => log::warn!("No GStreamer H.264 encoder found"),
+        }
+        element
+    })
+}
+
+/// About 0.1 bits per pixel, which is plenty for mostly static scenes.
+fn bitrate(width: u32, height: u32, fps: u32) -> u32 {
+    (width * height * fps / 10_000).clamp(100, 8_000)
+}
+
+fn launch_description(element: &Element, width: u32, height: u32, fps: u32) -> String {
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let (luma, chroma) = (width * height, chroma_width * chroma_height);
+    // A keyframe every second, since viewers joining can't ask the child for one.
+    let properties = (element.properties)(bitrate(width, height, fps), fps.max(1));
+    format!(
+        "fdsrc fd=0 ! rawvideoparse format=i420 width={} height={} framerate={}/1 frame-size={} \
+         plane-strides=\"<{},{},{}>\" plane-offsets=\"<0,{},{}>\" ! videoconvert ! {} {} ! \
+         h264parse config-interval=-1 ! video/x-h264,stream-format=byte-stream,alignment=au,profile={{constrained-baseline,baseline}} ! \
+         gdppay ! fdsink fd=1 sync=false",
+        width, height, fps.max(1), luma + 2 * chroma,
+        width, chroma_width, chroma_width, luma, luma + chroma,
+        element.name, properties,
+    )
+}
+
+pub struct GstEncoder {
+    element: &'static Element,
+    width: u32,
+    height: u32,
+    child: Child,
+    stdin: ChildStdin,
+    /// Access units read from the child, ending when it exits.
+    units: Receiver<Bytes>,
+}
+
+impl GstEncoder {
+    pub fn new(element: &'static Element, width: u32, height: u32, fps: u32) -> Result<Self> {
+        let description = launch_description(element, width, height, fps);
+        log::debug!("Launching {}", description);
+        let mut child = Command::new(GST_LAUNCH)
+            .arg("-q")
+            .arg(&description)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed running {}", GST_LAUNCH))?;
+        let stdin = child.stdin.take().context("no stdin for gst-launch")?;
+        let stdout = child.stdout.take().context("no stdout from gst-launch")?;
+
+        // Read on a thread of its own, as the child blocks writing output
+        // that isn't read while it is being fed.
+        let (tx, units) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = GdpReader::new(BufReader::new(stdout));
+            while let Ok(packet) = reader.next() {
+                if let GdpPacket::Buffer { data, .. } = packet {
+                    if tx.send(data).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(GstEncoder { element, width, height, child, stdin, units })
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn is_hardware(&self) -> bool {
+        self.element.hardware
+    }
+
+    /// Feeds a picture to the encoder and returns the next access unit if one
+    /// is ready. Units come out a frame or two behind the pictures going in.
+    pub fn encode(&mut self, picture: &I420) -> Result<Option<Bytes>> {
+        self.stdin.write_all(&picture.data).with_context(|| format!("{} stopped", self.element.name))?;
+        match self.units.try_recv() {
+            Ok(unit) => Ok(Some(unit)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => anyhow::bail!("{} stopped", self.element.name),
+        }
+    }
+}
+
+impl Drop for GstEncoder {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_hardware_encoders() {
+        assert_eq!(choose(|name| name == "x264enc" || name == "vaapih264enc").map(|element| element.name), Some("vaapih264enc"));
+        assert_eq!(choose(|name| name == "x264enc").map(|element| element.name), Some("x264enc"));
+        assert!(choose(|_| false).is_none());
+    }
+
+    #[test]
+    fn describes_pipeline() {
+        let x264 = ELEMENTS.iter().find(|element| element.name == "x264enc").unwrap();
+        let description = launch_description(x264, 642, 480, 15);
+        assert!(description.contains("width=642 height=480 framerate=15/1 frame-size=462240"), "{}", description);
+        assert!(description.contains("plane-strides=\"<642,321,321>\" plane-offsets=\"<0,308160,385200>\""), "{}", description);
+        assert!(description.contains("x264enc bitrate=462 key-int-max=15 tune=zerolatency"), "{}", description);
+        assert!(description.ends_with("gdppay ! fdsink fd=1 sync=false"));
+    }
+}
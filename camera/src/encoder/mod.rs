@@ -0,0 +1,246 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use crate::source::{Frame, Mode, PixelFormat};
+
+mod gstreamer;
+
+pub use gstreamer::GstEncoder;
+
+/// Planar 4:2:0 picture, the common input to software encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct I420 {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl I420 {
+    pub fn filled(width: u32, height: u32, y: u8, u: u8, v: u8) -> Self {
+        let luma = (width * height) as usize;
+        let chroma = luma_to_chroma(width, height);
+        let mut data = vec![y; luma + 2 * chroma];
+        data[luma..luma + chroma].iter_mut().for_each(|sample| *sample = u);
+        data[luma + chroma..].iter_mut().for_each(|sample| *sample = v);
+        I420 { width, height, data }
+    }
+
+    fn chroma_width(&self) -> u32 {
+        self.width.div_ceil(2)
+    }
+
+    fn chroma_height(&self) -> u32 {
+        self.height.div_ceil(2)
+    }
+
+    /// Luma sample, repeating the edge for coordinates outside the picture.
+    pub fn y(&self, x: u32, y: u32) -> u8 {
+        let (x, y) = (x.min(self.width - 1), y.min(self.height - 1));
+        self.data[(y * self.width + x) as usize]
+    }
+
+    pub fn u(&self, x: u32, y: u32) -> u8 {
+        let offset = (self.width * self.height) as usize;
+        self.chroma(offset, x, y)
+    }
+
+    pub fn v(&self, x: u32, y: u32) -> u8 {
+        let offset = (self.width * self.height) as usize + luma_to_chroma(self.width, self.height);
+        self.chroma(offset, x, y)
+    }
+
+    fn chroma(&self, offset: usize, x: u32, y: u32) -> u8 {
+        let (x, y) = (x.min(self.chroma_width() - 1), y.min(self.chroma_height() - 1));
+        self.data[offset + (y * self.chroma_width() + x) as usize]
+    }
+
+    /// Resizes by averaging the source samples each output sample covers,
+    /// which is only meant for shrinking.
+    pub fn scaled(&self, width: u32, height: u32) -> Self {
+        let mut out = I420::filled(width, height, 0, 0, 0);
+        let chroma_len = luma_to_chroma(width, height);
+        let (luma, chroma) = out.data.split_at_mut((width * height) as usize);
+        let (u_plane, v_plane) = chroma.split_at_mut(chroma_len);
+
+        let chroma_size = (width.div_ceil(2), height.div_ceil(2));
+        let chroma_from = (self.chroma_width(), self.chroma_height());
+        scale_plane(luma, (width, height), (self.width, self.height), |x, y| self.y(x, y));
+        scale_plane(u_plane, chroma_size, chroma_from, |x, y| self.u(x, y));
+        scale_plane(v_plane, chroma_size, chroma_from, |x, y| self.v(x, y));
+        out
+    }
+
+    /// Converts packed YUYV 4:2:2, averaging chroma over each pair of rows.
+    pub fn from_yuyv(width: u32, height: u32, yuyv: &[u8]) -> Result<Self> {
+        let stride = width as usize * 2;
+        anyhow::ensure!(yuyv.len() >= stride * height as usize, "short YUYV frame ({} bytes)", yuyv.len());
+
+        let mut out = I420::filled(width, height, 0, 0, 0);
+        let (luma, chroma) = out.data.split_at_mut((width * height) as usize);
+        let (u_plane, v_plane) = chroma.split_at_mut(luma_to_chroma(width, height));
+        let chroma_width = width.div_ceil(2) as usize;
+
+        for row in 0..height as usize {
+            let line = &yuyv[row * stride..(row + 1) * stride];
+            for col in 0..width as usize {
+                luma[row * width as usize + col] = line[col * 2];
+            }
+            if row % 2 == 0 {
+                let next = if row + 1 < height as usize { &yuyv[(row + 1) * stride..(row + 2) * stride] } else { line };
+                for pair in 0..width as usize / 2 {
+                    let idx = (row / 2) * chroma_width + pair;
+                    u_plane[idx] = ((line[pair * 4 + 1] as u16 + next[pair * 4 + 1] as u16) / 2) as u8;
+                    v_plane[idx] = ((line[pair * 4 + 3] as u16 + next[pair * 4 + 3] as u16) / 2) as u8;
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Converts packed 8-bit RGB using BT.601 limited range coefficients.
+    pub fn from_rgb(width: u32, height: u32, rgb: &[u8]) -> Result<Self> {
+        anyhow::ensure!(rgb.len() >= (width * height * 3) as usize, "short RGB frame ({} bytes)", rgb.len());
+
+        let mut out = I420::filled(width, height, 0, 0, 0);
+        let chroma_width = out.chroma_width();
+        let luma_len = (width * height) as usize;
+        let chroma_len = luma_to_chroma(width, height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let idx = ((y * width + x) * 3) as usize;
+                let (r, g, b) = (rgb[idx] as i32, rgb[idx + 1] as i32, rgb[idx + 2] as i32);
+                out.data[(y * width + x) as usize] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+                if x % 2 == 0 && y % 2 == 0 {
+                    let cidx = ((y / 2) * chroma_width + x / 2) as usize;
+                    out.data[luma_len + cidx] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+                    out.data[luma_len + chroma_len + cidx] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Box filters one plane of `from` samples into `plane`, which is `to` in size.
+fn scale_plane(plane: &mut [u8], (to_width, to_height): (u32, u32), (from_width, from_height): (u32, u32), sample: impl Fn(u32, u32) -> u8) {
+    // Source rows or columns covered by output index `idx`, at least one wide.
+    let span = |idx: u32, to: u32, from: u32| {
+        let start = idx * from / to;
+        start..((idx + 1) * from / to).max(start + 1)
+    };
+    for y in 0..to_height {
+        let rows = span(y, to_height, from_height);
+        for x in 0..to_width {
+            let cols = span(x, to_width, from_width);
+            let count = rows.len() * cols.len();
+            let sum = rows.clone().flat_map(|sy| cols.clone().map(move |sx| (sx, sy))).map(|(sx, sy)| sample(sx, sy) as usize).sum::<usize>();
+            plane[(y * to_width + x) as usize] = (sum / count) as u8;
+        }
+    }
+}
+
+fn luma_to_chroma(width: u32, height: u32) -> usize {
+    (width.div_ceil(2) * height.div_ceil(2)) as usize
+}
+
+/// Converts a raw captured frame to a picture. Encoded frames can't be decoded.
+pub fn decode(frame: &Frame) -> Result<I420> {
+    match frame.mode.format {
+        PixelFormat::Yuyv => I420::from_yuyv(frame.mode.width, frame.mode.height, &frame.data),
+        PixelFormat::Mjpeg => {
+            let mut decoder = jpeg_decoder::Decoder::new(&frame.data[..]);
+            let rgb = decoder.decode().context("Failed decoding MJPEG frame")?;
+            let info = decoder.info().context("MJPEG frame has no header")?;
+            anyhow::ensure!(info.pixel_format == jpeg_decoder::PixelFormat::RGB24, "unsupported MJPEG pixel format {:?}", info.pixel_format);
+            I420::from_rgb(info.width as u32, info.height as u32, &rgb)
+        }
+        format => anyhow::bail!("{:?} frames are encoded", format),
+    }
+}
+
+/// Turns captured frames into access units for the WebRTC track, encoding
+/// raw frames to H.264.
+pub enum Encoder {
+    /// The device already produces encoded video, typically from a hardware
+    /// encoder on the camera or SoC, so frames are forwarded untouched.
+    Passthrough,
+    Gst(GstEncoder),
+}
+
+impl Encoder {
+    /// Picks the cheapest way to get H.264 out of a capture mode.
+    pub fn for_mode(mode: &Mode) -> Result<Self> {
+        if mode.format.is_encoded() {
+            Ok(Encoder::Passthrough)
+        } else {
+            Encoder::encoding(mode.width, mode.height, mode.fps)
+        }
+    }
+
+    /// An encoder producing a smaller copy of a raw capture mode, or `None`
+    /// if the mode is already encoded and cannot be scaled.
+    pub fn scaled(mode: &Mode, width: u32, height: u32) -> Result<Option<Self>> {
+        if mode.format.is_encoded() {
+            Ok(None)
+        } else {
+            Encoder::encoding(width, height, mode.fps).map(Some)
+        }
+    }
+
+    fn encoding(width: u32, height: u32, fps: u32) -> Result<Self> {
+        let element = gstreamer::available()
+            .context("raw captures need a GStreamer H.264 encoder: v4l2h264enc, vah264enc, vaapih264enc, openh264enc or x264enc")?;
+        Ok(Encoder::Gst(GstEncoder::new(element, width, height, fps)?))
+    }
+
+    pub fn is_hardware(&self) -> bool {
+        match self {
+            Encoder::Passthrough => true,
+            Encoder::Gst(encoder) => encoder.is_hardware(),
+        }
+    }
+
+    /// The access unit to send for `frame`, if the encoder has one ready.
+    pub fn encode(&mut self, frame: &Frame) -> Result<Option<Bytes>> {
+        let encoder = match self {
+            Encoder::Passthrough => return Ok(Some(frame.data.clone())),
+            Encoder::Gst(encoder) => encoder,
+        };
+        let picture = decode(frame)?;
+        let (width, height) = encoder.size();
+        if (picture.width, picture.height) != (width, height) {
+            return encoder.encode(&picture.scaled(width, height));
+        }
+        encoder.encode(&picture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yuyv_to_i420() {
+        // 2x2 picture: Y0 U Y1 V per row.
+        let yuyv = [10, 100, 20, 200, 30, 110, 40, 210];
+        let picture = I420::from_yuyv(2, 2, &yuyv).unwrap();
+        assert_eq!(picture.data, vec![10, 20, 30, 40, 105, 205]);
+    }
+
+    #[test]
+    fn scales_down_by_averaging() {
+        let mut picture = I420::filled(4, 2, 0, 50, 150);
+        picture.data[..8].copy_from_slice(&[10, 20, 30, 40, 50, 60, 70, 80]);
+        let scaled = picture.scaled(2, 1);
+        assert_eq!(scaled.data, vec![35, 55, 50, 150]);
+    }
+
+    #[test]
+    fn rgb_to_i420() {
+        let white = I420::from_rgb(1, 1, &[255, 255, 255]).unwrap();
+        assert_eq!(white.data, vec![235, 128, 128]);
+        let black = I420::from_rgb(1, 1, &[0, 0, 0]).unwrap();
+        assert_eq!(black.data, vec![16, 128, 128]);
+    }
+}
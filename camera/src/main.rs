@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use clap::{App, crate_authors, crate_version};
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::{APIBuilder, API};
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::interceptor::registry::Registry;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+
+mod encoder;
+mod motion;
+mod payload;
+mod peer;
+mod pipeline;
+mod signalling;
+mod snapshot;
+mod source;
+mod stream;
+mod track;
+
+use encoder::Encoder;
+use peer::Session;
+use pipeline::{Motion, Output, Snapshots};
+use stream::VideoStream;
+use track::{Codec, MediaTrack};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let _ = env_logger::try_init();
+
+    let matches = App::new("rstream-camera")
+        .about("Streams a local camera to rstream viewers over WebRTC")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .arg("-s, --server=[url]     'Signalling server WebSocket URL'")
+        .arg("-n, --name=[name]      'Camera name shown to viewers'")
+        .arg("-d, --device=[spec]    'Capture device: test, v4l2:<path>, rtsp://<camera>, gst:<pipeline> or file:<path>'")
+        .arg("--once                 'Play files once instead of looping'")
+        .arg("--width=[pixels]       'Preferred capture width'")
+        .arg("--height=[pixels]      'Preferred capture height'")
+        .arg("--fps=[rate]           'Preferred capture frame rate'")
+        .arg("--sub-device=[spec]    'Capture device for the substream, such as a second RTSP URL'")
+        .arg("--layers=[widths]      'Comma separated widths of scaled-down layers encoded from raw captures'")
+        .arg("--motion               'Detect motion in raw captures and report it to the server'")
+        .arg("--motion-zones=[zones] 'Semicolon separated x,y,width,height rectangles watched for motion, in percent'")
+        .arg("--motion-sensitivity=[1-100] 'How small a change counts as motion'")
+        .arg("--snapshot-interval=[secs] 'Seconds between preview stills sent to the server, 0 for none'")
+        .arg("--stun=[url]           'STUN server for ICE'")
+        .arg("-u, --user=[name]      'User to connect to the server as'")
+        .arg("--password=[password]  'Password of the user, or else $RSTREAM_PASSWORD'")
+        .arg("-k, --insecure         'Accept invalid TLS certificates'")
+        .arg("-l, --list             'List capture devices and their modes'")
+        .get_matches();
+
+    if matches.is_present("list") {
+        for (spec, name) in source::list() {
+            println!("{}\t{}", spec, name);
+            if let Ok(device) = source::open(&spec, &Default::default()) {
+                for mode in device.modes().unwrap_or_default() {
+                    println!("\t{}", mode);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let url = matches.value_of("server").unwrap_or("wss://localhost:8080/ws");
+    let credentials = signalling::Credentials {
+        user: matches.value_of("user").context("The server only takes users, so give one with --user")?.to_string(),
+        password: match matches.value_of("password") {
+            Some(password) => password.to_string(),
+            None => std::env::var("RSTREAM_PASSWORD").context("Give the user's password with --password or $RSTREAM_PASSWORD")?,
+        },
+    };
+    let spec = matches.value_of("device").unwrap_or("test");
+    let width = matches.value_of_t("width").unwrap_or(640);
+    let height = matches.value_of_t("height").unwrap_or(480);
+    let fps = matches.value_of_t("fps").unwrap_or(15);
+
+    let options = source::Options { once: matches.is_present("once") };
+    let mut device = source::open(spec, &options)?;
+    let name = matches.value_of("name").map(str::to_string).unwrap_or_else(|| device.name());
+    let mode = source::negotiate(&device.modes()?, width, height, fps)
+        .with_context(|| format!("{} has no usable capture modes", spec))?;
+    let mut capture = device.open(mode)?;
+
+    let main = VideoStream::new(common::MAIN_STREAM, mode.format, mode.width, mode.height, mode.fps);
+    let mut outputs = vec![Output { encoder: Encoder::for_mode(&mode)?, track: main.track.clone() }];
+    let mut streams = vec![main];
+
+    // Lower resolution layers come from a second device if there is one, and
+    // from scaling down the main capture, which only works for raw formats.
+    let aspect = |width: u32| (width * mode.height / mode.width.max(1)) & !1;
+    if let Some(sub_spec) = matches.value_of("sub-device") {
+        let mut sub_device = source::open(sub_spec, &options)?;
+        let sub_mode = source::negotiate(&sub_device.modes()?, 320, aspect(320), mode.fps)
+            .with_context(|| format!("{} has no usable capture modes", sub_spec))?;
+        let sub = VideoStream::new(&stream::layer_id(0), sub_mode.format, sub_mode.width, sub_mode.height, sub_mode.fps);
+        spawn_capture(sub_device.open(sub_mode)?, vec![Output { encoder: Encoder::for_mode(&sub_mode)?, track: sub.track.clone() }], None, None);
+        streams.push(sub);
+    }
+    let layers = matches.value_of("layers").unwrap_or("320")
+        .split(',')
+        .map(|width| width.trim().parse::<u32>().map(|width| width & !1))
+        .collect::<Result<Vec<_>, _>>()
+        .context("--layers must be a list of widths")?;
+    for width in layers.into_iter().filter(|&width| width > 0 && width < mode.width) {
+        match Encoder::scaled(&mode, width, aspect(width))? {
+            Some(encoder) => {
+                let layer = VideoStream::new(&stream::layer_id(streams.len() - 1), mode.format, width, aspect(width), mode.fps);
+                outputs.push(Output { encoder, track: layer.track.clone() });
+                streams.push(layer);
+            }
+            None => {
+                log::info!("Not scaling: {:?} is sent as captured, use --sub-device for a second source", mode.format);
+                break;
+            }
+        }
+    }
+
+    let mut tracks = streams.iter().map(|stream| stream.track.clone()).collect::<Vec<_>>();
+    let mut audio_track = None;
+    if let Some(audio) = capture.take_audio() {
+        let track = Arc::new(MediaTrack::new(Codec::aac(&audio.config), "audio"));
+        tracks.push(track.clone());
+        audio_track = Some(track.clone());
+        tokio::spawn(pipeline::forward_audio(audio, track));
+    }
+    let (motion_tx, mut motion_rx) = mpsc::unbounded_channel();
+    let mut motion = None;
+    if matches.is_present("motion") {
+        let mut settings = motion::Settings::default();
+        if let Some(zones) = matches.value_of("motion-zones") {
+            settings.zones = zones.split(';').map(str::parse).collect::<Result<_>>()?;
+        }
+        if let Ok(sensitivity) = matches.value_of_t("motion-sensitivity") {
+            settings.sensitivity = sensitivity;
+        }
+        match motion::Detector::new(&mode, settings) {
+            Ok(detector) => motion = Some(Motion { detector, events: motion_tx }),
+            Err(err) => log::warn!("Not detecting motion: {:#}", err),
+        }
+    }
+    let (snapshot_tx, mut snapshot_rx) = mpsc::unbounded_channel();
+    let mut snapshots = None;
+    let interval = matches.value_of_t::<u64>("snapshot-interval").unwrap_or(10);
+    if interval > 0 {
+        let settings = snapshot::Settings { interval: Duration::from_secs(interval), ..Default::default() };
+        match snapshot::Snapshotter::new(&mode, settings) {
+            Ok(snapshotter) => snapshots = Some(Snapshots { snapshotter, stills: snapshot_tx }),
+            Err(err) => log::info!("Not sending previews: {:#}", err),
+        }
+    }
+    spawn_capture(capture, outputs, motion, snapshots);
+
+    let rtc_config = RTCConfiguration {
+        ice_servers: matches.value_of("stun")
+            .map(|url| vec![RTCIceServer { urls: vec![url.to_string()], ..Default::default() }])
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+
+    let (outbound, mut incoming) = signalling::connect(url, &credentials, matches.is_present("insecure")).await?;
+    let descriptor = common::CameraDescriptor { name, streams: streams.iter().map(|stream| stream.descriptor.clone()).collect() };
+    outbound.send(common::ServerMsg::Register { camera: descriptor })?;
+    let motion_outbound = outbound.clone();
+    tokio::spawn(async move {
+        while let Some(active) = motion_rx.recv().await {
+            log::info!("Motion {}", if active { "started" } else { "stopped" });
+            if motion_outbound.send(common::ServerMsg::Motion { active }).is_err() {
+                break;
+            }
+        }
+    });
+
+    let snapshot_outbound = outbound.clone();
+    tokio::spawn(async move {
+        while let Some(jpeg) = snapshot_rx.recv().await {
+            let jpeg = base64::engine::general_purpose::STANDARD.encode(jpeg);
+            if snapshot_outbound.send(common::ServerMsg::Snapshot { jpeg }).is_err() {
+                break;
+            }
+        }
+    });
+
+    let api = webrtc_api(&tracks)?;
+    let mut sessions: HashMap<Uuid, Session> = HashMap::new();
+    // Streams viewers have asked for, used when their offer arrives.
+    let mut watching: HashMap<Uuid, String> = HashMap::new();
+
+    // Only losing the connection ends the loop; malformed messages are dropped
+    // as they arrive.
+    while let Some(msg) = incoming.next().await {
+        let msg = msg?;
+        log::debug!("<- {:?}", msg);
+        match msg {
+            common::ClientMsg::Signal { signal: common::Signal::Offer { sdp }, sender } => {
+                if let Some(old) = sessions.remove(&sender) {
+                    old.close().await;
+                }
+                // The stream asked for goes first; the others are only sent
+                // to viewers offering a video section for each of them.
+                let stream = watching.get(&sender).and_then(|id| streams.iter().find(|stream| stream.descriptor.id == *id)).unwrap_or(&streams[0]);
+                let others = streams.iter().filter(|other| other.descriptor.id != stream.descriptor.id).map(|other| &other.track);
+                let session_tracks = std::iter::once(&stream.track).chain(others).chain(&audio_track).cloned().collect::<Vec<_>>();
+                match answer(&api, rtc_config.clone(), sender, outbound.clone(), sdp, &session_tracks).await {
+                    Ok((session, sdp)) => {
+                        let signal = common::Signal::Answer { sdp };
+                        outbound.send(common::ServerMsg::Signal { signal, recipient: sender })?;
+                        sessions.insert(sender, session);
+                    }
+                    Err(err) => {
+                        log::warn!("Rejecting offer from {}: {:#}", sender, err);
+                        outbound.send(common::ServerMsg::Signal { signal: common::Signal::Hangup, recipient: sender })?;
+                    }
+                }
+            }
+            common::ClientMsg::Signal { signal: common::Signal::NewIceCandidate { candidate }, sender } => {
+                if let Some(session) = sessions.get(&sender) {
+                    if let Err(err) = session.add_ice_candidate(candidate).await {
+                        log::warn!("Bad candidate from {}: {:#}", sender, err);
+                    }
+                }
+            }
+            common::ClientMsg::Signal { signal: common::Signal::Watch { stream }, sender } => {
+                match streams.iter().find(|candidate| candidate.descriptor.id == stream) {
+                    Some(selected) => watch(&sessions, &mut watching, sender, selected).await,
+                    None => log::warn!("Viewer {} asked for unknown stream {:?}", sender, stream),
+                }
+            }
+            common::ClientMsg::Signal { signal: common::Signal::Constrain { max_width, max_height }, sender } => {
+                let codec = sessions.get(&sender).and_then(Session::video_codec).unwrap_or_else(|| streams[0].descriptor.codec.clone());
+                if let Some(selected) = stream::select(&streams, &codec, max_width, max_height) {
+                    if watching.get(&sender) != Some(&selected.descriptor.id) {
+                        watch(&sessions, &mut watching, sender, selected).await;
+                    }
+                }
+            }
+            common::ClientMsg::Signal { signal: common::Signal::Hangup, sender } => {
+                watching.remove(&sender);
+                if let Some(session) = sessions.remove(&sender) {
+                    log::info!("Viewer {} hung up", sender);
+                    session.close().await;
+                }
+            }
+            common::ClientMsg::Signal { signal: common::Signal::Answer { .. }, sender } => {
+                log::warn!("Unexpected answer from {}", sender);
+            }
+            common::ClientMsg::Signal { signal: common::Signal::Motion { .. }, .. } => {}
+            // Only registering can be refused like this, and a camera the server won't take has nothing to do.
+            common::ClientMsg::Error { code: code @ (common::ErrorCode::NotGranted | common::ErrorCode::CameraNameTaken), message } => {
+                anyhow::bail!("Server refused to register the camera ({:?}): {}", code, message);
+            }
+            common::ClientMsg::Error { code, message } => {
+                log::warn!("Server rejected message ({:?}): {}", code, message);
+            }
+            common::ClientMsg::ListPeers { .. } | common::ClientMsg::ListCameras { .. } => {}
+        }
+    }
+
+    for session in sessions.values() {
+        session.close().await;
+    }
+    Ok(())
+}
+
+/// Starts a session answering a viewer's offer with `tracks`.
+async fn answer(api: &API, config: RTCConfiguration, viewer: Uuid, outbound: signalling::Outbound, offer: String, tracks: &[Arc<MediaTrack>]) -> Result<(Session, String)> {
+    let session = Session::new(api, config, viewer, outbound).await?;
+    match session.answer(offer, tracks).await {
+        Ok(sdp) => Ok((session, sdp)),
+        Err(err) => {
+            session.close().await;
+            Err(err)
+        }
+    }
+}
+
+/// Sends `stream` to a viewer from now on, switching its session over if it
+/// already has one.
+async fn watch(sessions: &HashMap<Uuid, Session>, watching: &mut HashMap<Uuid, String>, viewer: Uuid, stream: &VideoStream) {
+    let id = &stream.descriptor.id;
+    if let Some(session) = sessions.get(&viewer) {
+        if let Err(err) = session.switch_video(&stream.track).await {
+            return log::warn!("Cannot switch {} to the {} stream: {:#}", viewer, id, err);
+        }
+    }
+    log::info!("Viewer {} watching the {} stream", viewer, id);
+    watching.insert(viewer, id.clone());
+}
+
+/// Runs a capture in the background. The whole client exits with it, cleanly
+/// if the source simply ran out.
+fn spawn_capture(capture: Box<dyn source::Capture>, outputs: Vec<Output>, motion: Option<Motion>, snapshots: Option<Snapshots>) {
+    tokio::spawn(async move {
+        match pipeline::run(capture, outputs, motion, snapshots).await {
+            Ok(()) => {
+                log::info!("Capture finished");
+                std::process::exit(0);
+            }
+            Err(err) => {
+                log::error!("Capture stopped: {:#}", err);
+                std::process::exit(1);
+            }
+        }
+    });
+}
+
+fn webrtc_api(tracks: &[Arc<MediaTrack>]) -> Result<API> {
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs()?;
+    for codec in tracks.iter().map(|track| track.codec()).filter(|codec| !codec.is_builtin()) {
+        media_engine.register_codec(codec.parameters.clone(), codec.kind)?;
+    }
+    let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+    Ok(APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_interceptor_registry(registry)
+        .build())
+}
@@ -0,0 +1,203 @@
+//! Motion detection on raw captures.
+//!
+//! Frames are shrunk to a small luma grid a few times a second and compared
+//! with the previous one. Motion is reported once enough of the watched zones
+//! changes, and reported over after a quiet spell.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::encoder;
+use crate::source::{Frame, Mode};
+
+/// Width of the grid frames are compared at.
+const GRID_WIDTH: u32 = 64;
+/// Luma difference at which a grid sample counts as changed, well above sensor noise.
+const SAMPLE_THRESHOLD: u8 = 25;
+
+/// A rectangle of the picture, in fractions of its width and height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Zone {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Zone {
+    pub const WHOLE: Zone = Zone { x: 0.0, y: 0.0, width: 1.0, height: 1.0 };
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+}
+
+/// Parses `x,y,width,height` in percent of the picture.
+impl FromStr for Zone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s.split(',')
+            .map(|value| value.trim().parse::<u32>().ok().filter(|&value| value <= 100).map(|value| value as f32 / 100.0))
+            .collect::<Option<Vec<_>>>()
+            .with_context(|| format!("zone {:?} is not four percentages", s))?;
+        match values[..] {
+            [x, y, width, height] => Ok(Zone { x, y, width, height }),
+            _ => anyhow::bail!("zone {:?} is not x,y,width,height", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Parts of the picture watched, or empty to watch all of it.
+    pub zones: Vec<Zone>,
+    /// From 1 to 100: how small a change, as a share of the zones, counts as motion.
+    pub sensitivity: u8,
+    /// Time between analysed frames.
+    pub interval: Duration,
+    /// Motion is over once nothing has moved for this long.
+    pub hold: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            zones: Vec::new(),
+            sensitivity: 50,
+            interval: Duration::from_millis(200),
+            hold: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Settings {
+    /// Share of the watched samples that has to change: 5% at the default
+    /// sensitivity, down to 0.1% at the highest.
+    fn min_changed(&self) -> f32 {
+        (100 - self.sensitivity.clamp(1, 100)) as f32 / 1000.0 + 0.001
+    }
+}
+
+/// Share of the samples inside `zones` that differ between two grids.
+fn changed(previous: &[u8], current: &[u8], width: u32, height: u32, zones: &[Zone]) -> f32 {
+    let (mut watched, mut changed) = (0, 0);
+    for y in 0..height {
+        for x in 0..width {
+            let centre = ((x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32);
+            if !zones.iter().any(|zone| zone.contains(centre.0, centre.1)) {
+                continue;
+            }
+            let idx = (y * width + x) as usize;
+            watched += 1;
+            if previous[idx].abs_diff(current[idx]) > SAMPLE_THRESHOLD {
+                changed += 1;
+            }
+        }
+    }
+    if watched == 0 { 0.0 } else { changed as f32 / watched as f32 }
+}
+
+pub struct Detector {
+    settings: Settings,
+    grid: (u32, u32),
+    previous: Option<Vec<u8>>,
+    /// Capture time of the last analysed frame.
+    analysed: Option<Duration>,
+    /// Capture time motion was last seen, while it is ongoing.
+    moving: Option<Duration>,
+}
+
+impl Detector {
+    /// A detector for captures in `mode`, which has to be raw since encoded
+    /// video isn't decoded here.
+    pub fn new(mode: &Mode, mut settings: Settings) -> Result<Self> {
+        anyhow::ensure!(!mode.format.is_encoded(), "{:?} captures are passed through without decoding", mode.format);
+        if settings.zones.is_empty() {
+            settings.zones.push(Zone::WHOLE);
+        }
+        let grid = (GRID_WIDTH.min(mode.width), (GRID_WIDTH.min(mode.width) * mode.height / mode.width.max(1)).max(1));
+        Ok(Detector { settings, grid, previous: None, analysed: None, moving: None })
+    }
+
+    /// Looks at a frame if it is time to, returning whether motion started or stopped.
+    pub fn analyse(&mut self, frame: &Frame) -> Result<Option<bool>> {
+        if self.analysed.is_some_and(|analysed| frame.timestamp < analysed + self.settings.interval) {
+            return Ok(None);
+        }
+        self.analysed = Some(frame.timestamp);
+        let (width, height) = self.grid;
+        let picture = encoder::decode(frame)?.scaled(width, height);
+        let luma = picture.data[..(width * height) as usize].to_vec();
+        let moved = self.previous.as_ref()
+            .is_some_and(|previous| changed(previous, &luma, width, height, &self.settings.zones) >= self.settings.min_changed());
+        self.previous = Some(luma);
+        Ok(self.update(moved, frame.timestamp))
+    }
+
+    fn update(&mut self, moved: bool, time: Duration) -> Option<bool> {
+        match (moved, self.moving) {
+            (true, moving) => {
+                self.moving = Some(time);
+                moving.is_none().then_some(true)
+            }
+            (false, Some(last)) if time >= last + self.settings.hold => {
+                self.moving = None;
+                Some(false)
+            }
+            (false, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{Device, PixelFormat, TestPattern};
+
+    #[test]
+    fn parses_zones() {
+        assert_eq!("0, 50, 25, 50".parse::<Zone>().unwrap(), Zone { x: 0.0, y: 0.5, width: 0.25, height: 0.5 });
+        assert!("0,50,25".parse::<Zone>().is_err());
+        assert!("0,50,25,150".parse::<Zone>().is_err());
+    }
+
+    #[test]
+    fn counts_changes_inside_zones() {
+        let previous = [0; 4];
+        let current = [0, 100, 0, 20];
+        assert_eq!(changed(&previous, &current, 2, 2, &[Zone::WHOLE]), 0.25);
+        let right = Zone { x: 0.5, y: 0.0, width: 0.5, height: 1.0 };
+        assert_eq!(changed(&previous, &current, 2, 2, &[right]), 0.5);
+        let left = Zone { x: 0.0, y: 0.0, width: 0.5, height: 1.0 };
+        assert_eq!(changed(&previous, &current, 2, 2, &[left]), 0.0);
+    }
+
+    #[test]
+    fn detects_the_moving_test_pattern() {
+        let mode = Mode { format: PixelFormat::Yuyv, width: 128, height: 96, fps: 1000 };
+        let mut capture = TestPattern.open(mode).unwrap();
+        let settings = Settings { interval: Duration::from_millis(4), hold: Duration::from_millis(10), ..Default::default() };
+        let mut detector = Detector::new(&mode, settings).unwrap();
+
+        let mut events = Vec::new();
+        for _ in 0..10 {
+            let frame = capture.next_frame().unwrap();
+            events.extend(detector.analyse(&frame).unwrap());
+        }
+        assert_eq!(events, vec![true]);
+
+        // Nothing changes in a still picture, so motion ends after the hold.
+        let still = capture.next_frame().unwrap();
+        let ended = (0..4).filter_map(|step| {
+            let frame = Frame { mode, data: still.data.clone(), timestamp: still.timestamp + Duration::from_millis(5 * step) };
+            detector.analyse(&frame).unwrap()
+        }).collect::<Vec<_>>();
+        assert_eq!(ended, vec![false]);
+
+        let encoded = Mode { format: PixelFormat::H264, ..mode };
+        assert!(Detector::new(&encoded, Settings::default()).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! AAC over RTP as `mpeg4-generic` (RFC 3640).
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::Payloader;
+
+use super::{fmtp_parameter, AccessUnit, Depacketizer};
+
+/// Samples per AAC frame, and so the RTP timestamp step between access units.
+pub const SAMPLES_PER_FRAME: u32 = 1024;
+
+/// Stream parameters from the SDP `a=rtpmap` and `a=fmtp` lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AacConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// The AudioSpecificConfig decoders need to initialise.
+    pub config: Bytes,
+    size_length: u32,
+    index_length: u32,
+    index_delta_length: u32,
+}
+
+impl AacConfig {
+    /// Parses an rtpmap encoding such as `MPEG4-GENERIC/48000/2` and its fmtp
+    /// parameters, without the payload type prefix.
+    pub fn from_sdp(encoding: &str, fmtp: &str) -> Result<Self> {
+        let mut fields = encoding.split('/');
+        anyhow::ensure!(fields.next().is_some_and(|name| name.eq_ignore_ascii_case("mpeg4-generic")), "not an mpeg4-generic stream: {}", encoding);
+        let sample_rate = fields.next().context("rtpmap has no clock rate")?.parse().context("bad clock rate")?;
+        let channels = fields.next().map(str::parse).transpose().context("bad channel count")?.unwrap_or(1);
+
+        let param = |name: &str| fmtp_parameter(fmtp, name);
+        let number = |name: &str| -> Result<u32> {
+            param(name).map_or(Ok(0), |value| value.parse().with_context(|| format!("bad {}", name)))
+        };
+
+        let mode = param("mode").unwrap_or_default();
+        anyhow::ensure!(mode.eq_ignore_ascii_case("AAC-hbr") || mode.eq_ignore_ascii_case("AAC-lbr"), "unsupported mpeg4-generic mode {:?}", mode);
+        let config = param("config").context("fmtp has no config")?;
+        let config = (0..config.len() / 2)
+            .map(|idx| u8::from_str_radix(&config[idx * 2..idx * 2 + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .context("bad config")?;
+
+        let size_length = number("sizelength")?;
+        anyhow::ensure!((1..=16).contains(&size_length), "unsupported sizelength {}", size_length);
+        Ok(AacConfig {
+            sample_rate,
+            channels,
+            config: config.into(),
+            size_length,
+            index_length: number("indexlength")?,
+            index_delta_length: number("indexdeltalength")?,
+        })
+    }
+
+    /// fmtp parameters for the stream we republish, which always uses the
+    /// AAC-hbr header layout written by [`AacPayloader`].
+    pub fn fmtp_line(&self) -> String {
+        let config = self.config.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        format!("streamtype=5;profile-level-id=1;mode=AAC-hbr;sizelength=13;indexlength=3;indexdeltalength=3;config={}", config)
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.pos % 8)) & 1) as u32;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+}
+
+pub struct AacDepacketizer {
+    config: AacConfig,
+    /// An access unit spread over several packets, with its expected size.
+    fragment: Option<(BytesMut, usize)>,
+    next_seq: Option<u16>,
+}
+
+impl AacDepacketizer {
+    pub fn new(config: AacConfig) -> Self {
+        AacDepacketizer { config, fragment: None, next_seq: None }
+    }
+
+    fn sizes(&self, headers: &[u8], bits: usize) -> Option<Vec<usize>> {
+        let header_bits = self.config.size_length + self.config.index_length.max(self.config.index_delta_length);
+        let mut reader = BitReader { data: headers, pos: 0 };
+        let mut sizes = Vec::new();
+        while reader.pos + header_bits as usize <= bits {
+            sizes.push(reader.read(self.config.size_length)? as usize);
+            let index_length = if sizes.len() == 1 { self.config.index_length } else { self.config.index_delta_length };
+            reader.read(index_length)?;
+        }
+        Some(sizes)
+    }
+}
+
+impl Depacketizer for AacDepacketizer {
+    fn push(&mut self, packet: &Packet) -> Vec<AccessUnit> {
+        let seq = packet.header.sequence_number;
+        if self.next_seq.is_some_and(|next| next != seq) {
+            self.fragment = None;
+        }
+        self.next_seq = Some(seq.wrapping_add(1));
+
+        let payload = &packet.payload;
+        if payload.len() < 2 {
+            return Vec::new();
+        }
+        let bits = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        let data_start = 2 + bits.div_ceil(8);
+        let sizes = match payload.get(2..data_start).and_then(|headers| self.sizes(headers, bits)) {
+            Some(sizes) => sizes,
+            None => {
+                log::debug!("Malformed AAC AU headers");
+                return Vec::new();
+            }
+        };
+        let data = payload.slice(data_start.min(payload.len())..);
+        let timestamp = packet.header.timestamp;
+
+        if let (Some(&size), Some((buffer, expected))) = (sizes.first(), &mut self.fragment) {
+            if sizes.len() == 1 && size == *expected {
+                buffer.put_slice(&data);
+                if packet.header.marker || buffer.len() >= size {
+                    let (buffer, _) = self.fragment.take().unwrap();
+                    return vec![AccessUnit { data: buffer.freeze(), timestamp }];
+                }
+                return Vec::new();
+            }
+            self.fragment = None;
+        }
+
+        if let [size] = sizes[..] {
+            if size > data.len() {
+                let mut buffer = BytesMut::with_capacity(size);
+                buffer.put_slice(&data);
+                self.fragment = Some((buffer, size));
+                return Vec::new();
+            }
+        }
+
+        let mut units = Vec::with_capacity(sizes.len());
+        let mut offset = 0;
+        for (idx, size) in sizes.into_iter().enumerate() {
+            if offset + size > data.len() {
+                log::debug!("Truncated AAC access unit");
+                break;
+            }
+            units.push(AccessUnit {
+                data: data.slice(offset..offset + size),
+                timestamp: timestamp.wrapping_add(idx as u32 * SAMPLES_PER_FRAME),
+            });
+            offset += size;
+        }
+        units
+    }
+}
+
+/// Packetizes one access unit per packet with AAC-hbr headers, fragmenting
+/// those larger than the MTU.
+#[derive(Debug, Clone, Default)]
+pub struct AacPayloader;
+
+impl Payloader for AacPayloader {
+    fn payload(&mut self, mtu: usize, data: &Bytes) -> Result<Vec<Bytes>, webrtc::rtp::Error> {
+        if data.is_empty() || data.len() >= 1 << 13 {
+            return Ok(Vec::new());
+        }
+        let header = (data.len() as u16) << 3;
+        Ok(data.chunks(mtu.saturating_sub(4).max(1))
+            .map(|chunk| {
+                let mut payload = BytesMut::with_capacity(chunk.len() + 4);
+                payload.put_u16(16);
+                payload.put_u16(header);
+                payload.put_slice(chunk);
+                payload.freeze()
+            })
+            .collect())
+    }
+
+    fn clone_to(&self) -> Box<dyn Payloader + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::tests::packet;
+
+    fn config() -> AacConfig {
+        AacConfig::from_sdp(
+            "MPEG4-GENERIC/16000/1",
+            "streamtype=5; profile-level-id=15; mode=AAC-hbr; config=1408; SizeLength=13; IndexLength=3; IndexDeltaLength=3",
+        ).unwrap()
+    }
+
+    #[test]
+    fn parses_sdp() {
+        let config = config();
+        assert_eq!((config.sample_rate, config.channels), (16000, 1));
+        assert_eq!(config.config, Bytes::from_static(&[0x14, 0x08]));
+        assert!(config.fmtp_line().ends_with("config=1408"));
+        assert!(AacConfig::from_sdp("MPEG4-GENERIC/16000", "mode=CELP-cbr;config=00;sizelength=6").is_err());
+    }
+
+    #[test]
+    fn splits_aggregated_access_units() {
+        let mut depacketizer = AacDepacketizer::new(config());
+        // Two AU headers: sizes 2 and 3.
+        let payload = [0, 32, 0, 16, 0, 24, 1, 2, 3, 4, 5];
+        let units = depacketizer.push(&packet(0, 1000, true, &payload));
+        assert_eq!(units, vec![
+            AccessUnit { data: Bytes::from_static(&[1, 2]), timestamp: 1000 },
+            AccessUnit { data: Bytes::from_static(&[3, 4, 5]), timestamp: 1000 + SAMPLES_PER_FRAME },
+        ]);
+    }
+
+    #[test]
+    fn payloader_round_trip() {
+        let au = Bytes::from((0..300).map(|i| i as u8).collect::<Vec<_>>());
+        let payloads = AacPayloader.payload(128, &au).unwrap();
+        assert_eq!(payloads.len(), 3);
+
+        let mut depacketizer = AacDepacketizer::new(config());
+        let mut units = Vec::new();
+        for (idx, payload) in payloads.iter().enumerate() {
+            units.extend(depacketizer.push(&packet(idx as u16, 0, idx == 2, payload)));
+        }
+        assert_eq!(units, vec![AccessUnit { data: au, timestamp: 0 }]);
+    }
+}
@@ -0,0 +1,107 @@
+//! H.264 over RTP (RFC 6184), non-interleaved mode.
+
+use bytes::Bytes;
+use webrtc::rtp::packet::Packet;
+
+use super::{annex_b, AccessUnit, Depacketizer, NalAssembler};
+
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const STAP_A: u8 = 24;
+const FU_A: u8 = 28;
+
+#[derive(Default)]
+pub struct H264Depacketizer {
+    assembler: NalAssembler,
+    /// SPS and PPS from the SDP, for cameras that never repeat them in-band.
+    parameter_sets: Vec<Bytes>,
+}
+
+impl H264Depacketizer {
+    pub fn new(parameter_sets: Vec<Bytes>) -> Self {
+        H264Depacketizer { parameter_sets, ..Default::default() }
+    }
+
+    fn finish(&self, (timestamp, nals): (u32, Vec<Bytes>)) -> AccessUnit {
+        let has = |kind: u8| nals.iter().any(|nal| nal[0] & 0x1f == kind);
+        let data = if has(NAL_IDR) && !has(NAL_SPS) {
+            annex_b(self.parameter_sets.iter().chain(&nals))
+        } else {
+            annex_b(&nals)
+        };
+        AccessUnit { data, timestamp }
+    }
+}
+
+impl Depacketizer for H264Depacketizer {
+    fn push(&mut self, packet: &Packet) -> Vec<AccessUnit> {
+        let mut units = Vec::new();
+        units.extend(self.assembler.begin(packet));
+
+        let payload = &packet.payload;
+        match payload.first().map(|header| header & 0x1f) {
+            Some(1..=23) => self.assembler.push(payload.clone()),
+            Some(STAP_A) => {
+                let mut rest = payload.slice(1..);
+                while rest.len() > 2 {
+                    let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    if size > rest.len() - 2 {
+                        self.assembler.damage();
+                        break;
+                    }
+                    self.assembler.push(rest.slice(2..2 + size));
+                    rest = rest.slice(2 + size..);
+                }
+            }
+            Some(FU_A) if payload.len() > 2 => {
+                let fu = payload[1];
+                if fu & 0x80 != 0 {
+                    self.assembler.start_fragment(&[(payload[0] & 0xe0) | (fu & 0x1f)], &payload[2..]);
+                } else {
+                    self.assembler.continue_fragment(&payload[2..], fu & 0x40 != 0);
+                }
+            }
+            Some(kind) => {
+                log::debug!("Unsupported H.264 packetization type {}", kind);
+                self.assembler.damage();
+            }
+            None => {}
+        }
+
+        units.extend(self.assembler.end(packet.header.marker));
+        units.into_iter().map(|unit| self.finish(unit)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::tests::packet;
+
+    #[test]
+    fn reassembles_stap_a_and_fu_a() {
+        let mut depacketizer = H264Depacketizer::default();
+        assert!(depacketizer.push(&packet(1, 90, false, &[STAP_A, 0, 2, 0x67, 1, 0, 1, 0x68])).is_empty());
+        assert!(depacketizer.push(&packet(2, 90, false, &[0x7c, 0x85, 10, 11])).is_empty());
+        let units = depacketizer.push(&packet(3, 90, true, &[0x7c, 0x45, 12]));
+        assert_eq!(units, vec![AccessUnit {
+            data: Bytes::from_static(&[0, 0, 0, 1, 0x67, 1, 0, 0, 0, 1, 0x68, 0, 0, 0, 1, 0x65, 10, 11, 12]),
+            timestamp: 90,
+        }]);
+    }
+
+    #[test]
+    fn drops_damaged_units_and_injects_parameter_sets() {
+        let sps = Bytes::from_static(&[0x67, 1]);
+        let mut depacketizer = H264Depacketizer::new(vec![sps]);
+        depacketizer.push(&packet(1, 0, false, &[0x7c, 0x85, 10]));
+        // Sequence 2 is lost.
+        assert!(depacketizer.push(&packet(3, 0, true, &[0x7c, 0x45, 12])).is_empty());
+
+        let units = depacketizer.push(&packet(4, 3000, true, &[0x65, 20]));
+        assert_eq!(units[0].data, Bytes::from_static(&[0, 0, 0, 1, 0x67, 1, 0, 0, 0, 1, 0x65, 20]));
+        // Access units without a marker are flushed by the next timestamp.
+        assert!(depacketizer.push(&packet(5, 6000, false, &[0x41, 30])).is_empty());
+        assert_eq!(depacketizer.push(&packet(6, 9000, true, &[0x41, 40])).len(), 2);
+    }
+}
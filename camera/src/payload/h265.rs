@@ -0,0 +1,165 @@
+//! H.265 over RTP (RFC 7798), without DONL fields.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::Payloader;
+
+use super::{annex_b, split_annex_b, AccessUnit, Depacketizer, NalAssembler};
+
+const NAL_SPS: u8 = 33;
+const AP: u8 = 48;
+const FU: u8 = 49;
+
+fn nal_type(header: u8) -> u8 {
+    (header >> 1) & 0x3f
+}
+
+/// Random access point pictures (BLA, IDR and CRA).
+fn is_irap(kind: u8) -> bool {
+    (16..=21).contains(&kind)
+}
+
+#[derive(Default)]
+pub struct H265Depacketizer {
+    assembler: NalAssembler,
+    /// VPS, SPS and PPS from the SDP, for cameras that never repeat them in-band.
+    parameter_sets: Vec<Bytes>,
+}
+
+impl H265Depacketizer {
+    pub fn new(parameter_sets: Vec<Bytes>) -> Self {
+        H265Depacketizer { parameter_sets, ..Default::default() }
+    }
+
+    fn finish(&self, (timestamp, nals): (u32, Vec<Bytes>)) -> AccessUnit {
+        let irap = nals.iter().any(|nal| is_irap(nal_type(nal[0])));
+        let has_sps = nals.iter().any(|nal| nal_type(nal[0]) == NAL_SPS);
+        let data = if irap && !has_sps {
+            annex_b(self.parameter_sets.iter().chain(&nals))
+        } else {
+            annex_b(&nals)
+        };
+        AccessUnit { data, timestamp }
+    }
+}
+
+impl Depacketizer for H265Depacketizer {
+    fn push(&mut self, packet: &Packet) -> Vec<AccessUnit> {
+        let mut units = Vec::new();
+        units.extend(self.assembler.begin(packet));
+
+        let payload = &packet.payload;
+        if payload.len() > 2 {
+            match nal_type(payload[0]) {
+                0..=47 => self.assembler.push(payload.clone()),
+                AP => {
+                    let mut rest = payload.slice(2..);
+                    while rest.len() > 2 {
+                        let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                        if size > rest.len() - 2 {
+                            self.assembler.damage();
+                            break;
+                        }
+                        self.assembler.push(rest.slice(2..2 + size));
+                        rest = rest.slice(2 + size..);
+                    }
+                }
+                FU if payload.len() > 3 => {
+                    let fu = payload[2];
+                    if fu & 0x80 != 0 {
+                        let header = [(payload[0] & 0x81) | ((fu & 0x3f) << 1), payload[1]];
+                        self.assembler.start_fragment(&header, &payload[3..]);
+                    } else {
+                        self.assembler.continue_fragment(&payload[3..], fu & 0x40 != 0);
+                    }
+                }
+                kind => {
+                    log::debug!("Unsupported H.265 packetization type {}", kind);
+                    self.assembler.damage();
+                }
+            }
+        }
+
+        units.extend(self.assembler.end(packet.header.marker));
+        units.into_iter().map(|unit| self.finish(unit)).collect()
+    }
+}
+
+/// Packetizes Annex B access units, fragmenting NAL units larger than the MTU.
+#[derive(Debug, Clone, Default)]
+pub struct H265Payloader;
+
+impl Payloader for H265Payloader {
+    fn payload(&mut self, mtu: usize, data: &Bytes) -> Result<Vec<Bytes>, webrtc::rtp::Error> {
+        let mut payloads = Vec::new();
+        for nal in split_annex_b(data) {
+            if nal.len() < 3 {
+                continue;
+            }
+            if nal.len() <= mtu {
+                payloads.push(nal);
+                continue;
+            }
+
+            let header = [(nal[0] & 0x81) | (FU << 1), nal[1]];
+            let kind = nal_type(nal[0]);
+            let chunks = nal[2..].chunks(mtu.saturating_sub(3).max(1)).collect::<Vec<_>>();
+            for (idx, chunk) in chunks.iter().enumerate() {
+                let mut fu = kind;
+                if idx == 0 {
+                    fu |= 0x80;
+                }
+                if idx == chunks.len() - 1 {
+                    fu |= 0x40;
+                }
+                let mut payload = BytesMut::with_capacity(chunk.len() + 3);
+                payload.put_slice(&header);
+                payload.put_u8(fu);
+                payload.put_slice(chunk);
+                payloads.push(payload.freeze());
+            }
+        }
+        Ok(payloads)
+    }
+
+    fn clone_to(&self) -> Box<dyn Payloader + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::tests::packet;
+
+    #[test]
+    fn payloader_round_trip() {
+        // IDR_W_RADL (19) large enough to need three fragments.
+        let idr = (0..250).map(|i| (i % 200) as u8 + 1).collect::<Vec<_>>();
+        let mut idr_nal = vec![19 << 1, 1];
+        idr_nal.extend(&idr);
+        let au = annex_b(&[Bytes::from_static(&[NAL_SPS << 1, 1, 0xaa]), Bytes::from(idr_nal)]);
+
+        let payloads = H265Payloader.payload(100, &au).unwrap();
+        assert_eq!(payloads.len(), 4);
+        assert!(payloads.iter().all(|payload| payload.len() <= 100));
+        assert_eq!(nal_type(payloads[1][0]), FU);
+
+        let mut depacketizer = H265Depacketizer::default();
+        let mut units = Vec::new();
+        for (idx, payload) in payloads.iter().enumerate() {
+            units.extend(depacketizer.push(&packet(idx as u16, 0, idx == payloads.len() - 1, payload)));
+        }
+        assert_eq!(units, vec![AccessUnit { data: au, timestamp: 0 }]);
+    }
+
+    #[test]
+    fn injects_parameter_sets_before_irap() {
+        let vps = Bytes::from_static(&[32 << 1, 1, 0xbb]);
+        let mut depacketizer = H265Depacketizer::new(vec![vps.clone()]);
+        let units = depacketizer.push(&packet(0, 0, true, &[19 << 1, 1, 5]));
+        assert_eq!(units[0].data, annex_b(&[vps, Bytes::from_static(&[19 << 1, 1, 5])]));
+        let units = depacketizer.push(&packet(1, 3000, true, &[1 << 1, 1, 6]));
+        assert_eq!(units[0].data, Bytes::from_static(&[0, 0, 0, 1, 1 << 1, 1, 6]));
+    }
+}
@@ -0,0 +1,178 @@
+//! RTP payload formats: reassembling the encoded media IP cameras send and
+//! packetizing what we republish over WebRTC, for codecs the webrtc crate
+//! has no support for.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use webrtc::rtp::packet::Packet;
+
+mod aac;
+mod h264;
+mod h265;
+
+pub use aac::{AacConfig, AacDepacketizer, AacPayloader, SAMPLES_PER_FRAME};
+pub use h264::H264Depacketizer;
+pub use h265::{H265Depacketizer, H265Payloader};
+
+/// A complete frame of encoded media.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessUnit {
+    /// Annex B for video, raw access units for audio.
+    pub data: Bytes,
+    /// RTP timestamp, in the stream's clock rate.
+    pub timestamp: u32,
+}
+
+/// Reassembles RTP packets of one stream into access units.
+pub trait Depacketizer: Send {
+    /// Feeds the next packet, returning any access units it completes.
+    fn push(&mut self, packet: &Packet) -> Vec<AccessUnit>;
+}
+
+/// Looks up a parameter in an SDP `a=fmtp` parameter list such as
+/// `packetization-mode=1;sprop-parameter-sets=...`. Names are case-insensitive.
+pub fn fmtp_parameter<'a>(fmtp: &'a str, name: &str) -> Option<&'a str> {
+    fmtp.split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Splits an Annex B byte stream into NAL units, without start codes.
+pub fn split_annex_b(data: &Bytes) -> Vec<Bytes> {
+    let mut nals = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(start) = start {
+                // A four byte start code leaves a trailing zero on the previous unit.
+                let end = if data[i - 1] == 0 { i - 1 } else { i };
+                nals.push(data.slice(start..end));
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(start) = start {
+        nals.push(data.slice(start..));
+    }
+    nals.retain(|nal| !nal.is_empty());
+    nals
+}
+
+/// Joins NAL units into an Annex B byte stream.
+pub fn annex_b<'a>(nals: impl IntoIterator<Item = &'a Bytes>) -> Bytes {
+    let mut out = BytesMut::new();
+    for nal in nals {
+        out.put_slice(&[0, 0, 0, 1]);
+        out.put_slice(nal);
+    }
+    out.freeze()
+}
+
+/// Collects the NAL units of one access unit for the H.264 and H.265
+/// depacketizers, discarding access units damaged by packet loss.
+#[derive(Default)]
+struct NalAssembler {
+    timestamp: u32,
+    nals: Vec<Bytes>,
+    fragment: Option<BytesMut>,
+    next_seq: Option<u16>,
+    damaged: bool,
+}
+
+impl NalAssembler {
+    /// Starts processing `packet`, returning the previous access unit if the
+    /// packet belongs to a new one and the previous one was never marked.
+    fn begin(&mut self, packet: &Packet) -> Option<(u32, Vec<Bytes>)> {
+        let seq = packet.header.sequence_number;
+        if self.next_seq.is_some_and(|next| next != seq) {
+            log::debug!("RTP sequence gap before {}, dropping access unit", seq);
+            self.damaged = true;
+            self.fragment = None;
+        }
+        self.next_seq = Some(seq.wrapping_add(1));
+
+        let mut previous = None;
+        if packet.header.timestamp != self.timestamp {
+            previous = self.take();
+            self.timestamp = packet.header.timestamp;
+        }
+        previous
+    }
+
+    fn push(&mut self, nal: Bytes) {
+        if !nal.is_empty() {
+            self.nals.push(nal);
+        }
+    }
+
+    fn start_fragment(&mut self, header: &[u8], data: &[u8]) {
+        let mut fragment = BytesMut::with_capacity(header.len() + data.len() * 4);
+        fragment.put_slice(header);
+        fragment.put_slice(data);
+        self.fragment = Some(fragment);
+    }
+
+    fn continue_fragment(&mut self, data: &[u8], end: bool) {
+        match &mut self.fragment {
+            Some(fragment) => fragment.put_slice(data),
+            None => self.damaged = true,
+        }
+        if end {
+            if let Some(fragment) = self.fragment.take() {
+                self.push(fragment.freeze());
+            }
+        }
+    }
+
+    fn damage(&mut self) {
+        self.damaged = true;
+    }
+
+    /// Finishes processing a packet, returning the access unit it completes.
+    fn end(&mut self, marker: bool) -> Option<(u32, Vec<Bytes>)> {
+        if marker {
+            self.take()
+        } else {
+            None
+        }
+    }
+
+    fn take(&mut self) -> Option<(u32, Vec<Bytes>)> {
+        let nals = std::mem::take(&mut self.nals);
+        let damaged = std::mem::replace(&mut self.damaged, false);
+        self.fragment = None;
+        if damaged || nals.is_empty() {
+            return None;
+        }
+        Some((self.timestamp, nals))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use webrtc::rtp::header::Header;
+
+    pub fn packet(seq: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Packet {
+        Packet {
+            header: Header { sequence_number: seq, timestamp, marker, ..Default::default() },
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    #[test]
+    fn annex_b_round_trip() {
+        let data = Bytes::from_static(&[0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 1, 0x65, 4, 5]);
+        let nals = split_annex_b(&data);
+        assert_eq!(nals, vec![
+            Bytes::from_static(&[0x67, 1, 2]),
+            Bytes::from_static(&[0x68, 3]),
+            Bytes::from_static(&[0x65, 4, 5]),
+        ]);
+        assert_eq!(split_annex_b(&annex_b(&nals)), nals);
+    }
+}
@@ -0,0 +1,240 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use uuid::Uuid;
+use webrtc::api::API;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+
+use crate::signalling::Outbound;
+use crate::track::MediaTrack;
+
+/// A WebRTC connection to a single viewer.
+pub struct Session {
+    viewer: Uuid,
+    pc: Arc<RTCPeerConnection>,
+    /// The sender of the video track, and the codec it was negotiated with.
+    video: Mutex<Option<(Arc<RTCRtpSender>, String)>>,
+}
+
+impl Session {
+    pub async fn new(
+        api: &API,
+        config: RTCConfiguration,
+        viewer: Uuid,
+        outbound: Outbound,
+    ) -> Result<Self> {
+        let pc = Arc::new(api.new_peer_connection(config).await?);
+
+        pc.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+            let outbound = outbound.clone();
+            Box::pin(async move {
+                let candidate = match candidate.map(|candidate| candidate.to_json()) {
+                    Some(Ok(candidate)) => candidate,
+                    Some(Err(err)) => return log::error!("Failed serialising candidate: {}", err),
+                    None => return,
+                };
+                let candidate = common::IceCandidate {
+                    candidate: candidate.candidate,
+                    sdp_mid: candidate.sdp_mid,
+                    sdp_m_line_index: candidate.sdp_mline_index,
+                };
+                let signal = common::Signal::NewIceCandidate { candidate };
+                let _ = outbound.send(common::ServerMsg::Signal { signal, recipient: viewer });
+            })
+        }));
+
+        pc.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+            log::info!("Viewer {}: {}", viewer, state);
+            Box::pin(async {})
+        }));
+
+        Ok(Session { viewer, pc, video: Mutex::new(None) })
+    }
+
+    /// Applies the viewer's offer and returns our answer, sending every track
+    /// whose codec the viewer offered to receive. Video tracks fill as many
+    /// video sections as were offered, in order, so a viewer such as the
+    /// server's SFU can receive every layer by offering a section for each.
+    pub async fn answer(&self, offer: String, tracks: &[Arc<MediaTrack>]) -> Result<String> {
+        let (offered, mut video_sections) = offered_media(&offer)?;
+        self.pc.set_remote_description(RTCSessionDescription::offer(offer)?).await?;
+
+        for track in tracks {
+            let codec = track.codec().name();
+            if !offered.iter().any(|offered| offered.eq_ignore_ascii_case(codec)) {
+                log::info!("Viewer {} cannot receive {}, not sending it", self.viewer, codec);
+                continue;
+            }
+            let video = track.codec().kind == RTPCodecType::Video;
+            if video {
+                if video_sections == 0 {
+                    continue;
+                }
+                video_sections -= 1;
+            }
+            let sender = self.pc.add_track(track.track()).await?;
+            let mut sending = self.video.lock().unwrap();
+            if video && sending.is_none() {
+                *sending = Some((sender.clone(), codec.to_string()));
+            }
+            drop(sending);
+            // RTCP has to be read for interceptors such as NACK to work.
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 1500];
+                while sender.read(&mut buf).await.is_ok() {}
+            });
+        }
+
+        let answer = self.pc.create_answer(None).await?;
+        self.pc.set_local_description(answer.clone()).await?;
+        Ok(answer.sdp)
+    }
+
+    /// Encoding name of the first video being sent, once the offer is answered.
+    pub fn video_codec(&self) -> Option<String> {
+        self.video.lock().unwrap().as_ref().map(|(_, codec)| codec.clone())
+    }
+
+    /// Swaps the first video being sent for another track without
+    /// renegotiating, which only works if both use the same codec.
+    pub async fn switch_video(&self, track: &MediaTrack) -> Result<()> {
+        let (sender, codec) = self.video.lock().unwrap().clone().ok_or_else(|| anyhow::anyhow!("session is not sending video"))?;
+        anyhow::ensure!(track.codec().name().eq_ignore_ascii_case(&codec), "cannot switch from {} to {} without renegotiating", codec, track.codec().name());
+        sender.replace_track(Some(track.track())).await?;
+        Ok(())
+    }
+
+    pub async fn add_ice_candidate(&self, candidate: common::IceCandidate) -> Result<()> {
+        self.pc.add_ice_candidate(RTCIceCandidateInit {
+            candidate: candidate.candidate,
+            sdp_mid: candidate.sdp_mid,
+            sdp_mline_index: candidate.sdp_m_line_index,
+            username_fragment: None,
+        }).await?;
+        Ok(())
+    }
+
+    pub async fn close(&self) {
+        if let Err(err) = self.pc.close().await {
+            log::warn!("Failed closing session with {}: {}", self.viewer, err);
+        }
+    }
+}
+
+/// Encoding names of every format in an SDP offer, and how many video
+/// sections it has.
+fn offered_media(offer: &str) -> Result<(Vec<String>, usize)> {
+    let description = common::sdp::SessionDescription::parse(offer)?;
+    let codecs = description.media.iter()
+        .flat_map(|section| section.formats.iter().filter_map(move |format| section.codec(format)))
+        .map(str::to_string)
+        .collect();
+    let video = description.media.iter().filter(|section| section.kind == "video" && section.port != "0").count();
+    Ok((codecs, video))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+    use webrtc::rtp::packet::Packet;
+    use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+    use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+
+    use super::*;
+    use crate::source::PixelFormat;
+    use crate::stream::VideoStream;
+
+    /// Writes frames filled with `fill` to `track` until one arrives, checking
+    /// that the packets' sequence numbers only ever count on.
+    async fn deliver(track: &MediaTrack, fill: u8, received: &mut mpsc::UnboundedReceiver<Packet>, last: &mut Option<u16>) {
+        let frame = Bytes::from(vec![fill; 100]);
+        loop {
+            track.write(&frame, Duration::from_millis(33)).await.unwrap();
+            tokio::select! {
+                packet = received.recv() => {
+                    let packet = packet.unwrap();
+                    let sequence_number = packet.header.sequence_number;
+                    if let Some(last) = last.replace(sequence_number) {
+                        assert!((1..0x8000).contains(&sequence_number.wrapping_sub(last)), "packet {} came after {}", sequence_number, last);
+                    }
+                    if packet.payload.last() == Some(&fill) {
+                        return;
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_millis(33)) => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn switched_layer_reaches_viewer() {
+        let layers = [
+            VideoStream::new(common::MAIN_STREAM, PixelFormat::Vp8, 640, 480, 30),
+            VideoStream::new(&crate::stream::layer_id(0), PixelFormat::Vp8, 320, 240, 30),
+        ];
+        let tracks = layers.iter().map(|layer| layer.track.clone()).collect::<Vec<_>>();
+        let api = crate::webrtc_api(&tracks).unwrap();
+
+        let viewer = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await.unwrap());
+        let recvonly = RTCRtpTransceiverInit { direction: RTCRtpTransceiverDirection::Recvonly, send_encodings: Vec::new() };
+        viewer.add_transceiver_from_kind(RTPCodecType::Video, &[recvonly]).await.unwrap();
+        let (packets, mut received) = mpsc::unbounded_channel();
+        viewer.on_track(Box::new(move |track, _| {
+            let packets = packets.clone();
+            tokio::spawn(async move {
+                let track = match track {
+                    Some(track) => track,
+                    None => return,
+                };
+                while let Ok((packet, _)) = track.read_rtp().await {
+                    if packets.send(packet).is_err() {
+                        break;
+                    }
+                }
+            });
+            Box::pin(async {})
+        }));
+        let mut gathered = viewer.gathering_complete_promise().await;
+        let offer = viewer.create_offer(None).await.unwrap();
+        viewer.set_local_description(offer).await.unwrap();
+        let _ = gathered.recv().await;
+        let offer = viewer.local_description().await.unwrap().sdp;
+
+        let (outbound, mut signals) = mpsc::unbounded_channel();
+        let session = Session::new(&api, RTCConfiguration::default(), Uuid::new_v4(), outbound).await.unwrap();
+        // Only the main layer goes out, as the viewer offered one video section.
+        let answer = session.answer(offer, &tracks).await.unwrap();
+        viewer.set_remote_description(RTCSessionDescription::answer(answer).unwrap()).await.unwrap();
+        let candidates = viewer.clone();
+        tokio::spawn(async move {
+            while let Some(common::ServerMsg::Signal { signal: common::Signal::NewIceCandidate { candidate }, .. }) = signals.recv().await {
+                let candidate = RTCIceCandidateInit {
+                    candidate: candidate.candidate,
+                    sdp_mid: candidate.sdp_mid,
+                    sdp_mline_index: candidate.sdp_m_line_index,
+                    username_fragment: None,
+                };
+                let _ = candidates.add_ice_candidate(candidate).await;
+            }
+        });
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            let mut last = None;
+            deliver(&layers[0].track, 1, &mut received, &mut last).await;
+            session.switch_video(&layers[1].track).await.unwrap();
+            deliver(&layers[1].track, 2, &mut received, &mut last).await;
+        }).await.expect("The switched layer never reached the viewer");
+
+        session.close().await;
+        viewer.close().await.unwrap();
+    }
+}
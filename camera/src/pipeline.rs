@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+
+use crate::encoder::Encoder;
+use crate::motion::Detector;
+use crate::payload::SAMPLES_PER_FRAME;
+use crate::snapshot::Snapshotter;
+use crate::source::{AudioStream, Capture, EndOfStream};
+use crate::track::MediaTrack;
+
+/// A track fed from a capture, with the encoder producing its access units.
+pub struct Output {
+    pub encoder: Encoder,
+    pub track: Arc<MediaTrack>,
+}
+
+/// Motion detection on the frames of a capture.
+pub struct Motion {
+    pub detector: Detector,
+    /// Receives `true` when motion starts and `false` when it stops.
+    pub events: mpsc::UnboundedSender<bool>,
+}
+
+/// Periodic JPEG stills of a capture.
+pub struct Snapshots {
+    pub snapshotter: Snapshotter,
+    pub stills: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// Captures and encodes on a blocking thread, writing each access unit to the
+/// track of every output. Runs until the capture fails or reaches the end of
+/// its stream.
+pub async fn run(mut capture: Box<dyn Capture>, outputs: Vec<Output>, mut motion: Option<Motion>, mut snapshots: Option<Snapshots>) -> Result<()> {
+    let mode = capture.mode();
+    let frame_duration = Duration::from_secs(1) / mode.fps.max(1);
+    let (tx, mut rx) = mpsc::channel(2);
+    let (mut encoders, tracks): (Vec<_>, Vec<_>) = outputs.into_iter().map(|output| (output.encoder, output.track)).unzip();
+
+    let worker = tokio::task::spawn_blocking(move || -> Result<()> {
+        let hardware = encoders.iter().all(Encoder::is_hardware);
+        log::info!("Streaming {} using {} encoding", mode, if hardware { "hardware" } else { "software" });
+
+        let mut last = None;
+        loop {
+            let frame = match capture.next_frame() {
+                Err(err) if err.is::<EndOfStream>() => return Ok(()),
+                frame => frame?,
+            };
+            let duration = last.map_or(frame_duration, |last| frame.timestamp.saturating_sub(last));
+            last = Some(frame.timestamp);
+
+            if let Some(motion) = &mut motion {
+                match motion.detector.analyse(&frame) {
+                    Ok(Some(active)) => {
+                        let _ = motion.events.send(active);
+                    }
+                    Ok(None) => {}
+                    Err(err) => log::warn!("Motion detection failed: {:#}", err),
+                }
+            }
+            if let Some(snapshots) = &mut snapshots {
+                match snapshots.snapshotter.take(&frame) {
+                    Ok(Some(jpeg)) => {
+                        let _ = snapshots.stills.send(jpeg);
+                    }
+                    Ok(None) => {}
+                    Err(err) => log::warn!("Snapshot failed: {:#}", err),
+                }
+            }
+            let units = encoders.iter_mut().map(|encoder| encoder.encode(&frame)).collect::<Result<Vec<_>>>()?;
+            if tx.blocking_send((units, duration)).is_err() {
+                return Ok(());
+            }
+        }
+    });
+
+    while let Some((units, duration)) = rx.recv().await {
+        for (track, data) in tracks.iter().zip(&units).filter_map(|(track, data)| Some((track, data.as_ref()?))) {
+            if let Err(err) = track.write(data, duration).await {
+                log::warn!("Failed writing sample: {}", err);
+            }
+        }
+    }
+    worker.await?
+}
+
+/// Writes audio from the capture to `track` as it arrives, untouched.
+pub async fn forward_audio(mut audio: AudioStream, track: Arc<MediaTrack>) {
+    let frame_duration = Duration::from_secs(SAMPLES_PER_FRAME as u64) / audio.config.sample_rate.max(1);
+    let mut last = None;
+    while let Some(frame) = audio.frames.recv().await {
+        let duration = last.map_or(frame_duration, |last| frame.timestamp.saturating_sub(last));
+        last = Some(frame.timestamp);
+        if let Err(err) = track.write(&frame.data, duration).await {
+            log::warn!("Failed writing audio: {}", err);
+        }
+    }
+}
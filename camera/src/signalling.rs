@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use futures_util::{future, SinkExt, Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::Connector;
+
+/// Sends messages to the signalling server. Cheap to clone.
+pub type Outbound = mpsc::UnboundedSender<common::ServerMsg>;
+
+/// The user the camera connects as, since the server only takes users.
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+/// Logs in at the REST API of the server behind the WebSocket URL `url`,
+/// returning the URL with the token added.
+async fn login(url: &str, credentials: &Credentials, insecure: bool) -> Result<reqwest::Url> {
+    let mut socket = reqwest::Url::parse(url).with_context(|| format!("Malformed server URL {}", url))?;
+    let mut api = socket.join("/api/login")?;
+    let scheme = if socket.scheme() == "ws" { "http" } else { "https" };
+    api.set_scheme(scheme).map_err(|()| anyhow::anyhow!("Can't log in at {}", url))?;
+    let client = reqwest::Client::builder().danger_accept_invalid_certs(insecure).build()?;
+    let response = client.post(api.clone())
+        .json(&common::Credentials { name: credentials.user.clone(), password: credentials.password.clone() })
+        .send()
+        .await
+        .with_context(|| format!("Failed logging in at {}", api))?;
+    anyhow::ensure!(response.status().is_success(), "{} refused user {}: {}", api, credentials.user, response.status());
+    let token: common::Token = response.json().await.context("Malformed login response")?;
+    socket.query_pairs_mut().append_pair("token", &token.token);
+    Ok(socket)
+}
+
+/// Logs in and connects to the server's `/ws` endpoint, returning a handle
+/// for sending and the stream of messages from the server. Messages that
+/// can't be parsed are logged and skipped, so the stream only fails when the
+/// connection does.
+///
+/// `insecure` skips certificate verification, for the self-signed certificate
+/// the development server uses.
+pub async fn connect(url: &str, credentials: &Credentials, insecure: bool) -> Result<(Outbound, impl Stream<Item = Result<common::ClientMsg>> + Unpin)> {
+    let tls = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(insecure)
+        .build()?;
+    let authorized = login(url, credentials, insecure).await?;
+    let (socket, _) = tokio_tungstenite::connect_async_tls_with_config(authorized.as_str(), None, true, Some(Connector::NativeTls(tls)))
+        .await
+        .with_context(|| format!("Failed connecting to {}", url))?;
+    log::info!("Connected to {} as {}", url, credentials.user);
+
+    let (mut sink, stream) = socket.split();
+    let (outbound, mut rx) = mpsc::unbounded_channel::<common::ServerMsg>();
+
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            log::debug!("-> {:?}", msg);
+            let json = serde_json::to_string(&msg).unwrap();
+            if let Err(err) = sink.send(Message::text(json)).await {
+                log::error!("Failed sending to server: {}", err);
+                break;
+            }
+        }
+    });
+
+    let incoming = stream.filter_map(|msg| future::ready(
+        match msg {
+            Ok(Message::Text(text)) => match serde_json::from_str(&text) {
+                Ok(msg) => Some(Ok(msg)),
+                Err(err) => {
+                    log::warn!("Ignoring malformed message from server: {}", err);
+                    None
+                }
+            },
+            Ok(Message::Close(frame)) => Some(Err(anyhow::anyhow!("Server closed the connection: {:?}", frame))),
+            Ok(_) => None,
+            Err(err) => Some(Err(err.into())),
+        }
+    ));
+
+    Ok((outbound, incoming))
+}
@@ -0,0 +1,290 @@
+//! JPEG stills of the capture, sent to the server for previews.
+//!
+//! Raw frames are scaled and encoded here. Encoded captures are passed
+//! through without decoding, so once a still is due the next keyframe is
+//! decoded on the side by a one-shot `gst-launch-1.0`, leaving the capture
+//! to carry on meanwhile.
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use crate::encoder::{self, I420};
+use crate::payload::{annex_b, split_annex_b};
+use crate::source::{Frame, Mode, PixelFormat, GST_LAUNCH};
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Time between stills.
+    pub interval: Duration,
+    /// Width stills are scaled down to, keeping the aspect ratio.
+    pub width: u32,
+    /// JPEG quality from 1 to 100.
+    pub quality: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings { interval: Duration::from_secs(10), width: 320, quality: 75 }
+    }
+}
+
+/// Encodes a picture as a baseline JPEG.
+pub fn jpeg(picture: &I420, quality: u8) -> Result<Vec<u8>> {
+    let mut ycbcr = Vec::with_capacity((picture.width * picture.height * 3) as usize);
+    for y in 0..picture.height {
+        for x in 0..picture.width {
+            ycbcr.extend_from_slice(&[picture.y(x, y), picture.u(x / 2, y / 2), picture.v(x / 2, y / 2)]);
+        }
+    }
+    let mut out = Vec::new();
+    jpeg_encoder::Encoder::new(&mut out, quality.clamp(1, 100))
+        .encode(&ycbcr, picture.width as u16, picture.height as u16, jpeg_encoder::ColorType::Ycbcr)
+        .context("Failed encoding JPEG")?;
+    Ok(out)
+}
+
+/// Whether an encoded frame can be decoded on its own.
+fn is_keyframe(format: PixelFormat, data: &Bytes) -> bool {
+    match format {
+        PixelFormat::H264 => split_annex_b(data).iter().any(|nal| nal[0] & 0x1f == 5),
+        // IRAP pictures: BLA, IDR and CRA.
+        PixelFormat::H265 => split_annex_b(data).iter().any(|nal| (16..=21).contains(&((nal[0] >> 1) & 0x3f))),
+        PixelFormat::Vp8 => data.first().is_some_and(|byte| byte & 1 == 0),
+        PixelFormat::Vp9 => data.first().is_some_and(|&byte| {
+            // Two bits of frame_marker, profile_low_bit and profile_high_bit,
+            // a reserved bit in profile 3, then show_existing_frame and frame_type.
+            let profile = (byte >> 5 & 1) | (byte >> 3 & 2);
+            let show_existing = if profile == 3 { 2 } else { 3 };
+            byte >> show_existing & 1 == 0 && byte >> (show_existing - 1) & 1 == 0
+        }),
+        PixelFormat::Mjpeg | PixelFormat::Yuyv => true,
+    }
+}
+
+/// Whether a NAL unit is a parameter set, which keyframes need ahead of them.
+fn is_parameter_set(format: PixelFormat, nal: &[u8]) -> bool {
+    match format {
+        PixelFormat::H264 => matches!(nal[0] & 0x1f, 7 | 8),
+        PixelFormat::H265 => matches!((nal[0] >> 1) & 0x3f, 32..=34),
+        _ => false,
+    }
+}
+
+/// Pipeline decoding a keyframe written to stdin into a JPEG on stdout.
+fn launch_description(format: PixelFormat, (width, height): (u32, u32), quality: u8) -> String {
+    let parser = match format {
+        PixelFormat::H264 => "h264parse",
+        PixelFormat::H265 => "h265parse",
+        _ => "ivfparse",
+    };
+    format!(
+        "fdsrc fd=0 ! {} ! decodebin ! videoconvert ! videoscale ! video/x-raw,width={},height={} ! \
+         jpegenc quality={} ! fdsink fd=1",
+        parser, width, height, quality.clamp(1, 100),
+    )
+}
+
+/// A VP8 or VP9 frame in an IVF file of its own, which `ivfparse` can frame.
+fn ivf(mode: &Mode, frame: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(44 + frame.len());
+    data.extend_from_slice(b"DKIF");
+    data.extend_from_slice(&0u16.to_le_bytes());
+    data.extend_from_slice(&32u16.to_le_bytes());
+    data.extend_from_slice(if mode.format == PixelFormat::Vp9 { b"VP90" } else { b"VP80" });
+    data.extend_from_slice(&(mode.width as u16).to_le_bytes());
+    data.extend_from_slice(&(mode.height as u16).to_le_bytes());
+    data.extend_from_slice(&mode.fps.max(1).to_le_bytes());
+    data.extend_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.extend_from_slice(frame);
+    data
+}
+
+/// Runs `description` over `input`, returning what it writes.
+fn decode(description: &str, input: Vec<u8>) -> Result<Vec<u8>> {
+    let mut child = Command::new(GST_LAUNCH)
+        .arg("-q")
+        .arg(description)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed running {}; is GStreamer installed?", GST_LAUNCH))?;
+    let mut stdin = child.stdin.take().context("no stdin for gst-launch")?;
+    // Written from a thread of its own, as the child blocks writing output
+    // that isn't read while it is being fed.
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let mut jpeg = Vec::new();
+    child.stdout.take().context("no stdout from gst-launch")?.read_to_end(&mut jpeg)?;
+    let status = child.wait()?;
+    let _ = writer.join();
+    anyhow::ensure!(status.success() && !jpeg.is_empty(), "{} failed decoding a keyframe ({})", GST_LAUNCH, status);
+    Ok(jpeg)
+}
+
+/// Decodes keyframes of an encoded capture into stills.
+struct Keyframes {
+    mode: Mode,
+    /// Latest H.264 or H.265 parameter sets, which keyframes may leave out.
+    parameter_sets: Vec<Bytes>,
+    /// The still being decoded.
+    decoding: Option<Receiver<Result<Vec<u8>>>>,
+}
+
+impl Keyframes {
+    /// Looks at every frame for parameter sets, returning whether it is a keyframe.
+    fn observe(&mut self, frame: &Frame) -> bool {
+        if matches!(self.mode.format, PixelFormat::H264 | PixelFormat::H265) {
+            let sets = split_annex_b(&frame.data).into_iter().filter(|nal| is_parameter_set(self.mode.format, nal)).collect::<Vec<_>>();
+            if !sets.is_empty() {
+                self.parameter_sets = sets;
+            }
+        }
+        is_keyframe(self.mode.format, &frame.data)
+    }
+
+    /// The finished still, if the last decode has come back.
+    fn finished(&mut self) -> Result<Option<Vec<u8>>> {
+        let result = match &self.decoding {
+            Some(decoding) => match decoding.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => Err(anyhow::anyhow!("keyframe decoder stopped")),
+            },
+            None => return Ok(None),
+        };
+        self.decoding = None;
+        result.map(Some)
+    }
+
+    /// Starts decoding a keyframe into a still of `size`.
+    fn start(&mut self, frame: &Frame, size: (u32, u32), quality: u8) {
+        let input = match self.mode.format {
+            PixelFormat::H264 | PixelFormat::H265 => {
+                let nals = split_annex_b(&frame.data);
+                annex_b(self.parameter_sets.iter().chain(&nals)).to_vec()
+            }
+            _ => ivf(&self.mode, &frame.data),
+        };
+        let description = launch_description(self.mode.format, size, quality);
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(decode(&description, input));
+        });
+        self.decoding = Some(rx);
+    }
+}
+
+pub struct Snapshotter {
+    settings: Settings,
+    size: (u32, u32),
+    /// Capture time of the last still.
+    taken: Option<Duration>,
+    /// For encoded captures only.
+    keyframes: Option<Keyframes>,
+}
+
+impl Snapshotter {
+    /// Takes stills of captures in `mode`.
+    pub fn new(mode: &Mode, settings: Settings) -> Result<Self> {
+        let width = settings.width.clamp(2, mode.width.max(2)) & !1;
+        let height = ((width * mode.height / mode.width.max(1)) & !1).max(2);
+        let keyframes = mode.format.is_encoded().then(|| Keyframes { mode: *mode, parameter_sets: Vec::new(), decoding: None });
+        Ok(Snapshotter { settings, size: (width, height), taken: None, keyframes })
+    }
+
+    /// A JPEG still if it is time for another one. Stills of encoded captures
+    /// come back a few frames after the keyframe they show.
+    pub fn take(&mut self, frame: &Frame) -> Result<Option<Vec<u8>>> {
+        let due = self.taken.is_none_or(|taken| frame.timestamp >= taken + self.settings.interval);
+        let keyframes = match &mut self.keyframes {
+            Some(keyframes) => keyframes,
+            None if due => {
+                self.taken = Some(frame.timestamp);
+                let (width, height) = self.size;
+                let picture = encoder::decode(frame)?.scaled(width, height);
+                return jpeg(&picture, self.settings.quality).map(Some);
+            }
+            None => return Ok(None),
+        };
+        let keyframe = keyframes.observe(frame);
+        let still = keyframes.finished();
+        if due && keyframe && keyframes.decoding.is_none() {
+            self.taken = Some(frame.timestamp);
+            keyframes.start(frame, self.size, self.settings.quality);
+        }
+        still
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{Device, PixelFormat, TestPattern};
+
+    #[test]
+    fn takes_scaled_stills_at_the_interval() {
+        let mode = Mode { format: PixelFormat::Yuyv, width: 640, height: 480, fps: 1000 };
+        let mut capture = TestPattern.open(mode).unwrap();
+        let settings = Settings { interval: Duration::from_millis(5), ..Default::default() };
+        let mut snapshotter = Snapshotter::new(&mode, settings).unwrap();
+
+        let stills = (0..12).filter_map(|_| snapshotter.take(&capture.next_frame().unwrap()).unwrap()).collect::<Vec<_>>();
+        assert_eq!(stills.len(), 3);
+
+        let mut decoder = jpeg_decoder::Decoder::new(&stills[0][..]);
+        decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        assert_eq!((info.width, info.height), (320, 240));
+    }
+
+    #[test]
+    fn finds_keyframes() {
+        let h264 = |types: &[u8]| annex_b(&types.iter().map(|&kind| Bytes::from(vec![0x60 | kind, 0x88])).collect::<Vec<_>>());
+        assert!(is_keyframe(PixelFormat::H264, &h264(&[7, 8, 5])));
+        assert!(!is_keyframe(PixelFormat::H264, &h264(&[1])));
+        let h265 = |kind: u8| annex_b(&[Bytes::from(vec![kind << 1, 1, 0xaf])]);
+        assert!(is_keyframe(PixelFormat::H265, &h265(19)));
+        assert!(!is_keyframe(PixelFormat::H265, &h265(1)));
+        assert!(is_keyframe(PixelFormat::Vp8, &Bytes::from_static(&[0x50, 0x01, 0x00, 0x9d])));
+        assert!(!is_keyframe(PixelFormat::Vp8, &Bytes::from_static(&[0x51, 0x01])));
+        // Profile 0 key and inter frames, and a profile 3 keyframe.
+        assert!(is_keyframe(PixelFormat::Vp9, &Bytes::from_static(&[0b1000_0010])));
+        assert!(!is_keyframe(PixelFormat::Vp9, &Bytes::from_static(&[0b1000_0110])));
+        assert!(is_keyframe(PixelFormat::Vp9, &Bytes::from_static(&[0b1011_0001])));
+    }
+
+    #[test]
+    fn decodes_keyframes_with_the_latest_parameter_sets() {
+        let mode = Mode { format: PixelFormat::H264, width: 1280, height: 720, fps: 30 };
+        let snapshotter = Snapshotter::new(&mode, Settings::default()).unwrap();
+        assert_eq!(snapshotter.size, (320, 180));
+        let mut keyframes = snapshotter.keyframes.unwrap();
+
+        let frame = |nals: &[&[u8]]| Frame {
+            mode,
+            data: annex_b(&nals.iter().map(|nal| Bytes::copy_from_slice(nal)).collect::<Vec<_>>()),
+            timestamp: Duration::ZERO,
+        };
+        assert!(keyframes.observe(&frame(&[&[0x67, 1], &[0x68, 2], &[0x65, 3]])));
+        assert!(!keyframes.observe(&frame(&[&[0x41, 4]])));
+        assert!(!keyframes.observe(&frame(&[&[0x67, 5], &[0x68, 6], &[0x41, 7]])));
+        assert_eq!(keyframes.parameter_sets, vec![Bytes::from_static(&[0x67, 5]), Bytes::from_static(&[0x68, 6])]);
+
+        let description = launch_description(PixelFormat::H264, (320, 180), 75);
+        assert!(description.starts_with("fdsrc fd=0 ! h264parse ! decodebin"), "{}", description);
+        assert!(description.contains("video/x-raw,width=320,height=180 ! jpegenc quality=75"), "{}", description);
+        let vp8 = ivf(&Mode { format: PixelFormat::Vp8, ..mode }, &[0x50, 0x01]);
+        assert_eq!(&vp8[..4], b"DKIF");
+        assert_eq!(&vp8[8..12], b"VP80");
+        assert_eq!(&vp8[32..36], &2u32.to_le_bytes());
+    }
+}
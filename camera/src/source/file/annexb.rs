@@ -0,0 +1,285 @@
+//! Raw H.264 and H.265 elementary streams in Annex B format.
+//!
+//! These carry no timing, so access units are found from the NAL units
+//! themselves and played at whatever rate the capture mode asks for.
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::payload::{annex_b, split_annex_b};
+use crate::source::PixelFormat;
+
+/// Splits a stream into access units by the rules in H.264 7.4.1.2.3 and
+/// H.265 7.4.2.4.4: an access unit ends before the first slice of the next
+/// picture, or before the parameter sets and SEI that precede it.
+pub fn access_units(format: PixelFormat, data: &Bytes) -> Vec<Bytes> {
+    let mut units = Vec::new();
+    let mut nals: Vec<Bytes> = Vec::new();
+    let mut has_picture = false;
+    for nal in split_annex_b(data) {
+        let starts_unit = match format {
+            PixelFormat::H265 if nal.len() > 2 => match (nal[0] >> 1) & 0x3f {
+                // first_slice_segment_in_pic_flag.
+                0..=31 => nal[2] & 0x80 != 0,
+                32..=35 | 39 | 41..=44 | 48..=55 => true,
+                _ => false,
+            },
+            PixelFormat::H264 if nal.len() > 1 => match nal[0] & 0x1f {
+                // first_mb_in_slice is zero, which Exp-Golomb codes as a single set bit.
+                1..=5 => nal[1] & 0x80 != 0,
+                6..=9 | 14..=18 => true,
+                _ => false,
+            },
+            _ => false,
+        };
+        if starts_unit && has_picture {
+            units.push(annex_b(&nals));
+            nals.clear();
+            has_picture = false;
+        }
+        has_picture |= match format {
+            PixelFormat::H265 => (nal[0] >> 1) & 0x3f <= 31,
+            _ => (1..=5).contains(&(nal[0] & 0x1f)),
+        };
+        nals.push(nal);
+    }
+    if has_picture {
+        units.push(annex_b(&nals));
+    }
+    units
+}
+
+/// Reads Exp-Golomb coded fields from a NAL unit payload, skipping emulation
+/// prevention bytes.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    zeros: usize,
+    byte: u8,
+    bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0, zeros: 0, byte: 0, bits: 0 }
+    }
+
+    fn bit(&mut self) -> Result<u32> {
+        if self.bits == 0 {
+            let mut byte = *self.data.get(self.pos).ok_or_else(|| anyhow::anyhow!("parameter set is truncated"))?;
+            self.pos += 1;
+            if self.zeros >= 2 && byte == 3 {
+                byte = *self.data.get(self.pos).ok_or_else(|| anyhow::anyhow!("parameter set is truncated"))?;
+                self.pos += 1;
+                self.zeros = 0;
+            }
+            self.zeros = if byte == 0 { self.zeros + 1 } else { 0 };
+            self.byte = byte;
+            self.bits = 8;
+        }
+        self.bits -= 1;
+        Ok(((self.byte >> self.bits) & 1) as u32)
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            value = (value << 1) | self.bit()?;
+        }
+        Ok(value)
+    }
+
+    fn skip(&mut self, bits: u32) -> Result<()> {
+        for _ in 0..bits {
+            self.bit()?;
+        }
+        Ok(())
+    }
+
+    fn ue(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            anyhow::ensure!(zeros < 32, "bad Exp-Golomb code");
+        }
+        Ok(((1u64 << zeros) - 1 + self.read(zeros)? as u64) as u32)
+    }
+
+    fn se(&mut self) -> Result<i32> {
+        let value = self.ue()?;
+        Ok(if value & 1 == 1 { (value / 2 + 1) as i32 } else { -((value / 2) as i32) })
+    }
+}
+
+/// Picture size from the first sequence parameter set in the stream.
+pub fn dimensions(format: PixelFormat, data: &Bytes) -> Result<(u32, u32)> {
+    let is_sps = |nal: &Bytes| match format {
+        PixelFormat::H265 => nal.len() > 2 && (nal[0] >> 1) & 0x3f == 33,
+        _ => nal.len() > 1 && nal[0] & 0x1f == 7,
+    };
+    let sps = split_annex_b(data).into_iter().find(is_sps).ok_or_else(|| anyhow::anyhow!("stream has no sequence parameter set"))?;
+    match format {
+        PixelFormat::H265 => h265_dimensions(&sps[2..]),
+        _ => h264_dimensions(&sps[1..]),
+    }
+}
+
+/// H.264 7.3.2.1.1.
+fn h264_dimensions(sps: &[u8]) -> Result<(u32, u32)> {
+    let mut reader = BitReader::new(sps);
+    let profile = reader.read(8)?;
+    reader.skip(16)?;
+    reader.ue()?;
+    let mut chroma_format = 1;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile) {
+        chroma_format = reader.ue()?;
+        if chroma_format == 3 {
+            reader.skip(1)?;
+        }
+        reader.ue()?;
+        reader.ue()?;
+        reader.skip(1)?;
+        if reader.bit()? == 1 {
+            for list in 0..if chroma_format == 3 { 12 } else { 8 } {
+                if reader.bit()? == 0 {
+                    continue;
+                }
+                let size = if list < 6 { 16 } else { 64 };
+                let (mut last, mut next) = (8, 8);
+                for _ in 0..size {
+                    if next != 0 {
+                        next = (last + reader.se()? + 256) % 256;
+                    }
+                    if next != 0 {
+                        last = next;
+                    }
+                }
+            }
+        }
+    }
+    reader.ue()?;
+    match reader.ue()? {
+        0 => {
+            reader.ue()?;
+        }
+        1 => {
+            reader.skip(1)?;
+            reader.se()?;
+            reader.se()?;
+            for _ in 0..reader.ue()? {
+                reader.se()?;
+            }
+        }
+        _ => {}
+    }
+    reader.ue()?;
+    reader.skip(1)?;
+    let width_mbs = reader.ue()? + 1;
+    let height_units = reader.ue()? + 1;
+    let frame_mbs_only = reader.bit()?;
+    if frame_mbs_only == 0 {
+        reader.skip(1)?;
+    }
+    reader.skip(1)?;
+
+    let mut width = width_mbs * 16;
+    let mut height = (2 - frame_mbs_only) * height_units * 16;
+    if reader.bit()? == 1 {
+        let (crop_x, crop_y) = match chroma_format {
+            0 | 3 => (1, 2 - frame_mbs_only),
+            2 => (2, 2 - frame_mbs_only),
+            _ => (2, 2 * (2 - frame_mbs_only)),
+        };
+        width = width.saturating_sub((reader.ue()? + reader.ue()?) * crop_x);
+        height = height.saturating_sub((reader.ue()? + reader.ue()?) * crop_y);
+    }
+    Ok((width, height))
+}
+
+/// H.265 7.3.2.2.1.
+fn h265_dimensions(sps: &[u8]) -> Result<(u32, u32)> {
+    let mut reader = BitReader::new(sps);
+    reader.skip(4)?;
+    let sub_layers = reader.read(3)?;
+    reader.skip(1)?;
+
+    // profile_tier_level: the general profile and level, then those of each sub-layer.
+    reader.skip(96)?;
+    let present = (0..sub_layers).map(|_| Ok((reader.bit()?, reader.bit()?))).collect::<Result<Vec<_>>>()?;
+    if sub_layers > 0 {
+        reader.skip(2 * (8 - sub_layers))?;
+    }
+    for (profile, level) in present {
+        reader.skip(profile * 88 + level * 8)?;
+    }
+
+    reader.ue()?;
+    let chroma_format = reader.ue()?;
+    if chroma_format == 3 {
+        reader.skip(1)?;
+    }
+    let mut width = reader.ue()?;
+    let mut height = reader.ue()?;
+    if reader.bit()? == 1 {
+        let (crop_x, crop_y) = match chroma_format {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        width = width.saturating_sub((reader.ue()? + reader.ue()?) * crop_x);
+        height = height.saturating_sub((reader.ue()? + reader.ue()?) * crop_y);
+    }
+    Ok((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// High profile SPS for 1920x1088 macroblocks cropped to 1080 rows.
+    const H264_SPS: &[u8] = &[0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0x40];
+
+    #[test]
+    fn reads_dimensions() {
+        let stream = annex_b(&[Bytes::from_static(&[0x09, 0x10]), Bytes::from_static(H264_SPS)]);
+        assert_eq!(dimensions(PixelFormat::H264, &stream).unwrap(), (1920, 1080));
+
+        // Main profile 1920x1088 cropped the same way, with emulation prevention bytes in the profile.
+        let sps = [
+            33 << 1, 1, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03,
+            0x00, 0x00, 0x03, 0x00, 0x00, 0xa0, 0x03, 0xc0, 0x80, 0x11, 0x07, 0xcb,
+        ];
+        let stream = annex_b(&[Bytes::copy_from_slice(&sps)]);
+        assert_eq!(dimensions(PixelFormat::H265, &stream).unwrap(), (1920, 1080));
+        assert!(dimensions(PixelFormat::H265, &annex_b(&[Bytes::from_static(H264_SPS)])).is_err());
+    }
+
+    #[test]
+    fn splits_h264_access_units() {
+        let nals = [
+            Bytes::from_static(H264_SPS),
+            Bytes::from_static(&[0x68, 0xeb]),
+            // IDR in two slices, the second not starting at macroblock zero.
+            Bytes::from_static(&[0x65, 0x88, 0x01]),
+            Bytes::from_static(&[0x65, 0x40, 0x02]),
+            Bytes::from_static(&[0x41, 0x9a, 0x03]),
+            Bytes::from_static(&[0x09, 0x10]),
+            Bytes::from_static(&[0x41, 0x9a, 0x04]),
+        ];
+        let units = access_units(PixelFormat::H264, &annex_b(&nals));
+        assert_eq!(units, vec![annex_b(&nals[..4]), annex_b(&nals[4..5]), annex_b(&nals[5..])]);
+    }
+
+    #[test]
+    fn splits_h265_access_units() {
+        let nals = [
+            Bytes::from_static(&[32 << 1, 1, 0x0c]),
+            Bytes::from_static(&[19 << 1, 1, 0xaf, 0x01]),
+            Bytes::from_static(&[1 << 1, 1, 0xd0, 0x02]),
+            Bytes::from_static(&[1 << 1, 1, 0x50, 0x03]),
+            Bytes::from_static(&[1 << 1, 1, 0xd0, 0x04]),
+        ];
+        let units = access_units(PixelFormat::H265, &annex_b(&nals));
+        assert_eq!(units, vec![annex_b(&nals[..2]), annex_b(&nals[2..4]), annex_b(&nals[4..])]);
+    }
+}
@@ -0,0 +1,107 @@
+//! The IVF container written by libvpx and ffmpeg: a 32 byte file header,
+//! then each frame behind a 12 byte header giving its size and timestamp.
+
+use std::convert::TryInto;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use super::{Media, Timing};
+use crate::source::PixelFormat;
+
+pub const SIGNATURE: &[u8] = b"DKIF";
+
+fn u16_at(data: &[u8], offset: usize) -> u32 {
+    u16::from_le_bytes([data[offset], data[offset + 1]]) as u32
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+pub fn parse(data: &Bytes) -> Result<Media> {
+    anyhow::ensure!(data.len() >= 32 && data.starts_with(SIGNATURE), "not an IVF file");
+    let header_size = u16_at(data, 6) as usize;
+    let format = match &data[8..12] {
+        b"VP80" => PixelFormat::Vp8,
+        b"VP90" => PixelFormat::Vp9,
+        b"H264" => PixelFormat::H264,
+        b"H265" | b"HEVC" => PixelFormat::H265,
+        fourcc => anyhow::bail!("unsupported IVF codec {:?}", String::from_utf8_lossy(fourcc)),
+    };
+    let (width, height) = (u16_at(data, 12), u16_at(data, 14));
+    // Timestamps count in units of scale / rate seconds.
+    let (rate, scale) = (u32_at(data, 16) as u64, u32_at(data, 20) as u64);
+    anyhow::ensure!(rate > 0 && scale > 0, "IVF header has no time base");
+    let time = |pts: u64| Duration::from_nanos((pts as u128 * scale as u128 * 1_000_000_000 / rate as u128) as u64);
+
+    let mut frames = Vec::new();
+    let mut pts = Vec::new();
+    let mut offset = header_size.max(32);
+    while offset < data.len() {
+        let header = data.get(offset..offset + 12).context("truncated IVF frame header")?;
+        let size = u32_at(header, 0) as usize;
+        let start = offset + 12;
+        anyhow::ensure!(start + size <= data.len(), "truncated IVF frame");
+        pts.push(u64::from_le_bytes(header[4..12].try_into().unwrap()));
+        frames.push(data.slice(start..start + size));
+        offset = start + size;
+    }
+    anyhow::ensure!(!frames.is_empty(), "IVF file has no frames");
+
+    // The last frame lasts as long as the one before it, or one tick.
+    let last = pts[pts.len() - 1];
+    let end = last + pts.len().checked_sub(2).map_or(1, |idx| last.saturating_sub(pts[idx]).max(1));
+    let first = pts[0];
+    Ok(Media {
+        format,
+        width,
+        height,
+        frames,
+        timing: Some(Timing {
+            timestamps: pts.iter().map(|&pts| time(pts.saturating_sub(first))).collect(),
+            duration: time(end - first),
+        }),
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// An IVF file with a 1/30 time base.
+    pub fn file(fourcc: &[u8; 4], frames: &[(u64, &[u8])]) -> Bytes {
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data.extend_from_slice(&32u16.to_le_bytes());
+        data.extend_from_slice(fourcc);
+        data.extend_from_slice(&320u16.to_le_bytes());
+        data.extend_from_slice(&240u16.to_le_bytes());
+        data.extend_from_slice(&30u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        for (pts, frame) in frames {
+            data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            data.extend_from_slice(&pts.to_le_bytes());
+            data.extend_from_slice(frame);
+        }
+        data.into()
+    }
+
+    #[test]
+    fn parses_frames() {
+        let media = parse(&file(b"VP80", &[(0, &[1, 2]), (1, &[3]), (3, &[4, 5, 6])])).unwrap();
+        assert_eq!((media.format, media.width, media.height), (PixelFormat::Vp8, 320, 240));
+        assert_eq!(media.frames, vec![Bytes::from_static(&[1, 2]), Bytes::from_static(&[3]), Bytes::from_static(&[4, 5, 6])]);
+        let ticks = |ticks: u64| Duration::from_nanos(ticks * 1_000_000_000 / 30);
+        let timing = media.timing.unwrap();
+        assert_eq!(timing.timestamps, vec![ticks(0), ticks(1), ticks(3)]);
+        assert_eq!(timing.duration, ticks(5));
+
+        assert!(parse(&file(b"AV01", &[(0, &[1])])).is_err());
+        assert!(parse(&file(b"VP90", &[])).is_err());
+    }
+}
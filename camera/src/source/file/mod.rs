@@ -0,0 +1,231 @@
+//! Already encoded video played from a file in real time, looping by default.
+//!
+//! Useful for demos without a camera, and as a deterministic camera for
+//! automated tests of the signalling server. IVF and MP4 files carry their
+//! own timestamps; raw H.264 or H.265 Annex B streams are played at the
+//! requested frame rate.
+
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use super::{Capture, Device, EndOfStream, Frame, Mode, PixelFormat};
+
+mod annexb;
+mod ivf;
+mod mp4;
+
+/// Frame rates offered for streams that do not record their own.
+const RATES: [u32; 5] = [15, 24, 25, 30, 60];
+
+/// Presentation times recorded in a container.
+#[derive(Debug, Clone)]
+struct Timing {
+    timestamps: Vec<Duration>,
+    /// Length of the whole file, including the last frame.
+    duration: Duration,
+}
+
+impl Timing {
+    /// Nominal frame rate, for choosing and displaying the mode.
+    fn fps(&self) -> u32 {
+        let seconds = self.duration.as_secs_f64();
+        if seconds > 0.0 {
+            (self.timestamps.len() as f64 / seconds).round().max(1.0) as u32
+        } else {
+            1
+        }
+    }
+}
+
+/// A demuxed file.
+struct Media {
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    /// One access unit per frame, in decode order.
+    frames: Vec<Bytes>,
+    timing: Option<Timing>,
+}
+
+impl Media {
+    /// Detects the container from the file contents, falling back to the
+    /// extension to tell raw H.265 from H.264.
+    fn parse(path: &Path, data: Bytes) -> Result<Self> {
+        if data.starts_with(ivf::SIGNATURE) {
+            return ivf::parse(&data);
+        }
+        if data.get(4..8).is_some_and(|kind| [b"ftyp", b"moov", b"mdat", b"free"].iter().any(|known| kind == *known)) {
+            return mp4::parse(&data);
+        }
+        anyhow::ensure!(data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1]), "not an IVF, MP4 or Annex B file");
+
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_ascii_lowercase();
+        let format = match extension.as_str() {
+            "h265" | "265" | "hevc" => PixelFormat::H265,
+            _ => PixelFormat::H264,
+        };
+        let (width, height) = annexb::dimensions(format, &data)?;
+        let frames = annexb::access_units(format, &data);
+        Ok(Media { format, width, height, frames, timing: None })
+    }
+}
+
+pub struct FileDevice {
+    path: String,
+    media: Media,
+    looping: bool,
+}
+
+impl FileDevice {
+    /// Reads the whole file up front, so a bad file is reported before the
+    /// camera registers.
+    pub fn new(path: &str, looping: bool) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("failed to read {}", path))?;
+        let media = Media::parse(Path::new(path), data.into()).with_context(|| format!("failed to parse {}", path))?;
+        anyhow::ensure!(!media.frames.is_empty(), "{} has no frames", path);
+        Ok(FileDevice { path: path.to_string(), media, looping })
+    }
+}
+
+impl Device for FileDevice {
+    fn name(&self) -> String {
+        Path::new(&self.path).file_name().map_or_else(|| self.path.clone(), |name| name.to_string_lossy().into_owned())
+    }
+
+    fn modes(&self) -> Result<Vec<Mode>> {
+        let Media { format, width, height, .. } = self.media;
+        Ok(match &self.media.timing {
+            Some(timing) => vec![Mode { format, width, height, fps: timing.fps() }],
+            None => RATES.iter().map(|&fps| Mode { format, width, height, fps }).collect(),
+        })
+    }
+
+    fn open(&mut self, mode: Mode) -> Result<Box<dyn Capture>> {
+        Ok(Box::new(self.capture(mode)?))
+    }
+}
+
+impl FileDevice {
+    fn capture(&self, mode: Mode) -> Result<FileCapture> {
+        anyhow::ensure!(self.modes()?.contains(&mode), "{} cannot be played as {}", self.path, mode);
+        let timing = self.media.timing.clone().unwrap_or_else(|| {
+            let count = self.media.frames.len() as u32;
+            Timing {
+                timestamps: (0..count).map(|frame| Duration::from_secs(frame as u64) / mode.fps).collect(),
+                duration: Duration::from_secs(count as u64) / mode.fps,
+            }
+        });
+        Ok(FileCapture {
+            mode,
+            frames: self.media.frames.clone(),
+            timing,
+            looping: self.looping,
+            next: 0,
+            offset: Duration::ZERO,
+            started: None,
+        })
+    }
+}
+
+struct FileCapture {
+    mode: Mode,
+    frames: Vec<Bytes>,
+    timing: Timing,
+    looping: bool,
+    next: usize,
+    /// Start of the current pass through the file.
+    offset: Duration,
+    started: Option<Instant>,
+}
+
+impl FileCapture {
+    /// The next frame and its timestamp, without waiting for it to be due.
+    fn advance(&mut self) -> Result<(Bytes, Duration)> {
+        if self.next == self.frames.len() {
+            if !self.looping {
+                return Err(EndOfStream.into());
+            }
+            self.next = 0;
+            self.offset += self.timing.duration;
+        }
+        let frame = (self.frames[self.next].clone(), self.offset + self.timing.timestamps[self.next]);
+        self.next += 1;
+        Ok(frame)
+    }
+}
+
+impl Capture for FileCapture {
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn next_frame(&mut self) -> Result<Frame> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let (data, timestamp) = self.advance()?;
+        if let Some(wait) = timestamp.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+        Ok(Frame { mode: self.mode, data, timestamp })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(looping: bool) -> FileDevice {
+        let data = ivf::tests::file(b"VP90", &[(0, &[1]), (1, &[2]), (2, &[3])]);
+        let media = Media::parse(Path::new("clip.ivf"), data).unwrap();
+        FileDevice { path: "clip.ivf".to_string(), media, looping }
+    }
+
+    /// Opens a capture whose clock started long ago, so frames are read without waiting.
+    fn capture(device: &FileDevice, mode: Mode) -> FileCapture {
+        FileCapture { started: Some(Instant::now() - Duration::from_secs(10)), ..device.capture(mode).unwrap() }
+    }
+
+    #[test]
+    fn loops_with_increasing_timestamps() {
+        let device = device(true);
+        let modes = device.modes().unwrap();
+        assert_eq!(modes, vec![Mode { format: PixelFormat::Vp9, width: 320, height: 240, fps: 30 }]);
+
+        let mut capture = capture(&device, modes[0]);
+        let frames = (0..5).map(|_| capture.next_frame().unwrap()).collect::<Vec<_>>();
+        assert_eq!(frames.iter().map(|frame| frame.data[0]).collect::<Vec<_>>(), vec![1, 2, 3, 1, 2]);
+        let ticks = |ticks: u64| Duration::from_nanos(ticks * 1_000_000_000 / 30);
+        assert_eq!(frames.iter().map(|frame| frame.timestamp).collect::<Vec<_>>(), (0..5).map(ticks).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn ends_when_not_looping() {
+        let device = device(false);
+        let mut capture = capture(&device, device.modes().unwrap()[0]);
+        for _ in 0..3 {
+            capture.next_frame().unwrap();
+        }
+        assert!(capture.next_frame().err().unwrap().is::<EndOfStream>());
+    }
+
+    #[test]
+    fn plays_annex_b_at_requested_rate() {
+        let nals = [
+            // Baseline profile, 128x96.
+            Bytes::from_static(&[0x67, 0x42, 0x00, 0x0a, 0xf8, 0x41, 0xb2]),
+            Bytes::from_static(&[0x65, 0x88, 0x01]),
+            Bytes::from_static(&[0x41, 0x9a, 0x02]),
+        ];
+        let media = Media::parse(Path::new("clip.h264"), crate::payload::annex_b(&nals)).unwrap();
+        assert_eq!((media.format, media.width, media.height, media.frames.len()), (PixelFormat::H264, 128, 96, 2));
+
+        let device = FileDevice { path: "clip.h264".to_string(), media, looping: true };
+        let mode = device.modes().unwrap().into_iter().find(|mode| mode.fps == 25).unwrap();
+        let mut capture = capture(&device, mode);
+        let timestamps = (0..3).map(|_| capture.next_frame().unwrap().timestamp).collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![Duration::ZERO, Duration::from_millis(40), Duration::from_millis(80)]);
+    }
+}
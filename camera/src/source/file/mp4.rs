@@ -0,0 +1,335 @@
+//! The first video track of a non-fragmented MP4 (ISO/IEC 14496-12) file.
+//!
+//! Samples are sent in decode order with decode timestamps, since WebRTC has
+//! no way to carry B-frame reordering anyway.
+
+use std::convert::TryInto;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes};
+
+use super::{Media, Timing};
+use crate::payload::annex_b;
+use crate::source::PixelFormat;
+
+type FourCc = [u8; 4];
+
+/// Splits the contents of a box into its child boxes.
+fn children(data: &[u8]) -> Result<Vec<(FourCc, &[u8])>> {
+    let mut boxes = Vec::new();
+    let mut rest = data;
+    while rest.len() >= 8 {
+        let size = u32::from_be_bytes(rest[..4].try_into().unwrap()) as u64;
+        let kind: FourCc = rest[4..8].try_into().unwrap();
+        let (header, size) = match size {
+            0 => (8, rest.len() as u64),
+            1 => (16, u64::from_be_bytes(rest.get(8..16).context("truncated box header")?.try_into().unwrap())),
+            size => (8, size),
+        };
+        anyhow::ensure!(size >= header as u64 && size <= rest.len() as u64, "{} box overruns its parent", String::from_utf8_lossy(&kind));
+        boxes.push((kind, &rest[header..size as usize]));
+        rest = &rest[size as usize..];
+    }
+    Ok(boxes)
+}
+
+fn child<'a>(data: &'a [u8], kind: &FourCc) -> Result<Option<&'a [u8]>> {
+    Ok(children(data)?.into_iter().find(|(found, _)| found == kind).map(|(_, data)| data))
+}
+
+/// Follows a path of nested boxes.
+fn find<'a>(data: &'a [u8], path: &[&FourCc]) -> Result<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| {
+        child(data, kind)?.with_context(|| format!("missing {} box", String::from_utf8_lossy(*kind)))
+    })
+}
+
+/// Reads a full box's table: the version and flags, an entry count, then
+/// that many fixed size entries.
+fn table(data: &[u8], entry_size: usize) -> Result<impl Iterator<Item = &[u8]>> {
+    let count = data.get(4..8).context("truncated table")?;
+    let count = u32::from_be_bytes(count.try_into().unwrap()) as usize;
+    let entries = data.get(8..).filter(|entries| entries.len() / entry_size >= count).context("truncated table")?;
+    Ok(entries.chunks_exact(entry_size).take(count))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// How the samples of a track are coded.
+struct SampleEntry {
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+    /// Bytes in the length prefix of each NAL unit, for H.264 and H.265.
+    length_size: usize,
+    parameter_sets: Vec<Bytes>,
+}
+
+impl SampleEntry {
+    fn parse(stsd: &[u8]) -> Result<Self> {
+        let (kind, entry) = children(stsd.get(8..).context("truncated stsd box")?)?.into_iter().next().context("track has no sample description")?;
+        // VisualSampleEntry fields, then codec configuration boxes.
+        anyhow::ensure!(entry.len() >= 78, "truncated sample entry");
+        let width = u16::from_be_bytes([entry[24], entry[25]]) as u32;
+        let height = u16::from_be_bytes([entry[26], entry[27]]) as u32;
+        let boxes = &entry[78..];
+
+        let (format, length_size, parameter_sets) = match &kind {
+            b"avc1" | b"avc3" => {
+                let config = child(boxes, b"avcC")?.context("missing avcC box")?;
+                let (length_size, parameter_sets) = parse_avcc(config).context("bad avcC box")?;
+                (PixelFormat::H264, length_size, parameter_sets)
+            }
+            b"hvc1" | b"hev1" => {
+                let config = child(boxes, b"hvcC")?.context("missing hvcC box")?;
+                let (length_size, parameter_sets) = parse_hvcc(config).context("bad hvcC box")?;
+                (PixelFormat::H265, length_size, parameter_sets)
+            }
+            b"vp08" => (PixelFormat::Vp8, 0, Vec::new()),
+            b"vp09" => (PixelFormat::Vp9, 0, Vec::new()),
+            kind => anyhow::bail!("unsupported MP4 codec {:?}", String::from_utf8_lossy(kind)),
+        };
+        Ok(SampleEntry { format, width, height, length_size, parameter_sets })
+    }
+
+    /// Converts a sample to an Annex B access unit, with the out-of-band
+    /// parameter sets in front of random access points.
+    fn access_unit(&self, sample: &Bytes, sync: bool) -> Result<Bytes> {
+        if self.length_size == 0 {
+            return Ok(sample.clone());
+        }
+        let mut nals = Vec::new();
+        let mut rest = sample.clone();
+        while rest.has_remaining() {
+            anyhow::ensure!(rest.len() >= self.length_size, "truncated NAL unit length");
+            let size = rest.get_uint(self.length_size) as usize;
+            anyhow::ensure!(size <= rest.len(), "truncated NAL unit");
+            nals.push(rest.split_to(size));
+        }
+
+        let is_sps = |nal: &Bytes| match self.format {
+            PixelFormat::H265 => nal.first().is_some_and(|header| (header >> 1) & 0x3f == 33),
+            _ => nal.first().is_some_and(|header| header & 0x1f == 7),
+        };
+        Ok(if sync && !nals.iter().any(is_sps) {
+            annex_b(self.parameter_sets.iter().chain(&nals))
+        } else {
+            annex_b(&nals)
+        })
+    }
+}
+
+/// Reads a list of NAL units each behind a 16 bit length.
+fn length_prefixed(data: &mut &[u8], count: usize, nals: &mut Vec<Bytes>) -> Option<()> {
+    for _ in 0..count {
+        let size = u16::from_be_bytes([*data.first()?, *data.get(1)?]) as usize;
+        nals.push(Bytes::copy_from_slice(data.get(2..2 + size)?));
+        *data = &data[2 + size..];
+    }
+    Some(())
+}
+
+/// AVCDecoderConfigurationRecord (ISO/IEC 14496-15 5.3.3.1).
+fn parse_avcc(config: &[u8]) -> Option<(usize, Vec<Bytes>)> {
+    let length_size = (*config.get(4)? & 3) as usize + 1;
+    let mut parameter_sets = Vec::new();
+    let mut rest = config.get(6..)?;
+    length_prefixed(&mut rest, (config[5] & 0x1f) as usize, &mut parameter_sets)?;
+    let count = *rest.first()? as usize;
+    rest = &rest[1..];
+    length_prefixed(&mut rest, count, &mut parameter_sets)?;
+    Some((length_size, parameter_sets))
+}
+
+/// HEVCDecoderConfigurationRecord (ISO/IEC 14496-15 8.3.3.1).
+fn parse_hvcc(config: &[u8]) -> Option<(usize, Vec<Bytes>)> {
+    let length_size = (*config.get(21)? & 3) as usize + 1;
+    let mut parameter_sets = Vec::new();
+    let arrays = *config.get(22)?;
+    let mut rest = config.get(23..)?;
+    for _ in 0..arrays {
+        let count = u16::from_be_bytes([*rest.get(1)?, *rest.get(2)?]) as usize;
+        rest = &rest[3..];
+        length_prefixed(&mut rest, count, &mut parameter_sets)?;
+    }
+    Some((length_size, parameter_sets))
+}
+
+/// The first track whose handler is `vide`.
+fn video_track(moov: &[u8]) -> Result<&[u8]> {
+    for (kind, trak) in children(moov)? {
+        if &kind == b"trak" && find(trak, &[b"mdia", b"hdlr"])?.get(8..12) == Some(b"vide") {
+            return Ok(trak);
+        }
+    }
+    anyhow::bail!("file has no video track")
+}
+
+pub fn parse(data: &Bytes) -> Result<Media> {
+    let top = children(data)?;
+    let moov = top.iter().find(|(kind, _)| kind == b"moov").map(|(_, moov)| *moov).context("missing moov box")?;
+    anyhow::ensure!(top.iter().all(|(kind, _)| kind != b"moof"), "fragmented MP4 files are not supported");
+
+    let trak = video_track(moov)?;
+    let mdhd = find(trak, &[b"mdia", b"mdhd"])?;
+    let timescale = match mdhd.first() {
+        Some(1) => mdhd.get(20..24),
+        _ => mdhd.get(12..16),
+    };
+    let timescale = timescale.map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap())).context("truncated mdhd box")?;
+    anyhow::ensure!(timescale > 0, "track has no timescale");
+
+    let stbl = find(trak, &[b"mdia", b"minf", b"stbl"])?;
+    let entry = SampleEntry::parse(find(stbl, &[b"stsd"])?)?;
+
+    let stsz = find(stbl, &[b"stsz"])?;
+    anyhow::ensure!(stsz.len() >= 12, "truncated stsz box");
+    let sizes: Vec<u32> = match u32_at(stsz, 4) {
+        0 => {
+            let count = u32_at(stsz, 8) as usize;
+            let sizes = stsz[12..].chunks_exact(4).map(|entry| u32_at(entry, 0)).take(count).collect::<Vec<_>>();
+            anyhow::ensure!(sizes.len() == count, "truncated stsz box");
+            sizes
+        }
+        size => vec![size; u32_at(stsz, 8) as usize],
+    };
+    anyhow::ensure!(!sizes.is_empty(), "video track has no samples");
+
+    let offsets: Vec<u64> = match child(stbl, b"co64")? {
+        Some(co64) => table(co64, 8)?.map(|entry| u64::from_be_bytes(entry.try_into().unwrap())).collect(),
+        None => table(find(stbl, &[b"stco"])?, 4)?.map(|entry| u32_at(entry, 0) as u64).collect(),
+    };
+    // (first chunk, samples per chunk), with chunks numbered from one.
+    let runs = table(find(stbl, &[b"stsc"])?, 12)?.map(|entry| (u32_at(entry, 0) as usize, u32_at(entry, 4) as usize)).collect::<Vec<_>>();
+    let sync = child(stbl, b"stss")?
+        .map(|stss| Ok::<_, anyhow::Error>(table(stss, 4)?.map(|entry| u32_at(entry, 0) as usize).collect::<Vec<_>>()))
+        .transpose()?;
+
+    let mut frames = Vec::with_capacity(sizes.len());
+    let mut samples = sizes.iter().enumerate();
+    'chunks: for (chunk, &offset) in offsets.iter().enumerate() {
+        let per_chunk = runs.iter().rev().find(|(first, _)| *first <= chunk + 1).map_or(0, |(_, count)| *count);
+        let mut offset = offset as usize;
+        for _ in 0..per_chunk {
+            let Some((idx, &size)) = samples.next() else { break 'chunks };
+            let end = offset + size as usize;
+            let sample = data.get(offset..end).context("sample lies outside the file")?;
+            let is_sync = sync.as_ref().is_none_or(|sync| sync.contains(&(idx + 1)));
+            frames.push(entry.access_unit(&data.slice_ref(sample), is_sync)?);
+            offset = end;
+        }
+    }
+    anyhow::ensure!(frames.len() == sizes.len(), "sample table describes {} samples but only {} are in chunks", sizes.len(), frames.len());
+
+    let mut timestamps = Vec::with_capacity(frames.len());
+    let mut time = 0u64;
+    for entry in table(find(stbl, &[b"stts"])?, 8)? {
+        for _ in 0..u32_at(entry, 0) {
+            timestamps.push(Duration::from_nanos((time as u128 * 1_000_000_000 / timescale as u128) as u64));
+            time += u32_at(entry, 4) as u64;
+        }
+    }
+    anyhow::ensure!(timestamps.len() >= frames.len(), "stts box does not cover every sample");
+    timestamps.truncate(frames.len());
+
+    Ok(Media {
+        format: entry.format,
+        width: entry.width,
+        height: entry.height,
+        frames,
+        timing: Some(Timing {
+            timestamps,
+            duration: Duration::from_nanos((time as u128 * 1_000_000_000 / timescale as u128) as u64),
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &FourCc, parts: &[&[u8]]) -> Vec<u8> {
+        let body = parts.concat();
+        [&(body.len() as u32 + 8).to_be_bytes()[..], kind, &body].concat()
+    }
+
+    /// A full box table with 32 bit fields.
+    fn table_box(kind: &FourCc, entries: &[&[u32]]) -> Vec<u8> {
+        let mut body = vec![0; 4];
+        body.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        for field in entries.iter().flat_map(|entry| entry.iter()) {
+            body.extend_from_slice(&field.to_be_bytes());
+        }
+        mp4_box(kind, &[&body])
+    }
+
+    /// One H.264 track at 90 kHz with three samples in two chunks.
+    fn file() -> Vec<u8> {
+        let sps = [0x67, 0x42, 0x00, 0x1e];
+        let pps = [0x68, 0xce];
+        let samples: [&[u8]; 3] = [
+            &[0, 0, 0, 3, 0x65, 0x88, 0x01],
+            &[0, 0, 0, 2, 0x41, 0x9a],
+            &[0, 0, 0, 2, 0x41, 0x9b],
+        ];
+
+        let avcc = mp4_box(b"avcC", &[&[1, 0x42, 0, 0x1e, 0xff, 0xe1, 0, 4], &sps, &[1, 0, 2], &pps]);
+        let mut visual = vec![0; 78];
+        visual[24..28].copy_from_slice(&[0x02, 0x80, 0x01, 0xe0]);
+        let avc1 = mp4_box(b"avc1", &[&visual, &avcc]);
+        let stsd = mp4_box(b"stsd", &[&[0, 0, 0, 0, 0, 0, 0, 1], &avc1]);
+        let stts = table_box(b"stts", &[&[2, 3000], &[1, 6000]]);
+        let stss = table_box(b"stss", &[&[1]]);
+        let stsc = table_box(b"stsc", &[&[1, 2, 1], &[2, 1, 1]]);
+        let mut stsz = vec![0; 4];
+        for field in [0, 3, 7, 6, 6] {
+            stsz.extend_from_slice(&(field as u32).to_be_bytes());
+        }
+        let stsz = mp4_box(b"stsz", &[&stsz]);
+
+        // Samples go in an mdat box straight after ftyp, so their offsets are known up front.
+        let ftyp = mp4_box(b"ftyp", &[b"isom", &[0, 0, 2, 0]]);
+        let mdat = mp4_box(b"mdat", &samples);
+        let first = ftyp.len() as u32 + 8;
+        let stco = table_box(b"stco", &[&[first], &[first + 13]]);
+
+        let stbl = mp4_box(b"stbl", &[&stsd, &stts, &stss, &stsc, &stsz, &stco]);
+        let minf = mp4_box(b"minf", &[&stbl]);
+        let mut mdhd = vec![0; 24];
+        mdhd[12..16].copy_from_slice(&90000u32.to_be_bytes());
+        let mdhd = mp4_box(b"mdhd", &[&mdhd]);
+        let hdlr = mp4_box(b"hdlr", &[&[0; 8], b"vide", &[0; 13]]);
+        let mdia = mp4_box(b"mdia", &[&mdhd, &hdlr, &minf]);
+        let trak = mp4_box(b"trak", &[&mp4_box(b"tkhd", &[&[0; 84]]), &mdia]);
+        let moov = mp4_box(b"moov", &[&trak]);
+        [ftyp, mdat, moov].concat()
+    }
+
+    #[test]
+    fn reads_samples_as_annex_b() {
+        let media = parse(&Bytes::from(file())).unwrap();
+        assert_eq!((media.format, media.width, media.height), (PixelFormat::H264, 640, 480));
+        assert_eq!(media.frames, vec![
+            annex_b(&[
+                Bytes::from_static(&[0x67, 0x42, 0x00, 0x1e]),
+                Bytes::from_static(&[0x68, 0xce]),
+                Bytes::from_static(&[0x65, 0x88, 0x01]),
+            ]),
+            Bytes::from_static(&[0, 0, 0, 1, 0x41, 0x9a]),
+            Bytes::from_static(&[0, 0, 0, 1, 0x41, 0x9b]),
+        ]);
+        let timing = media.timing.unwrap();
+        assert_eq!(timing.timestamps, vec![Duration::ZERO, Duration::from_secs(1) / 30, Duration::from_secs(2) / 30]);
+        assert_eq!(timing.duration, Duration::from_secs(4) / 30);
+    }
+
+    #[test]
+    fn rejects_fragmented_files() {
+        let mut data = file();
+        data.extend(mp4_box(b"moof", &[]));
+        assert!(parse(&Bytes::from(data)).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+//! Sources described as GStreamer pipelines.
+//!
+//! The pipeline runs in a `gst-launch-1.0` child process rather than through
+//! the GStreamer bindings, so the camera client builds without GStreamer
+//! development packages and only needs the runtime where this source is used.
+//! The trailing `appsink` is replaced with `gdppay ! fdsink`, which serialises
+//! each buffer with its timestamp, and the caps negotiated for it, to stdout.
+
+use std::io::{BufReader, Read};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use super::{Capture, Device, Frame, Mode, PixelFormat};
+
+pub const GST_LAUNCH: &str = "gst-launch-1.0";
+
+/// Caps used when the appsink does not say what it expects.
+const DEFAULT_CAPS: &str = "video/x-h264,stream-format=byte-stream,alignment=au";
+
+/// Splits a pipeline description on `!` links, ignoring those inside quotes.
+fn split_links(description: &str) -> Vec<&str> {
+    let mut links = Vec::new();
+    let (mut start, mut quoted) = (0, false);
+    for (idx, c) in description.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '!' if !quoted => {
+                links.push(description[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    links.push(description[start..].trim());
+    links
+}
+
+/// Splits an element description into its factory name and properties.
+fn element_properties(element: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut tokens = Vec::new();
+    let (mut start, mut quoted) = (None, false);
+    for (idx, c) in element.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if let Some(start) = start.take() {
+                    tokens.push(&element[start..idx]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(idx);
+    }
+    tokens.extend(start.map(|start| &element[start..]));
+
+    let name = tokens.first().copied().unwrap_or_default();
+    let properties = tokens.iter()
+        .skip(1)
+        .filter_map(|token| token.split_once('='))
+        .map(|(key, value)| (key, value.trim_matches('"')))
+        .collect();
+    (name, properties)
+}
+
+/// A user-supplied pipeline ending in an appsink, e.g.
+/// `videotestsrc ! x264enc tune=zerolatency ! appsink`.
+#[derive(Debug, Clone, PartialEq)]
+struct Pipeline {
+    /// Everything before the appsink.
+    upstream: String,
+    caps: Caps,
+}
+
+impl Pipeline {
+    fn parse(description: &str) -> Result<Self> {
+        let mut links = split_links(description);
+        let sink = links.pop().unwrap_or_default();
+        let (name, properties) = element_properties(sink);
+        anyhow::ensure!(name == "appsink", "pipeline must end in an appsink, not {:?}", name);
+        anyhow::ensure!(!links.is_empty() && links.iter().all(|link| !link.is_empty()), "pipeline has nothing before the appsink");
+
+        let caps = properties.iter().find(|(key, _)| *key == "caps").map_or(DEFAULT_CAPS, |(_, caps)| caps);
+        Ok(Pipeline { upstream: links.join(" ! "), caps: Caps::parse(caps)? })
+    }
+
+    /// The description actually launched, writing GDP to stdout.
+    fn launch_description(&self) -> String {
+        format!("{} ! {} ! gdppay ! fdsink fd=1 sync=false", self.upstream, self.caps.raw)
+    }
+}
+
+/// The parts of GStreamer caps we care about.
+#[derive(Debug, Clone, PartialEq)]
+struct Caps {
+    raw: String,
+    mode: Mode,
+}
+
+impl Caps {
+    /// Parses caps such as `video/x-h264, width=(int)640, framerate=(fraction)30/1`.
+    fn parse(caps: &str) -> Result<Self> {
+        let caps = caps.trim_end_matches('\0').trim();
+        let mut fields = caps.split(',');
+        let format = match fields.next().map(str::trim) {
+            Some("video/x-h264") => PixelFormat::H264,
+            Some("video/x-h265") => PixelFormat::H265,
+            other => anyhow::bail!("appsink must take video/x-h264 or video/x-h265, not {:?}", other.unwrap_or_default()),
+        };
+
+        let mut mode = Mode { format, width: 0, height: 0, fps: 0 };
+        for (key, value) in fields.filter_map(|field| field.split_once('=')) {
+            // Values may carry a type, e.g. (int)640.
+            let value = value.trim();
+            let value = value.strip_prefix('(').and_then(|value| value.split_once(')')).map_or(value, |(_, value)| value);
+            match key.trim() {
+                "width" => mode.width = value.parse().unwrap_or(0),
+                "height" => mode.height = value.parse().unwrap_or(0),
+                "framerate" => {
+                    if let Some((num, den)) = value.split_once('/') {
+                        let (num, den) = (num.parse::<u32>().unwrap_or(0), den.parse::<u32>().unwrap_or(1).max(1));
+                        mode.fps = (num + den / 2) / den;
+                    }
+                }
+                "stream-format" => anyhow::ensure!(value == "byte-stream", "appsink caps must use stream-format=byte-stream"),
+                "alignment" => anyhow::ensure!(value == "au", "appsink caps must use alignment=au"),
+                _ => {}
+            }
+        }
+        Ok(Caps { raw: caps.to_string(), mode })
+    }
+}
+
+const GDP_HEADER_LENGTH: usize = 62;
+const GDP_PAYLOAD_BUFFER: u16 = 1;
+const GDP_PAYLOAD_CAPS: u16 = 2;
+const GST_CLOCK_TIME_NONE: u64 = u64::MAX;
+
+#[derive(Debug, PartialEq)]
+pub enum GdpPacket {
+    Buffer { pts: Option<Duration>, data: Bytes },
+    Caps(String),
+    /// Events and anything else we do not need.
+    Other,
+}
+
+/// Reads the GStreamer Data Protocol as written by `gdppay`.
+pub struct GdpReader<R> {
+    reader: R,
+}
+
+impl<R: Read> GdpReader<R> {
+    pub fn new(reader: R) -> Self {
+        GdpReader { reader }
+    }
+
+    pub fn next(&mut self) -> Result<GdpPacket> {
+        let mut header = [0u8; GDP_HEADER_LENGTH];
+        self.reader.read_exact(&mut header).context("GStreamer pipeline stopped")?;
+        anyhow::ensure!(header[0] == 1, "unsupported GDP version {}.{}", header[0], header[1]);
+
+        let payload_type = u16::from_be_bytes([header[4], header[5]]);
+        let length = u32::from_be_bytes([header[6], header[7], header[8], header[9]]) as usize;
+        let mut payload = vec![0; length];
+        self.reader.read_exact(&mut payload)?;
+
+        Ok(match payload_type {
+            GDP_PAYLOAD_BUFFER => {
+                let mut pts = [0u8; 8];
+                pts.copy_from_slice(&header[10..18]);
+                let pts = u64::from_be_bytes(pts);
+                GdpPacket::Buffer {
+                    pts: if pts == GST_CLOCK_TIME_NONE { None } else { Some(Duration::from_nanos(pts)) },
+                    data: payload.into(),
+                }
+            }
+            GDP_PAYLOAD_CAPS => GdpPacket::Caps(String::from_utf8_lossy(&payload).into_owned()),
+            _ => GdpPacket::Other,
+        })
+    }
+}
+
+pub struct GstDevice {
+    pipeline: Pipeline,
+}
+
+impl GstDevice {
+    pub fn new(description: &str) -> Result<Self> {
+        Ok(GstDevice { pipeline: Pipeline::parse(description)? })
+    }
+}
+
+impl Device for GstDevice {
+    fn name(&self) -> String {
+        "GStreamer pipeline".to_string()
+    }
+
+    /// Only what the appsink caps pin down; the rest is up to the pipeline.
+    fn modes(&self) -> Result<Vec<Mode>> {
+        Ok(vec![self.pipeline.caps.mode])
+    }
+
+    fn open(&mut self, _mode: Mode) -> Result<Box<dyn Capture>> {
+        let description = self.pipeline.launch_description();
+        log::debug!("Launching {}", description);
+        let mut child = Command::new(GST_LAUNCH)
+            .arg("-q")
+            .arg(&description)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed running {}; is GStreamer installed?", GST_LAUNCH))?;
+        let stdout = child.stdout.take().context("no stdout from gst-launch")?;
+
+        let mut capture = GstCapture {
+            child,
+            reader: GdpReader::new(BufReader::new(stdout)),
+            mode: self.pipeline.caps.mode,
+            first_pts: None,
+            last: Duration::ZERO,
+            pending: None,
+        };
+        capture.wait_for_caps()?;
+        log::info!("GStreamer pipeline negotiated {}", capture.mode);
+        Ok(Box::new(capture))
+    }
+}
+
+struct GstCapture {
+    child: Child,
+    reader: GdpReader<BufReader<ChildStdout>>,
+    mode: Mode,
+    first_pts: Option<Duration>,
+    last: Duration,
+    /// A buffer read while waiting for caps.
+    pending: Option<GdpPacket>,
+}
+
+impl GstCapture {
+    /// Reads ahead to the negotiated caps, which carry the actual size and
+    /// frame rate.
+    fn wait_for_caps(&mut self) -> Result<()> {
+        loop {
+            match self.reader.next()? {
+                GdpPacket::Caps(caps) => {
+                    let negotiated = Caps::parse(&caps)?;
+                    anyhow::ensure!(negotiated.mode.format == self.mode.format, "pipeline negotiated {:?}", negotiated.mode.format);
+                    self.mode = negotiated.mode;
+                    return Ok(());
+                }
+                packet @ GdpPacket::Buffer { .. } => {
+                    self.pending = Some(packet);
+                    return Ok(());
+                }
+                GdpPacket::Other => {}
+            }
+        }
+    }
+}
+
+impl Capture for GstCapture {
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn next_frame(&mut self) -> Result<Frame> {
+        loop {
+            let packet = match self.pending.take() {
+                Some(packet) => packet,
+                None => self.reader.next()?,
+            };
+            if let GdpPacket::Buffer { pts, data } = packet {
+                // Buffers without a timestamp are given the previous one.
+                let timestamp = match pts {
+                    Some(pts) => pts.saturating_sub(*self.first_pts.get_or_insert(pts)),
+                    None => self.last,
+                };
+                self.last = timestamp;
+                return Ok(Frame { mode: self.mode, data, timestamp });
+            }
+        }
+    }
+}
+
+impl Drop for GstCapture {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn gdp(payload_type: u16, pts: u64, payload: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; GDP_HEADER_LENGTH];
+        header[0] = 1;
+        header[4..6].copy_from_slice(&payload_type.to_be_bytes());
+        header[6..10].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+        header[10..18].copy_from_slice(&pts.to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    #[test]
+    fn rewrites_appsink() {
+        let pipeline = Pipeline::parse(r#"videotestsrc ! x265enc ! h265parse ! appsink caps="video/x-h265, stream-format=byte-stream, alignment=au, width=(int)320" sync=false"#).unwrap();
+        assert_eq!(pipeline.caps.mode, Mode { format: PixelFormat::H265, width: 320, height: 0, fps: 0 });
+        assert_eq!(
+            pipeline.launch_description(),
+            "videotestsrc ! x265enc ! h265parse ! video/x-h265, stream-format=byte-stream, alignment=au, width=(int)320 ! gdppay ! fdsink fd=1 sync=false",
+        );
+
+        let pipeline = Pipeline::parse("videotestsrc ! x264enc ! appsink").unwrap();
+        assert!(pipeline.launch_description().contains(DEFAULT_CAPS));
+        assert!(Pipeline::parse("videotestsrc ! autovideosink").is_err());
+        assert!(Pipeline::parse("appsink").is_err());
+        assert!(Pipeline::parse("videotestsrc ! appsink caps=video/x-raw").is_err());
+    }
+
+    #[test]
+    fn parses_negotiated_caps() {
+        let caps = Caps::parse("video/x-h264, stream-format=(string)byte-stream, alignment=(string)au, width=(int)1280, height=(int)720, framerate=(fraction)30000/1001\0").unwrap();
+        assert_eq!(caps.mode, Mode { format: PixelFormat::H264, width: 1280, height: 720, fps: 30 });
+        assert!(Caps::parse("video/x-h264, stream-format=(string)avc").is_err());
+    }
+
+    #[test]
+    fn reads_gdp_stream() {
+        let mut stream = gdp(64 + 10, 0, b"event");
+        stream.extend(gdp(GDP_PAYLOAD_CAPS, GST_CLOCK_TIME_NONE, b"video/x-h264, width=(int)64\0"));
+        stream.extend(gdp(GDP_PAYLOAD_BUFFER, 1_000_000_000, &[0, 0, 0, 1, 0x65]));
+        stream.extend(gdp(GDP_PAYLOAD_BUFFER, GST_CLOCK_TIME_NONE, &[0, 0, 0, 1, 0x41]));
+
+        let mut reader = GdpReader { reader: Cursor::new(stream) };
+        assert_eq!(reader.next().unwrap(), GdpPacket::Other);
+        assert_eq!(reader.next().unwrap(), GdpPacket::Caps("video/x-h264, width=(int)64\0".to_string()));
+        assert_eq!(reader.next().unwrap(), GdpPacket::Buffer { pts: Some(Duration::from_secs(1)), data: Bytes::from_static(&[0, 0, 0, 1, 0x65]) });
+        assert_eq!(reader.next().unwrap(), GdpPacket::Buffer { pts: None, data: Bytes::from_static(&[0, 0, 0, 1, 0x41]) });
+        assert!(reader.next().is_err());
+    }
+}
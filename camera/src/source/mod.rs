@@ -0,0 +1,186 @@
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use crate::payload::AacConfig;
+
+mod file;
+mod gstreamer;
+mod rtsp;
+mod testpattern;
+#[cfg(feature = "v4l2")]
+mod v4l2;
+
+pub use file::FileDevice;
+pub use gstreamer::{GdpPacket, GdpReader, GstDevice, GST_LAUNCH};
+pub use rtsp::RtspDevice;
+pub use testpattern::TestPattern;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(not(feature = "v4l2"), allow(dead_code))]
+pub enum PixelFormat {
+    /// H.264 Annex B access units.
+    H264,
+    /// H.265 Annex B access units.
+    H265,
+    Vp8,
+    Vp9,
+    Mjpeg,
+    /// Packed YUV 4:2:2.
+    Yuyv,
+}
+
+impl PixelFormat {
+    /// Whether frames are already compressed and can be sent as they are.
+    pub fn is_encoded(self) -> bool {
+        matches!(self, PixelFormat::H264 | PixelFormat::H265 | PixelFormat::Vp8 | PixelFormat::Vp9)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    pub format: PixelFormat,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {}x{}@{}", self.format, self.width, self.height, self.fps)
+    }
+}
+
+pub struct Frame {
+    pub mode: Mode,
+    pub data: Bytes,
+    /// Time since capture started.
+    pub timestamp: Duration,
+}
+
+/// Encoded AAC captured alongside the video.
+pub struct AudioStream {
+    pub config: AacConfig,
+    pub frames: mpsc::Receiver<AudioFrame>,
+}
+
+pub struct AudioFrame {
+    pub data: Bytes,
+    /// Time since capture started.
+    pub timestamp: Duration,
+}
+
+/// Returned by [`Capture::next_frame`] when a source has nothing more to play.
+#[derive(Debug, thiserror::Error)]
+#[error("end of stream")]
+pub struct EndOfStream;
+
+/// A camera that can be probed for its capture modes and then opened.
+pub trait Device: Send {
+    fn name(&self) -> String;
+    fn modes(&self) -> Result<Vec<Mode>>;
+    fn open(&mut self, mode: Mode) -> Result<Box<dyn Capture>>;
+}
+
+/// An open capture stream.
+pub trait Capture: Send {
+    fn mode(&self) -> Mode;
+    /// Blocks until the next frame is available.
+    fn next_frame(&mut self) -> Result<Frame>;
+    /// Audio from the same source, if it has any. Audio frames are only
+    /// produced while video frames are being read.
+    fn take_audio(&mut self) -> Option<AudioStream> {
+        None
+    }
+}
+
+/// Settings that only apply to some kinds of device.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Stop at the end of a file instead of looping.
+    pub once: bool,
+}
+
+/// Opens a device by specification: `test` for the synthetic test pattern,
+/// `v4l2:<path>` for a Video4Linux device, an `rtsp://` URL for an IP camera,
+/// `gst:<pipeline>` for a GStreamer pipeline ending in an appsink or
+/// `file:<path>` for an IVF, MP4 or Annex B video file.
+pub fn open(spec: &str, options: &Options) -> Result<Box<dyn Device>> {
+    if spec == "test" {
+        return Ok(Box::new(TestPattern));
+    }
+    if spec.starts_with("rtsp://") {
+        return Ok(Box::new(RtspDevice::new(spec)?));
+    }
+    if let Some(pipeline) = spec.strip_prefix("gst:") {
+        return Ok(Box::new(GstDevice::new(pipeline)?));
+    }
+    if let Some(path) = spec.strip_prefix("file:") {
+        return Ok(Box::new(FileDevice::new(path, !options.once)?));
+    }
+    if let Some(path) = spec.strip_prefix("v4l2:") {
+        #[cfg(feature = "v4l2")]
+        return Ok(Box::new(v4l2::V4l2Device::open(path)?));
+        #[cfg(not(feature = "v4l2"))]
+        anyhow::bail!("cannot open {}: built without the v4l2 feature", path);
+    }
+    anyhow::bail!("unknown source {:?}", spec)
+}
+
+/// Specifications of every device that can be opened on this machine.
+pub fn list() -> Vec<(String, String)> {
+    #[allow(unused_mut)]
+    let mut devices = vec![("test".to_string(), TestPattern.name())];
+    #[cfg(feature = "v4l2")]
+    devices.extend(v4l2::list());
+    devices
+}
+
+/// Chooses the mode closest to the requested size and frame rate, preferring
+/// formats that need the least work to turn into H.264.
+pub fn negotiate(modes: &[Mode], width: u32, height: u32, fps: u32) -> Option<Mode> {
+    fn format_cost(format: PixelFormat) -> u32 {
+        match format {
+            PixelFormat::H264 | PixelFormat::Vp8 | PixelFormat::Vp9 => 0,
+            // Passed through as well, but fewer viewers can decode it.
+            PixelFormat::H265 => 1,
+            PixelFormat::Yuyv => 2,
+            PixelFormat::Mjpeg => 3,
+        }
+    }
+
+    modes.iter()
+        .copied()
+        .min_by_key(|mode| {
+            let area = |w: u32, h: u32| (w * h) as i64;
+            let too_big = mode.width > width || mode.height > height;
+            let size_diff = (area(width, height) - area(mode.width, mode.height)).abs();
+            let fps_diff = (fps as i64 - mode.fps as i64).abs();
+            (too_big, size_diff, format_cost(mode.format), fps_diff)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(format: PixelFormat, width: u32, height: u32, fps: u32) -> Mode {
+        Mode { format, width, height, fps }
+    }
+
+    #[test]
+    fn prefers_native_h264_at_requested_size() {
+        let modes = [
+            mode(PixelFormat::Yuyv, 640, 480, 30),
+            mode(PixelFormat::Mjpeg, 1280, 720, 30),
+            mode(PixelFormat::H264, 1280, 720, 30),
+            mode(PixelFormat::H264, 1920, 1080, 30),
+        ];
+        assert_eq!(negotiate(&modes, 1280, 720, 30), Some(modes[2]));
+        assert_eq!(negotiate(&modes, 800, 600, 30), Some(modes[0]));
+        assert_eq!(negotiate(&[], 800, 600, 30), None);
+    }
+}
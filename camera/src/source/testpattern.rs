@@ -0,0 +1,111 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use super::{Capture, Device, Frame, Mode, PixelFormat};
+
+/// 75% colour bars in (Y, U, V): white, yellow, cyan, green, magenta, red, blue.
+const BARS: [(u8, u8, u8); 7] = [
+    (180, 128, 128),
+    (162, 44, 142),
+    (131, 156, 44),
+    (112, 72, 58),
+    (84, 184, 198),
+    (65, 100, 212),
+    (35, 212, 114),
+];
+
+/// A synthetic camera producing colour bars with a moving block, so the whole
+/// capture and streaming path can be exercised on a machine without a camera.
+#[derive(Debug, Default)]
+pub struct TestPattern;
+
+impl Device for TestPattern {
+    fn name(&self) -> String {
+        "Test pattern".to_string()
+    }
+
+    fn modes(&self) -> Result<Vec<Mode>> {
+        let sizes = [(320, 240), (640, 480), (1280, 720)];
+        Ok(sizes.iter()
+            .flat_map(|&(width, height)| [15, 30].iter().map(move |&fps| Mode { format: PixelFormat::Yuyv, width, height, fps }))
+            .collect())
+    }
+
+    fn open(&mut self, mode: Mode) -> Result<Box<dyn Capture>> {
+        anyhow::ensure!(mode.format == PixelFormat::Yuyv, "test pattern only produces YUYV");
+        anyhow::ensure!(mode.width.is_multiple_of(2) && mode.fps > 0, "unsupported mode {}", mode);
+        Ok(Box::new(TestPatternCapture { mode, started: None, frame: 0 }))
+    }
+}
+
+struct TestPatternCapture {
+    mode: Mode,
+    started: Option<Instant>,
+    frame: u64,
+}
+
+impl TestPatternCapture {
+    fn render(&self) -> Vec<u8> {
+        let (width, height) = (self.mode.width as usize, self.mode.height as usize);
+        let block = (height / 4).max(2) & !1;
+        let travel = (width - block).max(1);
+        let block_x = ((self.frame as usize * 4) % travel) & !1;
+        let block_y = (height - block) / 2;
+
+        let mut data = Vec::with_capacity(width * height * 2);
+        for y in 0..height {
+            for x in (0..width).step_by(2) {
+                let in_block = (block_x..block_x + block).contains(&x) && (block_y..block_y + block).contains(&y);
+                let (luma, u, v) = if in_block {
+                    (235, 128, 128)
+                } else {
+                    BARS[x * BARS.len() / width]
+                };
+                data.extend_from_slice(&[luma, u, luma, v]);
+            }
+        }
+        data
+    }
+}
+
+impl Capture for TestPatternCapture {
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn next_frame(&mut self) -> Result<Frame> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let timestamp = Duration::from_secs(self.frame) / self.mode.fps;
+        if let Some(wait) = timestamp.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+
+        let data = self.render();
+        self.frame += 1;
+        Ok(Frame { mode: self.mode, data: Bytes::from(data), timestamp })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::I420;
+
+    #[test]
+    fn renders_moving_block() {
+        let mode = Mode { format: PixelFormat::Yuyv, width: 64, height: 48, fps: 1000 };
+        let mut capture = TestPattern.open(mode).unwrap();
+
+        let first = capture.next_frame().unwrap();
+        let second = capture.next_frame().unwrap();
+        assert_eq!(first.data.len(), 64 * 48 * 2);
+        assert_ne!(first.data, second.data);
+        assert_eq!(second.timestamp, Duration::from_millis(1));
+
+        let picture = I420::from_yuyv(64, 48, &first.data).unwrap();
+        assert_eq!(picture.y(63, 0), BARS[6].0);
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use v4l::buffer::Type;
+use v4l::capability::Flags;
+use v4l::format::FourCC;
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
+use v4l::io::mmap::Stream as MmapStream;
+use v4l::io::traits::CaptureStream;
+use v4l::video::capture::Parameters;
+use v4l::video::Capture as _;
+
+use super::{Capture, Device, Frame, Mode, PixelFormat};
+
+const BUFFERS: u32 = 4;
+
+fn pixel_format(fourcc: &FourCC) -> Option<PixelFormat> {
+    match &fourcc.repr {
+        b"H264" => Some(PixelFormat::H264),
+        b"HEVC" => Some(PixelFormat::H265),
+        b"VP80" => Some(PixelFormat::Vp8),
+        b"VP90" => Some(PixelFormat::Vp9),
+        b"MJPG" => Some(PixelFormat::Mjpeg),
+        b"YUYV" => Some(PixelFormat::Yuyv),
+        _ => None,
+    }
+}
+
+fn fourcc(format: PixelFormat) -> FourCC {
+    match format {
+        PixelFormat::H264 => FourCC::new(b"H264"),
+        PixelFormat::H265 => FourCC::new(b"HEVC"),
+        PixelFormat::Vp8 => FourCC::new(b"VP80"),
+        PixelFormat::Vp9 => FourCC::new(b"VP90"),
+        PixelFormat::Mjpeg => FourCC::new(b"MJPG"),
+        PixelFormat::Yuyv => FourCC::new(b"YUYV"),
+    }
+}
+
+/// Video capture nodes, skipping metadata and codec nodes that cannot stream frames.
+pub fn list() -> Vec<(String, String)> {
+    v4l::context::enum_devices()
+        .into_iter()
+        .filter(|node| {
+            v4l::Device::with_path(node.path())
+                .and_then(|dev| dev.query_caps())
+                .map(|caps| caps.capabilities.contains(Flags::VIDEO_CAPTURE | Flags::STREAMING))
+                .unwrap_or(false)
+        })
+        .map(|node| {
+            let name = node.name().unwrap_or_else(|| node.path().display().to_string());
+            (format!("v4l2:{}", node.path().display()), name)
+        })
+        .collect()
+}
+
+pub struct V4l2Device {
+    path: PathBuf,
+    device: v4l::Device,
+    name: String,
+}
+
+impl V4l2Device {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let device = v4l::Device::with_path(&path).with_context(|| format!("Failed opening {}", path.display()))?;
+        let caps = device.query_caps()?;
+        anyhow::ensure!(
+            caps.capabilities.contains(Flags::VIDEO_CAPTURE | Flags::STREAMING),
+            "{} is not a streaming capture device", path.display()
+        );
+        Ok(V4l2Device { path, device, name: caps.card })
+    }
+}
+
+impl Device for V4l2Device {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn modes(&self) -> Result<Vec<Mode>> {
+        let mut modes = Vec::new();
+        for description in self.device.enum_formats()? {
+            let format = match pixel_format(&description.fourcc) {
+                Some(format) => format,
+                None => continue,
+            };
+            for framesize in self.device.enum_framesizes(description.fourcc)? {
+                let sizes = match framesize.size {
+                    FrameSizeEnum::Discrete(size) => vec![(size.width, size.height)],
+                    // Stepwise devices can do anything in range; offer the common sizes that fit.
+                    FrameSizeEnum::Stepwise(range) => [(640, 480), (1280, 720), (1920, 1080)]
+                        .iter()
+                        .copied()
+                        .filter(|&(w, h)| (range.min_width..=range.max_width).contains(&w) && (range.min_height..=range.max_height).contains(&h))
+                        .collect(),
+                };
+                for (width, height) in sizes {
+                    let intervals = self.device.enum_frameintervals(description.fourcc, width, height)?;
+                    for interval in intervals {
+                        let fraction = match interval.interval {
+                            FrameIntervalEnum::Discrete(fraction) => fraction,
+                            FrameIntervalEnum::Stepwise(range) => range.min,
+                        };
+                        if fraction.numerator > 0 {
+                            modes.push(Mode { format, width, height, fps: fraction.denominator / fraction.numerator });
+                        }
+                    }
+                }
+            }
+        }
+        Ok(modes)
+    }
+
+    fn open(&mut self, mode: Mode) -> Result<Box<dyn Capture>> {
+        let requested = v4l::Format::new(mode.width, mode.height, fourcc(mode.format));
+        let actual = self.device.set_format(&requested)?;
+        anyhow::ensure!(
+            pixel_format(&actual.fourcc) == Some(mode.format),
+            "{} refused {:?}, offered {}", self.path.display(), mode.format, actual.fourcc
+        );
+        let params = self.device.set_params(&Parameters::with_fps(mode.fps))?;
+        let fps = if params.interval.numerator > 0 { params.interval.denominator / params.interval.numerator } else { mode.fps };
+
+        let mode = Mode { format: mode.format, width: actual.width, height: actual.height, fps };
+        log::info!("{}: capturing {}", self.path.display(), mode);
+
+        let stream = MmapStream::with_buffers(&self.device, Type::VideoCapture, BUFFERS)?;
+        Ok(Box::new(V4l2Capture { mode, stream, start: None }))
+    }
+}
+
+struct V4l2Capture {
+    mode: Mode,
+    stream: MmapStream<'static>,
+    start: Option<Duration>,
+}
+
+impl Capture for V4l2Capture {
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn next_frame(&mut self) -> Result<Frame> {
+        let (buf, meta) = CaptureStream::next(&mut self.stream)?;
+        let data = Bytes::copy_from_slice(&buf[..meta.bytesused as usize]);
+
+        let captured = Duration::from_secs(meta.timestamp.sec as u64) + Duration::from_micros(meta.timestamp.usec as u64);
+        let start = *self.start.get_or_insert(captured);
+        Ok(Frame { mode: self.mode, data, timestamp: captured.saturating_sub(start) })
+    }
+}
@@ -0,0 +1,68 @@
+//! The video streams a camera offers: its main stream and any lower
+//! resolution layers encoded alongside it.
+
+use std::sync::Arc;
+
+use crate::source::PixelFormat;
+use crate::track::{Codec, MediaTrack};
+
+/// A video stream this camera offers to viewers.
+pub struct VideoStream {
+    pub descriptor: common::StreamDescriptor,
+    pub track: Arc<MediaTrack>,
+}
+
+impl VideoStream {
+    pub fn new(id: &str, format: PixelFormat, width: u32, height: u32, fps: u32) -> Self {
+        let track = Arc::new(MediaTrack::new(Codec::video(format), &format!("video-{}", id)));
+        let descriptor = common::StreamDescriptor { id: id.to_string(), codec: track.codec().name().to_string(), width, height, fps };
+        VideoStream { descriptor, track }
+    }
+}
+
+/// Identifier for the scaled layer at `index`, counting from zero after the
+/// main stream: `sub`, then `sub2`, `sub3` and so on.
+pub fn layer_id(index: usize) -> String {
+    match index {
+        0 => common::SUB_STREAM.to_string(),
+        index => format!("{}{}", common::SUB_STREAM, index + 1),
+    }
+}
+
+/// The largest stream that fits within the given size, or the smallest one
+/// if none does. Only streams sharing `codec` are considered, since a session
+/// cannot switch codecs without renegotiating.
+pub fn select<'a>(streams: &'a [VideoStream], codec: &str, max_width: u32, max_height: u32) -> Option<&'a VideoStream> {
+    let area = |stream: &VideoStream| stream.descriptor.width as u64 * stream.descriptor.height as u64;
+    let candidates = streams.iter().filter(|stream| stream.descriptor.codec.eq_ignore_ascii_case(codec));
+    let fitting = candidates.clone()
+        .filter(|stream| stream.descriptor.width <= max_width && stream.descriptor.height <= max_height)
+        .max_by_key(|stream| area(stream));
+    fitting.or_else(|| candidates.min_by_key(|stream| area(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn streams() -> Vec<VideoStream> {
+        vec![
+            VideoStream::new(common::MAIN_STREAM, PixelFormat::Yuyv, 1280, 720, 15),
+            VideoStream::new(&layer_id(0), PixelFormat::Yuyv, 640, 360, 15),
+            VideoStream::new(&layer_id(1), PixelFormat::Yuyv, 320, 180, 15),
+            VideoStream::new("other", PixelFormat::Vp8, 640, 360, 15),
+        ]
+    }
+
+    #[test]
+    fn picks_largest_layer_that_fits() {
+        let streams = streams();
+        let id = |max_width, max_height| select(&streams, "H264", max_width, max_height).map(|stream| stream.descriptor.id.as_str());
+        assert_eq!(id(1920, 1080), Some("main"));
+        assert_eq!(id(800, 600), Some("sub"));
+        assert_eq!(id(400, 400), Some("sub2"));
+        assert_eq!(id(100, 100), Some("sub2"));
+        assert_eq!(select(&streams, "VP8", 100, 100).unwrap().descriptor.id, "other");
+        assert!(select(&streams, "VP9", 100, 100).is_none());
+    }
+}
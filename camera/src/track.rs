@@ -0,0 +1,248 @@
+use std::any::Any;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::sync::Mutex;
+use webrtc::api::media_engine::{MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9};
+use webrtc::rtp::codecs::h264::H264Payloader;
+use webrtc::rtp::codecs::vp8::Vp8Payloader;
+use webrtc::rtp::codecs::vp9::Vp9Payloader;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::{Packetizer, Payloader};
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType};
+use webrtc::track::track_local::{TrackLocal, TrackLocalContext, TrackLocalWriter};
+
+use crate::payload::{AacConfig, AacPayloader, H265Payloader};
+use crate::source::PixelFormat;
+
+pub const MIME_TYPE_H265: &str = "video/H265";
+pub const MIME_TYPE_AAC: &str = "audio/MPEG4-GENERIC";
+
+/// Same as the webrtc crate uses for its own packetizers.
+const MTU: usize = 1200;
+/// How long a sender switched away from a track has to be bound to another
+/// before where it left off is forgotten.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a sender left off on a track, by the last packet it sent.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    sequence_number: u16,
+    timestamp: u32,
+    sent: Instant,
+}
+
+/// Where senders left off when they were unbound from a track, by SSRC, until
+/// they are bound to the next one. Each track numbers its packets from its own
+/// random start, and viewers drop packets whose numbers jump back as replays,
+/// so a sender switched between tracks carries on from here instead. Senders
+/// that were closed rather than switched are never picked up, so these time out.
+static HANDOVERS: std::sync::Mutex<Vec<(u32, Position)>> = std::sync::Mutex::new(Vec::new());
+
+/// A codec we send, with the payload type we register it under.
+#[derive(Debug, Clone)]
+pub struct Codec {
+    pub parameters: RTCRtpCodecParameters,
+    pub kind: RTPCodecType,
+}
+
+impl Codec {
+    pub fn video(format: PixelFormat) -> Self {
+        let mime_type = match format {
+            PixelFormat::H265 => MIME_TYPE_H265,
+            PixelFormat::Vp8 => MIME_TYPE_VP8,
+            PixelFormat::Vp9 => MIME_TYPE_VP9,
+            // Everything else is encoded to H.264 before it is sent.
+            _ => MIME_TYPE_H264,
+        };
+        let capability = RTCRtpCodecCapability { mime_type: mime_type.to_owned(), clock_rate: 90000, ..Default::default() };
+        let parameters = RTCRtpCodecParameters { capability, payload_type: 118, ..Default::default() };
+        Codec { parameters, kind: RTPCodecType::Video }
+    }
+
+    pub fn aac(config: &AacConfig) -> Self {
+        let capability = RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_AAC.to_owned(),
+            clock_rate: config.sample_rate,
+            channels: config.channels,
+            sdp_fmtp_line: config.fmtp_line(),
+            ..Default::default()
+        };
+        let parameters = RTCRtpCodecParameters { capability, payload_type: 110, ..Default::default() };
+        Codec { parameters, kind: RTPCodecType::Audio }
+    }
+
+    /// Encoding name as it appears in SDP `a=rtpmap` lines.
+    pub fn name(&self) -> &str {
+        let mime_type = &self.parameters.capability.mime_type;
+        mime_type.split_once('/').map_or(mime_type.as_str(), |(_, name)| name)
+    }
+
+    /// Whether the webrtc crate already knows this codec, or it has to be
+    /// registered with the media engine.
+    pub fn is_builtin(&self) -> bool {
+        [MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9].iter().any(|mime_type| self.parameters.capability.mime_type.eq_ignore_ascii_case(mime_type))
+    }
+
+    fn payloader(&self) -> Box<dyn Payloader + Send + Sync> {
+        match self.name().to_ascii_uppercase().as_str() {
+            "H265" => Box::new(H265Payloader),
+            "VP8" => Box::new(Vp8Payloader::default()),
+            "VP9" => Box::new(Vp9Payloader::default()),
+            "MPEG4-GENERIC" => Box::new(AacPayloader),
+            _ => Box::new(H264Payloader::default()),
+        }
+    }
+}
+
+/// A sender the track is bound to.
+struct Binding {
+    id: String,
+    ssrc: u32,
+    payload_type: u8,
+    write_stream: Arc<dyn TrackLocalWriter + Send + Sync>,
+    /// Where the sender left off on the track it was switched from.
+    handover: Option<Position>,
+    /// Added to the sequence numbers and timestamps of the track's packets,
+    /// once the first has been sent.
+    offsets: Option<(u16, u32)>,
+    last: Option<Position>,
+}
+
+impl Binding {
+    fn rewrite(&mut self, packet: &mut Packet, clock_rate: u32) {
+        let header = &mut packet.header;
+        let handover = self.handover;
+        let (sequence_offset, time_offset) = *self.offsets.get_or_insert_with(|| match handover {
+            // The first packet is a new frame, so it is stamped a little after the last one at least.
+            Some(from) => {
+                let elapsed = ((from.sent.elapsed().as_secs_f64() * clock_rate as f64).round() as u32).max(1);
+                (from.sequence_number.wrapping_add(1).wrapping_sub(header.sequence_number), from.timestamp.wrapping_add(elapsed).wrapping_sub(header.timestamp))
+            }
+            None => (0, 0),
+        });
+        header.ssrc = self.ssrc;
+        header.payload_type = self.payload_type;
+        header.sequence_number = header.sequence_number.wrapping_add(sequence_offset);
+        header.timestamp = header.timestamp.wrapping_add(time_offset);
+        self.last = Some(Position { sequence_number: header.sequence_number, timestamp: header.timestamp, sent: Instant::now() });
+    }
+}
+
+/// What the track's senders are bound to, writing each packet out to every
+/// one of them like the webrtc crate's `TrackLocalStaticRTP`, but numbered
+/// on from wherever the sender left off on the track it was switched from.
+struct Output {
+    capability: RTCRtpCodecCapability,
+    id: String,
+    bindings: Mutex<Vec<Binding>>,
+}
+
+#[async_trait]
+impl TrackLocal for Output {
+    async fn bind(&self, context: &TrackLocalContext) -> webrtc::error::Result<RTCRtpCodecParameters> {
+        let codecs = context.codec_parameters();
+        let same_codec = |codec: &&RTCRtpCodecParameters| codec.capability.mime_type.eq_ignore_ascii_case(&self.capability.mime_type);
+        let codec = codecs.iter().filter(same_codec).find(|codec| codec.capability.sdp_fmtp_line == self.capability.sdp_fmtp_line)
+            .or_else(|| codecs.iter().find(same_codec))
+            .ok_or(webrtc::Error::ErrUnsupportedCodec)?;
+        let write_stream = context.write_stream().ok_or(webrtc::Error::ErrRTPSenderTrackNil)?;
+        let handover = {
+            let mut handovers = HANDOVERS.lock().unwrap();
+            handovers.retain(|(_, position)| position.sent.elapsed() < HANDOVER_TIMEOUT);
+            let index = handovers.iter().position(|(ssrc, _)| *ssrc == context.ssrc());
+            index.map(|index| handovers.swap_remove(index).1)
+        };
+        self.bindings.lock().await.push(Binding {
+            id: context.id(),
+            ssrc: context.ssrc(),
+            payload_type: codec.payload_type,
+            write_stream,
+            handover,
+            offsets: None,
+            last: None,
+        });
+        Ok(codec.clone())
+    }
+
+    async fn unbind(&self, context: &TrackLocalContext) -> webrtc::error::Result<()> {
+        let mut bindings = self.bindings.lock().await;
+        let index = bindings.iter().position(|binding| binding.id == context.id()).ok_or(webrtc::Error::ErrUnbindFailed)?;
+        let binding = bindings.swap_remove(index);
+        // A sender that hasn't sent anything on this track yet is still where it left off before.
+        if let Some(position) = binding.last.or(binding.handover) {
+            HANDOVERS.lock().unwrap().push((binding.ssrc, position));
+        }
+        Ok(())
+    }
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn stream_id(&self) -> &str {
+        "rstream"
+    }
+
+    fn kind(&self) -> RTPCodecType {
+        if self.capability.mime_type.starts_with("audio/") { RTPCodecType::Audio } else { RTPCodecType::Video }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A track shared by every viewer, fed with already encoded access units.
+pub struct MediaTrack {
+    codec: Codec,
+    output: Arc<Output>,
+    packetizer: Mutex<Box<dyn Packetizer + Send + Sync>>,
+}
+
+impl MediaTrack {
+    pub fn new(codec: Codec, id: &str) -> Self {
+        let output = Arc::new(Output { capability: codec.parameters.capability.clone(), id: id.to_owned(), bindings: Mutex::new(Vec::new()) });
+        let packetizer = webrtc::rtp::packetizer::new_packetizer(
+            MTU,
+            codec.parameters.payload_type,
+            // Replaced per viewer when the track writes packets out.
+            0,
+            codec.payloader(),
+            Box::new(webrtc::rtp::sequence::new_random_sequencer()),
+            codec.parameters.capability.clock_rate,
+        );
+        MediaTrack { codec, output, packetizer: Mutex::new(Box::new(packetizer)) }
+    }
+
+    pub fn codec(&self) -> &Codec {
+        &self.codec
+    }
+
+    pub fn track(&self) -> Arc<dyn TrackLocal + Send + Sync> {
+        self.output.clone()
+    }
+
+    /// Sends one access unit lasting `duration` to every viewer, even if
+    /// some of them fail, returning the first failure.
+    pub async fn write(&self, data: &Bytes, duration: Duration) -> Result<()> {
+        let clock_rate = self.codec.parameters.capability.clock_rate;
+        let samples = (duration.as_secs_f64() * clock_rate as f64).round() as u32;
+        let packets = self.packetizer.lock().await.packetize(data, samples).await?;
+        let mut result = Ok(());
+        for binding in self.output.bindings.lock().await.iter_mut() {
+            for packet in &packets {
+                let mut packet = packet.clone();
+                binding.rewrite(&mut packet, clock_rate);
+                if let Err(err) = binding.write_stream.write_rtp(&packet).await {
+                    result = result.and(Err(err));
+                    break;
+                }
+            }
+        }
+        Ok(result?)
+    }
+}
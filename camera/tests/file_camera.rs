@@ -0,0 +1,244 @@
+//! Runs the signalling server with a camera playing a file, and watches the
+//! camera the way the web app does: both log in, the camera registers, a
+//! viewer sends an offer, the camera answers, and video arrives over the
+//! negotiated connection.
+
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::APIBuilder;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::interceptor::registry::Registry;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// A child process killed when the test is done with it.
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// The server binary, built if need be since it belongs to another package.
+fn server_binary() -> PathBuf {
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "-p", "rstream", "--bin", "rstream"])
+        .status()
+        .expect("Failed running cargo");
+    assert!(status.success(), "Failed building the server");
+    Path::new(env!("CARGO_BIN_EXE_camera")).with_file_name("rstream")
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// An IVF file of VP8 frames at 30 fps. The frames aren't decodable, which
+/// doesn't matter since only their delivery is checked.
+fn ivf(frames: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(b"DKIF");
+    data.extend_from_slice(&0u16.to_le_bytes());
+    data.extend_from_slice(&32u16.to_le_bytes());
+    data.extend_from_slice(b"VP80");
+    data.extend_from_slice(&320u16.to_le_bytes());
+    data.extend_from_slice(&240u16.to_le_bytes());
+    data.extend_from_slice(&30u32.to_le_bytes());
+    data.extend_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(&(frames as u32).to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    for pts in 0..frames as u64 {
+        let mut frame = vec![0x50, 0x01, 0x00, 0x9d, 0x01, 0x2a, 0x40, 0x01, 0xf0, 0x00];
+        frame.resize(200, pts as u8);
+        data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        data.extend_from_slice(&pts.to_le_bytes());
+        data.extend_from_slice(&frame);
+    }
+    data
+}
+
+/// Logs in as the admin the server was started with, returning a token.
+async fn login(port: u16) -> Result<String> {
+    let client = reqwest::Client::builder().danger_accept_invalid_certs(true).build()?;
+    let response = client.post(format!("https://localhost:{}/api/login", port))
+        .json(&common::Credentials { name: "admin".to_string(), password: "secret".to_string() })
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.json::<common::Token>().await?.token)
+}
+
+async fn connect(url: &str) -> Result<Socket> {
+    let tls = native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).build()?;
+    let (socket, _) = tokio_tungstenite::connect_async_tls_with_config(url, None, true, Some(Connector::NativeTls(tls))).await?;
+    Ok(socket)
+}
+
+async fn send(socket: &mut Socket, msg: &common::ServerMsg) -> Result<()> {
+    socket.send(Message::text(serde_json::to_string(msg)?)).await?;
+    Ok(())
+}
+
+async fn receive(socket: &mut Socket) -> Result<common::ClientMsg> {
+    loop {
+        match socket.next().await.context("server closed the connection")?? {
+            Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+            Message::Close(frame) => anyhow::bail!("server closed the connection: {:?}", frame),
+            _ => {}
+        }
+    }
+}
+
+/// Asks for the camera list until the camera shows up.
+async fn wait_for_camera(socket: &mut Socket, name: &str) -> Result<common::Camera> {
+    loop {
+        send(socket, &common::ServerMsg::ListCameras).await?;
+        if let common::ClientMsg::ListCameras { cameras } = receive(socket).await? {
+            if let Some(camera) = cameras.into_iter().find(|camera| camera.descriptor.name == name) {
+                return Ok(camera);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+async fn watch(url: &str, port: u16) -> Result<()> {
+    let mut socket = connect(&format!("{}?token={}", url, login(port).await?)).await?;
+    let camera = wait_for_camera(&mut socket, "Test file").await?;
+    assert_eq!(camera.descriptor.streams[0].id, common::MAIN_STREAM);
+
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs()?;
+    let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+    let api = APIBuilder::new().with_media_engine(media_engine).with_interceptor_registry(registry).build();
+    let pc = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?);
+    let recvonly = RTCRtpTransceiverInit { direction: RTCRtpTransceiverDirection::Recvonly, send_encodings: Vec::new() };
+    pc.add_transceiver_from_kind(RTPCodecType::Video, &[recvonly]).await?;
+
+    let (packets, mut received) = mpsc::unbounded_channel();
+    pc.on_track(Box::new(move |track, _| {
+        let packets = packets.clone();
+        tokio::spawn(async move {
+            let track = match track {
+                Some(track) => track,
+                None => return,
+            };
+            while let Ok((packet, _)) = track.read_rtp().await {
+                if packets.send(packet).is_err() {
+                    break;
+                }
+            }
+        });
+        Box::pin(async {})
+    }));
+
+    // Candidates go in the offer, so the camera's are the only ones trickled.
+    let mut gathered = pc.gathering_complete_promise().await;
+    let offer = pc.create_offer(None).await?;
+    pc.set_local_description(offer).await?;
+    let _ = gathered.recv().await;
+    let sdp = pc.local_description().await.context("no local description")?.sdp;
+    send(&mut socket, &common::ServerMsg::Signal { signal: common::Signal::Offer { sdp }, recipient: camera.id }).await?;
+
+    // The camera may trickle candidates ahead of its answer, which can only be
+    // added once the answer is set.
+    let mut answered = false;
+    let mut pending = Vec::new();
+    loop {
+        tokio::select! {
+            msg = receive(&mut socket) => match msg? {
+                common::ClientMsg::Signal { signal: common::Signal::Answer { sdp }, sender } if sender == camera.id => {
+                    pc.set_remote_description(RTCSessionDescription::answer(sdp)?).await?;
+                    answered = true;
+                    for candidate in pending.drain(..) {
+                        pc.add_ice_candidate(candidate).await?;
+                    }
+                }
+                common::ClientMsg::Signal { signal: common::Signal::NewIceCandidate { candidate }, sender } if sender == camera.id => {
+                    let candidate = RTCIceCandidateInit {
+                        candidate: candidate.candidate,
+                        sdp_mid: candidate.sdp_mid,
+                        sdp_mline_index: candidate.sdp_m_line_index,
+                        username_fragment: None,
+                    };
+                    if answered {
+                        pc.add_ice_candidate(candidate).await?;
+                    } else {
+                        pending.push(candidate);
+                    }
+                }
+                common::ClientMsg::Signal { signal: common::Signal::Hangup, .. } => anyhow::bail!("the camera hung up"),
+                common::ClientMsg::Error { code, message } => anyhow::bail!("server rejected a message ({:?}): {}", code, message),
+                _ => {}
+            },
+            packet = received.recv() => {
+                let packet = packet.context("track ended")?;
+                assert!(answered, "media arrived before the answer");
+                assert!(!packet.payload.is_empty());
+                break;
+            }
+        }
+    }
+    pc.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn file_camera_streams_to_a_viewer() {
+    let server = server_binary();
+    let dir = std::env::temp_dir().join(format!("rstream-file-camera-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    for file in ["localhost.crt", "localhost.key"] {
+        std::fs::copy(data.join(file), dir.join(file)).unwrap();
+    }
+    let video = dir.join("test.ivf");
+    std::fs::write(&video, ivf(30)).unwrap();
+
+    let port = free_port();
+    let _server = Process(Command::new(server)
+        .args(["--port", &port.to_string(), "--admin-password", "secret"])
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Failed starting the server"));
+    let url = format!("wss://localhost:{}/ws", port);
+    // The camera gives up if it can't connect, so wait for the server.
+    tokio::time::timeout(TIMEOUT, async {
+        while tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }).await.expect("The server didn't start listening");
+
+    let _camera = Process(Command::new(env!("CARGO_BIN_EXE_camera"))
+        .args(["--server", &url, "--insecure", "--name", "Test file", "--snapshot-interval", "0", "--user", "admin", "--password", "secret"])
+        .arg("--device").arg(format!("file:{}", video.display()))
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Failed starting the camera"));
+
+    let result = tokio::time::timeout(TIMEOUT, watch(&url, port)).await;
+    let _ = std::fs::remove_dir_all(&dir);
+    result.expect("Timed out watching the camera").unwrap();
+}
@@ -4,6 +4,8 @@ use anyhow::{Result,Context};
 
 use serde::{Deserialize, Serialize};
 
+pub mod sdp;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "kebab-case")]
 pub struct IceCandidate {
@@ -18,14 +20,325 @@ pub struct IceCandidate {
 pub enum Signal {
     Offer { sdp: String },    
     Answer { sdp: String },    
-    NewIceCandidate { candidate: IceCandidate }
+    NewIceCandidate { candidate: IceCandidate },
+    /// Chooses which of a camera's streams it sends to this viewer. Sent
+    /// before the offer; sent again during a session it switches streams.
+    Watch { stream: String },
+    /// Asks a camera for its largest stream that fits within the given size,
+    /// such as the tile the viewer shows it in.
+    Constrain { max_width: u32, max_height: u32 },
+    /// Motion started or stopped in front of the sending camera. Passed on by
+    /// the server to every other peer when a camera reports it.
+    Motion { active: bool },
+    /// The sender has left the session, either explicitly or because the
+    /// server lost contact with it.
+    Hangup
+}
+
+/// Stream every camera sends, at the best quality it has.
+pub const MAIN_STREAM: &str = "main";
+/// Low bitrate stream for small tiles, when the camera has one.
+pub const SUB_STREAM: &str = "sub";
+
+/// One of the video encodings a camera can send.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub struct StreamDescriptor {
+    pub id: String,
+    /// Encoding name as used in SDP, such as `H264`.
+    pub codec: String,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+/// What a camera client tells the server about itself when it registers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub struct CameraDescriptor {
+    pub name: String,
+    #[serde(default)]
+    pub streams: Vec<StreamDescriptor>,
+}
+
+impl CameraDescriptor {
+    pub fn stream(&self, id: &str) -> Option<&StreamDescriptor> {
+        self.streams.iter().find(|stream| stream.id == id)
+    }
+
+    /// The largest stream in `codec` that fits within the given size, or the
+    /// smallest one if none does, as a camera picks for [`Signal::Constrain`].
+    pub fn fitting(&self, codec: &str, max_width: u32, max_height: u32) -> Option<&StreamDescriptor> {
+        let area = |stream: &&StreamDescriptor| stream.width as u64 * stream.height as u64;
+        let candidates = self.streams.iter().filter(|stream| stream.codec.eq_ignore_ascii_case(codec));
+        candidates.clone()
+            .filter(|stream| stream.width <= max_width && stream.height <= max_height)
+            .max_by_key(area)
+            .or_else(|| candidates.min_by_key(area))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Camera {
+    pub id: Uuid,
+    pub descriptor: CameraDescriptor,
+}
+
+/// One segment file of a camera's recording on the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Recording {
+    /// Name of the recorded camera, as used for its directory.
+    pub camera: String,
+    /// Path of the segment relative to the recordings directory.
+    pub file: String,
+    /// Milliseconds since the Unix epoch.
+    pub start: u64,
+    /// Unset while the segment is still being written.
+    pub end: Option<u64>,
+    pub size: u64,
+}
+
+/// Something noteworthy that happened on a camera while it was recorded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RecordingEvent {
+    /// Milliseconds since the Unix epoch.
+    pub time: u64,
+    /// What happened, such as `motion`.
+    pub kind: String,
+}
+
+/// A stretch of time a camera was recorded without interruption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Span {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Everything recorded of one camera in a range of time, for playback.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Timeline {
+    pub camera: String,
+    /// Covered time, oldest first, with segments that follow on merged.
+    pub spans: Vec<Span>,
+    /// Segments overlapping the range, oldest first.
+    pub segments: Vec<Recording>,
+    /// Events within the range, oldest first.
+    pub events: Vec<RecordingEvent>,
+}
+
+impl Timeline {
+    /// The segment recorded at `time`, if any.
+    pub fn segment_at(&self, time: u64) -> Option<&Recording> {
+        self.segments.iter().rev().find(|segment| segment.start <= time && segment.end.is_none_or(|end| time < end))
+    }
+
+    /// The first segment that starts after `time`.
+    pub fn segment_after(&self, time: u64) -> Option<&Recording> {
+        self.segments.iter().find(|segment| segment.start > time)
+    }
+}
+
+/// Something that happened on the server, as passed to notification sinks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum EventKind {
+    CameraOnline { camera: Uuid, name: String },
+    CameraOffline { camera: Uuid, name: String },
+    Motion { camera: Uuid, name: String, active: bool },
+    /// A viewer offered to start a session with a camera.
+    ViewerConnected { viewer: Uuid, camera: Uuid },
+    /// A client was refused for giving bad credentials.
+    AuthFailure { remote: Option<String>, reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub struct Event {
+    /// Milliseconds since the Unix epoch.
+    pub time: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl EventKind {
+    /// Name of the event's type, as it appears in JSON.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::CameraOnline { .. } => "camera-online",
+            EventKind::CameraOffline { .. } => "camera-offline",
+            EventKind::Motion { .. } => "motion",
+            EventKind::ViewerConnected { .. } => "viewer-connected",
+            EventKind::AuthFailure { .. } => "auth-failure",
+        }
+    }
+}
+
+impl std::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventKind::CameraOnline { name, .. } => write!(f, "Camera {} is online", name),
+            EventKind::CameraOffline { name, .. } => write!(f, "Camera {} went offline", name),
+            EventKind::Motion { name, active: true, .. } => write!(f, "Motion on camera {}", name),
+            EventKind::Motion { name, active: false, .. } => write!(f, "Motion on camera {} stopped", name),
+            EventKind::ViewerConnected { viewer, camera } => write!(f, "Viewer {} connected to camera {}", viewer, camera),
+            EventKind::AuthFailure { remote: Some(remote), reason } => write!(f, "Failed login from {}: {}", remote, reason),
+            EventKind::AuthFailure { remote: None, reason } => write!(f, "Failed login: {}", reason),
+        }
+    }
+}
+
+/// A name and password to log in to the server's API with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Credentials {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Token {
+    /// Sent back as `Authorization: Bearer <token>`.
+    pub token: String,
+    /// When the token stops working, in milliseconds since the Unix epoch.
+    pub expires: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum PeerRole {
+    Camera,
+    Viewer,
+}
+
+/// A client connected to the signalling server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub struct PeerInfo {
+    pub id: Uuid,
+    pub role: PeerRole,
+    /// The camera's name, for cameras.
+    pub name: Option<String>,
+    pub remote: Option<String>,
+    /// Seconds since the peer connected.
+    pub connected: u64,
+    pub partners: Vec<Uuid>,
+    /// Bytes the server has received from the peer, its signalling and any
+    /// media the SFU takes in from it.
+    pub received: u64,
+    /// Bytes of signalling the server has sent the peer.
+    pub sent: u64,
+    /// Bits per second of video the peer's viewers last reported receiving:
+    /// from every camera it watches for a viewer, to every viewer watching it
+    /// for a camera. Missing until one of them reports.
+    #[serde(default)]
+    pub bitrate: Option<f64>,
+}
+
+/// A viewer and a camera signalling with each other.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub struct Session {
+    pub camera: Uuid,
+    pub camera_name: String,
+    pub viewer: Uuid,
+}
+
+/// A message from a peer the server couldn't act on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "kebab-case")]
+pub struct PeerError {
+    /// Milliseconds since the Unix epoch.
+    pub time: u64,
+    pub peer: Uuid,
+    pub message: String,
+}
+
+/// How a viewer's connection to a peer is doing, worked out by the browser
+/// from its WebRTC statistics between two samples.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConnectionStats {
+    /// Bits of video received per second.
+    pub bitrate: f64,
+    /// Frames decoded per second.
+    pub frame_rate: f64,
+    /// Fraction of the packets sent that were lost.
+    pub packet_loss: f64,
+    /// Jitter of the video packets, in seconds.
+    pub jitter: f64,
+    /// Round trip time to the peer in seconds, once the browser has measured it.
+    pub rtt: Option<f64>,
+}
+
+impl ConnectionStats {
+    /// Whether these could be real statistics: finite and not negative, with
+    /// no more than every packet lost.
+    pub fn is_plausible(&self) -> bool {
+        let values = [self.bitrate, self.frame_rate, self.packet_loss, self.jitter, self.rtt.unwrap_or(0.0)];
+        values.iter().all(|value| value.is_finite() && *value >= 0.0) && self.packet_loss <= 1.0
+    }
+}
+
+/// Keys a browser gives for encrypting the push messages sent to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushKeys {
+    /// The browser's P-256 public key, base64url encoded.
+    pub p256dh: String,
+    /// Authentication secret, base64url encoded.
+    pub auth: String,
+}
+
+/// A browser's Web Push subscription, as its `PushSubscription.toJSON()`
+/// gives it, with what it wants to be told about.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushSubscription {
+    pub endpoint: String,
+    pub keys: PushKeys,
+    /// Names of the cameras to send events from, or empty for every camera.
+    #[serde(default)]
+    pub cameras: Vec<String>,
+    /// Types of event to send, or empty for all of them.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    RateLimited,
+    SdpTooLarge,
+    TooManyCandidates,
+    TooManyConnections,
+    InvalidSdp,
+    UnknownStream,
+    InvalidSnapshot,
+    NotGranted,
+    /// Another connected camera has the name a camera registered with.
+    CameraNameTaken,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "kebab-case")]
 pub enum ClientMsg {
     Signal { signal: Signal, sender: Uuid },
-    ListPeers { peers: Vec<Uuid> }
+    ListPeers { peers: Vec<Uuid> },
+    ListCameras { cameras: Vec<Camera> },
+    /// A message from this client was rejected by the server.
+    Error { code: ErrorCode, message: String }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,13 +351,46 @@ pub struct PeerList {
 #[serde(tag = "type", rename_all = "kebab-case")]
 pub enum ServerMsg {
     Signal { signal: Signal, recipient: Uuid },
-    ListPeers
+    ListPeers,
+    /// Marks the sending peer as a camera.
+    Register { camera: CameraDescriptor },
+    ListCameras,
+    /// Reports motion starting or stopping in front of the sending camera.
+    Motion { active: bool },
+    /// A preview still from the sending camera, as a base64 JPEG.
+    Snapshot { jpeg: String },
+    /// How the sending viewer's connection to `peer` is doing.
+    Stats { peer: Uuid, stats: ConnectionStats },
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn events_are_flat_json() {
+        let event = Event { time: 1000, kind: EventKind::Motion { camera: Uuid::nil(), name: "Porch".to_string(), active: true } };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "motion");
+        assert_eq!(json["time"], 1000);
+        assert_eq!(json["type"], event.kind.name());
+        assert_eq!(serde_json::from_value::<Event>(json).unwrap(), event);
+    }
+
+    #[test]
+    fn rejects_implausible_stats() {
+        let stats = ConnectionStats { bitrate: 2e6, frame_rate: 30.0, packet_loss: 0.01, jitter: 0.005, rtt: Some(0.05) };
+        assert!(stats.is_plausible());
+        assert!(ConnectionStats::default().is_plausible());
+        assert!(!ConnectionStats { bitrate: f64::INFINITY, ..stats }.is_plausible());
+        assert!(!ConnectionStats { frame_rate: f64::NAN, ..stats }.is_plausible());
+        assert!(!ConnectionStats { jitter: -0.1, ..stats }.is_plausible());
+        assert!(!ConnectionStats { packet_loss: 1.5, ..stats }.is_plausible());
+        assert!(!ConnectionStats { rtt: Some(f64::NAN), ..stats }.is_plausible());
+    }
 }
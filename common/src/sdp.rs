@@ -0,0 +1,399 @@
+//! Minimal SDP (RFC 4566) parser used to validate and sanitise offers and
+//! answers before the server forwards them between peers.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SdpError {
+    #[error("line {line}: {reason}")]
+    Malformed { line: usize, reason: String },
+    #[error("missing required {0}= line")]
+    MissingField(char),
+    #[error("media section '{0}' is not permitted")]
+    MediaNotAllowed(String),
+    #[error("data channels are not permitted")]
+    DataChannelNotAllowed,
+    #[error("too many media sections ({count} > {max})")]
+    TooManyMediaSections { count: usize, max: usize },
+    #[error("no permitted codec left in {0} section")]
+    NoAllowedCodecs(String),
+}
+
+/// A single `<type>=<value>` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub kind: char,
+    pub value: String,
+}
+
+impl Line {
+    fn new(kind: char, value: impl Into<String>) -> Self {
+        Line { kind, value: value.into() }
+    }
+
+    /// For `a=name:value` lines returns `(name, Some(value))`, for flag
+    /// attributes `a=name` returns `(name, None)`.
+    pub fn attribute(&self) -> Option<(&str, Option<&str>)> {
+        if self.kind != 'a' {
+            return None;
+        }
+        match self.value.find(':') {
+            Some(idx) => Some((&self.value[..idx], Some(&self.value[idx + 1..]))),
+            None => Some((&self.value, None)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaSection {
+    pub kind: String,
+    pub port: String,
+    pub proto: String,
+    pub formats: Vec<String>,
+    pub lines: Vec<Line>,
+}
+
+impl MediaSection {
+    pub fn attributes<'a>(&'a self, name: &'a str) -> impl Iterator<Item = Option<&'a str>> + 'a {
+        self.lines.iter()
+            .filter_map(|line| line.attribute())
+            .filter(move |(attr, _)| *attr == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn has_attribute(&self, name: &str) -> bool {
+        self.attributes(name).next().is_some()
+    }
+
+    /// Codec name (e.g. `H264`, `opus`) for a payload type, from its `a=rtpmap`.
+    pub fn codec(&self, payload_type: &str) -> Option<&str> {
+        self.attributes("rtpmap")
+            .flatten()
+            .find_map(|value| {
+                let (pt, rest) = value.split_once(' ')?;
+                if pt == payload_type { rest.split('/').next() } else { None }
+            })
+    }
+
+    /// Payload type that an `rtx` format repairs, from its `a=fmtp:<pt> apt=<pt>`.
+    fn rtx_target(&self, payload_type: &str) -> Option<&str> {
+        self.attributes("fmtp")
+            .flatten()
+            .find_map(|value| {
+                let (pt, params) = value.split_once(' ')?;
+                if pt != payload_type {
+                    return None;
+                }
+                params.split(';').find_map(|param| param.trim().strip_prefix("apt="))
+            })
+    }
+
+    pub fn is_data_channel(&self) -> bool {
+        self.kind == "application" && self.proto.contains("DTLS/SCTP")
+    }
+
+    /// Removes the given payload types from the m-line and every attribute that refers to them.
+    fn remove_formats(&mut self, removed: &[String]) {
+        self.formats.retain(|format| !removed.contains(format));
+        self.lines.retain(|line| match line.attribute() {
+            Some(("rtpmap", Some(value))) | Some(("fmtp", Some(value))) | Some(("rtcp-fb", Some(value))) => {
+                let pt = value.split(' ').next().unwrap_or_default();
+                !removed.iter().any(|removed| removed == pt)
+            }
+            _ => true,
+        });
+    }
+
+    /// Sets the section's `b=AS` bandwidth, replacing any existing `b=` line.
+    fn set_bitrate(&mut self, kbps: u32) {
+        self.lines.retain(|line| line.kind != 'b');
+        // RFC 4566 orders i=, c= and b= before any attributes.
+        let idx = self.lines.iter().position(|line| !matches!(line.kind, 'i' | 'c')).unwrap_or(self.lines.len());
+        self.lines.insert(idx, Line::new('b', format!("AS:{}", kbps)));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionDescription {
+    /// Session-level lines, starting with `v=`.
+    pub session: Vec<Line>,
+    pub media: Vec<MediaSection>,
+}
+
+impl SessionDescription {
+    pub fn parse(sdp: &str) -> Result<Self, SdpError> {
+        let mut session = Vec::new();
+        let mut media: Vec<MediaSection> = Vec::new();
+
+        for (idx, raw) in sdp.lines().enumerate() {
+            let lineno = idx + 1;
+            let raw = raw.trim_end_matches('\r');
+            if raw.is_empty() {
+                continue;
+            }
+
+            let mut chars = raw.chars();
+            let (kind, eq) = (chars.next(), chars.next());
+            let kind = match (kind, eq) {
+                (Some(kind), Some('=')) if kind.is_ascii_lowercase() => kind,
+                _ => return Err(SdpError::Malformed { line: lineno, reason: "expected <type>=<value>".into() }),
+            };
+            let value = &raw[2..];
+
+            if lineno == 1 && (kind != 'v' || value != "0") {
+                return Err(SdpError::Malformed { line: lineno, reason: "SDP must start with v=0".into() });
+            }
+
+            if kind == 'm' {
+                let mut fields = value.split(' ');
+                let (kind, port, proto) = match (fields.next(), fields.next(), fields.next()) {
+                    (Some(kind), Some(port), Some(proto)) if !kind.is_empty() => (kind, port, proto),
+                    _ => return Err(SdpError::Malformed { line: lineno, reason: "m= needs <media> <port> <proto> <fmt>...".into() }),
+                };
+                let formats = fields.map(str::to_string).collect::<Vec<_>>();
+                if formats.is_empty() {
+                    return Err(SdpError::Malformed { line: lineno, reason: "m= line without formats".into() });
+                }
+                media.push(MediaSection {
+                    kind: kind.to_string(),
+                    port: port.to_string(),
+                    proto: proto.to_string(),
+                    formats,
+                    lines: Vec::new(),
+                });
+            } else if let Some(section) = media.last_mut() {
+                section.lines.push(Line::new(kind, value));
+            } else {
+                session.push(Line::new(kind, value));
+            }
+        }
+
+        if session.is_empty() {
+            return Err(SdpError::MissingField('v'));
+        }
+        for required in &['o', 's', 't'] {
+            if !session.iter().any(|line| line.kind == *required) {
+                return Err(SdpError::MissingField(*required));
+            }
+        }
+
+        Ok(SessionDescription { session, media })
+    }
+
+    /// Checks the description against `policy`, rewriting it in place where the
+    /// policy allows (dropping codecs, capping bitrate) and failing otherwise.
+    pub fn apply(&mut self, policy: &Policy) -> Result<(), SdpError> {
+        if self.media.len() > policy.max_media_sections {
+            return Err(SdpError::TooManyMediaSections { count: self.media.len(), max: policy.max_media_sections });
+        }
+
+        for section in &mut self.media {
+            if section.is_data_channel() {
+                if !policy.allow_data_channel {
+                    return Err(SdpError::DataChannelNotAllowed);
+                }
+                continue;
+            }
+            if !policy.allowed_media.contains(&section.kind) {
+                return Err(SdpError::MediaNotAllowed(section.kind.clone()));
+            }
+            // A rejected section (port 0) carries no media, so leave it be.
+            if section.port == "0" {
+                continue;
+            }
+
+            let mut removed = section.formats.iter()
+                .filter(|pt| match section.codec(pt) {
+                    Some(codec) if codec.eq_ignore_ascii_case("rtx") => false,
+                    Some(codec) => !policy.allows_codec(codec),
+                    // Static payload types without an rtpmap.
+                    None => true,
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            // Retransmission formats go with the codec they repair.
+            let orphaned_rtx = section.formats.iter()
+                .filter(|pt| section.codec(pt).is_some_and(|codec| codec.eq_ignore_ascii_case("rtx")))
+                .filter(|pt| section.rtx_target(pt).is_none_or(|apt| removed.iter().any(|removed| removed == apt)))
+                .cloned()
+                .collect::<Vec<_>>();
+            removed.extend(orphaned_rtx);
+
+            section.remove_formats(&removed);
+            let has_media_codec = section.formats.iter()
+                .any(|pt| section.codec(pt).is_some_and(|codec| !is_auxiliary(codec)));
+            if !has_media_codec {
+                return Err(SdpError::NoAllowedCodecs(section.kind.clone()));
+            }
+
+            if section.kind == "video" {
+                if let Some(kbps) = policy.max_video_bitrate_kbps {
+                    section.set_bitrate(kbps);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_auxiliary(codec: &str) -> bool {
+    ["rtx", "red", "ulpfec", "flexfec-03", "telephone-event", "CN"]
+        .iter()
+        .any(|aux| codec.eq_ignore_ascii_case(aux))
+}
+
+impl fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.session {
+            write!(f, "{}={}\r\n", line.kind, line.value)?;
+        }
+        for section in &self.media {
+            write!(f, "m={} {} {} {}\r\n", section.kind, section.port, section.proto, section.formats.join(" "))?;
+            for line in &section.lines {
+                write!(f, "{}={}\r\n", line.kind, line.value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the server accepts in offers and answers.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Codec names as they appear in `a=rtpmap`, compared case-insensitively.
+    /// `rtx` is implicitly allowed for any allowed codec.
+    pub allowed_codecs: Vec<String>,
+    pub allowed_media: Vec<String>,
+    pub allow_data_channel: bool,
+    pub max_media_sections: usize,
+    /// Written as `b=AS` on every video section when set.
+    pub max_video_bitrate_kbps: Option<u32>,
+}
+
+impl Policy {
+    pub fn allows_codec(&self, codec: &str) -> bool {
+        self.allowed_codecs.iter().any(|allowed| allowed.eq_ignore_ascii_case(codec))
+    }
+
+    /// Parses and sanitises `sdp`, returning the rewritten description.
+    pub fn sanitise(&self, sdp: &str) -> Result<String, SdpError> {
+        let mut description = SessionDescription::parse(sdp)?;
+        description.apply(self)?;
+        Ok(description.to_string())
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            allowed_codecs: ["opus", "G722", "PCMU", "PCMA", "telephone-event", "CN", "H264", "VP8", "VP9", "AV1", "red", "ulpfec"]
+                .iter()
+                .map(|codec| codec.to_string())
+                .collect(),
+            allowed_media: vec!["audio".to_string(), "video".to_string()],
+            allow_data_channel: false,
+            max_media_sections: 8,
+            max_video_bitrate_kbps: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME_OFFER: &str = include_str!("../testdata/chrome-offer.sdp");
+    const FIREFOX_OFFER: &str = include_str!("../testdata/firefox-offer.sdp");
+    const SAFARI_ANSWER: &str = include_str!("../testdata/safari-answer.sdp");
+
+    fn video(description: &SessionDescription) -> &MediaSection {
+        description.media.iter().find(|section| section.kind == "video").unwrap()
+    }
+
+    #[test]
+    fn parses_browser_sdp() {
+        for sdp in &[CHROME_OFFER, FIREFOX_OFFER, SAFARI_ANSWER] {
+            let description = SessionDescription::parse(sdp).unwrap();
+            assert_eq!(description.media.len(), 2);
+            assert_eq!(description.media[0].kind, "audio");
+            assert_eq!(description.media[1].kind, "video");
+        }
+
+        let chrome = SessionDescription::parse(CHROME_OFFER).unwrap();
+        assert_eq!(video(&chrome).codec("96"), Some("VP8"));
+        assert_eq!(video(&chrome).rtx_target("97"), Some("96"));
+    }
+
+    #[test]
+    fn round_trips() {
+        let description = SessionDescription::parse(CHROME_OFFER).unwrap();
+        let reparsed = SessionDescription::parse(&description.to_string()).unwrap();
+        assert_eq!(description, reparsed);
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(SessionDescription::parse(""), Err(SdpError::MissingField('v')));
+        assert!(matches!(SessionDescription::parse("v=1\r\n"), Err(SdpError::Malformed { line: 1, .. })));
+        assert!(matches!(SessionDescription::parse("v=0\r\ngarbage\r\n"), Err(SdpError::Malformed { line: 2, .. })));
+        assert_eq!(SessionDescription::parse("v=0\r\no=- 1 1 IN IP4 0.0.0.0\r\nt=0 0\r\n"), Err(SdpError::MissingField('s')));
+        assert!(matches!(
+            SessionDescription::parse("v=0\r\no=- 1 1 IN IP4 0.0.0.0\r\ns=-\r\nt=0 0\r\nm=video 9\r\n"),
+            Err(SdpError::Malformed { line: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn strips_disallowed_codecs_and_their_rtx() {
+        let policy = Policy { allowed_codecs: vec!["opus".into(), "h264".into()], ..Policy::default() };
+        let mut description = SessionDescription::parse(CHROME_OFFER).unwrap();
+        description.apply(&policy).unwrap();
+
+        let video = video(&description);
+        for pt in &video.formats {
+            let codec = video.codec(pt).unwrap();
+            assert!(codec == "H264" || codec == "rtx", "unexpected {}", codec);
+        }
+        assert!(!video.formats.contains(&"96".to_string()));
+        assert!(!video.formats.contains(&"97".to_string()));
+        assert!(video.formats.contains(&"102".to_string()));
+        assert!(video.formats.contains(&"103".to_string()));
+        assert!(!video.lines.iter().any(|line| line.value.starts_with("rtpmap:96 ") || line.value.starts_with("rtcp-fb:96 ")));
+        assert_eq!(description.media[0].formats, vec!["111".to_string()]);
+    }
+
+    #[test]
+    fn caps_video_bitrate() {
+        let policy = Policy { max_video_bitrate_kbps: Some(500), ..Policy::default() };
+        let sdp = policy.sanitise(FIREFOX_OFFER).unwrap();
+        let description = SessionDescription::parse(&sdp).unwrap();
+        let video = video(&description);
+
+        assert_eq!(video.lines.iter().filter(|line| line.kind == 'b').count(), 1);
+        let idx = video.lines.iter().position(|line| line.kind == 'b').unwrap();
+        assert_eq!(video.lines[idx].value, "AS:500");
+        assert_eq!(video.lines[idx - 1].kind, 'c');
+        assert!(!description.media[0].lines.iter().any(|line| line.kind == 'b'));
+    }
+
+    #[test]
+    fn enforces_media_policy() {
+        let audio_only = Policy { allowed_media: vec!["audio".into()], ..Policy::default() };
+        assert_eq!(audio_only.sanitise(SAFARI_ANSWER), Err(SdpError::MediaNotAllowed("video".into())));
+
+        let no_video_codecs = Policy { allowed_codecs: vec!["opus".into()], ..Policy::default() };
+        assert_eq!(no_video_codecs.sanitise(SAFARI_ANSWER), Err(SdpError::NoAllowedCodecs("video".into())));
+
+        let one_section = Policy { max_media_sections: 1, ..Policy::default() };
+        assert_eq!(one_section.sanitise(CHROME_OFFER), Err(SdpError::TooManyMediaSections { count: 2, max: 1 }));
+    }
+
+    #[test]
+    fn data_channels_need_permission() {
+        let with_data = format!("{}m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\nc=IN IP4 0.0.0.0\r\na=mid:2\r\na=sctp-port:5000\r\n", CHROME_OFFER);
+
+        assert_eq!(Policy::default().sanitise(&with_data), Err(SdpError::DataChannelNotAllowed));
+        let policy = Policy { allow_data_channel: true, ..Policy::default() };
+        assert!(policy.sanitise(&with_data).is_ok());
+    }
+}
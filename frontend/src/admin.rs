@@ -0,0 +1,317 @@
+//! Admin dashboard: who is connected, who is watching what and what went
+//! wrong lately, fed by the server's management API.
+
+use std::time::Duration;
+
+use anyhow::Error;
+use uuid::Uuid;
+use wasm_bindgen::JsValue;
+use yew::prelude::*;
+use yew::format::{Json, Nothing, Text};
+use yew::services::fetch::{FetchService, FetchTask, Method, Request, Response};
+use yew::services::interval::{IntervalService, IntervalTask};
+
+use common::{Event, PeerError, PeerInfo, PeerRole, Session};
+
+use crate::login;
+use crate::route::Route;
+
+/// How often the dashboard asks the server for news.
+const REFRESH: Duration = Duration::from_secs(5);
+/// Refused logins shown.
+const AUTH_FAILURES: usize = 20;
+
+#[derive(Clone, Properties)]
+pub struct Props {
+	/// Sends the user to another page, such as the login page when the token stops working.
+	pub navigate: Callback<Route>,
+}
+
+pub struct Admin {
+	link: ComponentLink<Self>,
+	props: Props,
+	token: Option<String>,
+	/// Requests of the latest refresh.
+	tasks: Vec<FetchTask>,
+	/// Disconnect or hangup in flight.
+	action: Option<FetchTask>,
+	_refresh: IntervalTask,
+	peers: Vec<PeerInfo>,
+	sessions: Vec<Session>,
+	errors: Vec<PeerError>,
+	auth_failures: Vec<Event>,
+	/// Set once the server says the user isn't an admin.
+	forbidden: bool,
+	status: String,
+}
+
+pub enum Msg {
+	/// The server doesn't take the token any more.
+	LoggedOut,
+	Forbidden,
+	Refresh,
+	Peers(Vec<PeerInfo>),
+	Sessions(Vec<Session>),
+	Errors(Vec<PeerError>),
+	AuthFailures(Vec<Event>),
+	Disconnect(Uuid),
+	EndSession(Uuid, Uuid),
+	/// A disconnect or hangup went through.
+	Done,
+	Failed(String),
+}
+
+fn format_time(time: u64) -> String {
+	js_sys::Date::new(&JsValue::from_f64(time as f64)).to_locale_time_string("default").into()
+}
+
+fn format_duration(secs: u64) -> String {
+	match secs {
+		0..=59 => format!("{}s", secs),
+		60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+		_ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+	}
+}
+
+fn format_bitrate(bits_per_sec: f64) -> String {
+	if bits_per_sec >= 1_000_000.0 {
+		format!("{:.1} Mbit/s", bits_per_sec / 1_000_000.0)
+	} else {
+		format!("{:.1} kbit/s", bits_per_sec / 1000.0)
+	}
+}
+
+impl Admin {
+	/// A request without a body, carrying the token.
+	fn request(&self, method: Method, url: &str) -> Request<Nothing> {
+		let mut request = Request::builder().method(method).uri(url);
+		if let Some(token) = &self.token {
+			request = request.header("Authorization", format!("Bearer {}", token));
+		}
+		request.body(Nothing).unwrap()
+	}
+
+	/// Sends a request, handing what comes back to `msg` unless the server
+	/// refuses the token.
+	fn send<B: Into<Text>, T: 'static>(&self, request: Request<B>, msg: fn(T) -> Msg) -> Option<FetchTask> where Json<Result<T, Error>>: From<Text> {
+		let uri = request.uri().to_string();
+		let callback = self.link.callback(move |response: Response<Json<Result<T, Error>>>| {
+			let (meta, Json(data)) = response.into_parts();
+			match (meta.status.as_u16(), data) {
+				(401, _) => Msg::LoggedOut,
+				(403, _) => Msg::Forbidden,
+				(_, Ok(data)) => msg(data),
+				(status, Err(err)) => Msg::Failed(format!("{} failed ({}): {}", uri, status, err)),
+			}
+		});
+		FetchService::fetch(request, callback)
+			.map_err(|err| log::error!("Failed sending request: {:?}", err))
+			.ok()
+	}
+
+	fn get<T: 'static>(&self, url: &str, msg: fn(T) -> Msg) -> Option<FetchTask> where Json<Result<T, Error>>: From<Text> {
+		self.send(self.request(Method::GET, url), msg)
+	}
+
+	/// Sends a DELETE, which answers with no content when it works.
+	fn delete(&self, url: &str) -> Option<FetchTask> {
+		let request = self.request(Method::DELETE, url);
+		let uri = url.to_string();
+		let callback = self.link.callback(move |response: Response<Text>| match response.status().as_u16() {
+			401 => Msg::LoggedOut,
+			403 => Msg::Forbidden,
+			// Already gone, which is what was asked for.
+			204 | 404 => Msg::Done,
+			status => Msg::Failed(format!("{} failed ({})", uri, status)),
+		});
+		FetchService::fetch(request, callback)
+			.map_err(|err| log::error!("Failed sending DELETE {}: {:?}", url, err))
+			.ok()
+	}
+
+	fn refresh(&mut self) {
+		if self.token.is_none() || self.forbidden {
+			return;
+		}
+		let auth_failures = format!("/api/events?type=auth-failure&limit={}", AUTH_FAILURES);
+		self.tasks = vec![
+			self.get("/api/peers", Msg::Peers),
+			self.get("/api/sessions", Msg::Sessions),
+			self.get("/api/errors", Msg::Errors),
+			self.get(&auth_failures, Msg::AuthFailures),
+		].into_iter().flatten().collect();
+	}
+
+	fn forget_token(&mut self) {
+		self.token = None;
+		self.tasks.clear();
+		login::clear_token();
+	}
+
+	fn peers_view(&self) -> Html {
+		let rows = self.peers.iter().map(|peer| {
+			let id = peer.id;
+			html!{
+				<tr>
+					<td>{ match peer.role { PeerRole::Camera => "Camera", PeerRole::Viewer => "Viewer" } }</td>
+					<td title=id.to_string()>{ peer.name.clone().unwrap_or_else(|| id.to_string()) }</td>
+					<td>{ peer.remote.as_deref().unwrap_or("") }</td>
+					<td>{ format_duration(peer.connected) }</td>
+					<td>{ peer.bitrate.map(format_bitrate).unwrap_or_default() }</td>
+					<td><button onclick=self.link.callback(move |_| Msg::Disconnect(id))>{ "Disconnect" }</button></td>
+				</tr>
+			}
+		}).collect::<Html>();
+		html!{
+			<table>
+				<tr><th>{ "Role" }</th><th>{ "Name" }</th><th>{ "Address" }</th><th>{ "Connected" }</th><th>{ "Video" }</th><th></th></tr>
+				{ rows }
+			</table>
+		}
+	}
+
+	fn sessions_view(&self) -> Html {
+		let rows = self.sessions.iter().map(|session| {
+			let (camera, viewer) = (session.camera, session.viewer);
+			html!{
+				<tr>
+					<td title=camera.to_string()>{ &session.camera_name }</td>
+					<td>{ viewer.to_string() }</td>
+					<td><button onclick=self.link.callback(move |_| Msg::EndSession(camera, viewer))>{ "Revoke" }</button></td>
+				</tr>
+			}
+		}).collect::<Html>();
+		html!{
+			<table>
+				<tr><th>{ "Camera" }</th><th>{ "Viewer" }</th><th></th></tr>
+				{ rows }
+			</table>
+		}
+	}
+
+	fn errors_view(&self) -> Html {
+		let errors = self.errors.iter().rev().map(|error| html!{
+			<li>{ format!("{} {}: {}", format_time(error.time), error.peer, error.message) }</li>
+		});
+		let auth_failures = self.auth_failures.iter().rev().map(|event| html!{
+			<li>{ format!("{} {}", format_time(event.time), event.kind) }</li>
+		});
+		html!{
+			<ul>{ for errors }{ for auth_failures }</ul>
+		}
+	}
+}
+
+impl Component for Admin {
+	type Message = Msg;
+	type Properties = Props;
+
+	fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+		let refresh = IntervalService::spawn(REFRESH, link.callback(|_| Msg::Refresh));
+		let mut admin = Admin {
+			link,
+			props,
+			token: login::token(),
+			tasks: Vec::new(),
+			action: None,
+			_refresh: refresh,
+			peers: Vec::new(),
+			sessions: Vec::new(),
+			errors: Vec::new(),
+			auth_failures: Vec::new(),
+			forbidden: false,
+			status: String::new(),
+		};
+		admin.refresh();
+		admin
+	}
+
+	fn change(&mut self, props: Self::Properties) -> ShouldRender {
+		self.props = props;
+		false
+	}
+
+	fn update(&mut self, msg: Self::Message) -> ShouldRender {
+		match msg {
+			Msg::LoggedOut => {
+				self.forget_token();
+				self.props.navigate.emit(Route::Login);
+				false
+			}
+			Msg::Forbidden => {
+				// The token still works for everything else.
+				self.forbidden = true;
+				self.tasks.clear();
+				true
+			}
+			Msg::Refresh => {
+				self.refresh();
+				false
+			}
+			Msg::Peers(peers) => {
+				self.peers = peers;
+				true
+			}
+			Msg::Sessions(sessions) => {
+				self.sessions = sessions;
+				true
+			}
+			Msg::Errors(errors) => {
+				self.errors = errors;
+				true
+			}
+			Msg::AuthFailures(events) => {
+				self.auth_failures = events;
+				true
+			}
+			Msg::Disconnect(id) => {
+				self.action = self.delete(&format!("/api/peers/{}", id));
+				false
+			}
+			Msg::EndSession(camera, viewer) => {
+				self.action = self.delete(&format!("/api/sessions/{}/{}", camera, viewer));
+				false
+			}
+			Msg::Done => {
+				self.refresh();
+				false
+			}
+			Msg::Failed(err) => {
+				log::warn!("{}", err);
+				self.status = err;
+				true
+			}
+		}
+	}
+
+	fn view(&self) -> Html {
+		if self.token.is_none() {
+			return html!{
+				<>
+				<h2>{ "Admin" }</h2>
+				<button onclick=self.props.navigate.reform(|_| Route::Login)>{ "Log in" }</button>
+				</>
+			};
+		}
+		if self.forbidden {
+			return html!{
+				<>
+				<h2>{ "Admin" }</h2>
+				<p>{ "Only admins can see the dashboard." }</p>
+				</>
+			};
+		}
+		html!{
+			<>
+			<h2>{ "Admin" }</h2>
+			<span>{ &self.status }</span>
+			<h3>{ "Peers" }</h3>
+			{ self.peers_view() }
+			<h3>{ "Sessions" }</h3>
+			{ self.sessions_view() }
+			<h3>{ "Recent errors" }</h3>
+			{ self.errors_view() }
+			</>
+		}
+	}
+}
@@ -5,8 +5,11 @@ use anyhow::Error;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use yew::prelude::*;
-use yew::format::Json;
+use yew::format::{Json, Nothing, Text};
 use yew::html::NodeRef;
+use yew::services::fetch::{FetchService, FetchTask, Request, Response};
+use yew::services::interval::{IntervalService, IntervalTask};
+use yew::services::timeout::{TimeoutService, TimeoutTask};
 use yew::services::websocket::{WebSocketService, WebSocketStatus, WebSocketTask};
 use yewtil::future::LinkFuture;
 use wasm_bindgen_futures::{JsFuture, spawn_local};
@@ -22,7 +25,8 @@ use common::{ClientMsg, ServerMsg, Signal};
 
 use peer::Peer;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 use uuid::Uuid;
 use webrtc::WebRtcTask;
 
@@ -30,30 +34,77 @@ use log::{debug, info, error};
 
 mod webrtc;
 mod peer;
-
+mod playback;
+mod notifications;
+mod admin;
+mod login;
+mod route;
+mod stats;
+
+use route::Route;
+
+/// How often camera previews are reloaded, about as often as cameras send them.
+const THUMBNAIL_REFRESH: Duration = Duration::from_secs(10);
+/// How often each connection's statistics are sampled.
+const STATS_INTERVAL: Duration = Duration::from_secs(2);
+/// How long the window has to keep its size before cameras are told, so
+/// dragging its edge doesn't send a constraint for every step.
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(300);
 
 struct Model {
 	ws: Option<WebSocketTask>,
 	link: ComponentLink<Model>,
+	route: Route,
 	peers: Vec<Uuid>,
 	connections: HashMap<Uuid, Arc<WebRtcTask>>,
+	/// Peers we asked for video, as opposed to ones that called us.
+	watching: HashSet<Uuid>,
+	/// Video received from each peer, and the element showing it.
+	streams: HashMap<Uuid, MediaStream>,
+	videos: HashMap<Uuid, NodeRef>,
+	/// The constraint last sent to each watched peer.
+	constraints: HashMap<Uuid, (u32, u32)>,
+	resize_task: Option<TimeoutTask>,
+	/// Cameras currently reporting motion.
+	motion: HashSet<Uuid>,
+	/// Peers registered as cameras, which have names and previews.
+	cameras: HashMap<Uuid, common::CameraDescriptor>,
+	/// Bumped to make the browser fetch previews again.
+	thumbnails: u32,
+	_thumbnail_task: IntervalTask,
+	/// How each connection to a watched peer did over the last sample.
+	stats: HashMap<Uuid, common::ConnectionStats>,
+	/// Whether those statistics are sent to the server too.
+	report_stats: bool,
+	_stats_task: IntervalTask,
 	mediastream: Option<MediaStream>,
 	//mediastream2: Arc<MediaStream>,
 	//in_streams: Vec<(NodeRef, MediaStream)>,
 	self_video: NodeRef,
-	other_video: NodeRef,
+	logout_task: Option<FetchTask>,
 }
 
 #[derive(Debug)]
 enum Action {
 	Connect,                         // connect to websocket server
+	Connected,                       // the websocket is open
 	Disconnected,                    // disconnected from server
 	Ignore,                          // ignore this message
+	ListPeers,                       // ask for the peers, and which of them are cameras
+	RefreshThumbnails,
+	PollStats,                       // sample every watched connection's statistics
+	Stats(Uuid, common::ConnectionStats),
+	ReportStats(bool),               // start or stop sending statistics to the server
 	Signal(ServerMsg),
-	ConnectPeer(Uuid), // fix this to uuid
 	Received(Result<ClientMsg, Error>), // data received from server
 	SetMediaStream(MediaStream),
 	MediaStreamAdded(Uuid, MediaStream),
+	Resized,                         // the window, and so the video tiles, changed size
+	ResizeSettled,                   // the window kept its size for a while
+	Navigate(Route),                 // go to another page
+	Navigated,                       // the browser went back or forward
+	LoggedIn,
+	Logout,
 }
 
 impl From<ServerMsg> for Action {
@@ -70,25 +121,217 @@ impl From<ClientMsg> for Action {
 
 impl Model {
 
+	/// A link to another page that doesn't load the app again.
+	fn link_to(&self, route: Route, label: &str) -> Html {
+		html!{
+			<a href=route.path() onclick=self.link.callback(move |e: MouseEvent| {
+				e.prevent_default();
+				Action::Navigate(route)
+			})>{ label }</a>
+		}
+	}
+
+	fn nav_view(&self) -> Html {
+		let account = if login::token().is_some() {
+			html!{ <a href="#" onclick=self.link.callback(|e: MouseEvent| { e.prevent_default(); Action::Logout })>{ "Log out" }</a> }
+		} else {
+			self.link_to(Route::Login, "Log in")
+		};
+		html!{
+			<nav>
+				{ self.link_to(Route::Cameras, "Cameras") }
+				{ self.link_to(Route::Grid, "Grid") }
+				{ self.link_to(Route::Recordings, "Recordings") }
+				{ self.link_to(Route::Settings, "Settings") }
+				{ self.link_to(Route::Admin, "Admin") }
+				{ account }
+				{ if self.ws.is_none() {
+					html!{ <button onclick=self.link.callback(|_| Action::Connect)>{ "Reconnect" }</button> }
+				} else {
+					html!{}
+				} }
+			</nav>
+		}
+	}
+
+	/// What to call a peer: the camera's name, or its ID for other peers.
+	fn peer_name(&self, id: &Uuid) -> String {
+		self.cameras.get(id).map_or_else(|| id.to_string(), |camera| camera.name.clone())
+	}
+
 	fn peer_view(&self) -> Html {
 		self.peers.iter().map(|id|  {
 			let id = id.clone();
 			html!{
-				<button onclick=self.link.callback(move |_| Action::ConnectPeer(id))>{ id.to_string() }</button>
+				<a class="peer" href=Route::Camera(id).path() onclick=self.link.callback(move |e: MouseEvent| {
+					e.prevent_default();
+					Action::Navigate(Route::Camera(id))
+				})>
+					{ if self.cameras.contains_key(&id) {
+						html!{ <img src=format!("/api/cameras/{}/snapshot?{}&{}", id, self.thumbnails, login::token_query()) alt="" style="display:block;width:160px" /> }
+					} else {
+						html!{}
+					} }
+					{ self.peer_name(&id) }
+					{ if self.motion.contains(&id) { " (motion)" } else { "" } }
+				</a>
 			}
 		}).collect::<Html>()
 	}
 
-	fn video_view(&self) -> Html {
+	fn cameras_view(&self) -> Html {
+		html!{
+			<>
+			<button onclick=self.link.callback(|_| Action::ListPeers)>{ "Refresh" }</button>
+			<div class="peers">{ self.peer_view() }</div>
+			</>
+		}
+	}
+
+	fn remote_video(&self, id: &Uuid) -> Html {
+		let video = self.videos.get(id).cloned().unwrap_or_default();
+		html!{
+			<div class="tile" style="position:relative;display:inline-block">
+				<video class="remotevideo" autoplay=true muted=true ref=video />
+				{ self.stats.get(id).map(stats::overlay).unwrap_or_default() }
+			</div>
+		}
+	}
+
+	fn settings_view(&self) -> Html {
+		let report = !self.report_stats;
 		html!{
 			<>
-			<video id="localvideo" autoplay=true ref=self.self_video.clone() />
-			<video id="remotevideo" autoplay=true ref=self.other_video.clone() />
+			<notifications::Notifications />
+			<h2>{ "Monitoring" }</h2>
+			<label>
+				<input type="checkbox" checked=self.report_stats onclick=self.link.callback(move |_| Action::ReportStats(report)) />
+				{ "Send connection quality to the server" }
+			</label>
 			</>
 		}
 	}
 
+	fn camera_view(&self, id: &Uuid) -> Html {
+		html!{
+			<>
+			<h2>{ self.peer_name(id) }{ if self.motion.contains(id) { " (motion)" } else { "" } }</h2>
+			{ self.remote_video(id) }
+			<video id="localvideo" autoplay=true muted=true ref=self.self_video.clone() />
+			</>
+		}
+	}
+
+	fn grid_view(&self) -> Html {
+		let tiles = self.cameras.keys().map(|id| {
+			let id = *id;
+			html!{
+				<figure>
+					{ self.remote_video(&id) }
+					<figcaption>{ self.link_to(Route::Camera(id), &self.peer_name(&id)) }</figcaption>
+				</figure>
+			}
+		}).collect::<Html>();
+		html!{ <div class="grid">{ tiles }</div> }
+	}
+
+	fn page_view(&self) -> Html {
+		match self.route {
+			Route::Login => html!{ <login::Login onlogin=self.link.callback(|_| Action::LoggedIn) /> },
+			Route::Cameras => self.cameras_view(),
+			Route::Camera(id) => self.camera_view(&id),
+			Route::Grid => self.grid_view(),
+			Route::Recordings => html!{ <playback::Playback /> },
+			Route::Settings => self.settings_view(),
+			Route::Admin => html!{ <admin::Admin navigate=self.link.callback(Action::Navigate) /> },
+			Route::NotFound => html!{ <p>{ "There is no such page. " }{ self.link_to(Route::Cameras, "See the cameras") }</p> },
+		}
+	}
+
+	/// Rendered size of a peer's video in device pixels, which is as much
+	/// resolution as is worth asking a camera for.
+	fn tile_size(&self, id: &Uuid) -> Option<(u32, u32)> {
+		let video = self.videos.get(id)?.cast::<HtmlVideoElement>()?;
+		let ratio = window()?.device_pixel_ratio();
+		let scale = |pixels: i32| (pixels.max(0) as f64 * ratio).round() as u32;
+		Some((scale(video.client_width()), scale(video.client_height())))
+	}
+
+	/// Asks every watched camera for the layer that best fits its tile.
+	/// Peers that only send one stream ignore this, and a camera is only
+	/// asked again once its tile calls for a different layer, since the
+	/// server limits how many messages each client sends.
+	fn constrain_peers(&mut self) {
+		let sizes = self.watching.iter()
+			.filter_map(|id| self.tile_size(id).map(|size| (*id, size)))
+			.filter(|(_, (width, height))| *width > 0 && *height > 0)
+			.collect::<Vec<_>>();
+		for (id, (max_width, max_height)) in sizes {
+			let unchanged = self.constraints.get(&id).map_or(false, |&(width, height)| {
+				match self.cameras.get(&id) {
+					Some(camera) => fitting(camera, width, height) == fitting(camera, max_width, max_height),
+					None => (width, height) == (max_width, max_height),
+				}
+			});
+			if unchanged {
+				continue;
+			}
+			if let Some(ref mut task) = self.ws {
+				let msg = ServerMsg::Signal { signal: Signal::Constrain { max_width, max_height }, recipient: id };
+				task.send(Json(&msg));
+				self.constraints.insert(id, (max_width, max_height));
+			}
+		}
+	}
+
+	/// Calls the peers the page shows and hangs up on ones it no longer does.
+	fn sync_connections(&mut self) {
+		if self.ws.is_none() {
+			return;
+		}
+		let wanted = match self.route {
+			Route::Camera(id) => vec![id],
+			Route::Grid => self.cameras.keys().copied().collect(),
+			_ => Vec::new(),
+		};
+		let unwanted = self.watching.iter().filter(|id| !wanted.contains(id)).copied().collect::<Vec<_>>();
+		for id in unwanted {
+			if let Some(ref mut task) = self.ws {
+				task.send(Json(&ServerMsg::Signal { signal: Signal::Hangup, recipient: id }));
+			}
+			self.hung_up(&id);
+		}
+		for id in wanted {
+			if self.watching.insert(id) {
+				self.request_connection(id);
+			}
+		}
+	}
+
+	/// Connects again as whoever is logged in now. Dropping the old socket
+	/// drops its listeners too, so it doesn't report the disconnect.
+	fn reconnect(&mut self) {
+		if self.ws.take().is_some() {
+			for id in self.connections.keys().copied().collect::<Vec<_>>() {
+				self.hung_up(&id);
+			}
+			self.link.send_message(Action::Connect);
+		}
+	}
+
+	fn hung_up(&mut self, id: &Uuid) {
+		if let Some(pc) = self.connections.remove(id) {
+			pc.close();
+		}
+		self.watching.remove(id);
+		self.constraints.remove(id);
+		self.stats.remove(id);
+		self.streams.remove(id);
+		self.videos.remove(id);
+	}
+
 	fn new_peer(&mut self, id: Uuid) -> Arc<WebRtcTask> {
+		self.videos.entry(id).or_default();
 		let pc = self.connections.entry(id).or_insert_with(|| 
 			Arc::new(WebRtcTask::new().unwrap())
 		).clone();
@@ -132,6 +375,18 @@ impl Model {
 
 }
 
+/// The streams a camera picks for a tile of the given size, one for each
+/// codec it offers.
+fn fitting(camera: &common::CameraDescriptor, max_width: u32, max_height: u32) -> Vec<&str> {
+	let mut codecs = camera.streams.iter().map(|stream| stream.codec.to_ascii_uppercase()).collect::<Vec<_>>();
+	codecs.sort();
+	codecs.dedup();
+	codecs.iter()
+		.filter_map(|codec| camera.fitting(codec, max_width, max_height))
+		.map(|stream| stream.id.as_str())
+		.collect()
+}
+
 async fn get_user_media() -> Result<MediaStream, JsValue> {
 	let window = web_sys::window().unwrap();
 	let navigator = window.navigator();;
@@ -164,14 +419,41 @@ impl Component for Model {
 			}
 		});
 
+		let resized = link.callback(|_| Action::Resized);
+		let onresize = Closure::wrap(Box::new(move |_: web_sys::Event| resized.emit(())) as Box<dyn FnMut(web_sys::Event)>);
+		window().unwrap().add_event_listener_with_callback("resize", onresize.as_ref().unchecked_ref()).unwrap();
+		onresize.forget();
+
+		let navigated = link.callback(|_| Action::Navigated);
+		let onpopstate = Closure::wrap(Box::new(move |_: web_sys::Event| navigated.emit(())) as Box<dyn FnMut(web_sys::Event)>);
+		window().unwrap().add_event_listener_with_callback("popstate", onpopstate.as_ref().unchecked_ref()).unwrap();
+		onpopstate.forget();
+
+		let thumbnail_task = IntervalService::spawn(THUMBNAIL_REFRESH, link.callback(|_| Action::RefreshThumbnails));
+		let stats_task = IntervalService::spawn(STATS_INTERVAL, link.callback(|_| Action::PollStats));
+		link.send_message(Action::Connect);
+
 		Model {
 			ws: None,
 			link: link,
+			route: Route::current(),
 			peers: Vec::new(),
 			connections: HashMap::new(),
+			watching: HashSet::new(),
+			streams: HashMap::new(),
+			videos: HashMap::new(),
+			constraints: HashMap::new(),
+			resize_task: None,
+			motion: HashSet::new(),
+			cameras: HashMap::new(),
+			thumbnails: 0,
+			_thumbnail_task: thumbnail_task,
+			stats: HashMap::new(),
+			report_stats: stats::reporting(),
+			_stats_task: stats_task,
 			mediastream: None,
 			self_video: NodeRef::default(),
-			other_video: NodeRef::default(),
+			logout_task: None,
 		}
     }
 
@@ -187,33 +469,83 @@ impl Component for Model {
 				let cbnot = self.link.callback(|input| {
 					log::debug!("Notification: {:?}", input);
 					match input {
+						WebSocketStatus::Opened => Action::Connected,
 						WebSocketStatus::Closed | WebSocketStatus::Error => {
 							Action::Disconnected
 						}
-						_ => Action::Ignore,
 					}
 				});
 				if self.ws.is_none() {
 					let url = Url::new(&window().unwrap().location().origin().unwrap()).unwrap(); 
 					url.set_protocol(&url.protocol().replace("http", "ws"));
 					url.set_pathname("/ws");
+					// The server only takes users, and records who is watching.
+					url.set_search(&login::token_query());
 					let task = WebSocketService::connect(&url.href(), cbout, cbnot.into()).unwrap();
 					self.ws = Some(task);
 				}
 				true
 			}
+			Action::Connected => {
+				self.link.send_message(Action::ListPeers);
+				self.sync_connections();
+				false
+			}
 			Action::Disconnected => {
 				self.ws = None;
+				// The server hung up every session for us, so start over once reconnected.
+				for id in self.connections.keys().copied().collect::<Vec<_>>() {
+					self.hung_up(&id);
+				}
 				true
 			}
 			Action::Ignore => {
 				false
 			}
 
-			Action::ConnectPeer(id) => {
-				self.request_connection(id);
+			Action::ListPeers => {
+				if let Some(ref mut task) = self.ws {
+					task.send(Json(&ServerMsg::ListPeers));
+					task.send(Json(&ServerMsg::ListCameras));
+				}
+				false
+			}
+			Action::RefreshThumbnails => {
+				self.thumbnails += 1;
+				self.route == Route::Cameras && !self.cameras.is_empty()
+			}
+			Action::PollStats => {
+				for id in &self.watching {
+					if let Some(pc) = self.connections.get(id).cloned() {
+						let id = *id;
+						let callback = self.link.callback(move |stats| Action::Stats(id, stats));
+						spawn_local(async move {
+							if let Some(stats) = pc.stats().await {
+								callback.emit(stats);
+							}
+						});
+					}
+				}
 				false
 			}
+			Action::Stats(id, stats) => {
+				// The connection may have been hung up while sampling.
+				if !self.watching.contains(&id) {
+					return false;
+				}
+				self.stats.insert(id, stats);
+				if self.report_stats {
+					if let Some(ref mut task) = self.ws {
+						task.send(Json(&ServerMsg::Stats { peer: id, stats }));
+					}
+				}
+				true
+			}
+			Action::ReportStats(report) => {
+				stats::set_reporting(report);
+				self.report_stats = report;
+				true
+			}
 
 			Action::Signal(signal) => {
 				if let Some(ref mut task) = self.ws {
@@ -247,18 +579,85 @@ impl Component for Model {
 							})
 						}
                     }
-                    _ => {
 
-                    }
+					common::ClientMsg::Signal { signal: common::Signal::Hangup, sender, .. } => {
+						log::info!("Peer {} hung up", sender);
+						self.hung_up(&sender);
+					}
+
+					common::ClientMsg::Signal { signal: common::Signal::Watch { .. }, sender, .. } |
+					common::ClientMsg::Signal { signal: common::Signal::Constrain { .. }, sender, .. } => {
+						log::debug!("Peer {} asked for a different stream, but we only send one", sender);
+					}
+
+					common::ClientMsg::Signal { signal: common::Signal::Motion { active }, sender, .. } => {
+						log::info!("Camera {} motion {}", sender, if active { "started" } else { "stopped" });
+						if active {
+							self.motion.insert(sender);
+						} else {
+							self.motion.remove(&sender);
+						}
+					}
+
+					common::ClientMsg::Error { code, message } => {
+						log::warn!("Server rejected message ({:?}): {}", code, message);
+					}
+
+					common::ClientMsg::ListCameras { cameras } => {
+						self.cameras = cameras.into_iter().map(|camera| (camera.id, camera.descriptor)).collect();
+						if self.route == Route::Grid {
+							self.sync_connections();
+						}
+					}
                 }
 				true
 			}
 			Action::MediaStreamAdded(id, stream) => {
-				if let Some(video) = self.other_video.cast::<HtmlVideoElement>() {
-					video.set_src_object(Some(&stream));
+				// Shown once rendered, since the page may not have its video yet.
+				self.streams.insert(id, stream);
+				true
+			}
+			Action::Resized => {
+				// Replacing the timeout cancels the one pending.
+				self.resize_task = Some(TimeoutService::spawn(RESIZE_DEBOUNCE, self.link.callback(|_| Action::ResizeSettled)));
+				false
+			}
+			Action::ResizeSettled => {
+				self.resize_task = None;
+				self.constrain_peers();
+				false
+			}
+			Action::Navigate(route) => {
+				if route != self.route {
+					route.push();
+					self.route = route;
+					self.sync_connections();
 				}
+				true
+			}
+			Action::Navigated => {
+				self.route = Route::current();
+				self.sync_connections();
+				true
+			}
+			Action::LoggedIn => {
+				self.reconnect();
+				self.link.send_message(Action::Navigate(Route::Cameras));
 				false
 			}
+			Action::Logout => {
+				if let Some(token) = login::token() {
+					let request = Request::post("/api/logout")
+						.header("Authorization", format!("Bearer {}", token))
+						.body(Nothing)
+						.unwrap();
+					self.logout_task = FetchService::fetch(request, self.link.callback(|_: Response<Text>| Action::Ignore)).ok();
+				}
+				login::clear_token();
+				self.reconnect();
+				self.link.send_message(Action::Navigate(Route::Login));
+				true
+			}
 			Action::Received(Err(s)) => {
 				log::error!("error:{:?}", s);
 				true
@@ -277,16 +676,28 @@ impl Component for Model {
 		}
 	}
 
+    fn rendered(&mut self, _first_render: bool) {
+		// Videos come and go with the page, so they are given their streams after each render.
+		for (id, stream) in &self.streams {
+			if let Some(video) = self.videos.get(id).and_then(|video| video.cast::<HtmlVideoElement>()) {
+				if video.src_object().as_ref() != Some(stream) {
+					video.set_src_object(Some(stream));
+				}
+			}
+		}
+		if let (Some(video), Some(mediastream)) = (self.self_video.cast::<HtmlVideoElement>(), &self.mediastream) {
+			if video.src_object().as_ref() != Some(mediastream) {
+				video.set_src_object(Some(mediastream));
+			}
+		}
+		self.constrain_peers();
+	}
+
     fn view(&self) -> Html {
 		html! {
             <>
-			// connect button
-			<button onclick=self.link.callback(|_| Action::Connect)>{ "Connect" }</button>
-			<button onclick=self.link.callback(|_| Action::Signal(ServerMsg::ListPeers))>{ "Get Peers" }</button>
-			{ self.peer_view() }
-			// text showing whether we're connected or not
-			<p>{ "Connected: " } { !self.ws.is_none() } </p><br/>
-			<p>{ self.video_view() }</p>
+			{ self.nav_view() }
+			{ self.page_view() }
             </>
 		}
 	}
@@ -0,0 +1,131 @@
+//! Logging in to the server's API, and the token that keeps us logged in.
+
+use anyhow::Error;
+use yew::prelude::*;
+use yew::format::{Json, Text};
+use yew::services::fetch::{FetchService, FetchTask, Request, Response};
+
+use common::{Credentials, Token};
+
+/// Where the API token is kept, so reloading the page doesn't log out.
+const TOKEN_KEY: &str = "rstream-token";
+
+fn storage() -> Option<web_sys::Storage> {
+	web_sys::window()?.session_storage().ok()?
+}
+
+/// The API token, when logged in.
+pub fn token() -> Option<String> {
+	storage()?.get_item(TOKEN_KEY).ok()?
+}
+
+/// The token as a query parameter, for images, videos and WebSockets, which
+/// can't carry an `Authorization` header. Tokens are URL safe as they are.
+pub fn token_query() -> String {
+	format!("token={}", token().unwrap_or_default())
+}
+
+pub fn set_token(token: &str) {
+	if let Some(storage) = storage() {
+		let _ = storage.set_item(TOKEN_KEY, token);
+	}
+}
+
+pub fn clear_token() {
+	if let Some(storage) = storage() {
+		let _ = storage.remove_item(TOKEN_KEY);
+	}
+}
+
+#[derive(Clone, Properties)]
+pub struct Props {
+	/// Called once the token is stored.
+	pub onlogin: Callback<()>,
+}
+
+pub struct Login {
+	link: ComponentLink<Self>,
+	props: Props,
+	task: Option<FetchTask>,
+	name: String,
+	password: String,
+	status: String,
+}
+
+pub enum Msg {
+	Name(String),
+	Password(String),
+	Submit,
+	Done(Result<Token, String>),
+}
+
+impl Component for Login {
+	type Message = Msg;
+	type Properties = Props;
+
+	fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+		Login { link, props, task: None, name: String::new(), password: String::new(), status: String::new() }
+	}
+
+	fn change(&mut self, props: Self::Properties) -> ShouldRender {
+		self.props = props;
+		false
+	}
+
+	fn update(&mut self, msg: Self::Message) -> ShouldRender {
+		match msg {
+			Msg::Name(name) => {
+				self.name = name;
+				false
+			}
+			Msg::Password(password) => {
+				self.password = password;
+				false
+			}
+			Msg::Submit => {
+				let body: Text = Json(&Credentials { name: self.name.clone(), password: self.password.clone() }).into();
+				let request = Request::post("/api/login")
+					.header("Content-Type", "application/json")
+					.body(body)
+					.unwrap();
+				let callback = self.link.callback(|response: Response<Json<Result<Token, Error>>>| {
+					let (meta, Json(data)) = response.into_parts();
+					match (meta.status.as_u16(), data) {
+						(401, _) => Msg::Done(Err("Wrong name or password".to_string())),
+						(_, data) => Msg::Done(data.map_err(|err| err.to_string())),
+					}
+				});
+				self.task = FetchService::fetch(request, callback)
+					.map_err(|err| log::error!("Failed logging in: {:?}", err))
+					.ok();
+				self.status = "Logging in…".to_string();
+				true
+			}
+			Msg::Done(Ok(token)) => {
+				set_token(&token.token);
+				self.password.clear();
+				self.status.clear();
+				self.props.onlogin.emit(());
+				true
+			}
+			Msg::Done(Err(err)) => {
+				self.status = err;
+				true
+			}
+		}
+	}
+
+	fn view(&self) -> Html {
+		html!{
+			<>
+			<h2>{ "Log in" }</h2>
+			<input placeholder="Name" value=self.name.clone()
+				oninput=self.link.callback(|e: InputData| Msg::Name(e.value)) />
+			<input type="password" placeholder="Password" value=self.password.clone()
+				oninput=self.link.callback(|e: InputData| Msg::Password(e.value)) />
+			<button onclick=self.link.callback(|_| Msg::Submit)>{ "Log in" }</button>
+			<span>{ &self.status }</span>
+			</>
+		}
+	}
+}
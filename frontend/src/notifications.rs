@@ -0,0 +1,238 @@
+//! Web Push notifications: registers the service worker, subscribes this
+//! browser with the server's key and tells the server what to send it.
+
+use std::collections::BTreeSet;
+
+use anyhow::Error;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::{JsFuture, spawn_local};
+use web_sys::{Notification, PushManager, PushSubscriptionOptionsInit, ServiceWorkerRegistration};
+use yew::prelude::*;
+use yew::format::{Json, Nothing, Text};
+use yew::services::fetch::{FetchService, FetchTask, Request, Response};
+
+use common::PushSubscription;
+
+use crate::login;
+
+/// Event types offered, named as the server names them.
+const EVENTS: [(&str, &str); 5] = [
+	("camera-online", "Camera online"),
+	("camera-offline", "Camera offline"),
+	("motion", "Motion"),
+	("viewer-connected", "Viewer connected"),
+	("auth-failure", "Refused login"),
+];
+
+pub struct Notifications {
+	link: ComponentLink<Self>,
+	task: Option<FetchTask>,
+	/// Server's VAPID public key, when it sends notifications at all.
+	key: Option<String>,
+	subscribed: bool,
+	/// Camera names, comma separated, or empty for all of them.
+	cameras: String,
+	events: BTreeSet<&'static str>,
+	status: String,
+}
+
+pub enum Msg {
+	Key(Result<serde_json::Value, Error>),
+	Cameras(String),
+	ToggleEvent(&'static str),
+	Subscribe,
+	Unsubscribe,
+	/// The browser's subscription, to pass to the server.
+	Subscription(Result<PushSubscription, String>),
+	Unsubscribed(Result<String, String>),
+	Saved(bool),
+	Removed,
+}
+
+fn js_error(err: JsValue) -> String {
+	err.as_string()
+		.or_else(|| err.dyn_ref::<js_sys::Error>().map(|err| err.message().into()))
+		.unwrap_or_else(|| format!("{:?}", err))
+}
+
+/// Decodes the base64url key the server gives into the bytes `applicationServerKey` takes.
+fn decode_key(key: &str) -> Result<js_sys::Uint8Array, String> {
+	let mut base64 = key.replace('-', "+").replace('_', "/");
+	while base64.len() % 4 != 0 {
+		base64.push('=');
+	}
+	let binary = web_sys::window().unwrap().atob(&base64).map_err(js_error)?;
+	let bytes = binary.chars().map(|c| c as u8).collect::<Vec<_>>();
+	Ok(js_sys::Uint8Array::from(&bytes[..]))
+}
+
+async fn push_manager() -> Result<PushManager, String> {
+	let container = web_sys::window().unwrap().navigator().service_worker();
+	JsFuture::from(container.register("/sw.js")).await.map_err(js_error)?;
+	let registration: ServiceWorkerRegistration = JsFuture::from(container.ready().map_err(js_error)?).await.map_err(js_error)?.unchecked_into();
+	registration.push_manager().map_err(js_error)
+}
+
+/// Asks for permission to notify, then subscribes with the push service.
+async fn subscribe(key: String) -> Result<PushSubscription, String> {
+	let permission = JsFuture::from(Notification::request_permission().map_err(js_error)?).await.map_err(js_error)?;
+	if permission.as_string().as_deref() != Some("granted") {
+		return Err("Notifications were not allowed".to_string());
+	}
+	let mut options = PushSubscriptionOptionsInit::new();
+	options.user_visible_only(true).application_server_key(Some(&decode_key(&key)?.into()));
+	let subscription = JsFuture::from(push_manager().await?.subscribe_with_options(&options).map_err(js_error)?).await.map_err(js_error)?;
+	let json: String = js_sys::JSON::stringify(&subscription).map_err(js_error)?.into();
+	serde_json::from_str(&json).map_err(|err| err.to_string())
+}
+
+/// Drops this browser's subscription, returning its endpoint.
+async fn unsubscribe() -> Result<String, String> {
+	let subscription = JsFuture::from(push_manager().await?.get_subscription().map_err(js_error)?).await.map_err(js_error)?;
+	let subscription = subscription.dyn_into::<web_sys::PushSubscription>().map_err(|_| "Not subscribed".to_string())?;
+	JsFuture::from(subscription.unsubscribe().map_err(js_error)?).await.map_err(js_error)?;
+	Ok(subscription.endpoint())
+}
+
+/// The `Authorization` header for the push API, which wants a user.
+fn authorization() -> String {
+	format!("Bearer {}", login::token().unwrap_or_default())
+}
+
+impl Notifications {
+	fn send(&self, request: Request<Text>, msg: fn(bool) -> Msg) -> Option<FetchTask> {
+		let callback = self.link.callback(move |response: Response<Text>| msg(response.status().is_success()));
+		FetchService::fetch(request, callback)
+			.map_err(|err| log::error!("Failed sending push subscription: {:?}", err))
+			.ok()
+	}
+
+	fn preferences_view(&self) -> Html {
+		let events = EVENTS.iter().map(|&(name, label)| {
+			html!{
+				<label>
+					<input type="checkbox" checked=self.events.contains(name) onclick=self.link.callback(move |_| Msg::ToggleEvent(name)) />
+					{ label }
+				</label>
+			}
+		}).collect::<Html>();
+		html!{
+			<div>
+				<input placeholder="Cameras, or empty for all" value=self.cameras.clone()
+					oninput=self.link.callback(|e: InputData| Msg::Cameras(e.value)) />
+				{ events }
+			</div>
+		}
+	}
+}
+
+impl Component for Notifications {
+	type Message = Msg;
+	type Properties = ();
+
+	fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
+		let request = Request::get("/api/push/key").header("Authorization", authorization()).body(Nothing).unwrap();
+		let task = FetchService::fetch(request, link.callback(|response: Response<Json<Result<serde_json::Value, Error>>>| {
+			let Json(data) = response.into_body();
+			Msg::Key(data)
+		})).ok();
+		Notifications {
+			link,
+			task,
+			key: None,
+			subscribed: false,
+			cameras: String::new(),
+			events: ["camera-offline", "motion"].iter().copied().collect(),
+			status: String::new(),
+		}
+	}
+
+	fn change(&mut self, _: Self::Properties) -> ShouldRender {
+		false
+	}
+
+	fn update(&mut self, msg: Self::Message) -> ShouldRender {
+		match msg {
+			Msg::Key(Ok(body)) => {
+				self.key = body["key"].as_str().map(str::to_string);
+				true
+			}
+			// The server doesn't send notifications.
+			Msg::Key(Err(_)) => false,
+			Msg::Cameras(cameras) => {
+				self.cameras = cameras;
+				false
+			}
+			Msg::ToggleEvent(name) => {
+				if !self.events.remove(name) {
+					self.events.insert(name);
+				}
+				true
+			}
+			Msg::Subscribe => {
+				if let Some(key) = self.key.clone() {
+					let done = self.link.callback(Msg::Subscription);
+					spawn_local(async move { done.emit(subscribe(key).await) });
+					self.status = "Subscribing…".to_string();
+				}
+				true
+			}
+			Msg::Subscription(Ok(mut subscription)) => {
+				// Subscribing again keeps the endpoint, and just updates the preferences.
+				subscription.cameras = self.cameras.split(',').map(str::trim).filter(|name| !name.is_empty()).map(str::to_string).collect();
+				subscription.events = self.events.iter().map(|name| name.to_string()).collect();
+				let request = Request::post("/api/push/subscriptions")
+					.header("Authorization", authorization())
+					.header("Content-Type", "application/json")
+					.body(Json(&subscription).into())
+					.unwrap();
+				self.task = self.send(request, Msg::Saved);
+				false
+			}
+			Msg::Unsubscribe => {
+				let done = self.link.callback(Msg::Unsubscribed);
+				spawn_local(async move { done.emit(unsubscribe().await) });
+				false
+			}
+			Msg::Unsubscribed(Ok(endpoint)) => {
+				let request = Request::delete("/api/push/subscriptions")
+					.header("Authorization", authorization())
+					.header("Content-Type", "application/json")
+					.body(Json(&serde_json::json!({ "endpoint": endpoint })).into())
+					.unwrap();
+				self.task = self.send(request, |_| Msg::Removed);
+				false
+			}
+			Msg::Subscription(Err(err)) | Msg::Unsubscribed(Err(err)) => {
+				log::warn!("Push subscription failed: {}", err);
+				self.status = err;
+				true
+			}
+			Msg::Saved(ok) => {
+				self.subscribed = ok;
+				self.status = if ok { "Notifications on" } else { "The server refused the subscription" }.to_string();
+				true
+			}
+			Msg::Removed => {
+				self.subscribed = false;
+				self.status = "Notifications off".to_string();
+				true
+			}
+		}
+	}
+
+	fn view(&self) -> Html {
+		if self.key.is_none() {
+			return html!{};
+		}
+		html! {
+			<>
+			<h2>{ "Notifications" }</h2>
+			{ self.preferences_view() }
+			<button onclick=self.link.callback(|_| Msg::Subscribe)>{ if self.subscribed { "Update" } else { "Notify me" } }</button>
+			<button onclick=self.link.callback(|_| Msg::Unsubscribe)>{ "Stop notifications" }</button>
+			<span>{ &self.status }</span>
+			</>
+		}
+	}
+}
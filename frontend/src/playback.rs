@@ -0,0 +1,309 @@
+//! Playback of recorded footage, with a timeline of what each camera recorded.
+
+use std::collections::BTreeSet;
+
+use anyhow::Error;
+use wasm_bindgen::JsValue;
+use web_sys::{Element, HtmlVideoElement};
+use yew::prelude::*;
+use yew::format::{Json, Nothing};
+use yew::html::NodeRef;
+use yew::services::fetch::{FetchService, FetchTask, Request, Response};
+
+use common::{Recording, Timeline};
+
+use crate::login;
+
+/// Playback rates offered, as multiples of real time.
+const SPEEDS: [f64; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+/// How far back the timeline reaches, in milliseconds.
+const TIMELINE_LENGTH: u64 = 24 * 60 * 60 * 1000;
+/// Jumping back from just after an event skips to the one before it, rather
+/// than to the same event again.
+const EVENT_SKIP: u64 = 1000;
+
+pub struct Playback {
+	link: ComponentLink<Self>,
+	recordings_task: Option<FetchTask>,
+	timeline_task: Option<FetchTask>,
+	cameras: Vec<String>,
+	timeline: Option<Timeline>,
+	/// Times the timeline covers, in milliseconds since the Unix epoch.
+	range: (u64, u64),
+	/// Segment loaded into the video.
+	segment: Option<Recording>,
+	/// Seconds into the segment to seek to once it has loaded.
+	seek: Option<f64>,
+	/// Time of the frame shown, in milliseconds since the Unix epoch.
+	position: Option<u64>,
+	speed: f64,
+	video: NodeRef,
+	bar: NodeRef,
+}
+
+pub enum Msg {
+	Refresh,
+	Recordings(Result<Vec<Recording>, Error>),
+	SelectCamera(String),
+	Timeline(Result<Timeline, Error>),
+	Scrub(MouseEvent),
+	Seek(u64),
+	Loaded,
+	TimeUpdate,
+	Ended,
+	Speed(f64),
+	PreviousEvent,
+	NextEvent,
+	Ignore,
+}
+
+fn now() -> u64 {
+	js_sys::Date::now() as u64
+}
+
+fn format_time(time: u64) -> String {
+	js_sys::Date::new(&JsValue::from_f64(time as f64)).to_locale_string("default", &JsValue::UNDEFINED).into()
+}
+
+impl Playback {
+	fn fetch<T: 'static>(&self, url: &str, msg: fn(Result<T, Error>) -> Msg) -> Option<FetchTask> where Json<Result<T, Error>>: From<yew::format::Text> {
+		let request = Request::get(url)
+			.header("Authorization", format!("Bearer {}", login::token().unwrap_or_default()))
+			.body(Nothing)
+			.unwrap();
+		let callback = self.link.callback(move |response: Response<Json<Result<T, Error>>>| {
+			let Json(data) = response.into_body();
+			msg(data)
+		});
+		FetchService::fetch(request, callback)
+			.map_err(|err| log::error!("Failed fetching {}: {:?}", url, err))
+			.ok()
+	}
+
+	fn load_timeline(&mut self, camera: &str) {
+		self.range = (now().saturating_sub(TIMELINE_LENGTH), now());
+		let url = format!("/api/recordings/{}/timeline?from={}&to={}", camera, self.range.0, self.range.1);
+		self.timeline_task = self.fetch(&url, Msg::Timeline);
+	}
+
+	/// Shows the recording at `time`, or the next one after it if the camera
+	/// wasn't recorded then.
+	fn seek(&mut self, time: u64) -> bool {
+		let (video, timeline) = match (self.video.cast::<HtmlVideoElement>(), &self.timeline) {
+			(Some(video), Some(timeline)) => (video, timeline),
+			_ => return false,
+		};
+		let segment = match timeline.segment_at(time).or_else(|| timeline.segment_after(time)) {
+			Some(segment) => segment.clone(),
+			None => return false,
+		};
+		let time = time.max(segment.start);
+		let offset = (time - segment.start) as f64 / 1000.0;
+		if self.segment.as_ref().is_some_and(|current| current.file == segment.file) {
+			video.set_current_time(offset);
+		} else {
+			video.set_src(&format!("/recordings/{}?{}", segment.file, login::token_query()));
+			self.seek = Some(offset);
+			self.segment = Some(segment);
+		}
+		self.position = Some(time);
+		true
+	}
+
+	/// Where in the timeline's range `time` falls, as a percentage.
+	fn percent(&self, time: u64) -> f64 {
+		let (from, to) = self.range;
+		(time.clamp(from, to) - from) as f64 * 100.0 / (to - from).max(1) as f64
+	}
+
+	fn camera_view(&self) -> Html {
+		self.cameras.iter().map(|camera| {
+			let name = camera.clone();
+			html!{
+				<button onclick=self.link.callback(move |_| Msg::SelectCamera(name.clone()))>{ camera }</button>
+			}
+		}).collect::<Html>()
+	}
+
+	fn timeline_view(&self) -> Html {
+		let timeline = match &self.timeline {
+			Some(timeline) => timeline,
+			None => return html!{},
+		};
+		let spans = timeline.spans.iter().map(|span| {
+			let style = format!("position:absolute;top:0;bottom:0;left:{}%;width:{}%;background:#4a8", self.percent(span.start), self.percent(span.end) - self.percent(span.start));
+			html!{ <div style=style /> }
+		}).collect::<Html>();
+		let events = timeline.events.iter().map(|event| {
+			let time = event.time;
+			let style = format!("position:absolute;top:0;bottom:0;left:{}%;width:3px;background:#d33;cursor:pointer", self.percent(time));
+			let onclick = self.link.callback(move |e: MouseEvent| {
+				e.stop_propagation();
+				Msg::Seek(time)
+			});
+			html!{ <div style=style title=format!("{} at {}", event.kind, format_time(time)) onclick=onclick /> }
+		}).collect::<Html>();
+		let playhead = match self.position {
+			Some(position) => {
+				let style = format!("position:absolute;top:0;bottom:0;left:{}%;width:2px;background:#000", self.percent(position));
+				html!{ <div style=style /> }
+			}
+			None => html!{},
+		};
+		html!{
+			<div>
+				<div ref=self.bar.clone() onclick=self.link.callback(Msg::Scrub)
+					style="position:relative;height:24px;background:#ddd;cursor:pointer">
+					{ spans }
+					{ events }
+					{ playhead }
+				</div>
+				<span>{ format_time(self.range.0) }</span>
+				<span style="float:right">{ format_time(self.range.1) }</span>
+			</div>
+		}
+	}
+
+	fn controls_view(&self) -> Html {
+		let speeds = SPEEDS.iter().map(|speed| {
+			html!{ <option value=speed.to_string() selected=*speed == self.speed>{ format!("{}×", speed) }</option> }
+		}).collect::<Html>();
+		html!{
+			<div>
+				<button onclick=self.link.callback(|_| Msg::PreviousEvent)>{ "Previous event" }</button>
+				<button onclick=self.link.callback(|_| Msg::NextEvent)>{ "Next event" }</button>
+				<select onchange=self.link.callback(|data| match data {
+					ChangeData::Select(select) => select.value().parse().map(Msg::Speed).unwrap_or(Msg::Ignore),
+					_ => Msg::Ignore,
+				})>{ speeds }</select>
+				<span>{ self.position.map(format_time).unwrap_or_default() }</span>
+			</div>
+		}
+	}
+}
+
+impl Component for Playback {
+	type Message = Msg;
+	type Properties = ();
+
+	fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
+		link.send_message(Msg::Refresh);
+		Playback {
+			link,
+			recordings_task: None,
+			timeline_task: None,
+			cameras: Vec::new(),
+			timeline: None,
+			range: (0, 0),
+			segment: None,
+			seek: None,
+			position: None,
+			speed: 1.0,
+			video: NodeRef::default(),
+			bar: NodeRef::default(),
+		}
+	}
+
+	fn change(&mut self, _: Self::Properties) -> ShouldRender {
+		false
+	}
+
+	fn update(&mut self, msg: Self::Message) -> ShouldRender {
+		match msg {
+			Msg::Refresh => {
+				self.recordings_task = self.fetch("/api/recordings", Msg::Recordings);
+				false
+			}
+			Msg::Recordings(Ok(recordings)) => {
+				self.cameras = recordings.into_iter().map(|recording| recording.camera).collect::<BTreeSet<_>>().into_iter().collect();
+				true
+			}
+			Msg::SelectCamera(camera) => {
+				self.segment = None;
+				self.position = None;
+				self.load_timeline(&camera);
+				true
+			}
+			Msg::Timeline(Ok(timeline)) => {
+				self.timeline = Some(timeline);
+				true
+			}
+			Msg::Recordings(Err(err)) | Msg::Timeline(Err(err)) => {
+				log::error!("Failed loading recordings: {:?}", err);
+				false
+			}
+			Msg::Scrub(e) => {
+				let rect = match self.bar.cast::<Element>() {
+					Some(bar) => bar.get_bounding_client_rect(),
+					None => return false,
+				};
+				let fraction = ((e.client_x() as f64 - rect.left()) / rect.width()).clamp(0.0, 1.0);
+				let (from, to) = self.range;
+				self.seek(from + ((to - from) as f64 * fraction) as u64)
+			}
+			Msg::Seek(time) => self.seek(time),
+			Msg::Loaded => {
+				if let (Some(video), Some(offset)) = (self.video.cast::<HtmlVideoElement>(), self.seek.take()) {
+					video.set_current_time(offset);
+				}
+				false
+			}
+			Msg::TimeUpdate => {
+				match (self.video.cast::<HtmlVideoElement>(), &self.segment) {
+					(Some(video), Some(segment)) => {
+						self.position = Some(segment.start + (video.current_time() * 1000.0) as u64);
+						true
+					}
+					_ => false,
+				}
+			}
+			Msg::Ended => {
+				// Carry on into the next segment, skipping over gaps in the recording.
+				let next = self.segment.as_ref()
+					.and_then(|segment| self.timeline.as_ref()?.segment_after(segment.start))
+					.map(|segment| segment.start);
+				next.is_some_and(|start| self.seek(start))
+			}
+			Msg::Speed(speed) => {
+				self.speed = speed;
+				if let Some(video) = self.video.cast::<HtmlVideoElement>() {
+					// The default rate carries over when the next segment is loaded.
+					video.set_default_playback_rate(speed);
+					video.set_playback_rate(speed);
+				}
+				true
+			}
+			Msg::PreviousEvent => {
+				let position = self.position.unwrap_or_else(now);
+				let event = self.timeline.as_ref()
+					.and_then(|timeline| timeline.events.iter().rev().find(|event| event.time + EVENT_SKIP < position))
+					.map(|event| event.time);
+				event.is_some_and(|time| self.seek(time))
+			}
+			Msg::NextEvent => {
+				let position = self.position.unwrap_or(self.range.0);
+				let event = self.timeline.as_ref()
+					.and_then(|timeline| timeline.events.iter().find(|event| event.time > position))
+					.map(|event| event.time);
+				event.is_some_and(|time| self.seek(time))
+			}
+			Msg::Ignore => false,
+		}
+	}
+
+	fn view(&self) -> Html {
+		html! {
+			<>
+			<h2>{ "Recordings" }</h2>
+			<button onclick=self.link.callback(|_| Msg::Refresh)>{ "Refresh" }</button>
+			{ self.camera_view() }
+			{ self.timeline_view() }
+			<video id="playback" autoplay=true controls=true ref=self.video.clone()
+				onloadedmetadata=self.link.callback(|_| Msg::Loaded)
+				ontimeupdate=self.link.callback(|_| Msg::TimeUpdate)
+				onended=self.link.callback(|_| Msg::Ended) />
+			{ self.controls_view() }
+			</>
+		}
+	}
+}
@@ -0,0 +1,62 @@
+//! Pages of the app and the paths they live at, kept in the browser's
+//! history so they can be bookmarked and navigated with back and forward.
+
+use uuid::Uuid;
+use wasm_bindgen::JsValue;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+	Login,
+	Cameras,
+	/// Live view of one peer, usually a camera.
+	Camera(Uuid),
+	Grid,
+	Recordings,
+	Settings,
+	Admin,
+	NotFound,
+}
+
+impl Route {
+	pub fn parse(path: &str) -> Self {
+		let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+		match segments.as_slice() {
+			[""] | ["cameras"] => Route::Cameras,
+			["login"] => Route::Login,
+			["camera", id] => id.parse().map_or(Route::NotFound, Route::Camera),
+			["grid"] => Route::Grid,
+			["recordings"] => Route::Recordings,
+			["settings"] => Route::Settings,
+			["admin"] => Route::Admin,
+			_ => Route::NotFound,
+		}
+	}
+
+	pub fn path(&self) -> String {
+		match self {
+			Route::Login => "/login".to_string(),
+			Route::Cameras | Route::NotFound => "/".to_string(),
+			Route::Camera(id) => format!("/camera/{}", id),
+			Route::Grid => "/grid".to_string(),
+			Route::Recordings => "/recordings".to_string(),
+			Route::Settings => "/settings".to_string(),
+			Route::Admin => "/admin".to_string(),
+		}
+	}
+
+	/// The page the browser is on.
+	pub fn current() -> Self {
+		let path = web_sys::window().and_then(|window| window.location().pathname().ok());
+		Route::parse(path.as_deref().unwrap_or("/"))
+	}
+
+	/// Records the page in the browser's history without loading it again.
+	pub fn push(&self) {
+		let history = web_sys::window().and_then(|window| window.history().ok());
+		if let Some(history) = history {
+			if let Err(err) = history.push_state_with_url(&JsValue::NULL, "", Some(&self.path())) {
+				log::warn!("Failed navigating to {}: {:?}", self.path(), err);
+			}
+		}
+	}
+}
@@ -0,0 +1,50 @@
+//! Connection quality shown over each video, and whether it is also sent
+//! to the server for monitoring.
+
+use yew::prelude::*;
+
+use common::ConnectionStats;
+
+/// Where the choice to report statistics is kept, across visits.
+const REPORT_KEY: &str = "rstream-report-stats";
+
+fn storage() -> Option<web_sys::Storage> {
+	web_sys::window()?.local_storage().ok()?
+}
+
+/// Whether the user agreed to send connection statistics to the server.
+pub fn reporting() -> bool {
+	storage().and_then(|storage| storage.get_item(REPORT_KEY).ok()?).as_deref() == Some("true")
+}
+
+pub fn set_reporting(report: bool) {
+	if let Some(storage) = storage() {
+		let _ = storage.set_item(REPORT_KEY, if report { "true" } else { "false" });
+	}
+}
+
+fn format_bitrate(bits_per_sec: f64) -> String {
+	if bits_per_sec >= 1_000_000.0 {
+		format!("{:.1} Mbit/s", bits_per_sec / 1_000_000.0)
+	} else {
+		format!("{:.0} kbit/s", bits_per_sec / 1000.0)
+	}
+}
+
+/// A line of figures laid over the top of a video tile.
+pub fn overlay(stats: &ConnectionStats) -> Html {
+	let rtt = stats.rtt.map(|rtt| format!(" · RTT {:.0} ms", rtt * 1000.0)).unwrap_or_default();
+	let text = format!(
+		"{} · {:.0} fps · {:.1}% lost · jitter {:.0} ms{}",
+		format_bitrate(stats.bitrate),
+		stats.frame_rate,
+		stats.packet_loss * 100.0,
+		stats.jitter * 1000.0,
+		rtt,
+	);
+	html!{
+		<div class="stats" style="position:absolute;top:0;left:0;padding:2px 4px;background:rgba(0,0,0,0.5);color:white;font:12px monospace">
+			{ text }
+		</div>
+	}
+}
@@ -1,3 +1,5 @@
+use std::cell::Cell;
+
 use js_sys::{Reflect, Error};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
@@ -33,19 +35,97 @@ pub struct WebRtcService {
     pc: RtcPeerConnection
 }
 
+/// Running totals from one `getStats()` report, which rates are worked out
+/// from between two of them.
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    /// Milliseconds, as the report's timestamps are.
+    timestamp: f64,
+    bytes_received: f64,
+    frames_decoded: f64,
+    packets_received: f64,
+    packets_lost: f64,
+    jitter: f64,
+    rtt: Option<f64>,
+}
+
+impl Totals {
+    fn from_report(report: &JsValue) -> Self {
+        let mut totals = Totals::default();
+        report.unchecked_ref::<js_sys::Map>().for_each(&mut |stat, _| {
+            let text = |key: &str| Reflect::get(&stat, &JsValue::from_str(key)).ok().and_then(|value| value.as_string());
+            let number = |key: &str| Reflect::get(&stat, &JsValue::from_str(key)).ok().and_then(|value| value.as_f64());
+            match text("type").as_deref() {
+                Some("inbound-rtp") if text("kind").or_else(|| text("mediaType")).as_deref() == Some("video") => {
+                    totals.timestamp = totals.timestamp.max(number("timestamp").unwrap_or(0.0));
+                    totals.bytes_received += number("bytesReceived").unwrap_or(0.0);
+                    totals.frames_decoded += number("framesDecoded").unwrap_or(0.0);
+                    totals.packets_received += number("packetsReceived").unwrap_or(0.0);
+                    totals.packets_lost += number("packetsLost").unwrap_or(0.0);
+                    totals.jitter = totals.jitter.max(number("jitter").unwrap_or(0.0));
+                }
+                Some("candidate-pair") if text("state").as_deref() == Some("succeeded") && Reflect::get(&stat, &JsValue::from_str("nominated")).ok() == Some(JsValue::TRUE) => {
+                    totals.rtt = number("currentRoundTripTime").or(totals.rtt);
+                }
+                _ => (),
+            }
+        });
+        totals
+    }
+
+    /// How the connection did since `before`, if any time has passed.
+    fn since(&self, before: &Totals) -> Option<common::ConnectionStats> {
+        let secs = (self.timestamp - before.timestamp) / 1000.0;
+        if secs <= 0.0 {
+            return None;
+        }
+        let received = self.packets_received - before.packets_received;
+        let lost = self.packets_lost - before.packets_lost;
+        Some(common::ConnectionStats {
+            bitrate: (self.bytes_received - before.bytes_received).max(0.0) * 8.0 / secs,
+            frame_rate: (self.frames_decoded - before.frames_decoded).max(0.0) / secs,
+            packet_loss: if received + lost > 0.0 { (lost / (received + lost)).clamp(0.0, 1.0) } else { 0.0 },
+            jitter: self.jitter,
+            rtt: self.rtt,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct WebRtcTask {
-    peer_connection: RtcPeerConnection
+    peer_connection: RtcPeerConnection,
+    /// Totals of the last `stats()` call.
+    last_totals: Cell<Option<Totals>>,
 }
 
 impl WebRtcTask {
     pub fn new() -> Result<WebRtcTask, WebRtcError> {
         let pc = RtcPeerConnection::new_with_configuration(&RtcConfiguration::new()).unwrap();
         Ok(WebRtcTask {
-            peer_connection: pc
+            peer_connection: pc,
+            last_totals: Cell::new(None),
         })
     }
 
+    /// How the connection did since this was last called, or `None` the
+    /// first time and when the browser has no statistics to give.
+    pub async fn stats(&self) -> Option<common::ConnectionStats> {
+        let report = match JsFuture::from(self.peer_connection.get_stats()).await {
+            Ok(report) => report,
+            Err(err) => {
+                log::warn!("Failed getting connection statistics: {:?}", err);
+                return None;
+            }
+        };
+        let totals = Totals::from_report(&report);
+        let before = self.last_totals.replace(Some(totals))?;
+        totals.since(&before)
+    }
+
+    pub fn close(&self) {
+        self.peer_connection.close();
+    }
+
     pub fn log_pc(&self) {
         web_sys::console::log_1(self.peer_connection.as_ref());
     }
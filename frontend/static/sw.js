@@ -0,0 +1,19 @@
+// Shows the server's push messages as notifications, and opens rstream when
+// one is clicked.
+
+self.addEventListener('push', event => {
+	const message = event.data ? event.data.json() : { title: 'rstream', body: 'Something happened' };
+	event.waitUntil(self.registration.showNotification(message.title, {
+		body: message.body,
+		data: message.event,
+		tag: message.event ? message.event.type : undefined,
+	}));
+});
+
+self.addEventListener('notificationclick', event => {
+	event.notification.close();
+	event.waitUntil(clients.matchAll({ type: 'window' }).then(windows => {
+		const open = windows.find(client => 'focus' in client);
+		return open ? open.focus() : clients.openWindow('/');
+	}));
+});
@@ -0,0 +1,642 @@
+//! REST API for managing the server, alongside the signalling WebSocket.
+//!
+//! Clients log in at `POST /api/login` and send the token they get back as a
+//! bearer token. Admins may use every route; viewers may see the server's
+//! status and the cameras granted to them. The OpenAPI description is served
+//! at `/api/openapi.json`.
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use common::{Credentials, PeerError, PeerInfo, PeerRole, Session, Token};
+
+use crate::auth::{Accounts, AuthError, Role, User};
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::metrics::Metrics;
+use crate::registry::{Registry, RegistryError};
+use crate::storage::Storage;
+use crate::PeerMsg;
+
+/// Events returned by a query that doesn't give a limit.
+const DEFAULT_EVENT_LIMIT: usize = 100;
+/// Largest request body accepted.
+const BODY_LIMIT: u64 = 16 * 1024;
+
+impl warp::reject::Reject for AuthError {}
+
+/// What the API works on.
+pub struct Api {
+    pub peers: Arc<Registry>,
+    pub accounts: Arc<Accounts>,
+    pub bus: Arc<EventBus>,
+    pub storage: Arc<dyn Storage>,
+    pub config: Arc<Config>,
+    pub metrics: Arc<Metrics>,
+    started: Instant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct Status {
+    pub version: String,
+    /// Seconds since the server started.
+    pub uptime: u64,
+    pub peers: usize,
+    pub cameras: usize,
+    pub sessions: usize,
+    pub sfu: bool,
+    pub recording: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct CameraInfo {
+    /// The camera's peer ID, while it is connected.
+    pub id: Option<Uuid>,
+    pub name: String,
+    /// The streams it has, or had when it last registered.
+    pub streams: Vec<common::StreamDescriptor>,
+    /// Peers in a session with the camera.
+    pub viewers: Vec<Uuid>,
+    /// Seconds since the camera connected, while it is.
+    pub connected: Option<u64>,
+    /// Whether `/api/cameras/{id}/snapshot` has a still.
+    pub snapshot: bool,
+    /// When the camera last registered, in milliseconds since the Unix epoch.
+    pub last_seen: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct NewUser {
+    pub name: String,
+    pub password: String,
+    pub role: Role,
+    /// Names of the cameras the user may watch, or for camera users stream
+    /// as, or `*` for all of them.
+    #[serde(default)]
+    pub cameras: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Grants {
+    /// Names of the cameras the user may watch, or `*` for all of them.
+    pub cameras: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventQuery {
+    /// Only events after this time, in milliseconds since the Unix epoch.
+    pub since: Option<u64>,
+    /// Only events of this type, such as `camera-offline`.
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// Most events returned, the latest ones. 100 by default.
+    pub limit: Option<usize>,
+}
+
+/// A token in the query, for requests that can't carry headers.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "rstream", description = "Management API of the rstream signalling server."),
+    paths(login, logout, status, cameras, sessions, end_session, peers, disconnect, errors, users, add_user, remove_user, grant, events),
+    components(schemas(
+        Credentials, Token, Status, CameraInfo, PeerRole, PeerInfo, Session, PeerError, NewUser, Grants, ErrorBody, User, Role,
+        common::StreamDescriptor, common::Event, common::EventKind,
+    )),
+    modifiers(&BearerToken),
+)]
+pub struct ApiDoc;
+
+struct BearerToken;
+
+impl utoipa::Modify for BearerToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("token", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+    }
+}
+
+fn error(status: StatusCode, err: impl Display) -> Box<dyn Reply> {
+    Box::new(warp::reply::with_status(warp::reply::json(&ErrorBody { error: err.to_string() }), status))
+}
+
+fn auth_error(err: &AuthError) -> Box<dyn Reply> {
+    let status = match err {
+        AuthError::InvalidCredentials | AuthError::InvalidToken => StatusCode::UNAUTHORIZED,
+        AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+        AuthError::UnknownUser(_) => StatusCode::NOT_FOUND,
+        AuthError::UserExists(_) | AuthError::LastAdmin => StatusCode::CONFLICT,
+        AuthError::EmptyName => StatusCode::BAD_REQUEST,
+        AuthError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+        AuthError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error(status, err)
+}
+
+fn millis(time: std::time::SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn bearer(header: Option<&str>) -> Option<&str> {
+    header?.strip_prefix("Bearer ").map(str::trim)
+}
+
+impl Api {
+    pub fn new(peers: Arc<Registry>, accounts: Arc<Accounts>, bus: Arc<EventBus>, storage: Arc<dyn Storage>, config: Arc<Config>, metrics: Arc<Metrics>) -> Self {
+        Api { peers, accounts, bus, storage, config, metrics, started: Instant::now() }
+    }
+
+    /// Makes peer `id` the camera `descriptor` describes, if `user` may stream
+    /// as it, and remembers the camera across restarts.
+    pub fn register_camera(&self, user: &User, id: Uuid, descriptor: common::CameraDescriptor) -> Result<(), RegistryError> {
+        if !user.may_stream(&descriptor.name) {
+            return Err(RegistryError::MayNotStream(descriptor.name));
+        }
+        self.peers.register_camera(&id, descriptor.clone())?;
+        if let Err(err) = self.storage.register_camera(&descriptor, millis(std::time::SystemTime::now())) {
+            log::warn!("Failed storing camera {:?}: {}", descriptor.name, err);
+        }
+        self.bus.publish(common::EventKind::CameraOnline { camera: id, name: descriptor.name });
+        Ok(())
+    }
+
+    fn refused(&self, remote: Option<SocketAddr>, err: &AuthError) {
+        let remote = remote.map(|remote| remote.ip().to_string());
+        log::info!("Refused API client {}: {}", remote.as_deref().unwrap_or("?"), err);
+        self.bus.publish(common::EventKind::AuthFailure { remote, reason: err.to_string() });
+    }
+
+    /// The user a request's bearer token belongs to, if they have `role`.
+    /// Tokens that don't work are reported as auth failures, but requests
+    /// without one aren't, since that's just a client that hasn't logged in.
+    fn authorize(&self, header: Option<&str>, remote: Option<SocketAddr>, role: Role) -> Result<User, AuthError> {
+        let token = bearer(header).ok_or(AuthError::InvalidToken)?;
+        let user = self.accounts.authenticate(token).inspect_err(|err| self.refused(remote, err))?;
+        if role == Role::Admin && user.role != Role::Admin {
+            return Err(AuthError::Forbidden(Role::Admin));
+        }
+        Ok(user)
+    }
+
+    fn sessions(&self) -> Vec<Session> {
+        self.peers.peers().into_iter()
+            .filter_map(|peer| peer.camera().map(|camera| (peer, camera.name)))
+            .flat_map(|(peer, name)| peer.partners().into_iter().map(move |viewer| Session { camera: peer.id, camera_name: name.clone(), viewer }))
+            .collect()
+    }
+}
+
+#[utoipa::path(post, path = "/api/login", request_body = Credentials, responses(
+    (status = 200, description = "Logged in", body = Token),
+    (status = 401, description = "Wrong name or password", body = ErrorBody),
+    (status = 429, description = "Too many failed logins from this address or for this user", body = ErrorBody),
+))]
+async fn login(api: Arc<Api>, credentials: Credentials, remote: Option<SocketAddr>) -> Box<dyn Reply> {
+    // Hashing the password would hold up other requests on this thread.
+    let (accounts, name) = (api.accounts.clone(), credentials.name.clone());
+    let login = tokio1::task::spawn_blocking(move || accounts.login(&credentials.name, &credentials.password, remote.map(|remote| remote.ip())));
+    let result = match login.await {
+        Ok(result) => result,
+        Err(err) => return error(StatusCode::INTERNAL_SERVER_ERROR, err),
+    };
+    match result {
+        Ok((token, expires)) => {
+            log::info!("User {} logged in", name);
+            Box::new(warp::reply::json(&Token { token, expires: millis(expires) }))
+        }
+        Err(err) => {
+            api.refused(remote, &err);
+            auth_error(&err)
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/api/logout", responses((status = 204, description = "The token no longer works")), security(("token" = [])))]
+fn logout(api: &Api, header: Option<&str>) -> Box<dyn Reply> {
+    if let Some(token) = bearer(header) {
+        api.accounts.logout(token);
+    }
+    Box::new(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/api/status", responses((status = 200, body = Status)), security(("token" = [])))]
+fn status(api: &Api) -> Box<dyn Reply> {
+    let peers = api.peers.peers();
+    Box::new(warp::reply::json(&Status {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime: api.started.elapsed().as_secs(),
+        peers: peers.len(),
+        cameras: peers.iter().filter(|peer| peer.camera().is_some()).count(),
+        sessions: api.sessions().len(),
+        sfu: api.config.sfu,
+        recording: api.config.recording.is_some(),
+    }))
+}
+
+#[utoipa::path(get, path = "/api/cameras", responses((status = 200, description = "Cameras the user may watch: connected ones, then those that have registered before", body = [CameraInfo])), security(("token" = [])))]
+fn cameras(api: &Api, user: &User) -> Box<dyn Reply> {
+    let mut cameras = api.peers.peers().into_iter()
+        .filter_map(|peer| peer.camera().map(|camera| (peer, camera)))
+        .filter(|(_, camera)| user.may_watch(&camera.name))
+        .map(|(peer, camera)| CameraInfo {
+            id: Some(peer.id),
+            name: camera.name,
+            streams: camera.streams,
+            viewers: peer.partners(),
+            connected: Some(peer.connected_at.elapsed().as_secs()),
+            snapshot: peer.snapshot().is_some(),
+            last_seen: None,
+        })
+        .collect::<Vec<_>>();
+    let known = api.storage.cameras().unwrap_or_else(|err| {
+        log::warn!("Failed reading stored cameras: {}", err);
+        Vec::new()
+    });
+    for stored in known {
+        match cameras.iter_mut().find(|camera| camera.name == stored.descriptor.name) {
+            Some(camera) => camera.last_seen = Some(stored.last_seen),
+            None if user.may_watch(&stored.descriptor.name) => cameras.push(CameraInfo {
+                id: None,
+                name: stored.descriptor.name,
+                streams: stored.descriptor.streams,
+                viewers: Vec::new(),
+                connected: None,
+                snapshot: false,
+                last_seen: Some(stored.last_seen),
+            }),
+            None => {}
+        }
+    }
+    Box::new(warp::reply::json(&cameras))
+}
+
+#[utoipa::path(get, path = "/api/sessions", responses((status = 200, body = [Session])), security(("token" = [])))]
+fn sessions(api: &Api) -> Box<dyn Reply> {
+    Box::new(warp::reply::json(&api.sessions()))
+}
+
+/// Hangs up a session, telling both ends the other left.
+#[utoipa::path(delete, path = "/api/sessions/{camera}/{viewer}", params(("camera" = Uuid, Path, description = "The camera's peer ID"), ("viewer" = Uuid, Path, description = "The viewer's peer ID")), responses(
+    (status = 204, description = "Hung up"),
+    (status = 404, description = "No such session"),
+), security(("token" = [])))]
+fn end_session(api: &Api, user: &User, camera: Uuid, viewer: Uuid) -> Box<dyn Reply> {
+    if !api.peers.get(&camera).is_some_and(|peer| peer.partners().contains(&viewer)) {
+        return Box::new(StatusCode::NOT_FOUND);
+    }
+    log::info!("{} ended the session between camera {} and {}", user.name, camera, viewer);
+    api.peers.unpair(&camera, &viewer);
+    for (sender, recipient) in [(camera, viewer), (viewer, camera)] {
+        let _ = api.peers.send(PeerMsg { signal: common::Signal::Hangup, sender, recipient });
+    }
+    Box::new(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/api/peers", responses((status = 200, body = [PeerInfo])), security(("token" = [])))]
+fn peers(api: &Api) -> Box<dyn Reply> {
+    let peers = api.peers.peers().into_iter().map(|peer| {
+        let camera = peer.camera();
+        PeerInfo {
+            id: peer.id,
+            role: if camera.is_some() { PeerRole::Camera } else { PeerRole::Viewer },
+            name: camera.map(|camera| camera.name),
+            remote: peer.remote.map(|remote| remote.to_string()),
+            connected: peer.connected_at.elapsed().as_secs(),
+            partners: peer.partners(),
+            received: peer.received(),
+            sent: peer.sent(),
+            bitrate: api.metrics.video_bitrate(&peer.id),
+        }
+    }).collect::<Vec<_>>();
+    Box::new(warp::reply::json(&peers))
+}
+
+/// Closes a peer's connection, which hangs up all its sessions.
+#[utoipa::path(delete, path = "/api/peers/{id}", params(("id" = Uuid, Path, description = "Peer ID")), responses(
+    (status = 204, description = "Disconnected"),
+    (status = 404, description = "No such peer"),
+), security(("token" = [])))]
+fn disconnect(api: &Api, user: &User, id: Uuid) -> Box<dyn Reply> {
+    match api.peers.get(&id) {
+        Some(peer) => {
+            log::info!("{} disconnected peer {}", user.name, id);
+            peer.queue.close();
+            Box::new(StatusCode::NO_CONTENT)
+        }
+        None => Box::new(StatusCode::NOT_FOUND),
+    }
+}
+
+#[utoipa::path(get, path = "/api/errors", responses((status = 200, description = "Recent signalling errors, oldest first", body = [PeerError])), security(("token" = [])))]
+fn errors(api: &Api) -> Box<dyn Reply> {
+    Box::new(warp::reply::json(&api.peers.errors()))
+}
+
+#[utoipa::path(get, path = "/api/users", responses((status = 200, body = [User])), security(("token" = [])))]
+fn users(api: &Api) -> Box<dyn Reply> {
+    Box::new(warp::reply::json(&api.accounts.users()))
+}
+
+#[utoipa::path(post, path = "/api/users", request_body = NewUser, responses(
+    (status = 201, description = "Created", body = User),
+    (status = 409, description = "The name is taken", body = ErrorBody),
+), security(("token" = [])))]
+fn add_user(api: &Api, new: NewUser) -> Box<dyn Reply> {
+    let user = User { name: new.name, role: new.role, cameras: new.cameras };
+    match api.accounts.add(user.clone(), &new.password) {
+        Ok(()) => Box::new(warp::reply::with_status(warp::reply::json(&user), StatusCode::CREATED)),
+        Err(err) => auth_error(&err),
+    }
+}
+
+#[utoipa::path(delete, path = "/api/users/{name}", params(("name" = String, Path, description = "User name")), responses(
+    (status = 204, description = "Removed, and logged out"),
+    (status = 404, description = "No such user", body = ErrorBody),
+    (status = 409, description = "The user is the last admin", body = ErrorBody),
+), security(("token" = [])))]
+fn remove_user(api: &Api, name: String) -> Box<dyn Reply> {
+    match api.accounts.remove(&name) {
+        Ok(()) => Box::new(StatusCode::NO_CONTENT),
+        Err(err) => auth_error(&err),
+    }
+}
+
+/// Replaces the cameras a user may watch.
+#[utoipa::path(put, path = "/api/users/{name}/grants", params(("name" = String, Path, description = "User name")), request_body = Grants, responses(
+    (status = 200, body = User),
+    (status = 404, description = "No such user", body = ErrorBody),
+), security(("token" = [])))]
+fn grant(api: &Api, name: String, grants: Grants) -> Box<dyn Reply> {
+    match api.accounts.grant(&name, grants.cameras) {
+        Ok(user) => Box::new(warp::reply::json(&user)),
+        Err(err) => auth_error(&err),
+    }
+}
+
+#[utoipa::path(get, path = "/api/events", params(EventQuery), responses((status = 200, description = "Recent events, oldest first", body = [Event])), security(("token" = [])))]
+fn events(api: &Api, query: EventQuery) -> Box<dyn Reply> {
+    match api.bus.recent(query.since, query.kind.as_deref(), query.limit.unwrap_or(DEFAULT_EVENT_LIMIT)) {
+        Ok(events) => Box::new(warp::reply::json(&events)),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err),
+    }
+}
+
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(BODY_LIMIT).and(warp::body::json())
+}
+
+fn with_api(api: Arc<Api>) -> impl Filter<Extract = (Arc<Api>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || api.clone())
+}
+
+/// Rejects requests without a token for a user with `role`. Browsers can't
+/// set headers for WebSockets, images and videos, so the token may also come
+/// as a `token` query parameter.
+pub fn authorized(api: Arc<Api>, role: Role) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<TokenQuery>().or(warp::any().map(TokenQuery::default)).unify())
+        .and(crate::tls::remote())
+        .and_then(move |header: Option<String>, query: TokenQuery, remote: Option<SocketAddr>| {
+            let header = header.or_else(|| query.token.map(|token| format!("Bearer {}", token)));
+            futures_util::future::ready(api.authorize(header.as_deref(), remote, role).map_err(warp::reject::custom))
+        })
+}
+
+/// Answers refused tokens and malformed bodies, leaving other rejections be.
+pub async fn recover(rejection: Rejection) -> Result<Box<dyn Reply>, Rejection> {
+    if let Some(err) = rejection.find::<AuthError>() {
+        Ok(auth_error(err))
+    } else if let Some(err) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        Ok(error(StatusCode::BAD_REQUEST, err))
+    } else {
+        Err(rejection)
+    }
+}
+
+/// Every route of the API. Requests for other paths are rejected as usual,
+/// so the API can be combined with the rest of the server.
+pub fn routes(api: Arc<Api>) -> impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone {
+    let admin = || with_api(api.clone()).and(authorized(api.clone(), Role::Admin));
+    let viewer = || with_api(api.clone()).and(authorized(api.clone(), Role::Viewer));
+
+    let login = warp::post().and(warp::path!("api" / "login"))
+        .and(with_api(api.clone())).and(json_body()).and(crate::tls::remote())
+        .then(|api: Arc<Api>, credentials: Credentials, remote: Option<SocketAddr>| login(api, credentials, remote));
+    let logout = warp::post().and(warp::path!("api" / "logout"))
+        .and(with_api(api.clone())).and(warp::header::optional::<String>("authorization"))
+        .map(|api: Arc<Api>, header: Option<String>| logout(&api, header.as_deref()));
+    let openapi = warp::get().and(warp::path!("api" / "openapi.json"))
+        .map(|| Box::new(warp::reply::json(&ApiDoc::openapi())) as Box<dyn Reply>);
+
+    let status = warp::get().and(warp::path!("api" / "status")).and(viewer())
+        .map(|api: Arc<Api>, _: User| status(&api));
+    let cameras = warp::get().and(warp::path!("api" / "cameras")).and(viewer())
+        .map(|api: Arc<Api>, user: User| cameras(&api, &user));
+    let sessions = warp::get().and(warp::path!("api" / "sessions")).and(admin())
+        .map(|api: Arc<Api>, _: User| sessions(&api));
+    let end_session = warp::delete().and(warp::path!("api" / "sessions" / Uuid / Uuid)).and(admin())
+        .map(|camera: Uuid, viewer: Uuid, api: Arc<Api>, user: User| end_session(&api, &user, camera, viewer));
+    let peers = warp::get().and(warp::path!("api" / "peers")).and(admin())
+        .map(|api: Arc<Api>, _: User| peers(&api));
+    let disconnect = warp::delete().and(warp::path!("api" / "peers" / Uuid)).and(admin())
+        .map(|id: Uuid, api: Arc<Api>, user: User| disconnect(&api, &user, id));
+    let errors = warp::get().and(warp::path!("api" / "errors")).and(admin())
+        .map(|api: Arc<Api>, _: User| errors(&api));
+    let users = warp::get().and(warp::path!("api" / "users")).and(admin())
+        .map(|api: Arc<Api>, _: User| users(&api));
+    let add_user = warp::post().and(warp::path!("api" / "users")).and(admin()).and(json_body())
+        .map(|api: Arc<Api>, _: User, new: NewUser| add_user(&api, new));
+    let remove_user = warp::delete().and(warp::path!("api" / "users" / String)).and(admin())
+        .map(|name: String, api: Arc<Api>, _: User| remove_user(&api, name));
+    let grant = warp::put().and(warp::path!("api" / "users" / String / "grants")).and(admin()).and(json_body())
+        .map(|name: String, api: Arc<Api>, _: User, grants: Grants| grant(&api, name, grants));
+    let events = warp::get().and(warp::path!("api" / "events")).and(admin()).and(warp::query::<EventQuery>())
+        .map(|api: Arc<Api>, _: User, query: EventQuery| events(&api, query));
+
+    login.or(logout).unify()
+        .or(openapi).unify()
+        .or(status).unify()
+        .or(cameras).unify()
+        .or(sessions).unify()
+        .or(end_session).unify()
+        .or(peers).unify()
+        .or(disconnect).unify()
+        .or(errors).unify()
+        .or(users).unify()
+        .or(add_user).unify()
+        .or(remove_user).unify()
+        .or(grant).unify()
+        .or(events).unify()
+        .recover(recover).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ALL_CAMERAS;
+    use crate::registry::Peer;
+    use crate::storage::{MemoryStorage, SqliteStorage};
+
+    fn api() -> Arc<Api> {
+        with_storage(Arc::new(MemoryStorage::default()))
+    }
+
+    fn with_storage(storage: Arc<dyn Storage>) -> Arc<Api> {
+        let accounts = Accounts::for_tests("secret");
+        let api = Api::new(Arc::new(Registry::new()), Arc::new(accounts), Arc::new(EventBus::new(storage.clone())), storage, Arc::new(Config::default()), Arc::new(Metrics::new()));
+        Arc::new(api)
+    }
+
+    fn camera(api: &Api, name: &str) -> Uuid {
+        let peer = Arc::new(Peer::new(Uuid::new_v4(), 8));
+        peer.set_camera(common::CameraDescriptor { name: name.to_string(), streams: Vec::new() });
+        api.peers.register(peer.clone()).unwrap();
+        peer.id
+    }
+
+    async fn token(routes: &(impl Filter<Extract = (Box<dyn Reply>,), Error = Rejection> + Clone + 'static), name: &str, password: &str) -> String {
+        let response = warp::test::request().method("POST").path("/api/login")
+            .json(&serde_json::json!({ "name": name, "password": password }))
+            .reply(routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice::<Token>(response.body()).unwrap().token
+    }
+
+    #[tokio1::test(crate = "tokio1")]
+    async fn requires_tokens_and_roles() {
+        let api = api();
+        let routes = routes(api.clone());
+
+        let anonymous = warp::test::request().path("/api/status").reply(&routes).await;
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+        let refused = warp::test::request().method("POST").path("/api/login")
+            .json(&serde_json::json!({ "name": "admin", "password": "guess" }))
+            .reply(&routes).await;
+        assert_eq!(refused.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(api.bus.recent(None, Some("auth-failure"), 10).unwrap().len(), 1);
+
+        let admin = token(&routes, "admin", "secret").await;
+        let created = warp::test::request().method("POST").path("/api/users")
+            .header("authorization", format!("Bearer {}", admin))
+            .json(&serde_json::json!({ "name": "sam", "password": "hunter2", "role": "viewer", "cameras": ["Porch"] }))
+            .reply(&routes).await;
+        assert_eq!(created.status(), StatusCode::CREATED);
+
+        let viewer = token(&routes, "sam", "hunter2").await;
+        let status = warp::test::request().path("/api/status").header("authorization", format!("Bearer {}", viewer)).reply(&routes).await;
+        assert_eq!(status.status(), StatusCode::OK);
+        let users = warp::test::request().path("/api/users").header("authorization", format!("Bearer {}", viewer)).reply(&routes).await;
+        assert_eq!(users.status(), StatusCode::FORBIDDEN);
+
+        // Images and WebSockets can't set headers, so the query may carry the token.
+        let queried = warp::test::request().path(&format!("/api/status?token={}", viewer)).reply(&routes).await;
+        assert_eq!(queried.status(), StatusCode::OK);
+
+        // Unknown paths are left for the rest of the server.
+        assert!(warp::test::request().path("/index.html").filter(&routes).await.is_err());
+    }
+
+    #[tokio1::test(crate = "tokio1")]
+    async fn shows_viewers_their_cameras() {
+        let api = api();
+        let routes = routes(api.clone());
+        api.accounts.add(User { name: "sam".to_string(), role: Role::Viewer, cameras: vec!["Porch".to_string()] }, "hunter2").unwrap();
+        let porch = camera(&api, "Porch");
+        camera(&api, "Food bowl");
+
+        let viewer = token(&routes, "sam", "hunter2").await;
+        let list = |token: String| warp::test::request().path("/api/cameras").header("authorization", format!("Bearer {}", token)).reply(&routes);
+        let cameras: Vec<CameraInfo> = serde_json::from_slice(list(viewer.clone()).await.body()).unwrap();
+        assert_eq!(cameras.iter().map(|camera| camera.id).collect::<Vec<_>>(), vec![Some(porch)]);
+
+        api.accounts.grant("sam", vec![ALL_CAMERAS.to_string()]).unwrap();
+        let cameras: Vec<CameraInfo> = serde_json::from_slice(list(viewer).await.body()).unwrap();
+        assert_eq!(cameras.len(), 2);
+    }
+
+    #[tokio1::test(crate = "tokio1")]
+    async fn remembers_cameras_across_restarts() {
+        let path = std::env::temp_dir().join(format!("rstream-api-{}.db", Uuid::new_v4()));
+        let admin = User { name: "admin".to_string(), role: Role::Admin, cameras: Vec::new() };
+        let viewer = User { name: "sam".to_string(), role: Role::Viewer, cameras: vec!["Porch".to_string()] };
+        let stream = common::StreamDescriptor { id: common::MAIN_STREAM.to_string(), codec: "H264".to_string(), width: 640, height: 480, fps: 30 };
+        let porch = common::CameraDescriptor { name: "Porch".to_string(), streams: vec![stream] };
+        {
+            let api = with_storage(Arc::new(SqliteStorage::open(&path).unwrap()));
+            let peer = Arc::new(Peer::new(Uuid::new_v4(), 8));
+            api.peers.register(peer.clone()).unwrap();
+            assert_eq!(api.register_camera(&viewer, peer.id, porch.clone()), Err(RegistryError::MayNotStream("Porch".to_string())));
+            api.register_camera(&admin, peer.id, porch.clone()).unwrap();
+            assert_eq!(api.bus.recent(None, Some("camera-online"), 10).unwrap().len(), 1);
+        }
+
+        let api = with_storage(Arc::new(SqliteStorage::open(&path).unwrap()));
+        let routes = routes(api.clone());
+        let token = token(&routes, "admin", "secret").await;
+        let response = warp::test::request().path("/api/cameras").header("authorization", format!("Bearer {}", token)).reply(&routes).await;
+        let cameras: Vec<CameraInfo> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(cameras.len(), 1);
+        assert_eq!((cameras[0].id, cameras[0].name.as_str(), &cameras[0].streams), (None, "Porch", &porch.streams));
+        assert!(cameras[0].last_seen.is_some());
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[tokio1::test(crate = "tokio1")]
+    async fn ends_sessions_and_disconnects_peers() {
+        let api = api();
+        let routes = routes(api.clone());
+        let camera = camera(&api, "Porch");
+        let viewer = Arc::new(Peer::new(Uuid::new_v4(), 8));
+        api.peers.register(viewer.clone()).unwrap();
+        api.peers.pair(&camera, &viewer.id);
+
+        let admin = token(&routes, "admin", "secret").await;
+        let sessions: Vec<Session> = serde_json::from_slice(warp::test::request().path("/api/sessions")
+            .header("authorization", format!("Bearer {}", admin)).reply(&routes).await.body()).unwrap();
+        assert_eq!(sessions, vec![Session { camera, camera_name: "Porch".to_string(), viewer: viewer.id }]);
+
+        let end = || warp::test::request().method("DELETE").path(&format!("/api/sessions/{}/{}", camera, viewer.id))
+            .header("authorization", format!("Bearer {}", admin)).reply(&routes);
+        assert_eq!(end().await.status(), StatusCode::NO_CONTENT);
+        assert!(matches!(viewer.queue.pop().await.unwrap().signal, common::Signal::Hangup));
+        assert_eq!(end().await.status(), StatusCode::NOT_FOUND);
+
+        let kicked = warp::test::request().method("DELETE").path(&format!("/api/peers/{}", viewer.id))
+            .header("authorization", format!("Bearer {}", admin)).reply(&routes).await;
+        assert_eq!(kicked.status(), StatusCode::NO_CONTENT);
+        assert!(viewer.queue.pop().await.is_err());
+    }
+
+    #[test]
+    fn describes_every_route() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for path in ["/api/login", "/api/cameras", "/api/sessions/{camera}/{viewer}", "/api/errors", "/api/users/{name}/grants", "/api/events"] {
+            assert!(doc["paths"].get(path).is_some(), "{} is missing", path);
+        }
+        assert!(doc["components"]["securitySchemes"].get("token").is_some());
+    }
+}
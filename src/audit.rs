@@ -0,0 +1,166 @@
+//! Append-only record of who watched which camera when, kept apart from the
+//! diagnostic log so it can be retained and checked on its own.
+//!
+//! Each line is a JSON [`Record`]. A watch is written when it starts and
+//! again when it ends, so one cut short by a crash still shows up.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::registry::Peer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    WatchStarted,
+    WatchEnded,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Record {
+    /// Milliseconds since the Unix epoch.
+    pub time: u64,
+    pub action: Action,
+    /// Shared by the start and end of one watch, and logged with its signalling.
+    pub session: Uuid,
+    pub viewer: Uuid,
+    /// The API user the viewer connected as, if it sent a token.
+    pub user: Option<String>,
+    pub remote: Option<String>,
+    pub camera: Uuid,
+    pub camera_name: String,
+}
+
+pub struct AuditLog {
+    file: Mutex<File>,
+    /// Watches started and not ended yet, by viewer and camera.
+    open: Mutex<HashMap<(Uuid, Uuid), Record>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as u64)
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed opening audit log {}", path.display()))?;
+        Ok(AuditLog { file: Mutex::new(file), open: Mutex::new(HashMap::new()) })
+    }
+
+    fn write(&self, record: &Record) {
+        let mut line = serde_json::to_string(record).unwrap();
+        line.push('\n');
+        // A single write per line, so lines from concurrent watches don't interleave.
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            log::error!("Failed writing audit log: {}", err);
+        }
+    }
+
+    /// Records `viewer` starting to watch a camera, unless it already is,
+    /// and returns the watch's session ID.
+    pub fn started(&self, viewer: &Peer, camera: Uuid, camera_name: &str) -> Uuid {
+        let mut open = self.open.lock().unwrap();
+        if let Some(record) = open.get(&(viewer.id, camera)) {
+            return record.session;
+        }
+        let record = Record {
+            time: now(),
+            action: Action::WatchStarted,
+            session: Uuid::new_v4(),
+            viewer: viewer.id,
+            user: viewer.user.clone(),
+            remote: viewer.remote.map(|remote| remote.to_string()),
+            camera,
+            camera_name: camera_name.to_string(),
+        };
+        self.write(&record);
+        let session = record.session;
+        open.insert((viewer.id, camera), record);
+        session
+    }
+
+    /// Session ID of a watch between two peers, whichever is the viewer.
+    pub fn session(&self, a: Uuid, b: Uuid) -> Option<Uuid> {
+        let open = self.open.lock().unwrap();
+        open.get(&(a, b)).or_else(|| open.get(&(b, a))).map(|record| record.session)
+    }
+
+    /// Records the end of a watch between two peers, whichever is the viewer.
+    pub fn ended(&self, a: Uuid, b: Uuid) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(record) = open.remove(&(a, b)).or_else(|| open.remove(&(b, a))) {
+            self.write(&Record { time: now(), action: Action::WatchEnded, ..record });
+        }
+    }
+
+    /// Ends every watch `peer` took part in, once it has gone.
+    pub fn left(&self, peer: Uuid) {
+        let mut open = self.open.lock().unwrap();
+        let ended = open.keys().filter(|(viewer, camera)| *viewer == peer || *camera == peer).copied().collect::<Vec<_>>();
+        for key in ended {
+            if let Some(record) = open.remove(&key) {
+                self.write(&Record { time: now(), action: Action::WatchEnded, ..record });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(path: &Path) -> Vec<Record> {
+        std::fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn records_each_watch_once() {
+        let path = std::env::temp_dir().join(format!("rstream-audit-{}.jsonl", Uuid::new_v4()));
+        let mut viewer = Peer::new(Uuid::new_v4(), 4);
+        viewer.remote = Some("192.0.2.7".parse().unwrap());
+        viewer.user = Some("alice".to_string());
+        let (porch, yard) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let audit = AuditLog::open(&path).unwrap();
+        let session = audit.started(&viewer, porch, "Porch");
+        assert_eq!(audit.started(&viewer, porch, "Porch"), session);
+        assert_eq!(audit.session(porch, viewer.id), Some(session));
+        audit.started(&viewer, yard, "Yard");
+        audit.ended(porch, viewer.id);
+        audit.ended(porch, viewer.id);
+        drop(audit);
+
+        // Reopening appends rather than starting over.
+        viewer.user = None;
+        let audit = AuditLog::open(&path).unwrap();
+        audit.started(&viewer, porch, "Porch");
+        audit.left(viewer.id);
+
+        let records = records(&path);
+        let summary = records.iter().map(|record| (record.action, record.camera_name.as_str(), record.user.as_deref())).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (Action::WatchStarted, "Porch", Some("alice")),
+            (Action::WatchStarted, "Yard", Some("alice")),
+            (Action::WatchEnded, "Porch", Some("alice")),
+            (Action::WatchStarted, "Porch", None),
+            (Action::WatchEnded, "Porch", None),
+        ]);
+        assert_eq!(records[2].session, session);
+        assert_eq!(records[0].remote.as_deref(), Some("192.0.2.7"));
+        let _ = std::fs::remove_file(path);
+    }
+}
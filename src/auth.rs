@@ -0,0 +1,479 @@
+//! Users of the REST API, what they may see, and the tokens they log in for.
+//!
+//! Accounts are kept in the server's [`Storage`] with PBKDF2 password hashes,
+//! and those older versions saved in a JSON file are imported into it. Tokens
+//! only live in memory, so everyone logs in again after a restart.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::storage::{Storage, StorageError, StoredUser};
+
+/// PBKDF2 iterations for new password hashes.
+const ROUNDS: u32 = 100_000;
+/// How long a login lasts.
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+/// Granted in place of camera names to allow every camera.
+pub const ALL_CAMERAS: &str = "*";
+/// Failed logins allowed for one user name from one address, and from one
+/// address for any names, per `FAILURE_WINDOW`.
+const MAX_FAILURES: usize = 5;
+const MAX_REMOTE_FAILURES: usize = 20;
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Manages the server and its users, and may watch every camera.
+    Admin,
+    /// Watches the cameras granted to them.
+    Viewer,
+    /// Streams as the cameras granted to it, and watches none.
+    Camera,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+            Role::Camera => "camera",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    /// Names of the cameras the user may watch, or for camera users stream
+    /// as, or `*` for all of them.
+    #[serde(default)]
+    pub cameras: Vec<String>,
+}
+
+impl User {
+    fn granted(&self, camera: &str) -> bool {
+        self.cameras.iter().any(|granted| granted == camera || granted == ALL_CAMERAS)
+    }
+
+    pub fn may_watch(&self, camera: &str) -> bool {
+        match self.role {
+            Role::Admin => true,
+            Role::Viewer => self.granted(camera),
+            Role::Camera => false,
+        }
+    }
+
+    /// Whether the user may register as the camera named `camera`.
+    pub fn may_stream(&self, camera: &str) -> bool {
+        match self.role {
+            Role::Admin => true,
+            Role::Camera => self.granted(camera),
+            Role::Viewer => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AuthError {
+    #[error("wrong user name or password")]
+    InvalidCredentials,
+    #[error("missing, unknown or expired token")]
+    InvalidToken,
+    #[error("only users with the {0} role may do this")]
+    Forbidden(Role),
+    #[error("no user named {0:?}")]
+    UnknownUser(String),
+    #[error("a user named {0:?} already exists")]
+    UserExists(String),
+    #[error("user names can't be empty")]
+    EmptyName,
+    #[error("the last admin can't be removed")]
+    LastAdmin,
+    #[error("too many failed logins, try again later")]
+    TooManyAttempts,
+    #[error("failed storing users: {0}")]
+    Storage(String),
+}
+
+impl From<StorageError> for AuthError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::UnknownUser(name) => AuthError::UnknownUser(name),
+            StorageError::UserExists(name) => AuthError::UserExists(name),
+            StorageError::Backend(err) => AuthError::Storage(err),
+        }
+    }
+}
+
+/// A user as older versions saved them in JSON, with their password hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Account {
+    #[serde(flatten)]
+    user: User,
+    password: String,
+}
+
+struct Login {
+    user: String,
+    expires: SystemTime,
+}
+
+/// Recent failed logins by key, so passwords can't be guessed faster than a
+/// few a `FAILURE_WINDOW`.
+struct Failures<K> {
+    failures: HashMap<K, Vec<Instant>>,
+}
+
+impl<K: Hash + Eq> Failures<K> {
+    fn new() -> Self {
+        Failures { failures: HashMap::new() }
+    }
+
+    fn exceeded(&mut self, key: &K, limit: usize, now: Instant) -> bool {
+        self.failures.retain(|_, times| {
+            times.retain(|time| now.saturating_duration_since(*time) < FAILURE_WINDOW);
+            !times.is_empty()
+        });
+        self.failures.get(key).is_some_and(|times| times.len() >= limit)
+    }
+
+    fn record(&mut self, key: K, now: Instant) {
+        self.failures.entry(key).or_default().push(now);
+    }
+
+    /// Forgets one failure, for an attempt that turned out to succeed.
+    fn forgive(&mut self, key: &K) {
+        if let Some(times) = self.failures.get_mut(key) {
+            times.pop();
+        }
+    }
+}
+
+/// Failed logins for each user name from each address, and from each address.
+/// Counting names only per address means nobody can lock a user out
+/// everywhere by failing to log in as them.
+struct Throttle {
+    remotes: Failures<IpAddr>,
+    names: Failures<(String, Option<IpAddr>)>,
+}
+
+impl Throttle {
+    /// Counts an attempt as failed until it is known to have succeeded, so
+    /// attempts made in parallel can't all slip in under the limit.
+    fn attempt(&mut self, name: &str, remote: Option<IpAddr>, now: Instant) -> Result<(), AuthError> {
+        let name = (name.to_string(), remote);
+        if remote.is_some_and(|remote| self.remotes.exceeded(&remote, MAX_REMOTE_FAILURES, now)) || self.names.exceeded(&name, MAX_FAILURES, now) {
+            return Err(AuthError::TooManyAttempts);
+        }
+        if let Some(remote) = remote {
+            self.remotes.record(remote, now);
+        }
+        self.names.record(name, now);
+        Ok(())
+    }
+
+    fn succeeded(&mut self, name: &str, remote: Option<IpAddr>) {
+        if let Some(remote) = remote {
+            self.remotes.forgive(&remote);
+        }
+        self.names.forgive(&(name.to_string(), remote));
+    }
+}
+
+fn random_string(bytes: usize) -> String {
+    let mut random = vec![0; bytes];
+    OsRng.fill_bytes(&mut random);
+    URL_SAFE_NO_PAD.encode(random)
+}
+
+/// Hashes a password as `pbkdf2-sha256$<rounds>$<salt>$<hash>`.
+fn hash_password(password: &str, rounds: u32) -> String {
+    let mut salt = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let mut hash = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, rounds, &mut hash);
+    format!("pbkdf2-sha256${}${}${}", rounds, STANDARD_NO_PAD.encode(salt), STANDARD_NO_PAD.encode(hash))
+}
+
+fn verify_password(password: &str, stored: &str) -> bool {
+    let parts = stored.split('$').collect::<Vec<_>>();
+    let (rounds, salt, hash) = match parts[..] {
+        ["pbkdf2-sha256", rounds, salt, hash] => match (rounds.parse(), STANDARD_NO_PAD.decode(salt), STANDARD_NO_PAD.decode(hash)) {
+            (Ok(rounds), Ok(salt), Ok(hash)) => (rounds, salt, hash),
+            _ => return false,
+        },
+        _ => return false,
+    };
+    let mut computed = vec![0; hash.len()];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, rounds, &mut computed);
+    computed.ct_eq(&hash).into()
+}
+
+pub struct Accounts {
+    storage: Arc<dyn Storage>,
+    rounds: u32,
+    /// The stored users, since every signalling message looks its sender up.
+    users: Mutex<Vec<User>>,
+    logins: Mutex<HashMap<String, Login>>,
+    throttle: Mutex<Throttle>,
+}
+
+impl Accounts {
+    /// Reads the users kept in `storage`, first importing those saved in
+    /// `legacy` by older versions if there are none. With no users at all, an
+    /// `admin` user is created with `admin_password`, or a random password
+    /// that is logged.
+    pub fn load(storage: Arc<dyn Storage>, legacy: &Path, admin_password: Option<&str>) -> anyhow::Result<Self> {
+        use anyhow::Context;
+        if storage.users()?.is_empty() {
+            let imported = match std::fs::read_to_string(legacy) {
+                Ok(text) => serde_json::from_str::<Vec<Account>>(&text).with_context(|| format!("Malformed users in {}", legacy.display()))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(err).with_context(|| format!("Failed reading users from {}", legacy.display())),
+            };
+            for account in &imported {
+                storage.add_user(&StoredUser { user: account.user.clone(), password: account.password.clone() })?;
+            }
+            if !imported.is_empty() {
+                log::info!("Imported {} users from {}", imported.len(), legacy.display());
+            }
+        }
+        let accounts = Accounts::new(storage, ROUNDS)?;
+        if accounts.users().is_empty() {
+            let password = admin_password.map(str::to_string).unwrap_or_else(|| random_string(12));
+            accounts.add(User { name: "admin".to_string(), role: Role::Admin, cameras: Vec::new() }, &password)?;
+            match admin_password {
+                Some(_) => log::info!("Created user admin"),
+                None => log::warn!("Created user admin with password {}", password),
+            }
+        }
+        Ok(accounts)
+    }
+
+    fn new(storage: Arc<dyn Storage>, rounds: u32) -> Result<Self, AuthError> {
+        let users = storage.users()?.into_iter().map(|stored| stored.user).collect();
+        let throttle = Throttle { remotes: Failures::new(), names: Failures::new() };
+        Ok(Accounts { storage, rounds, users: Mutex::new(users), logins: Mutex::new(HashMap::new()), throttle: Mutex::new(throttle) })
+    }
+
+    /// Cheap to hash for, with an admin, for tests elsewhere in the server.
+    #[cfg(test)]
+    pub(crate) fn for_tests(admin_password: &str) -> Self {
+        let accounts = Accounts::new(Arc::new(crate::storage::MemoryStorage::default()), 10).unwrap();
+        accounts.add(User { name: "admin".to_string(), role: Role::Admin, cameras: Vec::new() }, admin_password).unwrap();
+        accounts
+    }
+
+    pub fn users(&self) -> Vec<User> {
+        self.users.lock().unwrap().clone()
+    }
+
+    pub fn user(&self, name: &str) -> Option<User> {
+        self.users.lock().unwrap().iter().find(|user| user.name == name).cloned()
+    }
+
+    /// Exchanges a user's name and password for a token, returning it with
+    /// when it expires. Hashing the password takes a while, so this belongs
+    /// on a blocking thread. Too many failures from `remote`, or for `name`
+    /// from `remote`, refuse further attempts from there for a while.
+    pub fn login(&self, name: &str, password: &str, remote: Option<IpAddr>) -> Result<(String, SystemTime), AuthError> {
+        self.throttle.lock().unwrap().attempt(name, remote, Instant::now())?;
+        let account = self.storage.user(name)?;
+        // Unknown users cost a hash as well, so their names can't be told apart by timing.
+        let stored = match &account {
+            Some(account) => account.password.clone(),
+            None => format!("pbkdf2-sha256${}${}${}", self.rounds, "A".repeat(22), "A".repeat(43)),
+        };
+        if !verify_password(password, &stored) || account.is_none() {
+            return Err(AuthError::InvalidCredentials);
+        }
+        self.throttle.lock().unwrap().succeeded(name, remote);
+        let token = random_string(32);
+        let expires = SystemTime::now() + TOKEN_LIFETIME;
+        self.logins.lock().unwrap().insert(token.clone(), Login { user: name.to_string(), expires });
+        Ok((token, expires))
+    }
+
+    pub fn logout(&self, token: &str) {
+        self.logins.lock().unwrap().remove(token);
+    }
+
+    /// The user a token was given to, if it is still valid.
+    pub fn authenticate(&self, token: &str) -> Result<User, AuthError> {
+        let now = SystemTime::now();
+        let mut logins = self.logins.lock().unwrap();
+        logins.retain(|_, login| login.expires > now);
+        let name = logins.get(token).map(|login| login.user.clone()).ok_or(AuthError::InvalidToken)?;
+        drop(logins);
+        self.user(&name).ok_or(AuthError::InvalidToken)
+    }
+
+    pub fn add(&self, user: User, password: &str) -> Result<(), AuthError> {
+        if user.name.trim().is_empty() {
+            return Err(AuthError::EmptyName);
+        }
+        let password = hash_password(password, self.rounds);
+        let mut users = self.users.lock().unwrap();
+        self.storage.add_user(&StoredUser { user: user.clone(), password })?;
+        users.push(user);
+        Ok(())
+    }
+
+    /// Removes a user and ends their logins.
+    pub fn remove(&self, name: &str) -> Result<(), AuthError> {
+        let mut users = self.users.lock().unwrap();
+        let index = users.iter().position(|user| user.name == name).ok_or_else(|| AuthError::UnknownUser(name.to_string()))?;
+        if users[index].role == Role::Admin && users.iter().filter(|user| user.role == Role::Admin).count() == 1 {
+            return Err(AuthError::LastAdmin);
+        }
+        self.storage.remove_user(name)?;
+        users.remove(index);
+        drop(users);
+        self.logins.lock().unwrap().retain(|_, login| login.user != name);
+        Ok(())
+    }
+
+    /// Replaces the cameras a user may watch.
+    pub fn grant(&self, name: &str, cameras: Vec<String>) -> Result<User, AuthError> {
+        let mut users = self.users.lock().unwrap();
+        let user = users.iter_mut().find(|user| user.name == name).ok_or_else(|| AuthError::UnknownUser(name.to_string()))?;
+        self.storage.set_grants(name, &cameras)?;
+        *user = self.storage.user(name)?.ok_or_else(|| AuthError::UnknownUser(name.to_string()))?.user;
+        Ok(user.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn accounts() -> Accounts {
+        Accounts::for_tests("secret")
+    }
+
+    #[test]
+    fn logs_users_in_with_tokens() {
+        let accounts = accounts();
+        assert_eq!(accounts.login("admin", "wrong", None).unwrap_err(), AuthError::InvalidCredentials);
+        assert_eq!(accounts.login("nobody", "secret", None).unwrap_err(), AuthError::InvalidCredentials);
+
+        let (token, expires) = accounts.login("admin", "secret", None).unwrap();
+        assert!(expires > SystemTime::now());
+        assert_eq!(accounts.authenticate(&token).unwrap().role, Role::Admin);
+        assert_eq!(accounts.authenticate("made-up").unwrap_err(), AuthError::InvalidToken);
+        accounts.logout(&token);
+        assert_eq!(accounts.authenticate(&token).unwrap_err(), AuthError::InvalidToken);
+    }
+
+    #[test]
+    fn manages_users_and_grants() {
+        let storage = Arc::new(MemoryStorage::default());
+        let accounts = Accounts::new(storage.clone(), 10).unwrap();
+        accounts.add(User { name: "admin".to_string(), role: Role::Admin, cameras: Vec::new() }, "secret").unwrap();
+        let viewer = User { name: "sam".to_string(), role: Role::Viewer, cameras: vec!["Porch".to_string()] };
+        accounts.add(viewer.clone(), "hunter2").unwrap();
+        assert_eq!(accounts.add(viewer.clone(), "again").unwrap_err(), AuthError::UserExists("sam".to_string()));
+        assert!(viewer.may_watch("Porch"));
+        assert!(!viewer.may_watch("Food bowl"));
+
+        let (token, _) = accounts.login("sam", "hunter2", None).unwrap();
+        let granted = accounts.grant("sam", vec![ALL_CAMERAS.to_string()]).unwrap();
+        assert!(granted.may_watch("Food bowl"));
+        assert_eq!(accounts.authenticate(&token).unwrap(), granted);
+
+        // Stored users come back with their passwords.
+        let reloaded = Accounts::load(storage, Path::new("no-such-users.json"), None).unwrap();
+        assert_eq!(reloaded.users(), accounts.users());
+        assert!(reloaded.login("sam", "hunter2", None).is_ok());
+
+        assert_eq!(accounts.remove("admin").unwrap_err(), AuthError::LastAdmin);
+        accounts.remove("sam").unwrap();
+        assert_eq!(accounts.authenticate(&token).unwrap_err(), AuthError::InvalidToken);
+        assert_eq!(accounts.remove("sam").unwrap_err(), AuthError::UnknownUser("sam".to_string()));
+    }
+
+    #[test]
+    fn only_camera_users_stream() {
+        let grants = vec!["Porch".to_string()];
+        let camera = User { name: "porch".to_string(), role: Role::Camera, cameras: grants.clone() };
+        let viewer = User { name: "sam".to_string(), role: Role::Viewer, cameras: grants };
+        let admin = User { name: "admin".to_string(), role: Role::Admin, cameras: Vec::new() };
+        assert!(camera.may_stream("Porch") && !camera.may_stream("Yard") && !camera.may_watch("Porch"));
+        assert!(!viewer.may_stream("Porch") && viewer.may_watch("Porch"));
+        assert!(admin.may_stream("Yard"));
+    }
+
+    #[test]
+    fn imports_users_from_older_versions() {
+        let path = std::env::temp_dir().join(format!("rstream-users-{}.json", uuid::Uuid::new_v4()));
+        let sam = Account { user: User { name: "sam".to_string(), role: Role::Viewer, cameras: vec!["Porch".to_string()] }, password: hash_password("hunter2", 10) };
+        std::fs::write(&path, serde_json::to_string(&[&sam]).unwrap()).unwrap();
+
+        let storage = Arc::new(MemoryStorage::default());
+        let accounts = Accounts::load(storage.clone(), &path, Some("secret")).unwrap();
+        assert_eq!(accounts.users(), vec![sam.user]);
+        assert!(accounts.login("sam", "hunter2", None).is_ok());
+        // The file is only read while there are no users, so removed users stay removed.
+        accounts.add(User { name: "admin".to_string(), role: Role::Admin, cameras: Vec::new() }, "secret").unwrap();
+        accounts.remove("sam").unwrap();
+        assert_eq!(Accounts::load(storage, &path, None).unwrap().user("sam"), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn throttles_failed_logins() {
+        let accounts = accounts();
+        accounts.add(User { name: "sam".to_string(), role: Role::Viewer, cameras: Vec::new() }, "hunter2").unwrap();
+        let (guesser, elsewhere): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+
+        // Logging in doesn't count against anyone.
+        for _ in 0..MAX_FAILURES {
+            accounts.login("sam", "hunter2", Some(guesser)).unwrap();
+        }
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(accounts.login("admin", "guess", Some(guesser)).unwrap_err(), AuthError::InvalidCredentials);
+        }
+        // Not even the right password gets admin in from that address now,
+        // but admin isn't locked out elsewhere, and other names may still try.
+        assert_eq!(accounts.login("admin", "secret", Some(guesser)).unwrap_err(), AuthError::TooManyAttempts);
+        assert!(accounts.login("admin", "secret", Some(elsewhere)).is_ok());
+        assert!(accounts.login("sam", "hunter2", Some(guesser)).is_ok());
+
+        // Spreading guesses over many names only gets so far from one address.
+        for guess in 0..MAX_REMOTE_FAILURES - MAX_FAILURES {
+            assert_eq!(accounts.login(&format!("user{}", guess), "guess", Some(guesser)).unwrap_err(), AuthError::InvalidCredentials);
+        }
+        assert_eq!(accounts.login("sam", "hunter2", Some(guesser)).unwrap_err(), AuthError::TooManyAttempts);
+        assert!(accounts.login("sam", "hunter2", Some(elsewhere)).is_ok());
+    }
+
+    #[test]
+    fn forgets_failures_after_a_while() {
+        let mut failures = Failures::new();
+        let start = Instant::now();
+        for _ in 0..MAX_FAILURES {
+            failures.record("sam", start);
+        }
+        assert!(failures.exceeded(&"sam", MAX_FAILURES, start));
+        assert!(!failures.exceeded(&"alex", MAX_FAILURES, start));
+        assert!(!failures.exceeded(&"sam", MAX_FAILURES, start + FAILURE_WINDOW));
+        assert!(failures.failures.is_empty());
+    }
+}
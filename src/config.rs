@@ -0,0 +1,51 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::events;
+use crate::limits::Limits;
+use crate::queue;
+use crate::recorder;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Messages buffered per peer before the queue overflow policy applies.
+    pub queue_capacity: usize,
+    /// How often the server pings each WebSocket.
+    pub ping_interval: Duration,
+    /// How long a peer may go without sending anything (including pongs) before it is dropped.
+    pub ping_timeout: Duration,
+    pub limits: Limits,
+    /// Applied to every offer and answer before it is forwarded.
+    pub sdp_policy: common::sdp::Policy,
+    /// Forward camera streams through the server rather than connecting viewers to cameras directly.
+    pub sfu: bool,
+    /// Where and what to record, if recording is enabled.
+    pub recording: Option<recorder::Settings>,
+    /// Where events are sent.
+    pub events: events::Settings,
+    /// SQLite database users, events and the recording index are kept in.
+    pub database: PathBuf,
+    /// File older versions kept the REST API's users in, imported into the
+    /// database while it has none.
+    pub users: PathBuf,
+    /// File recording who watched which camera when, appended to.
+    pub audit_log: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            queue_capacity: queue::DEFAULT_CAPACITY,
+            ping_interval: Duration::from_secs(10),
+            ping_timeout: Duration::from_secs(30),
+            limits: Limits::default(),
+            sdp_policy: common::sdp::Policy::default(),
+            sfu: false,
+            recording: None,
+            events: events::Settings::default(),
+            database: PathBuf::from("./rstream.db"),
+            users: PathBuf::from("./users.json"),
+            audit_log: PathBuf::from("./audit.jsonl"),
+        }
+    }
+}
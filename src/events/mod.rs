@@ -0,0 +1,215 @@
+//! Server events, and the sinks that tell people about them.
+//!
+//! Parts of the server publish typed events to the bus without waiting on
+//! anything. Every sink has a subscription of its own and delivers the events
+//! it wants from a tokio 1 runtime on the bus's thread, one at a time, so a
+//! slow mail server holds up the email and nothing else. Events are also
+//! kept in the server's storage for the API to query.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tokio1::sync::broadcast::{self, error::RecvError};
+
+use common::{Event, EventKind};
+
+use crate::storage::{self, Storage};
+
+pub mod email;
+pub mod push;
+mod webhook;
+
+pub use email::Email;
+pub use push::Push;
+pub use webhook::Webhook;
+
+/// Events held for each sink before the slowest start missing them.
+const BUS_CAPACITY: usize = 1024;
+
+/// Somewhere events are delivered to.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Describes the sink in logs.
+    fn name(&self) -> String;
+
+    /// Whether the sink wants to hear about `event` at all.
+    fn accepts(&self, _event: &Event) -> bool {
+        true
+    }
+
+    async fn deliver(&self, event: &Event) -> Result<()>;
+}
+
+/// Whether an event is one of `kinds`, named as in JSON; an empty list takes everything.
+fn wanted(kinds: &[String], event: &Event) -> bool {
+    kinds.is_empty() || kinds.iter().any(|kind| kind == event.kind.name())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// URLs every event is posted to.
+    pub webhooks: Vec<String>,
+    pub email: Option<email::Settings>,
+    /// Web Push to subscribed browsers. Left out of [`Settings::sinks`], since
+    /// the server also needs the sink to take subscriptions.
+    pub push: Option<push::Settings>,
+}
+
+impl Settings {
+    /// The sinks these settings describe.
+    pub fn sinks(&self) -> Result<Vec<Box<dyn Sink>>> {
+        let mut sinks = Vec::<Box<dyn Sink>>::new();
+        for url in &self.webhooks {
+            sinks.push(Box::new(Webhook::new(url)?));
+        }
+        if let Some(email) = &self.email {
+            sinks.push(Box::new(Email::new(email)?));
+        }
+        Ok(sinks)
+    }
+}
+
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+    history: Arc<dyn Storage>,
+}
+
+impl EventBus {
+    /// A bus keeping every event it is given in `history`.
+    pub fn new(history: Arc<dyn Storage>) -> Self {
+        let (tx, _) = broadcast::channel(BUS_CAPACITY);
+        EventBus { tx, history }
+    }
+
+    /// Stamps `kind` with the current time and passes it to every sink.
+    pub fn publish(&self, kind: EventKind) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let event = Event { time, kind };
+        log::debug!("Event: {:?}", event);
+        if let Err(err) = self.history.add_event(&event) {
+            log::warn!("Failed storing {} event: {}", event.kind.name(), err);
+        }
+        // Nobody listening is fine.
+        let _ = self.tx.send(event);
+    }
+
+    /// Up to `limit` of the latest events after `since`, of the given type if
+    /// any, oldest first.
+    pub fn recent(&self, since: Option<u64>, kind: Option<&str>, limit: usize) -> storage::Result<Vec<Event>> {
+        self.history.events(since, kind, limit)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    /// Delivers events published from now on to `sinks`, on a thread of their own.
+    pub fn start(&self, sinks: Vec<Box<dyn Sink>>) -> Result<()> {
+        if sinks.is_empty() {
+            return Ok(());
+        }
+        let runtime = tokio1::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("events")
+            .build()
+            .context("Failed starting events runtime")?;
+        let subscriptions = sinks.into_iter().map(|sink| (Arc::<dyn Sink>::from(sink), self.subscribe())).collect::<Vec<_>>();
+        for (sink, _) in &subscriptions {
+            log::info!("Sending events to {}", sink.name());
+        }
+        std::thread::Builder::new()
+            .name("events".to_string())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let tasks = subscriptions.into_iter().map(|(sink, events)| tokio1::spawn(drain(sink, events))).collect::<Vec<_>>();
+                    for task in tasks {
+                        let _ = task.await;
+                    }
+                })
+            })
+            .context("Failed starting events thread")?;
+        Ok(())
+    }
+}
+
+async fn drain(sink: Arc<dyn Sink>, mut events: broadcast::Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                if sink.accepts(&event) {
+                    if let Err(err) = sink.deliver(&event).await {
+                        log::warn!("Failed sending {} event to {}: {:#}", event.kind.name(), sink.name(), err);
+                    }
+                }
+            }
+            Err(RecvError::Lagged(missed)) => log::warn!("{} fell behind and missed {} events", sink.name(), missed),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio1::sync::mpsc;
+    use uuid::Uuid;
+
+    use crate::storage::MemoryStorage;
+
+    struct Collect {
+        kinds: Vec<String>,
+        events: mpsc::UnboundedSender<Event>,
+    }
+
+    #[async_trait]
+    impl Sink for Collect {
+        fn name(&self) -> String {
+            "collector".to_string()
+        }
+
+        fn accepts(&self, event: &Event) -> bool {
+            wanted(&self.kinds, event)
+        }
+
+        async fn deliver(&self, event: &Event) -> Result<()> {
+            self.events.send(event.clone())?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn delivers_wanted_events_to_sinks() {
+        let bus = EventBus::new(Arc::new(MemoryStorage::default()));
+        let (all_tx, mut all) = mpsc::unbounded_channel();
+        let (offline_tx, mut offline) = mpsc::unbounded_channel();
+        bus.start(vec![
+            Box::new(Collect { kinds: Vec::new(), events: all_tx }),
+            Box::new(Collect { kinds: vec!["camera-offline".to_string()], events: offline_tx }),
+        ]).unwrap();
+
+        let camera = Uuid::new_v4();
+        bus.publish(EventKind::CameraOnline { camera, name: "Porch".to_string() });
+        bus.publish(EventKind::CameraOffline { camera, name: "Porch".to_string() });
+
+        let names = (0..2).map(|_| all.blocking_recv().unwrap().kind.name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["camera-online", "camera-offline"]);
+        assert_eq!(offline.blocking_recv().unwrap().kind, EventKind::CameraOffline { camera, name: "Porch".to_string() });
+    }
+
+    #[test]
+    fn keeps_recent_events() {
+        let bus = EventBus::new(Arc::new(MemoryStorage::default()));
+        let camera = Uuid::new_v4();
+        for _ in 0..3 {
+            bus.publish(EventKind::CameraOnline { camera, name: "Porch".to_string() });
+            bus.publish(EventKind::CameraOffline { camera, name: "Porch".to_string() });
+        }
+        let names = |events: Vec<Event>| events.into_iter().map(|event| event.kind.name()).collect::<Vec<_>>();
+        assert_eq!(names(bus.recent(None, None, 3).unwrap()), vec!["camera-offline", "camera-online", "camera-offline"]);
+        assert_eq!(names(bus.recent(None, Some("camera-online"), 10).unwrap()).len(), 3);
+        let last = bus.recent(None, None, 1).unwrap()[0].time;
+        assert!(bus.recent(Some(last), None, 10).unwrap().is_empty());
+    }
+}
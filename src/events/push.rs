@@ -0,0 +1,526 @@
+//! Web Push notifications to subscribed browsers.
+//!
+//! Messages are encrypted for each browser as RFC 8291 describes and sent to
+//! its push service with a VAPID signature (RFC 8292) proving they come from
+//! the server whose public key the browser subscribed with.
+//!
+//! Each subscription belongs to the user who made it, and only carries the
+//! events that user may see. Push services are only reached at public
+//! addresses, so a subscriber can't have the server post into its own network.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::Aes128Gcm;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hkdf::Hkdf;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use common::{Event, EventKind, PushSubscription};
+
+use super::{wanted, Sink};
+use crate::auth::{Accounts, Role, User};
+
+/// How long push services keep a message for a browser that is offline.
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// VAPID signatures are accepted for at most a day; this leaves room for clock skew.
+const SIGNATURE_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Record size written into the message header. Notifications fit in one record.
+const RECORD_SIZE: u32 = 4096;
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Holds the server's VAPID private key, created on first use.
+    pub key: PathBuf,
+    /// Holds the subscriptions, so they outlast restarts.
+    pub subscriptions: PathBuf,
+    /// Contact for push services to reach the operator, a `mailto:` or `https:` URL.
+    pub subject: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            key: PathBuf::from("./vapid.key"),
+            subscriptions: PathBuf::from("./push-subscriptions.json"),
+            subject: "mailto:admin@localhost".to_string(),
+        }
+    }
+}
+
+/// The server's VAPID key pair.
+struct Vapid {
+    key: SigningKey,
+    public_key: String,
+}
+
+impl Vapid {
+    /// Reads the private key from `path`, generating and saving one if there is none yet.
+    fn load_or_create(path: &Path) -> Result<Self> {
+        let secret = match std::fs::read_to_string(path) {
+            Ok(text) => {
+                let bytes = URL_SAFE_NO_PAD.decode(text.trim()).with_context(|| format!("{} is not a base64url VAPID key", path.display()))?;
+                SecretKey::from_be_bytes(&bytes).with_context(|| format!("{} is not a P-256 private key", path.display()))?
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let secret = SecretKey::random(&mut OsRng);
+                write_private(path, &URL_SAFE_NO_PAD.encode(secret.to_be_bytes()))
+                    .with_context(|| format!("Failed saving VAPID key to {}", path.display()))?;
+                log::info!("Generated a VAPID key in {}", path.display());
+                secret
+            }
+            Err(err) => return Err(err).with_context(|| format!("Failed reading VAPID key from {}", path.display())),
+        };
+        Ok(Vapid::new(secret))
+    }
+
+    fn new(secret: SecretKey) -> Self {
+        let public_key = URL_SAFE_NO_PAD.encode(secret.public_key().to_encoded_point(false).as_bytes());
+        Vapid { key: SigningKey::from(secret), public_key }
+    }
+
+    /// The `Authorization` header for a message to `endpoint`.
+    fn authorization(&self, endpoint: &reqwest::Url, subject: &str, now: SystemTime) -> String {
+        let audience = endpoint.origin().ascii_serialization();
+        let expires = (now + SIGNATURE_LIFETIME).duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let header = URL_SAFE_NO_PAD.encode(br#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::json!({ "aud": audience, "exp": expires, "sub": subject }).to_string());
+        let signed = format!("{}.{}", header, claims);
+        let signature: Signature = self.key.sign(signed.as_bytes());
+        format!("vapid t={}.{}, k={}", signed, URL_SAFE_NO_PAD.encode(signature.to_vec()), self.public_key)
+    }
+}
+
+/// Writes a file only its owner can read.
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+fn hkdf_expand(salt: &[u8], ikm: &[u8], info: &[u8], out: &mut [u8]) {
+    // Lengths are fixed and far below HKDF's limit.
+    Hkdf::<Sha256>::new(Some(salt), ikm).expand(info, out).expect("HKDF output too long");
+}
+
+/// Encrypts `payload` for a browser with the `aes128gcm` content coding,
+/// using the given ephemeral key and salt.
+fn encrypt(payload: &[u8], keys: &common::PushKeys, ephemeral: &SecretKey, salt: [u8; 16]) -> Result<Vec<u8>> {
+    let browser_bytes = URL_SAFE_NO_PAD.decode(keys.p256dh.trim_end_matches('=')).context("Malformed p256dh key")?;
+    let browser = PublicKey::from_sec1_bytes(&browser_bytes).context("p256dh is not a P-256 public key")?;
+    let auth = URL_SAFE_NO_PAD.decode(keys.auth.trim_end_matches('=')).context("Malformed auth secret")?;
+    let server = ephemeral.public_key().to_encoded_point(false);
+    let shared = p256::ecdh::diffie_hellman(ephemeral.to_nonzero_scalar(), browser.as_affine());
+
+    let key_info = [&b"WebPush: info\0"[..], &browser_bytes, server.as_bytes()].concat();
+    let mut ikm = [0; 32];
+    hkdf_expand(&auth, shared.raw_secret_bytes(), &key_info, &mut ikm);
+    let (mut cek, mut nonce) = ([0; 16], [0; 12]);
+    hkdf_expand(&salt, &ikm, b"Content-Encoding: aes128gcm\0", &mut cek);
+    hkdf_expand(&salt, &ikm, b"Content-Encoding: nonce\0", &mut nonce);
+
+    // A single record, ended by the last record delimiter.
+    let plaintext = [payload, &[2]].concat();
+    let cipher = Aes128Gcm::new_from_slice(&cek).expect("key is 16 bytes");
+    let ciphertext = cipher.encrypt(&nonce.into(), &plaintext[..]).map_err(|_| anyhow::anyhow!("Failed encrypting push message"))?;
+
+    let mut body = salt.to_vec();
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(server.as_bytes().len() as u8);
+    body.extend_from_slice(server.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+/// Whether `ip` is on the public internet, rather than this host or a private network.
+fn public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // 0.0.0.0/8 is "this network", 100.64.0.0/10 carrier-grade NAT.
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+                || ip.is_documentation() || ip.is_multicast() || first == 0 || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                // fc00::/7 is unique local, fe80::/10 link-local.
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Checks that `endpoint` is an https URL whose host only has public addresses.
+async fn check_endpoint(endpoint: &str) -> Result<reqwest::Url> {
+    let url = reqwest::Url::parse(endpoint).context("Malformed endpoint")?;
+    anyhow::ensure!(url.scheme() == "https", "Endpoint {} is not https", endpoint);
+    let host = url.host_str().context("Endpoint has no host")?;
+    let addresses = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio1::net::lookup_host((host, url.port_or_known_default().unwrap_or(443))).await
+            .with_context(|| format!("Failed resolving {}", host))?
+            .map(|address| address.ip())
+            .collect(),
+    };
+    anyhow::ensure!(!addresses.is_empty(), "{} has no addresses", host);
+    if let Some(ip) = addresses.iter().find(|&&ip| !public(ip)) {
+        anyhow::bail!("{} is at {}, which isn't a public address", host, ip);
+    }
+    Ok(url)
+}
+
+/// Resolves push services to their public addresses only, so a name that
+/// was public when checked can't point somewhere private by the time it's
+/// connected to.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addresses = tokio1::net::lookup_host((name.as_str(), 0)).await?
+                .filter(|address| public(address.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public addresses", name.as_str()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// A subscription as saved, with the user it belongs to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Subscription {
+    /// Saved before subscriptions had owners, for those that are dropped on loading.
+    #[serde(default)]
+    owner: String,
+    #[serde(flatten)]
+    subscription: PushSubscription,
+}
+
+/// Subscriptions by endpoint, saved to a file as they change.
+struct Subscriptions {
+    path: PathBuf,
+    subscriptions: Mutex<Vec<Subscription>>,
+}
+
+impl Subscriptions {
+    fn load(path: &Path) -> Result<Self> {
+        let mut subscriptions: Vec<Subscription> = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).with_context(|| format!("Malformed push subscriptions in {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err).with_context(|| format!("Failed reading push subscriptions from {}", path.display())),
+        };
+        let count = subscriptions.len();
+        subscriptions.retain(|subscription| !subscription.owner.is_empty());
+        if subscriptions.len() < count {
+            log::warn!("Dropping {} push subscriptions saved without a user", count - subscriptions.len());
+        }
+        Ok(Subscriptions { path: path.to_path_buf(), subscriptions: Mutex::new(subscriptions) })
+    }
+
+    fn all(&self) -> Vec<Subscription> {
+        self.subscriptions.lock().unwrap().clone()
+    }
+
+    /// Adds a subscription for `owner`, replacing their earlier one for the
+    /// same endpoint. Returns false, changing nothing, when the endpoint is
+    /// someone else's.
+    fn add(&self, owner: &str, subscription: PushSubscription) -> Result<bool> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if subscriptions.iter().any(|existing| existing.subscription.endpoint == subscription.endpoint && existing.owner != owner) {
+            return Ok(false);
+        }
+        subscriptions.retain(|existing| existing.subscription.endpoint != subscription.endpoint);
+        subscriptions.push(Subscription { owner: owner.to_string(), subscription });
+        self.save(&subscriptions)?;
+        Ok(true)
+    }
+
+    /// Removes the subscription for `endpoint`, if `owner` is given only when
+    /// it's theirs, returning whether there was one.
+    fn remove(&self, endpoint: &str, owner: Option<&str>) -> Result<bool> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let count = subscriptions.len();
+        subscriptions.retain(|existing| existing.subscription.endpoint != endpoint || owner.is_some_and(|owner| existing.owner != owner));
+        if subscriptions.len() == count {
+            return Ok(false);
+        }
+        self.save(&subscriptions)?;
+        Ok(true)
+    }
+
+    fn save(&self, subscriptions: &[Subscription]) -> Result<()> {
+        let json = serde_json::to_string_pretty(subscriptions)?;
+        std::fs::write(&self.path, json).with_context(|| format!("Failed saving push subscriptions to {}", self.path.display()))
+    }
+}
+
+/// Name of the camera an event is about, where it has one.
+fn camera_name(kind: &EventKind) -> Option<&str> {
+    match kind {
+        EventKind::CameraOnline { name, .. } | EventKind::CameraOffline { name, .. } | EventKind::Motion { name, .. } => Some(name),
+        EventKind::ViewerConnected { .. } | EventKind::AuthFailure { .. } => None,
+    }
+}
+
+/// Whether `subscription` asked to hear about `event`.
+fn subscribed(subscription: &PushSubscription, event: &Event) -> bool {
+    let camera = subscription.cameras.is_empty()
+        || camera_name(&event.kind).is_some_and(|name| subscription.cameras.iter().any(|camera| camera == name));
+    camera && wanted(&subscription.events, event)
+}
+
+/// Whether `user` may hear about `event`: events from a camera need a grant
+/// for it, and the rest, such as refused logins with their addresses, are for
+/// admins.
+fn visible(user: &User, event: &Event) -> bool {
+    match camera_name(&event.kind) {
+        Some(name) => user.may_watch(name),
+        None => user.role == Role::Admin,
+    }
+}
+
+/// Sends events to subscribed browsers. Clones share the key and subscriptions,
+/// so one can serve the subscription API while another is the bus's sink.
+#[derive(Clone)]
+pub struct Push {
+    vapid: Arc<Vapid>,
+    subject: String,
+    subscriptions: Arc<Subscriptions>,
+    /// Owners of subscriptions, for what they may see.
+    accounts: Arc<Accounts>,
+    client: reqwest::Client,
+}
+
+impl Push {
+    pub fn new(settings: &Settings, accounts: Arc<Accounts>) -> Result<Self> {
+        let vapid = Vapid::load_or_create(&settings.key)?;
+        let subscriptions = Subscriptions::load(&settings.subscriptions)?;
+        // Redirects could lead anywhere, and push services don't send them.
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Push { vapid: Arc::new(vapid), subject: settings.subject.clone(), subscriptions: Arc::new(subscriptions), accounts, client })
+    }
+
+    /// Uncompressed public key, base64url encoded, as browsers take it for `applicationServerKey`.
+    pub fn public_key(&self) -> &str {
+        &self.vapid.public_key
+    }
+
+    /// Starts sending to a browser for `owner`, or updates which events it
+    /// wants. Returns false when the browser is subscribed for someone else.
+    pub async fn subscribe(&self, owner: &User, subscription: PushSubscription) -> Result<bool> {
+        check_endpoint(&subscription.endpoint).await?;
+        self.subscriptions.add(&owner.name, subscription)
+    }
+
+    /// Stops sending to a browser `owner` subscribed, returning whether they had.
+    pub fn unsubscribe(&self, owner: &User, endpoint: &str) -> Result<bool> {
+        self.subscriptions.remove(endpoint, Some(&owner.name))
+    }
+
+    async fn send(&self, subscription: &PushSubscription, payload: &[u8], now: SystemTime) -> Result<()> {
+        let endpoint = check_endpoint(&subscription.endpoint).await?;
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let body = encrypt(payload, &subscription.keys, &SecretKey::random(&mut OsRng), salt)?;
+        let response = self.client.post(endpoint.clone())
+            .header("Authorization", self.vapid.authorization(&endpoint, &self.subject, now))
+            .header("TTL", TTL.as_secs().to_string())
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .body(body)
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            // The browser unsubscribed, or the subscription expired.
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE => {
+                log::info!("Dropping expired push subscription {}", subscription.endpoint);
+                self.subscriptions.remove(&subscription.endpoint, None)?;
+                Ok(())
+            }
+            status => anyhow::bail!("{} refused with {}", endpoint.origin().ascii_serialization(), status),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for Push {
+    fn name(&self) -> String {
+        "web push".to_string()
+    }
+
+    /// Motion ending is left out, like it is from email.
+    fn accepts(&self, event: &Event) -> bool {
+        !matches!(event.kind, EventKind::Motion { active: false, .. })
+    }
+
+    async fn deliver(&self, event: &Event) -> Result<()> {
+        let payload = serde_json::json!({ "title": "rstream", "body": event.kind.to_string(), "event": event }).to_string();
+        let now = SystemTime::now();
+        let mut failed = 0;
+        for Subscription { owner, subscription } in self.subscriptions.all() {
+            // Users removed since subscribing hear nothing more.
+            let allowed = self.accounts.user(&owner).is_some_and(|owner| visible(&owner, event));
+            if !allowed || !subscribed(&subscription, event) {
+                continue;
+            }
+            if let Err(err) = self.send(&subscription, payload.as_bytes(), now).await {
+                log::debug!("Push to {} failed: {:#}", subscription.endpoint, err);
+                failed += 1;
+            }
+        }
+        anyhow::ensure!(failed == 0, "{} push messages failed", failed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::{TryFrom, TryInto};
+    use p256::ecdsa::signature::Verifier;
+    use p256::ecdsa::VerifyingKey;
+
+    fn decode(text: &str) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(text).unwrap()
+    }
+
+    #[test]
+    fn encrypts_like_rfc_8291() {
+        // The example from RFC 8291 appendix A.
+        let server = SecretKey::from_be_bytes(&decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw")).unwrap();
+        let keys = common::PushKeys {
+            p256dh: "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4".to_string(),
+            auth: "BTBZMqHH6r4Tts7J_aSIgg".to_string(),
+        };
+        let salt = decode("DGv6ra1nlYgDCS1FRnbzlw").try_into().unwrap();
+        let body = encrypt(b"When I grow up, I want to be a watermelon", &keys, &server, salt).unwrap();
+        assert_eq!(URL_SAFE_NO_PAD.encode(body), "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN");
+    }
+
+    #[test]
+    fn signs_vapid_tokens() {
+        let vapid = Vapid::new(SecretKey::random(&mut OsRng));
+        let endpoint = reqwest::Url::parse("https://push.example.net/send/abc").unwrap();
+        let header = vapid.authorization(&endpoint, "mailto:me@example.com", UNIX_EPOCH);
+
+        let (token, key) = header.strip_prefix("vapid t=").unwrap().split_once(", k=").unwrap();
+        assert_eq!(key, vapid.public_key);
+        let (signed, signature) = token.rsplit_once('.').unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(&decode(signed.split_once('.').unwrap().1)).unwrap();
+        assert_eq!(claims["aud"], "https://push.example.net");
+        assert_eq!(claims["exp"], SIGNATURE_LIFETIME.as_secs());
+
+        let verifying = VerifyingKey::from_sec1_bytes(&decode(key)).unwrap();
+        let signature = Signature::try_from(&decode(signature)[..]).unwrap();
+        assert!(verifying.verify(signed.as_bytes(), &signature).is_ok());
+    }
+
+    #[test]
+    fn keeps_subscriptions_and_preferences() {
+        let dir = std::env::temp_dir().join(format!("rstream-push-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = common::PushKeys { p256dh: String::new(), auth: String::new() };
+        let subscription = |endpoint: &str, cameras: &[&str]| PushSubscription {
+            endpoint: endpoint.to_string(),
+            keys: keys.clone(),
+            cameras: cameras.iter().map(|camera| camera.to_string()).collect(),
+            events: vec!["motion".to_string()],
+        };
+
+        let subscriptions = Subscriptions::load(&dir.join("subscriptions.json")).unwrap();
+        assert!(subscriptions.add("sam", subscription("https://a", &["Food bowl"])).unwrap());
+        assert!(subscriptions.add("sam", subscription("https://b", &[])).unwrap());
+        assert!(subscriptions.add("sam", subscription("https://a", &["Porch"])).unwrap());
+        assert!(subscriptions.remove("https://b", Some("sam")).unwrap());
+        assert!(!subscriptions.remove("https://b", Some("sam")).unwrap());
+
+        let reloaded = Subscriptions::load(&dir.join("subscriptions.json")).unwrap().all();
+        assert_eq!(reloaded, vec![Subscription { owner: "sam".to_string(), subscription: subscription("https://a", &["Porch"]) }]);
+
+        let reloaded = &reloaded[0].subscription;
+        let motion = |name: &str| Event { time: 0, kind: EventKind::Motion { camera: uuid::Uuid::nil(), name: name.to_string(), active: true } };
+        assert!(subscribed(reloaded, &motion("Porch")));
+        assert!(!subscribed(reloaded, &motion("Food bowl")));
+        assert!(!subscribed(reloaded, &Event { time: 0, kind: EventKind::CameraOffline { camera: uuid::Uuid::nil(), name: "Porch".to_string() } }));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_subscriptions_to_their_owners() {
+        let path = std::env::temp_dir().join(format!("rstream-push-{}.json", uuid::Uuid::new_v4()));
+        let subscription = PushSubscription {
+            endpoint: "https://a".to_string(),
+            keys: common::PushKeys { p256dh: String::new(), auth: String::new() },
+            cameras: Vec::new(),
+            events: Vec::new(),
+        };
+        let subscriptions = Subscriptions::load(&path).unwrap();
+        assert!(subscriptions.add("sam", subscription.clone()).unwrap());
+        assert!(!subscriptions.add("alex", subscription).unwrap());
+        assert!(!subscriptions.remove("https://a", Some("alex")).unwrap());
+        assert_eq!(subscriptions.all()[0].owner, "sam");
+        // An expired subscription goes whoever it belongs to.
+        assert!(subscriptions.remove("https://a", None).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_shows_what_owners_may_see() {
+        let viewer = User { name: "sam".to_string(), role: Role::Viewer, cameras: vec!["Porch".to_string()] };
+        let admin = User { name: "admin".to_string(), role: Role::Admin, cameras: Vec::new() };
+        let motion = |name: &str| Event { time: 0, kind: EventKind::Motion { camera: uuid::Uuid::nil(), name: name.to_string(), active: true } };
+        let refused = Event { time: 0, kind: EventKind::AuthFailure { remote: Some("192.0.2.1".to_string()), reason: "wrong password".to_string() } };
+
+        assert!(visible(&viewer, &motion("Porch")));
+        assert!(!visible(&viewer, &motion("Food bowl")));
+        assert!(!visible(&viewer, &refused));
+        assert!(visible(&admin, &motion("Food bowl")));
+        assert!(visible(&admin, &refused));
+    }
+
+    #[tokio1::test(crate = "tokio1")]
+    async fn only_accepts_public_https_endpoints() {
+        assert!(check_endpoint("https://8.8.8.8/push/abc").await.is_ok());
+        assert!(check_endpoint("https://[2001:4860:4860::8888]/push").await.is_ok());
+        for endpoint in [
+            "http://8.8.8.8/push",
+            "https://127.0.0.1/push",
+            "https://localhost/push",
+            "https://10.1.2.3/push",
+            "https://169.254.169.254/latest/meta-data",
+            "https://100.64.0.1/push",
+            "https://[::1]/push",
+            "https://[fd00::1]/push",
+            "https://[::ffff:192.168.1.1]/push",
+            "not a url",
+        ] {
+            assert!(check_endpoint(endpoint).await.is_err(), "{} was accepted", endpoint);
+        }
+    }
+}
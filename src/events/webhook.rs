@@ -0,0 +1,122 @@
+//! Posts events as JSON to an HTTP endpoint.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+
+use common::Event;
+
+use super::Sink;
+
+/// Tries given to each event before it is dropped.
+const ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for each one after.
+const BACKOFF: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Webhook {
+    url: reqwest::Url,
+    client: reqwest::Client,
+    backoff: Duration,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Self> {
+        let url = reqwest::Url::parse(url).with_context(|| format!("Invalid webhook URL {:?}", url))?;
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        Ok(Webhook { url, client, backoff: BACKOFF })
+    }
+}
+
+#[async_trait]
+impl Sink for Webhook {
+    fn name(&self) -> String {
+        format!("webhook {}", self.url)
+    }
+
+    /// Retries server errors, rate limiting and failed connections with
+    /// exponential backoff. Other refusals would only be refused again.
+    async fn deliver(&self, event: &Event) -> Result<()> {
+        let mut backoff = self.backoff;
+        for attempt in 1..=ATTEMPTS {
+            let error = match self.client.post(self.url.clone()).json(event).send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) if response.status().is_client_error() && response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    anyhow::bail!("refused with {}", response.status());
+                }
+                Ok(response) => anyhow::anyhow!("failed with {}", response.status()),
+                Err(err) => err.into(),
+            };
+            if attempt == ATTEMPTS {
+                return Err(error.context(format!("giving up after {} attempts", ATTEMPTS)));
+            }
+            log::debug!("Webhook {} {:#}, retrying in {:?}", self.url, error, backoff);
+            tokio1::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio1::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio1::net::TcpListener;
+    use tokio1::sync::mpsc;
+
+    /// Answers each request with the next of `statuses`, sending on the request bodies.
+    async fn stand_in(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (bodies, rx) = mpsc::unbounded_channel();
+        tokio1::spawn(async move {
+            for status in statuses {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut socket = BufReader::new(socket);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    socket.read_line(&mut line).await.unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                socket.read_exact(&mut body).await.unwrap();
+                bodies.send(String::from_utf8(body).unwrap()).unwrap();
+                let response = format!("HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                socket.get_mut().write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn event() -> Event {
+        Event { time: 1000, kind: common::EventKind::CameraOffline { camera: uuid::Uuid::nil(), name: "Porch".to_string() } }
+    }
+
+    #[tokio1::test(crate = "tokio1")]
+    async fn retries_until_delivered() {
+        let (url, mut bodies) = stand_in(vec![503, 429, 200]).await;
+        let webhook = Webhook { backoff: Duration::from_millis(1), ..Webhook::new(&url).unwrap() };
+        webhook.deliver(&event()).await.unwrap();
+        for _ in 0..3 {
+            let body = bodies.recv().await.unwrap();
+            assert_eq!(serde_json::from_str::<Event>(&body).unwrap(), event());
+        }
+    }
+
+    #[tokio1::test(crate = "tokio1")]
+    async fn gives_up_when_refused() {
+        let (url, mut bodies) = stand_in(vec![400, 200]).await;
+        let webhook = Webhook { backoff: Duration::from_millis(1), ..Webhook::new(&url).unwrap() };
+        assert!(webhook.deliver(&event()).await.is_err());
+        assert!(bodies.recv().await.is_some());
+        assert!(bodies.try_recv().is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use uuid::Uuid;
+
+pub mod api;
+pub mod audit;
+pub mod auth;
+pub mod config;
+pub mod events;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod queue;
+pub mod recorder;
+pub mod registry;
+pub mod rtc;
+pub mod sfu;
+pub mod snapshot;
+pub mod storage;
+pub mod tls;
+
+#[derive(Debug)]
+pub struct PeerMsg {
+    pub signal: common::Signal,
+    pub sender: Uuid,
+    pub recipient: Uuid
+}
+
+impl PeerMsg {
+    pub fn is_ice_candidate(&self) -> bool {
+        matches!(self.signal, common::Signal::NewIceCandidate { .. })
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Sustained signalling messages per second allowed on one connection.
+    pub messages_per_sec: f64,
+    /// Messages a connection may send in a burst before being throttled.
+    pub burst: u32,
+    /// Largest WebSocket frame/message the server will read, in bytes.
+    pub max_frame_size: usize,
+    /// Largest SDP accepted in an offer or answer, in bytes.
+    pub max_sdp_size: usize,
+    /// ICE candidates a peer may send to one recipient per negotiation.
+    pub max_ice_candidates: usize,
+    /// Concurrent WebSocket connections allowed from a single IP address.
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            messages_per_sec: 20.0,
+            burst: 50,
+            max_frame_size: 64 * 1024,
+            max_sdp_size: 32 * 1024,
+            max_ice_candidates: 64,
+            max_connections_per_ip: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LimitError {
+    #[error("rate limit exceeded")]
+    RateLimited,
+    #[error("SDP of {size} bytes exceeds the limit of {max} bytes")]
+    SdpTooLarge { size: usize, max: usize },
+    #[error("more than {max} ICE candidates sent to {recipient}")]
+    TooManyCandidates { recipient: Uuid, max: usize },
+    #[error("too many connections from {0}")]
+    TooManyConnections(IpAddr),
+}
+
+impl LimitError {
+    pub fn code(&self) -> common::ErrorCode {
+        match self {
+            LimitError::RateLimited => common::ErrorCode::RateLimited,
+            LimitError::SdpTooLarge { .. } => common::ErrorCode::SdpTooLarge,
+            LimitError::TooManyCandidates { .. } => common::ErrorCode::TooManyCandidates,
+            LimitError::TooManyConnections(_) => common::ErrorCode::TooManyConnections,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refill_per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Limits enforced on messages read from a single connection.
+#[derive(Debug)]
+pub struct ConnectionLimits {
+    limits: Limits,
+    bucket: TokenBucket,
+    candidates: HashMap<Uuid, usize>,
+}
+
+impl ConnectionLimits {
+    pub fn new(limits: &Limits) -> Self {
+        ConnectionLimits {
+            limits: limits.clone(),
+            bucket: TokenBucket::new(limits.burst, limits.messages_per_sec),
+            candidates: HashMap::new(),
+        }
+    }
+
+    pub fn check_rate(&mut self) -> Result<(), LimitError> {
+        if self.bucket.try_take() {
+            Ok(())
+        } else {
+            Err(LimitError::RateLimited)
+        }
+    }
+
+    pub fn check_signal(&mut self, recipient: Uuid, signal: &common::Signal) -> Result<(), LimitError> {
+        match signal {
+            common::Signal::Offer { sdp } | common::Signal::Answer { sdp } => {
+                if sdp.len() > self.limits.max_sdp_size {
+                    return Err(LimitError::SdpTooLarge { size: sdp.len(), max: self.limits.max_sdp_size });
+                }
+                // A new negotiation starts a fresh round of candidates.
+                self.candidates.remove(&recipient);
+            }
+            common::Signal::NewIceCandidate { .. } => {
+                let count = self.candidates.entry(recipient).or_insert(0);
+                if *count >= self.limits.max_ice_candidates {
+                    return Err(LimitError::TooManyCandidates { recipient, max: self.limits.max_ice_candidates });
+                }
+                *count += 1;
+            }
+            common::Signal::Hangup => {
+                self.candidates.remove(&recipient);
+            }
+            common::Signal::Watch { .. } | common::Signal::Constrain { .. } | common::Signal::Motion { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Counts open connections per remote IP address.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionTracker {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionTracker {
+    pub fn acquire(&self, ip: IpAddr, max: usize) -> Result<ConnectionPermit, LimitError> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= max {
+            return Err(LimitError::TooManyConnections(ip));
+        }
+        *count += 1;
+        Ok(ConnectionPermit { ip, counts: self.counts.clone() })
+    }
+}
+
+/// Held for the lifetime of a connection; releases its slot when dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket_refills() {
+        let mut bucket = TokenBucket::new(2, 1.0);
+        let start = bucket.last_refill;
+
+        assert!(bucket.try_take_at(start));
+        assert!(bucket.try_take_at(start));
+        assert!(!bucket.try_take_at(start));
+        assert!(!bucket.try_take_at(start + Duration::from_millis(500)));
+        assert!(bucket.try_take_at(start + Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn caps_candidates_per_negotiation() {
+        let limits = Limits { max_ice_candidates: 1, ..Limits::default() };
+        let mut conn = ConnectionLimits::new(&limits);
+        let recipient = Uuid::new_v4();
+        let candidate = common::Signal::NewIceCandidate {
+            candidate: common::IceCandidate { candidate: String::new(), sdp_mid: None, sdp_m_line_index: None },
+        };
+
+        assert_eq!(conn.check_signal(recipient, &candidate), Ok(()));
+        assert_eq!(conn.check_signal(recipient, &candidate), Err(LimitError::TooManyCandidates { recipient, max: 1 }));
+        conn.check_signal(recipient, &common::Signal::Offer { sdp: String::new() }).unwrap();
+        assert_eq!(conn.check_signal(recipient, &candidate), Ok(()));
+    }
+
+    #[test]
+    fn releases_connection_slots() {
+        let tracker = ConnectionTracker::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let permit = tracker.acquire(ip, 1).unwrap();
+        assert_eq!(tracker.acquire(ip, 1).unwrap_err(), LimitError::TooManyConnections(ip));
+        drop(permit);
+        assert!(tracker.acquire(ip, 1).is_ok());
+    }
+}
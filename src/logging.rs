@@ -0,0 +1,282 @@
+//! Log output for the server, from `log` records and `tracing` events alike.
+//!
+//! Each line names the spans it was logged in along with their fields, so a
+//! message from a client's handler says which peer, user and address it
+//! concerns. Lines are filtered with `RUST_LOG` as env_logger filtered them.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+thread_local! {
+    /// Spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+struct SpanData {
+    parent: Option<u64>,
+    name: &'static str,
+    /// Formatted as ` name=value` each.
+    fields: String,
+    refs: usize,
+}
+
+/// Formats fields as ` name=value`, keeping an event's message apart.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+pub struct Logger {
+    filter: env_filter::Filter,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    next_id: AtomicU64,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+fn log_level(level: &tracing::Level) -> log::Level {
+    match *level {
+        tracing::Level::ERROR => log::Level::Error,
+        tracing::Level::WARN => log::Level::Warn,
+        tracing::Level::INFO => log::Level::Info,
+        tracing::Level::DEBUG => log::Level::Debug,
+        tracing::Level::TRACE => log::Level::Trace,
+    }
+}
+
+impl Logger {
+    pub fn new(filter: env_filter::Filter, output: Box<dyn Write + Send>) -> Self {
+        Logger { filter, spans: Mutex::new(HashMap::new()), next_id: AtomicU64::new(1), output: Mutex::new(output) }
+    }
+
+    fn enabled_for(&self, level: log::Level, target: &str) -> bool {
+        self.filter.enabled(&log::Metadata::builder().level(level).target(target).build())
+    }
+
+    fn current() -> Option<u64> {
+        ENTERED.with(|entered| entered.borrow().last().copied())
+    }
+
+    /// Names and fields of `span` and the spans it is in, outermost first.
+    fn context(&self, span: Option<u64>) -> String {
+        let spans = self.spans.lock().unwrap();
+        let mut chain = Vec::new();
+        let mut next = span;
+        while let Some(data) = next.and_then(|id| spans.get(&id)) {
+            chain.push(format!("{}{{{}}}", data.name, data.fields.trim_start()));
+            next = data.parent;
+        }
+        chain.reverse();
+        chain.join(":")
+    }
+
+    fn write(&self, level: log::Level, target: &str, span: Option<u64>, message: fmt::Arguments) {
+        let context = self.context(span);
+        let separator = if context.is_empty() { "" } else { " " };
+        let mut output = self.output.lock().unwrap();
+        let _ = writeln!(output, "[{:.0} {:<5} {}{}{}] {}", jiff::Timestamp::now(), level, target, separator, context, message);
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.filter.matches(record) {
+            self.write(record.level(), record.target(), Logger::current(), *record.args());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.output.lock().unwrap().flush();
+    }
+}
+
+/// The logger as a `tracing` subscriber.
+pub struct Tracing(pub &'static Logger);
+
+impl Subscriber for Tracing {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // Spans are kept whatever their level, for the context they give.
+        metadata.is_span() || self.0.enabled_for(log_level(metadata.level()), metadata.target())
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = Fields::default();
+        span.record(&mut fields);
+        let parent = if span.is_contextual() { Logger::current() } else { span.parent().map(Id::into_u64) };
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        let data = SpanData { parent, name: span.metadata().name(), fields: fields.fields, refs: 1 };
+        self.0.spans.lock().unwrap().insert(id, data);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        if let Some(data) = self.0.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.fields.push_str(&fields.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let span = if event.is_contextual() { Logger::current() } else { event.parent().map(Id::into_u64) };
+        let metadata = event.metadata();
+        self.0.write(log_level(metadata.level()), metadata.target(), span, format_args!("{}{}", fields.message, fields.fields));
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self.0.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.0.spans.lock().unwrap();
+        match spans.get_mut(&span.into_u64()) {
+            Some(data) if data.refs > 1 => {
+                data.refs -= 1;
+                false
+            }
+            Some(_) => {
+                spans.remove(&span.into_u64());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Logs to stderr, filtered by `RUST_LOG`, for both `log` and `tracing`.
+pub fn init() -> Result<()> {
+    let filter = env_filter::Builder::from_env("RUST_LOG").build();
+    let max_level = filter.filter();
+    let logger: &'static Logger = Box::leak(Box::new(Logger::new(filter, Box::new(io::stderr()))));
+    log::set_logger(logger).context("A logger is already set")?;
+    log::set_max_level(max_level);
+    tracing::subscriber::set_global_default(Tracing(logger)).context("A tracing subscriber is already set")?;
+    Ok(())
+}
+
+/// A signal as logged at info level and above: offers, answers and ICE
+/// candidates are summarised, since they give away the peers' addresses.
+/// Debug logging shows them in full.
+pub struct Redacted<'a>(pub &'a common::Signal);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            common::Signal::Offer { sdp } => write!(f, "offer ({} bytes of SDP)", sdp.len()),
+            common::Signal::Answer { sdp } => write!(f, "answer ({} bytes of SDP)", sdp.len()),
+            common::Signal::NewIceCandidate { candidate } => write!(f, "ICE candidate for {}", candidate.sdp_mid.as_deref().unwrap_or("?")),
+            common::Signal::Watch { stream } => write!(f, "watch {}", stream),
+            common::Signal::Constrain { max_width, max_height } => write!(f, "constrain to {}x{}", max_width, max_height),
+            common::Signal::Motion { active } => write!(f, "motion {}", if *active { "started" } else { "stopped" }),
+            common::Signal::Hangup => write!(f, "hangup"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Output shared with the test, which reads what was logged.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lines_carry_span_fields() {
+        let captured = Captured::default();
+        let filter = env_filter::Builder::new().parse("info").build();
+        let logger: &'static Logger = Box::leak(Box::new(Logger::new(filter, Box::new(captured.clone()))));
+        tracing::subscriber::with_default(Tracing(logger), || {
+            let peer = tracing::info_span!("peer", peer = 7, user = "alice");
+            let _peer = peer.enter();
+            tracing::info!(recipient = 9, "Forwarding");
+            tracing::debug!("Not shown");
+            {
+                let _session = tracing::info_span!("session", session = 3).entered();
+                log::Log::log(logger, &log::Record::builder().level(log::Level::Warn).target("rstream").args(format_args!("From log")).build());
+            }
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2, "{}", output);
+        assert!(lines[0].ends_with("peer{peer=7 user=alice}] Forwarding recipient=9"), "{}", lines[0]);
+        assert!(lines[1].ends_with("rstream peer{peer=7 user=alice}:session{session=3}] From log"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn redacts_sdp_and_candidates() {
+        let offer = common::Signal::Offer { sdp: "v=0\r\nc=IN IP4 192.0.2.1\r\n".to_string() };
+        assert_eq!(Redacted(&offer).to_string(), "offer (25 bytes of SDP)");
+        let candidate = common::Signal::NewIceCandidate { candidate: common::IceCandidate {
+            candidate: "candidate:1 1 udp 2122260223 192.0.2.1 54400 typ host".to_string(),
+            sdp_mid: Some("0".to_string()),
+            sdp_m_line_index: Some(0),
+        } };
+        assert_eq!(Redacted(&candidate).to_string(), "ICE candidate for 0");
+    }
+}
@@ -0,0 +1,274 @@
+//! Server metrics, served at `/metrics` in the Prometheus text format.
+//!
+//! Counters are kept here as the server works; gauges of connected peers and
+//! queues are read off the registry when scraped.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::registry::Registry;
+
+/// Upper bounds of the session setup latency buckets, in seconds.
+const SETUP_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// A figure from a viewer's connection statistics, if it has one.
+type StatsField = fn(&common::ConnectionStats) -> Option<f64>;
+
+/// Gauges averaging what viewers report about their connections.
+const VIEWER_GAUGES: [(&str, &str, StatsField); 5] = [
+    ("rstream_viewer_bitrate_bits_per_second", "Mean video bitrate viewers receive.", |stats| Some(stats.bitrate)),
+    ("rstream_viewer_frame_rate", "Mean frames per second viewers decode.", |stats| Some(stats.frame_rate)),
+    ("rstream_viewer_packet_loss_ratio", "Mean fraction of video packets viewers lose.", |stats| Some(stats.packet_loss)),
+    ("rstream_viewer_jitter_seconds", "Mean jitter of the video viewers receive.", |stats| Some(stats.jitter)),
+    ("rstream_viewer_rtt_seconds", "Mean round trip time between viewers and their peers.", |stats| stats.rtt),
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations at or below each of [`SETUP_BUCKETS`], not cumulative.
+    buckets: [u64; SETUP_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = SETUP_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Messages from clients by type.
+    messages: Mutex<BTreeMap<&'static str, u64>>,
+    /// Client messages the server couldn't act on, by error code.
+    errors: Mutex<BTreeMap<String, u64>>,
+    tls_handshake_failures: AtomicU64,
+    /// Offers not answered yet, by viewer and camera.
+    offers: Mutex<HashMap<(Uuid, Uuid), Instant>>,
+    session_setup: Mutex<Histogram>,
+    /// Latest statistics viewers reported, by viewer and peer.
+    connections: Mutex<HashMap<(Uuid, Uuid), common::ConnectionStats>>,
+}
+
+/// Name of a client message's type, as it appears in JSON.
+fn message_type(msg: &common::ServerMsg) -> &'static str {
+    match msg {
+        common::ServerMsg::ListPeers => "list-peers",
+        common::ServerMsg::Register { .. } => "register",
+        common::ServerMsg::ListCameras => "list-cameras",
+        common::ServerMsg::Motion { .. } => "motion",
+        common::ServerMsg::Snapshot { .. } => "snapshot",
+        common::ServerMsg::Stats { .. } => "stats",
+        common::ServerMsg::Signal { signal, .. } => match signal {
+            common::Signal::Offer { .. } => "offer",
+            common::Signal::Answer { .. } => "answer",
+            common::Signal::NewIceCandidate { .. } => "new-ice-candidate",
+            common::Signal::Hangup => "hangup",
+            common::Signal::Watch { .. } => "watch",
+            common::Signal::Constrain { .. } => "constrain",
+            common::Signal::Motion { .. } => "motion-signal",
+        },
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn count_message(&self, msg: &common::ServerMsg) {
+        *self.messages.lock().unwrap().entry(message_type(msg)).or_default() += 1;
+    }
+
+    /// Counts a refused client message, under its error code or `other`.
+    pub fn count_error(&self, code: Option<common::ErrorCode>) {
+        let code = code
+            .and_then(|code| serde_json::to_value(code).ok())
+            .and_then(|code| code.as_str().map(str::to_string))
+            .unwrap_or_else(|| "other".to_string());
+        *self.errors.lock().unwrap().entry(code).or_default() += 1;
+    }
+
+    pub fn count_tls_handshake_failure(&self) {
+        self.tls_handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Starts timing a session when a viewer offers to a camera.
+    pub fn offered(&self, viewer: Uuid, camera: Uuid) {
+        self.offers.lock().unwrap().insert((viewer, camera), Instant::now());
+    }
+
+    /// Stops timing a session when the answer reaches the viewer.
+    pub fn answered(&self, viewer: Uuid, camera: Uuid) {
+        let offered = self.offers.lock().unwrap().remove(&(viewer, camera));
+        if let Some(offered) = offered {
+            self.observe_setup(offered.elapsed());
+        }
+    }
+
+    fn observe_setup(&self, latency: Duration) {
+        self.session_setup.lock().unwrap().observe(latency.as_secs_f64());
+    }
+
+    /// Keeps the latest statistics of a viewer's connection to a peer.
+    pub fn report_connection(&self, viewer: Uuid, peer: Uuid, stats: common::ConnectionStats) {
+        self.connections.lock().unwrap().insert((viewer, peer), stats);
+    }
+
+    /// Bits per second of video on a peer's connections, as their viewers
+    /// last reported it, or `None` if none of them has reported yet.
+    pub fn video_bitrate(&self, peer: &Uuid) -> Option<f64> {
+        let connections = self.connections.lock().unwrap();
+        let rates = connections.iter()
+            .filter(|((viewer, partner), _)| viewer == peer || partner == peer)
+            .map(|(_, stats)| stats.bitrate)
+            .collect::<Vec<_>>();
+        if rates.is_empty() { None } else { Some(rates.iter().sum()) }
+    }
+
+    /// Forgets offers and connection statistics involving a peer once it has gone.
+    pub fn forget(&self, peer: &Uuid) {
+        self.offers.lock().unwrap().retain(|(viewer, camera), _| viewer != peer && camera != peer);
+        self.connections.lock().unwrap().retain(|(viewer, partner), _| viewer != peer && partner != peer);
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn render(&self, peers: &Registry) -> String {
+        let mut out = String::new();
+        let peers = peers.peers();
+
+        let cameras = peers.iter().filter(|peer| peer.camera().is_some()).count();
+        out.push_str("# HELP rstream_peers Connected peers by role.\n# TYPE rstream_peers gauge\n");
+        let _ = writeln!(out, "rstream_peers{{role=\"camera\"}} {}", cameras);
+        let _ = writeln!(out, "rstream_peers{{role=\"viewer\"}} {}", peers.len() - cameras);
+
+        let depths = peers.iter().map(|peer| peer.queue.stats().depth).collect::<Vec<_>>();
+        out.push_str("# HELP rstream_queued_messages Messages waiting in every peer's outbound queue.\n# TYPE rstream_queued_messages gauge\n");
+        let _ = writeln!(out, "rstream_queued_messages {}", depths.iter().sum::<usize>());
+        out.push_str("# HELP rstream_queue_depth_max Messages waiting in the fullest outbound queue.\n# TYPE rstream_queue_depth_max gauge\n");
+        let _ = writeln!(out, "rstream_queue_depth_max {}", depths.iter().max().copied().unwrap_or(0));
+
+        out.push_str("# HELP rstream_messages_total Messages received from clients by type.\n# TYPE rstream_messages_total counter\n");
+        for (kind, count) in self.messages.lock().unwrap().iter() {
+            let _ = writeln!(out, "rstream_messages_total{{type=\"{}\"}} {}", kind, count);
+        }
+
+        out.push_str("# HELP rstream_signalling_errors_total Client messages refused, by error code.\n# TYPE rstream_signalling_errors_total counter\n");
+        for (code, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "rstream_signalling_errors_total{{code=\"{}\"}} {}", code, count);
+        }
+
+        out.push_str("# HELP rstream_tls_handshake_failures_total Connections dropped during the TLS handshake.\n# TYPE rstream_tls_handshake_failures_total counter\n");
+        let _ = writeln!(out, "rstream_tls_handshake_failures_total {}", self.tls_handshake_failures.load(Ordering::Relaxed));
+
+        out.push_str("# HELP rstream_session_setup_seconds Time from a viewer's offer to the answer reaching it.\n# TYPE rstream_session_setup_seconds histogram\n");
+        let setup = self.session_setup.lock().unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in SETUP_BUCKETS.iter().zip(setup.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(out, "rstream_session_setup_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+        }
+        let _ = writeln!(out, "rstream_session_setup_seconds_bucket{{le=\"+Inf\"}} {}", setup.count);
+        let _ = writeln!(out, "rstream_session_setup_seconds_sum {}", setup.sum);
+        let _ = writeln!(out, "rstream_session_setup_seconds_count {}", setup.count);
+
+        let connections = self.connections.lock().unwrap();
+        out.push_str("# HELP rstream_viewer_connections Viewer connections reporting their statistics.\n# TYPE rstream_viewer_connections gauge\n");
+        let _ = writeln!(out, "rstream_viewer_connections {}", connections.len());
+        for (name, help, value) in VIEWER_GAUGES.iter() {
+            let _ = write!(out, "# HELP {} {}\n# TYPE {} gauge\n", name, help, name);
+            let values = connections.values().filter_map(value).collect::<Vec<_>>();
+            if !values.is_empty() {
+                let _ = writeln!(out, "{} {}", name, values.iter().sum::<f64>() / values.len() as f64);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::registry::Peer;
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let registry = Registry::new();
+        let camera = Arc::new(Peer::new(Uuid::new_v4(), 4));
+        camera.set_camera(common::CameraDescriptor { name: "Porch".to_string(), streams: Vec::new() });
+        registry.register(camera.clone()).unwrap();
+        registry.register(Arc::new(Peer::new(Uuid::new_v4(), 4))).unwrap();
+
+        let metrics = Metrics::new();
+        metrics.count_message(&common::ServerMsg::ListPeers);
+        metrics.count_message(&common::ServerMsg::ListPeers);
+        metrics.count_error(Some(common::ErrorCode::RateLimited));
+        metrics.count_error(None);
+        metrics.observe_setup(Duration::from_millis(300));
+        metrics.observe_setup(Duration::from_secs(60));
+
+        let text = metrics.render(&registry);
+        for line in [
+            "rstream_peers{role=\"camera\"} 1",
+            "rstream_peers{role=\"viewer\"} 1",
+            "rstream_messages_total{type=\"list-peers\"} 2",
+            "rstream_signalling_errors_total{code=\"rate-limited\"} 1",
+            "rstream_signalling_errors_total{code=\"other\"} 1",
+            "rstream_session_setup_seconds_bucket{le=\"0.25\"} 0",
+            "rstream_session_setup_seconds_bucket{le=\"0.5\"} 1",
+            "rstream_session_setup_seconds_bucket{le=\"30\"} 1",
+            "rstream_session_setup_seconds_bucket{le=\"+Inf\"} 2",
+        ] {
+            assert!(text.lines().any(|rendered| rendered == line), "{} missing from\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn times_answered_offers_only() {
+        let metrics = Metrics::new();
+        let (viewer, camera, gone) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        metrics.offered(viewer, camera);
+        metrics.offered(viewer, gone);
+        metrics.forget(&gone);
+        metrics.answered(viewer, gone);
+        metrics.answered(viewer, camera);
+        metrics.answered(viewer, camera);
+        assert_eq!(metrics.session_setup.lock().unwrap().count, 1);
+    }
+
+    #[test]
+    fn averages_reported_connections() {
+        let metrics = Metrics::new();
+        let (viewer, camera, other) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let stats = common::ConnectionStats { bitrate: 1_000_000.0, frame_rate: 30.0, packet_loss: 0.0, jitter: 0.01, rtt: Some(0.05) };
+        metrics.report_connection(viewer, camera, stats);
+        metrics.report_connection(viewer, other, common::ConnectionStats { bitrate: 3_000_000.0, rtt: None, ..stats });
+
+        let text = metrics.render(&Registry::new());
+        for line in ["rstream_viewer_connections 2", "rstream_viewer_bitrate_bits_per_second 2000000", "rstream_viewer_rtt_seconds 0.05"] {
+            assert!(text.lines().any(|rendered| rendered == line), "{} missing from\n{}", line, text);
+        }
+
+        assert_eq!(metrics.video_bitrate(&viewer), Some(4_000_000.0));
+        assert_eq!(metrics.video_bitrate(&camera), Some(1_000_000.0));
+        assert_eq!(metrics.video_bitrate(&Uuid::new_v4()), None);
+
+        metrics.forget(&viewer);
+        assert_eq!(metrics.video_bitrate(&camera), None);
+        let text = metrics.render(&Registry::new());
+        assert!(text.lines().any(|rendered| rendered == "rstream_viewer_connections 0"));
+        assert!(!text.lines().any(|rendered| rendered.starts_with("rstream_viewer_frame_rate ")));
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::Notify;
+
+use crate::PeerMsg;
+
+/// Default number of messages buffered for a peer before the overflow policy kicks in.
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// Why a queue stopped accepting messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The owning connection went away.
+    Disconnected,
+    /// The recipient fell too far behind and is being disconnected.
+    SlowConsumer,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum QueueError {
+    #[error("recipient queue is closed ({0:?})")]
+    Closed(CloseReason),
+    #[error("recipient is not keeping up and has been disconnected")]
+    SlowConsumer,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub high_watermark: usize,
+    pub dropped: usize,
+}
+
+struct State {
+    msgs: VecDeque<PeerMsg>,
+    closed: Option<CloseReason>,
+}
+
+/// Bounded outbound queue for a single peer.
+///
+/// Pushing never waits: when the queue is full the oldest queued ICE candidate is
+/// dropped to make room, and if there is nothing droppable the queue is closed
+/// with `CloseReason::SlowConsumer` so the recipient's handler disconnects it.
+pub struct OutboundQueue {
+    state: Mutex<State>,
+    notify: Notify,
+    capacity: usize,
+    high_watermark: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize) -> Self {
+        OutboundQueue {
+            state: Mutex::new(State { msgs: VecDeque::with_capacity(capacity), closed: None }),
+            notify: Notify::new(),
+            capacity,
+            high_watermark: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, msg: PeerMsg) -> Result<(), QueueError> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(reason) = state.closed {
+            return Err(QueueError::Closed(reason));
+        }
+
+        if state.msgs.len() >= self.capacity {
+            let oldest_candidate = state.msgs.iter().position(PeerMsg::is_ice_candidate);
+            match oldest_candidate {
+                Some(idx) => {
+                    state.msgs.remove(idx);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    log::debug!("Dropped queued ICE candidate for {}", msg.recipient);
+                }
+                None => {
+                    state.msgs.clear();
+                    state.closed = Some(CloseReason::SlowConsumer);
+                    drop(state);
+                    self.notify.notify();
+                    log::warn!("Peer {} is not draining its queue, disconnecting", msg.recipient);
+                    return Err(QueueError::SlowConsumer);
+                }
+            }
+        }
+
+        state.msgs.push_back(msg);
+        self.high_watermark.fetch_max(state.msgs.len(), Ordering::Relaxed);
+        drop(state);
+        self.notify.notify();
+        Ok(())
+    }
+
+    /// Waits for the next message, or for the queue to be closed.
+    pub async fn pop(&self) -> Result<PeerMsg, CloseReason> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(reason) = state.closed {
+                    return Err(reason);
+                }
+                if let Some(msg) = state.msgs.pop_front() {
+                    return Ok(msg);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed.get_or_insert(CloseReason::Disconnected);
+        state.msgs.clear();
+        drop(state);
+        self.notify.notify();
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.state.lock().unwrap().msgs.len(),
+            high_watermark: self.high_watermark.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn offer() -> PeerMsg {
+        PeerMsg {
+            signal: common::Signal::Offer { sdp: String::new() },
+            sender: Uuid::nil(),
+            recipient: Uuid::nil(),
+        }
+    }
+
+    fn candidate(candidate: &str) -> PeerMsg {
+        PeerMsg {
+            signal: common::Signal::NewIceCandidate {
+                candidate: common::IceCandidate {
+                    candidate: candidate.to_string(),
+                    sdp_mid: None,
+                    sdp_m_line_index: None,
+                },
+            },
+            sender: Uuid::nil(),
+            recipient: Uuid::nil(),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_oldest_candidate_when_full() {
+        let queue = OutboundQueue::new(3);
+        queue.push(offer()).unwrap();
+        queue.push(candidate("a")).unwrap();
+        queue.push(candidate("b")).unwrap();
+        queue.push(candidate("c")).unwrap();
+
+        assert_eq!(queue.stats(), QueueStats { depth: 3, high_watermark: 3, dropped: 1 });
+        assert!(!queue.pop().await.unwrap().is_ice_candidate());
+        match queue.pop().await.unwrap().signal {
+            common::Signal::NewIceCandidate { candidate } => assert_eq!(candidate.candidate, "b"),
+            signal => panic!("unexpected {:?}", signal),
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_slow_consumer() {
+        let queue = OutboundQueue::new(2);
+        queue.push(offer()).unwrap();
+        queue.push(offer()).unwrap();
+
+        assert_eq!(queue.push(offer()), Err(QueueError::SlowConsumer));
+        assert_eq!(queue.pop().await.unwrap_err(), CloseReason::SlowConsumer);
+        assert_eq!(queue.push(offer()), Err(QueueError::Closed(CloseReason::SlowConsumer)));
+    }
+}
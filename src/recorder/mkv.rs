@@ -0,0 +1,383 @@
+//! A minimal streaming Matroska muxer.
+//!
+//! The segment and its clusters are written with unknown sizes, as live
+//! WebM is, so a file can be read while it is still being recorded and a
+//! recording cut short by a crash is still playable up to its last block.
+//! Finishing a file appends Cues for its keyframe clusters and fills in the
+//! space left at the start for the segment's size, its duration and where
+//! the Cues are, so finished recordings can be seeked.
+
+use std::io::{self, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Matroska writes "unknown" as an eight byte size with every bit set.
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+/// Block timecodes are 16 bit offsets from their cluster's.
+const MAX_CLUSTER_SPAN: u64 = i16::MAX as u64;
+/// Seconds from the Unix epoch to Matroska's, 2001-01-01.
+const MATROSKA_EPOCH: u64 = 978_307_200;
+
+mod id {
+    pub const EBML: u32 = 0x1a45dfa3;
+    pub const EBML_VERSION: u32 = 0x4286;
+    pub const EBML_READ_VERSION: u32 = 0x42f7;
+    pub const EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+    pub const EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
+    pub const DOC_TYPE: u32 = 0x4282;
+    pub const DOC_TYPE_VERSION: u32 = 0x4287;
+    pub const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+    pub const SEGMENT: u32 = 0x18538067;
+    pub const INFO: u32 = 0x1549a966;
+    pub const TIMECODE_SCALE: u32 = 0x2ad7b1;
+    pub const MUXING_APP: u32 = 0x4d80;
+    pub const WRITING_APP: u32 = 0x5741;
+    pub const DATE_UTC: u32 = 0x4461;
+    pub const TRACKS: u32 = 0x1654ae6b;
+    pub const TRACK_ENTRY: u32 = 0xae;
+    pub const TRACK_NUMBER: u32 = 0xd7;
+    pub const TRACK_UID: u32 = 0x73c5;
+    pub const TRACK_TYPE: u32 = 0x83;
+    pub const FLAG_LACING: u32 = 0x9c;
+    pub const CODEC_ID: u32 = 0x86;
+    pub const CODEC_PRIVATE: u32 = 0x63a2;
+    pub const VIDEO: u32 = 0xe0;
+    pub const PIXEL_WIDTH: u32 = 0xb0;
+    pub const PIXEL_HEIGHT: u32 = 0xba;
+    pub const AUDIO: u32 = 0xe1;
+    pub const SAMPLING_FREQUENCY: u32 = 0xb5;
+    pub const CHANNELS: u32 = 0x9f;
+    pub const CLUSTER: u32 = 0x1f43b675;
+    pub const TIMECODE: u32 = 0xe7;
+    pub const SIMPLE_BLOCK: u32 = 0xa3;
+    pub const VOID: u32 = 0xec;
+    pub const SEEK_HEAD: u32 = 0x114d9b74;
+    pub const SEEK: u32 = 0x4dbb;
+    pub const SEEK_ID: u32 = 0x53ab;
+    pub const SEEK_POSITION: u32 = 0x53ac;
+    pub const DURATION: u32 = 0x4489;
+    pub const CUES: u32 = 0x1c53bb6b;
+    pub const CUE_POINT: u32 = 0xbb;
+    pub const CUE_TIME: u32 = 0xb3;
+    pub const CUE_TRACK_POSITIONS: u32 = 0xb7;
+    pub const CUE_TRACK: u32 = 0xf7;
+    pub const CUE_CLUSTER_POSITION: u32 = 0xf1;
+}
+
+/// Room left for the SeekHead pointing at the Cues, which takes 26 bytes.
+const SEEK_HEAD_SPACE: usize = 32;
+/// Room left in the Info for the Duration, a float taking 11 bytes.
+const DURATION_SPACE: usize = 11;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Media {
+    /// Picture size, if known.
+    Video { size: Option<(u32, u32)> },
+    Audio { sample_rate: u32, channels: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub media: Media,
+    /// Matroska codec ID, such as `V_VP8`.
+    pub codec_id: &'static str,
+    pub codec_private: Option<Vec<u8>>,
+}
+
+impl Track {
+    pub fn is_video(&self) -> bool {
+        matches!(self.media, Media::Video { .. })
+    }
+
+    /// Whether WebM, the subset of Matroska browsers play, allows this codec.
+    fn is_webm(&self) -> bool {
+        ["V_VP8", "V_VP9", "A_OPUS"].contains(&self.codec_id)
+    }
+}
+
+/// File extension for a recording of these tracks.
+pub fn extension(tracks: &[Track]) -> &'static str {
+    if tracks.iter().all(Track::is_webm) { "webm" } else { "mkv" }
+}
+
+/// EBML variable length size.
+fn vint(value: u64) -> Vec<u8> {
+    let length = (1..8).find(|length| value < (1 << (7 * length)) - 1).unwrap_or(8);
+    let marked = value | 1 << (7 * length);
+    marked.to_be_bytes()[8 - length as usize..].to_vec()
+}
+
+fn uint(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take(7).take_while(|&&byte| byte == 0).count();
+    bytes[skip..].to_vec()
+}
+
+fn element(id: u32, data: &[u8]) -> Vec<u8> {
+    let mut out = uint(id as u64);
+    out.extend(vint(data.len() as u64));
+    out.extend_from_slice(data);
+    out
+}
+
+fn master(id: u32, children: &[Vec<u8>]) -> Vec<u8> {
+    element(id, &children.concat())
+}
+
+/// A Void element taking exactly `length` bytes, from 2 to 128, to be
+/// overwritten later.
+fn void(length: usize) -> Vec<u8> {
+    element(id::VOID, &vec![0; length - 2])
+}
+
+/// An eight byte unsigned integer, for values patched in after the fact.
+fn fixed_uint(value: u64) -> Vec<u8> {
+    value.to_be_bytes().to_vec()
+}
+
+fn track_entry(number: u64, track: &Track) -> Vec<u8> {
+    let mut children = vec![
+        element(id::TRACK_NUMBER, &uint(number)),
+        element(id::TRACK_UID, &uint(number)),
+        element(id::TRACK_TYPE, &uint(if track.is_video() { 1 } else { 2 })),
+        element(id::FLAG_LACING, &uint(0)),
+        element(id::CODEC_ID, track.codec_id.as_bytes()),
+    ];
+    if let Some(private) = &track.codec_private {
+        children.push(element(id::CODEC_PRIVATE, private));
+    }
+    match track.media {
+        Media::Video { size: Some((width, height)) } => children.push(master(id::VIDEO, &[
+            element(id::PIXEL_WIDTH, &uint(width as u64)),
+            element(id::PIXEL_HEIGHT, &uint(height as u64)),
+        ])),
+        Media::Video { size: None } => {}
+        Media::Audio { sample_rate, channels } => children.push(master(id::AUDIO, &[
+            element(id::SAMPLING_FREQUENCY, &(sample_rate as f64).to_be_bytes()),
+            element(id::CHANNELS, &uint(channels as u64)),
+        ])),
+    }
+    master(id::TRACK_ENTRY, &children)
+}
+
+/// Writes blocks into clusters, starting a new cluster at each keyframe of
+/// the first video track so that any cluster can be played on its own.
+pub struct Writer<W: Write> {
+    out: W,
+    key_track: u64,
+    /// Bytes written so far.
+    position: u64,
+    /// Where the segment's size, its data, the space for the SeekHead and the
+    /// space for the Duration start.
+    segment_size: u64,
+    segment_data: u64,
+    seek_head: u64,
+    duration: u64,
+    /// Timecode of the open cluster, in milliseconds.
+    cluster: Option<u64>,
+    /// Timecode and position in the segment of each cluster starting at a keyframe.
+    cues: Vec<(u64, u64)>,
+    last_timecode: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the file header. Tracks are numbered from one in the order given.
+    pub fn new(out: W, tracks: &[Track], date: SystemTime) -> io::Result<Self> {
+        let mut writer = Writer {
+            out,
+            key_track: tracks.iter().position(Track::is_video).unwrap_or(0) as u64 + 1,
+            position: 0,
+            segment_size: 0,
+            segment_data: 0,
+            seek_head: 0,
+            duration: 0,
+            cluster: None,
+            cues: Vec::new(),
+            last_timecode: 0,
+        };
+
+        let doc_type = if extension(tracks) == "webm" { "webm" } else { "matroska" };
+        writer.put(&master(id::EBML, &[
+            element(id::EBML_VERSION, &uint(1)),
+            element(id::EBML_READ_VERSION, &uint(1)),
+            element(id::EBML_MAX_ID_LENGTH, &uint(4)),
+            element(id::EBML_MAX_SIZE_LENGTH, &uint(8)),
+            element(id::DOC_TYPE, doc_type.as_bytes()),
+            element(id::DOC_TYPE_VERSION, &uint(4)),
+            element(id::DOC_TYPE_READ_VERSION, &uint(2)),
+        ]))?;
+
+        writer.put(&uint(id::SEGMENT as u64))?;
+        writer.segment_size = writer.position;
+        writer.put(&UNKNOWN_SIZE)?;
+        writer.segment_data = writer.position;
+        writer.seek_head = writer.position;
+        writer.put(&void(SEEK_HEAD_SPACE))?;
+
+        let since_epoch = date.duration_since(UNIX_EPOCH).unwrap_or_default().saturating_sub(std::time::Duration::from_secs(MATROSKA_EPOCH));
+        let app = concat!("rstream ", env!("CARGO_PKG_VERSION"));
+        let info = [
+            element(id::TIMECODE_SCALE, &uint(1_000_000)),
+            element(id::MUXING_APP, app.as_bytes()),
+            element(id::WRITING_APP, app.as_bytes()),
+            element(id::DATE_UTC, &(since_epoch.as_nanos() as i64).to_be_bytes()),
+        ];
+        let info = master(id::INFO, &[&info[..], &[void(DURATION_SPACE)]].concat());
+        writer.duration = writer.position + info.len() as u64 - DURATION_SPACE as u64;
+        writer.put(&info)?;
+        let entries = tracks.iter().zip(1..).map(|(track, number)| track_entry(number, track)).collect::<Vec<_>>();
+        writer.put(&master(id::TRACKS, &entries))?;
+        Ok(writer)
+    }
+
+    fn put(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes a frame of `track` at `timecode` milliseconds into the file.
+    pub fn write(&mut self, track: u64, timecode: u64, keyframe: bool, data: &[u8]) -> io::Result<()> {
+        let key = track == self.key_track && keyframe;
+        let starts_cluster = match self.cluster {
+            None => true,
+            Some(cluster) => key || timecode > cluster + MAX_CLUSTER_SPAN,
+        };
+        if starts_cluster {
+            // Clusters are flushed whole, so readers of a live file only see complete ones.
+            self.out.flush()?;
+            if key {
+                self.cues.push((timecode, self.position - self.segment_data));
+            }
+            self.put(&uint(id::CLUSTER as u64))?;
+            self.put(&UNKNOWN_SIZE)?;
+            self.put(&element(id::TIMECODE, &uint(timecode)))?;
+            self.cluster = Some(timecode);
+        }
+        let cluster = self.cluster.unwrap_or_default();
+        let offset = (timecode as i64 - cluster as i64).clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+        self.last_timecode = self.last_timecode.max(timecode);
+
+        let mut block = vint(track);
+        block.extend_from_slice(&offset.to_be_bytes());
+        block.push(if keyframe { 0x80 } else { 0 });
+        block.extend_from_slice(data);
+        self.put(&element(id::SIMPLE_BLOCK, &block))
+    }
+}
+
+impl<W: Write + Seek> Writer<W> {
+    /// Appends the Cues and fills in the segment's size, its duration and the
+    /// SeekHead pointing at the Cues.
+    pub fn finish(mut self) -> io::Result<W> {
+        let cues_position = self.position - self.segment_data;
+        let points = self.cues.iter().map(|&(timecode, position)| master(id::CUE_POINT, &[
+            element(id::CUE_TIME, &uint(timecode)),
+            master(id::CUE_TRACK_POSITIONS, &[
+                element(id::CUE_TRACK, &uint(self.key_track)),
+                element(id::CUE_CLUSTER_POSITION, &uint(position)),
+            ]),
+        ])).collect::<Vec<_>>();
+        if !points.is_empty() {
+            self.put(&master(id::CUES, &points))?;
+        }
+        let end = self.position;
+
+        let mut size = fixed_uint(end - self.segment_data);
+        size[0] = 0x01;
+        self.patch(self.segment_size, &size)?;
+        if !points.is_empty() {
+            let seek_head = master(id::SEEK_HEAD, &[master(id::SEEK, &[
+                element(id::SEEK_ID, &uint(id::CUES as u64)),
+                element(id::SEEK_POSITION, &fixed_uint(cues_position)),
+            ])]);
+            self.patch(self.seek_head, &[seek_head.clone(), void(SEEK_HEAD_SPACE - seek_head.len())].concat())?;
+        }
+        self.patch(self.duration, &element(id::DURATION, &(self.last_timecode as f64).to_be_bytes()))?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn patch(&mut self, position: u64, data: &[u8]) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(position))?;
+        self.out.write_all(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn encodes_sizes() {
+        assert_eq!(vint(0), vec![0x80]);
+        assert_eq!(vint(126), vec![0xfe]);
+        // All ones is reserved for unknown sizes, so 127 needs two bytes.
+        assert_eq!(vint(127), vec![0x40, 0x7f]);
+        assert_eq!(vint(0x3ffe), vec![0x7f, 0xfe]);
+        assert_eq!(uint(0), vec![0]);
+        assert_eq!(uint(0x1234), vec![0x12, 0x34]);
+    }
+
+    #[test]
+    fn writes_clusters_at_keyframes() {
+        let tracks = [
+            Track { media: Media::Video { size: Some((320, 240)) }, codec_id: "V_VP8", codec_private: None },
+            Track { media: Media::Audio { sample_rate: 48000, channels: 2 }, codec_id: "A_OPUS", codec_private: Some(b"OpusHead".to_vec()) },
+        ];
+        assert_eq!(extension(&tracks), "webm");
+
+        let mut writer = Writer::new(Cursor::new(Vec::new()), &tracks, UNIX_EPOCH).unwrap();
+        let header = writer.position as usize;
+        writer.write(1, 0, true, &[1]).unwrap();
+        writer.write(2, 10, true, &[2]).unwrap();
+        writer.write(1, 40, false, &[3]).unwrap();
+        writer.write(1, 1000, true, &[4]).unwrap();
+        let clusters_end = writer.position as usize;
+        let out = writer.finish().unwrap().into_inner();
+
+        assert!(out.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]));
+        assert!(out.windows(4).any(|window| window == b"webm"));
+        let cluster = |timecode: u8| [&[0x1f, 0x43, 0xb6, 0x75][..], &UNKNOWN_SIZE, &[0xe7, 0x81, timecode]].concat();
+        let block = |track: u8, offset: i16, flags: u8, data: u8| [&[0xa3, 0x85, 0x80 | track][..], &offset.to_be_bytes(), &[flags, data]].concat();
+        let expected = [
+            cluster(0), block(1, 0, 0x80, 1), block(2, 10, 0x80, 2), block(1, 40, 0, 3),
+            [&[0x1f, 0x43, 0xb6, 0x75][..], &UNKNOWN_SIZE, &[0xe7, 0x82, 0x03, 0xe8]].concat(), block(1, 0, 0x80, 4),
+        ].concat();
+        assert_eq!(&out[header..clusters_end], &expected[..]);
+    }
+
+    #[test]
+    fn finishing_adds_cues_and_duration() {
+        let tracks = [Track { media: Media::Video { size: None }, codec_id: "V_VP8", codec_private: None }];
+        let mut writer = Writer::new(Cursor::new(Vec::new()), &tracks, UNIX_EPOCH).unwrap();
+        let first = writer.position - writer.segment_data;
+        writer.write(1, 0, true, &[1]).unwrap();
+        writer.write(1, 40, false, &[2]).unwrap();
+        let second = writer.position - writer.segment_data;
+        writer.write(1, 1000, true, &[3]).unwrap();
+        let (segment_size, segment_data, clusters_end) = (writer.segment_size as usize, writer.segment_data as usize, writer.position as usize);
+        let out = writer.finish().unwrap().into_inner();
+
+        // The segment has a known size, reaching to the end of the Cues.
+        assert_eq!(out[segment_size], 0x01);
+        assert_eq!(u64::from_be_bytes(out[segment_size..segment_data].try_into().unwrap()) & 0x00ff_ffff_ffff_ffff, (out.len() - segment_data) as u64);
+        // The SeekHead replaces the Void and points at the Cues.
+        let cues_position = (clusters_end - segment_data) as u64;
+        let seek_head = [&[0x11, 0x4d, 0x9b, 0x74, 0x95, 0x4d, 0xbb, 0x92, 0x53, 0xab, 0x84, 0x1c, 0x53, 0xbb, 0x6b, 0x53, 0xac, 0x88][..], &cues_position.to_be_bytes(), &[0xec, 0x84, 0, 0, 0, 0]].concat();
+        assert_eq!(&out[segment_data..segment_data + SEEK_HEAD_SPACE], &seek_head[..]);
+        let duration = [&[0x44, 0x89, 0x88][..], &1000f64.to_be_bytes()].concat();
+        assert!(out.windows(duration.len()).any(|window| window == &duration[..]));
+
+        let positions = |position: u64| vec![0xb7, 0x86, 0xf7, 0x81, 0x01, 0xf1, 0x81, position as u8];
+        let cues = [
+            vec![0x1c, 0x53, 0xbb, 0x6b, 0x9b],
+            vec![0xbb, 0x8b, 0xb3, 0x81, 0x00], positions(first),
+            vec![0xbb, 0x8c, 0xb3, 0x82, 0x03, 0xe8], positions(second),
+        ].concat();
+        assert_eq!(&out[clusters_end..], &cues[..]);
+    }
+}
@@ -0,0 +1,169 @@
+//! Continuous recording of camera streams.
+//!
+//! The recorder is a peer run by the server itself. It watches for cameras it
+//! is configured to record, offers each a receive-only session like any viewer
+//! would, and writes the media it is sent into segment files without
+//! transcoding: fragmented MP4 for H.264 and Matroska otherwise. Motion the
+//! camera reports is noted alongside its segments, which are indexed in the
+//! server's storage. Old segments are removed by age and by total size.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use tokio1::sync::mpsc;
+use uuid::Uuid;
+use webrtc::api::API;
+
+use crate::queue::{self, CloseReason};
+use crate::registry::{Peer, Registry};
+use crate::rtc;
+use crate::storage::Storage;
+
+mod mkv;
+mod mp4;
+mod session;
+mod store;
+
+pub use store::{camera_dir, reindex, timeline, Retention};
+
+/// How often the registry is checked for cameras to start recording.
+const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the retention policy is applied.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Recordings are written to a subdirectory of this for each camera.
+    pub dir: PathBuf,
+    /// Names of the cameras to record, or empty to record all of them.
+    pub cameras: Vec<String>,
+    /// Segments are closed at the first keyframe after they reach this length.
+    pub segment: Duration,
+    pub retention: Retention,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            dir: PathBuf::from("./recordings"),
+            cameras: Vec::new(),
+            segment: Duration::from_secs(300),
+            retention: Retention::default(),
+        }
+    }
+}
+
+impl Settings {
+    fn records(&self, camera: &str) -> bool {
+        self.cameras.is_empty() || self.cameras.iter().any(|name| name == camera)
+    }
+}
+
+/// Handle to a running recorder.
+pub struct Recorder {
+    id: Uuid,
+}
+
+impl Recorder {
+    /// Registers the recorder as a peer and starts recording on a thread of
+    /// its own, indexing segments in `index`.
+    pub fn start(peers: Arc<Registry>, settings: Settings, index: Arc<dyn Storage>) -> Result<Self> {
+        let peer = Arc::new(Peer::new(Uuid::new_v4(), queue::DEFAULT_CAPACITY));
+        let id = peer.id;
+        let api = Arc::new(rtc::api()?);
+        log::info!("Recording {} to {}", if settings.cameras.is_empty() { "every camera".to_string() } else { settings.cameras.join(", ") }, settings.dir.display());
+        let task_peers = peers.clone();
+        rtc::spawn("recorder", peers, peer.clone(), async move { run(&peer, task_peers, api, Arc::new(settings), index).await })?;
+        Ok(Recorder { id })
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+/// Notes motion on a camera being recorded, so playback can jump to it.
+fn tag_motion(peers: &Registry, settings: &Arc<Settings>, camera: Uuid) {
+    let name = match peers.get(&camera).and_then(|peer| peer.camera()) {
+        Some(descriptor) => descriptor.name,
+        None => return,
+    };
+    let dir = settings.dir.join(store::camera_dir(&name));
+    let event = common::RecordingEvent { time: store::millis(SystemTime::now()), kind: "motion".to_string() };
+    tokio1::task::spawn_blocking(move || {
+        if let Err(err) = store::append_event(&dir, &event) {
+            log::warn!("Failed noting motion in {}: {}", dir.display(), err);
+        }
+    });
+}
+
+async fn run(peer: &Peer, peers: Arc<Registry>, api: Arc<API>, settings: Arc<Settings>, index: Arc<dyn Storage>) {
+    let mut sessions: HashMap<Uuid, mpsc::UnboundedSender<common::Signal>> = HashMap::new();
+    let (ended_tx, mut ended) = mpsc::unbounded_channel();
+    let mut scan = tokio1::time::interval(SCAN_INTERVAL);
+    let mut prune = tokio1::time::interval(PRUNE_INTERVAL);
+
+    loop {
+        tokio1::select! {
+            msg = peer.queue.pop() => match msg {
+                Ok(crate::PeerMsg { signal: common::Signal::Motion { active }, sender, .. }) => {
+                    if active && sessions.contains_key(&sender) {
+                        tag_motion(&peers, &settings, sender);
+                    }
+                }
+                Ok(msg) => {
+                    if let Some(signals) = sessions.get(&msg.sender) {
+                        let _ = signals.send(msg.signal);
+                    }
+                }
+                Err(CloseReason::SlowConsumer) => {
+                    log::error!("Recorder fell behind on signalling ({:?}), stopping", peer.queue.stats());
+                    break;
+                }
+                Err(CloseReason::Disconnected) => break,
+            },
+            _ = scan.tick() => {
+                let cameras = peers.cameras().into_iter()
+                    .filter(|camera| settings.records(&camera.descriptor.name) && !sessions.contains_key(&camera.id))
+                    .collect::<Vec<_>>();
+                for camera in cameras {
+                    log::info!("Recording camera {} ({:?})", camera.id, camera.descriptor.name);
+                    peers.pair(&peer.id, &camera.id);
+                    let (signals, rx) = mpsc::unbounded_channel();
+                    sessions.insert(camera.id, signals);
+                    let (recorder, api, peers, settings, index, ended) = (peer.id, api.clone(), peers.clone(), settings.clone(), index.clone(), ended_tx.clone());
+                    tokio1::spawn(async move {
+                        let id = camera.id;
+                        if let Err(err) = session::run(camera, recorder, &api, &peers, &settings, index, rx).await {
+                            log::warn!("Recording of camera {} failed: {:#}", id, err);
+                        }
+                        let _ = ended.send(id);
+                    });
+                }
+            }
+            _ = prune.tick() => {
+                let (settings, index) = (settings.clone(), index.clone());
+                tokio1::task::spawn_blocking(move || {
+                    match store::enforce(&settings.dir, &*index, &settings.retention, SystemTime::now()) {
+                        Ok(removed) => for path in removed {
+                            log::info!("Removed recording {}", path.display());
+                        },
+                        Err(err) => log::warn!("Failed applying retention policy to {}: {}", settings.dir.display(), err),
+                    }
+                });
+            }
+            Some(camera) = ended.recv() => {
+                // Hanging up lets the camera close its side; it is recorded again once the registry shows it.
+                sessions.remove(&camera);
+                peers.unpair(&peer.id, &camera);
+                let _ = peers.send(crate::PeerMsg { signal: common::Signal::Hangup, sender: peer.id, recipient: camera });
+            }
+        }
+    }
+    for signals in sessions.values() {
+        let _ = signals.send(common::Signal::Hangup);
+    }
+}
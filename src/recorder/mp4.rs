@@ -0,0 +1,353 @@
+//! A minimal fragmented MP4 muxer, for H.264 which browsers play from MP4
+//! but not from Matroska.
+//!
+//! The file starts with a movie box describing the tracks and no samples,
+//! followed by a fragment for each run of frames from one keyframe of the
+//! video to the next. Like the Matroska muxer, a file can be played while it
+//! is being recorded, up to its last complete fragment. Finishing a file
+//! fills in its duration and appends an index of its fragments for seeking.
+
+use std::io::{self, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::mkv::{Media, Track};
+
+/// Times are in milliseconds, as they are in Matroska files.
+const TIMESCALE: u32 = 1000;
+/// Seconds from MP4's epoch, 1904-01-01, to the Unix epoch.
+const MP4_EPOCH: u64 = 2_082_844_800;
+/// Frames are buffered until the next keyframe, but no longer than this.
+const MAX_FRAGMENT_SPAN: u64 = 10_000;
+
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000];
+
+/// Sample flags (ISO 14496-12 8.8.3.1) of frames others depend on, and of
+/// frames depending on others, which can't be seeked to.
+const SYNC_SAMPLE: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE: u32 = 0x0101_0000;
+
+/// Whether these tracks can be written to MP4: H.264 video, with or without
+/// Opus audio.
+pub fn supports(tracks: &[Track]) -> bool {
+    tracks.iter().any(|track| track.codec_id == "V_MPEG4/ISO/AVC")
+        && tracks.iter().all(|track| matches!(track.codec_id, "V_MPEG4/ISO/AVC" | "A_OPUS") && track.codec_private.is_some())
+}
+
+fn mp4_box(kind: &[u8; 4], children: &[Vec<u8>]) -> Vec<u8> {
+    let data = children.concat();
+    let mut out = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(kind);
+    out.extend(data);
+    out
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, children: &[Vec<u8>]) -> Vec<u8> {
+    let header = (flags | (version as u32) << 24).to_be_bytes().to_vec();
+    mp4_box(kind, &[&[header][..], children].concat())
+}
+
+fn u16s(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_be_bytes()).collect()
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_be_bytes()).collect()
+}
+
+/// `dOps` (Opus in ISOBMFF 4.3.2) from the OpusHead Matroska keeps, which has
+/// the same fields in little endian.
+fn opus_specific(head: &[u8]) -> Vec<u8> {
+    let field = |range: std::ops::Range<usize>| head.get(range).map(<[u8]>::to_vec).unwrap_or_default();
+    let reversed = |range| field(range).into_iter().rev().collect::<Vec<_>>();
+    mp4_box(b"dOps", &[vec![0], field(9..10), reversed(10..12), reversed(12..16), reversed(16..18), vec![0]])
+}
+
+fn sample_entry(track: &Track) -> Vec<u8> {
+    let private = track.codec_private.clone().unwrap_or_default();
+    match track.media {
+        Media::Video { size } => {
+            let (width, height) = size.unwrap_or_default();
+            mp4_box(b"avc1", &[
+                vec![0; 6], u16s(&[1, 0, 0]), u32s(&[0, 0, 0]),
+                u16s(&[width as u16, height as u16]), u32s(&[0x0048_0000, 0x0048_0000, 0]), u16s(&[1]),
+                vec![0; 32], u16s(&[0x0018, 0xffff]),
+                mp4_box(b"avcC", &[private]),
+            ])
+        }
+        Media::Audio { channels, .. } => mp4_box(b"Opus", &[
+            vec![0; 6], u16s(&[1]), u32s(&[0, 0]),
+            u16s(&[channels, 16, 0, 0]), u32s(&[48000 << 16]),
+            opus_specific(&private),
+        ]),
+    }
+}
+
+fn track_box(number: u32, track: &Track, time: u32) -> Vec<u8> {
+    let (handler, header, volume, (width, height)) = match track.media {
+        Media::Video { size } => (b"vide", full_box(b"vmhd", 0, 1, &[u16s(&[0, 0, 0, 0])]), 0, size.unwrap_or_default()),
+        Media::Audio { .. } => (b"soun", full_box(b"smhd", 0, 0, &[u16s(&[0, 0])]), 0x0100, (0, 0)),
+    };
+    let empty = |kind| full_box(kind, 0, 0, &[u32s(&[0])]);
+    mp4_box(b"trak", &[
+        full_box(b"tkhd", 0, 3, &[
+            u32s(&[time, time, number, 0, 0, 0, 0]), u16s(&[0, 0, volume, 0]), u32s(&MATRIX), u32s(&[width << 16, height << 16]),
+        ]),
+        mp4_box(b"mdia", &[
+            full_box(b"mdhd", 0, 0, &[u32s(&[time, time, TIMESCALE, 0]), u16s(&[0x55c4, 0])]),
+            full_box(b"hdlr", 0, 0, &[u32s(&[0]), handler.to_vec(), u32s(&[0, 0, 0]), b"rstream\0".to_vec()]),
+            mp4_box(b"minf", &[
+                header,
+                mp4_box(b"dinf", &[full_box(b"dref", 0, 0, &[u32s(&[1]), full_box(b"url ", 0, 1, &[])])]),
+                mp4_box(b"stbl", &[
+                    full_box(b"stsd", 0, 0, &[u32s(&[1]), sample_entry(track)]),
+                    empty(b"stts"),
+                    empty(b"stsc"),
+                    full_box(b"stsz", 0, 0, &[u32s(&[0, 0])]),
+                    empty(b"stco"),
+                ]),
+            ]),
+        ]),
+    ])
+}
+
+struct Sample {
+    timecode: u64,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+/// Writes frames into fragments, each starting at a keyframe of the first
+/// video track where possible so that any fragment can be played on its own.
+pub struct Writer<W: Write> {
+    out: W,
+    key_track: u32,
+    /// Bytes written so far.
+    position: u64,
+    /// Where the movie header's duration is, to be filled in when finished.
+    duration: u64,
+    /// Frames of the fragment being gathered, for each track.
+    pending: Vec<Vec<Sample>>,
+    /// How long each track's last frame lasted, for frames with none after them.
+    durations: Vec<u32>,
+    sequence: u32,
+    /// Time and position of each fragment starting at a keyframe, and the
+    /// number of the key track's `traf` in it.
+    fragments: Vec<(u64, u64, u8)>,
+    end: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the file header. Tracks are numbered from one in the order given.
+    pub fn new(out: W, tracks: &[Track], date: SystemTime) -> io::Result<Self> {
+        let time = (date.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() + MP4_EPOCH) as u32;
+        let next_track = tracks.len() as u32 + 1;
+        let ftyp = mp4_box(b"ftyp", &[b"isom".to_vec(), u32s(&[0x200]), b"isomiso6avc1mp41".to_vec()]);
+        let mvhd = full_box(b"mvhd", 0, 0, &[
+            u32s(&[time, time, TIMESCALE, 0, 0x0001_0000]), u16s(&[0x0100, 0]), u32s(&[0, 0]),
+            u32s(&MATRIX), u32s(&[0; 6]), u32s(&[next_track]),
+        ]);
+        let mut children = vec![mvhd];
+        children.extend(tracks.iter().zip(1..).map(|(track, number)| track_box(number, track, time)));
+        let trex = (1..next_track).map(|number| full_box(b"trex", 0, 0, &[u32s(&[number, 1, 0, 0, 0])])).collect::<Vec<_>>();
+        children.push(mp4_box(b"mvex", &trex));
+
+        let mut writer = Writer {
+            out,
+            key_track: tracks.iter().position(Track::is_video).unwrap_or(0) as u32 + 1,
+            position: 0,
+            // In the movie box, the movie header's duration follows its size,
+            // type, version and flags, two times and the timescale.
+            duration: ftyp.len() as u64 + 8 + 24,
+            pending: tracks.iter().map(|_| Vec::new()).collect(),
+            durations: vec![0; tracks.len()],
+            sequence: 0,
+            fragments: Vec::new(),
+            end: 0,
+        };
+        writer.put(&ftyp)?;
+        writer.put(&mp4_box(b"moov", &children))?;
+        writer.out.flush()?;
+        Ok(writer)
+    }
+
+    fn put(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    /// Adds a frame of `track` at `timecode` milliseconds to the file.
+    pub fn write(&mut self, track: u64, timecode: u64, keyframe: bool, data: &[u8]) -> io::Result<()> {
+        let index = match (track as usize).checked_sub(1).filter(|&index| index < self.pending.len()) {
+            Some(index) => index,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no track {}", track))),
+        };
+        let start = self.pending.iter().flatten().map(|sample| sample.timecode).min();
+        let key = track == self.key_track as u64 && keyframe;
+        if start.is_some_and(|start| key || timecode > start + MAX_FRAGMENT_SPAN) {
+            self.fragment(Some(timecode))?;
+        }
+        self.pending[index].push(Sample { timecode, keyframe, data: data.to_vec() });
+        Ok(())
+    }
+
+    /// Writes the frames gathered so far as a fragment. Each track's last
+    /// frame lasts until `next`, the time of the frame after them, if known.
+    fn fragment(&mut self, next: Option<u64>) -> io::Result<()> {
+        self.sequence += 1;
+        let key_index = self.key_track as usize - 1;
+        let starts_with_key = self.pending[key_index].first().is_some_and(|sample| sample.keyframe);
+        let mut runs = Vec::new();
+        for (index, samples) in self.pending.iter().enumerate().filter(|(_, samples)| !samples.is_empty()) {
+            let mut entries = Vec::new();
+            for (position, sample) in samples.iter().enumerate() {
+                let following = samples.get(position + 1).map(|next| next.timecode).or(next);
+                let duration = match following {
+                    Some(following) if following > sample.timecode => (following - sample.timecode) as u32,
+                    _ => self.durations[index],
+                };
+                self.durations[index] = duration;
+                let flags = if sample.keyframe { SYNC_SAMPLE } else { NON_SYNC_SAMPLE };
+                entries.push((duration, sample.data.len() as u32, flags));
+            }
+            let last = samples.last().map_or(0, |sample| sample.timecode + self.durations[index] as u64);
+            self.end = self.end.max(last);
+            runs.push((index, samples[0].timecode, entries));
+        }
+
+        let moof = |offsets: &[u32]| {
+            let mut children = vec![full_box(b"mfhd", 0, 0, &[u32s(&[self.sequence])])];
+            for ((index, base, entries), offset) in runs.iter().zip(offsets) {
+                let samples = entries.iter().flat_map(|&(duration, size, flags)| u32s(&[duration, size, flags])).collect::<Vec<_>>();
+                children.push(mp4_box(b"traf", &[
+                    // Data offsets count from the start of the moof.
+                    full_box(b"tfhd", 0, 0x02_0000, &[u32s(&[*index as u32 + 1])]),
+                    full_box(b"tfdt", 1, 0, &[base.to_be_bytes().to_vec()]),
+                    full_box(b"trun", 0, 0x0701, &[u32s(&[entries.len() as u32, *offset]), samples]),
+                ]));
+            }
+            mp4_box(b"moof", &children)
+        };
+        let length = moof(&vec![0; runs.len()]).len() as u32 + 8;
+        let offsets = runs.iter()
+            .scan(length, |offset, (index, ..)| {
+                let start = *offset;
+                *offset += self.pending[*index].iter().map(|sample| sample.data.len() as u32).sum::<u32>();
+                Some(start)
+            })
+            .collect::<Vec<_>>();
+
+        let moof = moof(&offsets);
+        if starts_with_key {
+            let traf = runs.iter().position(|(index, ..)| *index == key_index).unwrap_or(0) as u8 + 1;
+            self.fragments.push((self.pending[key_index][0].timecode, self.position, traf));
+        }
+        let data = runs.iter().flat_map(|(index, ..)| self.pending[*index].iter().map(|sample| &sample.data[..])).collect::<Vec<_>>().concat();
+        for samples in &mut self.pending {
+            samples.clear();
+        }
+        self.put(&moof)?;
+        self.put(&mp4_box(b"mdat", &[data]))?;
+        // Fragments are flushed whole, so readers of a live file only see complete ones.
+        self.out.flush()
+    }
+}
+
+impl<W: Write + Seek> Writer<W> {
+    /// Writes the frames still gathered, fills in the duration and appends the
+    /// index of fragments starting at keyframes.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.pending.iter().any(|samples| !samples.is_empty()) {
+            self.fragment(None)?;
+        }
+        let entries = self.fragments.iter()
+            .flat_map(|&(time, offset, traf)| [time.to_be_bytes().to_vec(), offset.to_be_bytes().to_vec(), vec![traf, 1, 1]].concat())
+            .collect::<Vec<_>>();
+        let tfra = full_box(b"tfra", 1, 0, &[u32s(&[self.key_track, 0, self.fragments.len() as u32]), entries]);
+        // The mfro's size field counts the whole mfra, itself included.
+        let size = tfra.len() as u32 + 8 + 16;
+        self.put(&mp4_box(b"mfra", &[tfra, full_box(b"mfro", 0, 0, &[u32s(&[size])])]))?;
+        let end = self.position;
+
+        self.out.seek(SeekFrom::Start(self.duration))?;
+        self.out.write_all(&(self.end as u32).to_be_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// The boxes in `data`, as their types and contents.
+    fn boxes(mut data: &[u8]) -> Vec<(String, &[u8])> {
+        let mut boxes = Vec::new();
+        while data.len() >= 8 {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            boxes.push((String::from_utf8_lossy(&data[4..8]).into_owned(), &data[8..size]));
+            data = &data[size..];
+        }
+        boxes
+    }
+
+    fn tracks() -> Vec<Track> {
+        vec![
+            Track { media: Media::Video { size: Some((320, 240)) }, codec_id: "V_MPEG4/ISO/AVC", codec_private: Some(vec![1, 0x42, 0, 0x1e, 0xff, 0xe1]) },
+            Track { media: Media::Audio { sample_rate: 48000, channels: 2 }, codec_id: "A_OPUS", codec_private: Some(b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00".to_vec()) },
+        ]
+    }
+
+    #[test]
+    fn supports_h264_with_opus() {
+        let tracks = tracks();
+        assert!(supports(&tracks));
+        assert!(supports(&tracks[..1]));
+        assert!(!supports(&tracks[1..]));
+        let vp8 = Track { media: Media::Video { size: None }, codec_id: "V_VP8", codec_private: None };
+        assert!(!supports(&[tracks[0].clone(), vp8]));
+    }
+
+    #[test]
+    fn converts_opus_head() {
+        let head = &tracks()[1].codec_private.clone().unwrap();
+        assert_eq!(opus_specific(head), [&[0, 0, 0, 19][..], b"dOps", &[0, 2, 0x01, 0x38, 0, 0, 0xbb, 0x80, 0, 0, 0]].concat());
+    }
+
+    #[test]
+    fn writes_fragments_at_keyframes() {
+        let mut writer = Writer::new(Cursor::new(Vec::new()), &tracks(), UNIX_EPOCH).unwrap();
+        writer.write(1, 0, true, &[1, 1]).unwrap();
+        writer.write(2, 10, true, &[2]).unwrap();
+        writer.write(1, 40, false, &[3]).unwrap();
+        writer.write(1, 80, true, &[4, 4, 4]).unwrap();
+        let out = writer.finish().unwrap().into_inner();
+
+        let top = boxes(&out);
+        let kinds = top.iter().map(|(kind, _)| kind.as_str()).collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["ftyp", "moov", "moof", "mdat", "moof", "mdat", "mfra"]);
+        // The first fragment holds both tracks, video first.
+        assert_eq!(top[3].1, &[1, 1, 3, 2]);
+        assert_eq!(top[5].1, &[4, 4, 4]);
+
+        let trafs = boxes(top[2].1).into_iter().filter(|(kind, _)| kind == "traf").collect::<Vec<_>>();
+        assert_eq!(trafs.len(), 2);
+        let video = boxes(trafs[0].1);
+        let trun = video.iter().find(|(kind, _)| kind == "trun").unwrap().1;
+        let moof_length = top[2].1.len() as u32 + 8;
+        // Two samples starting right after the mdat header, lasting until the next frame.
+        assert_eq!(&trun[4..12], &u32s(&[2, moof_length + 8])[..]);
+        assert_eq!(&trun[12..], &u32s(&[40, 2, SYNC_SAMPLE, 40, 1, NON_SYNC_SAMPLE])[..]);
+
+        // Finished files know their length, up to the end of the last frame.
+        let mvhd = boxes(top[1].1)[0].1;
+        assert_eq!(&mvhd[16..20], &u32s(&[120])[..]);
+        let mfra = boxes(top[6].1);
+        assert_eq!(mfra[0].0, "tfra");
+        assert_eq!(u32::from_be_bytes(mfra[1].1[4..8].try_into().unwrap()) as usize, top[6].1.len() + 8);
+    }
+}
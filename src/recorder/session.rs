@@ -0,0 +1,427 @@
+//! The recorder's session with one camera, muxing what it receives into segments.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use tokio1::sync::mpsc;
+use uuid::Uuid;
+use webrtc::api::API;
+use webrtc::media::io::sample_builder::SampleBuilder;
+use webrtc::rtp::codecs::h264::H264Packet;
+use webrtc::rtp::codecs::opus::OpusPacket;
+use webrtc::rtp::codecs::vp8::Vp8Packet;
+use webrtc::rtp::codecs::vp9::Vp9Packet;
+use webrtc::rtp::packetizer::Depacketizer;
+use webrtc::track::track_remote::TrackRemote;
+
+use super::mkv::{self, Media, Track};
+use super::mp4;
+use super::{store, Settings};
+use crate::registry::Registry;
+use crate::rtc::Receiver;
+use crate::storage::Storage;
+
+/// How many packets a sample may wait for ones that arrived out of order.
+const MAX_LATE: u16 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Codec {
+    H264,
+    Vp8,
+    Vp9,
+    Opus,
+}
+
+impl Codec {
+    fn from_mime_type(mime_type: &str) -> Option<Self> {
+        match mime_type.to_ascii_lowercase().as_str() {
+            "video/h264" => Some(Codec::H264),
+            "video/vp8" => Some(Codec::Vp8),
+            "video/vp9" => Some(Codec::Vp9),
+            "audio/opus" => Some(Codec::Opus),
+            _ => None,
+        }
+    }
+
+    fn codec_id(self) -> &'static str {
+        match self {
+            Codec::H264 => "V_MPEG4/ISO/AVC",
+            Codec::Vp8 => "V_VP8",
+            Codec::Vp9 => "V_VP9",
+            Codec::Opus => "A_OPUS",
+        }
+    }
+
+    fn is_keyframe(self, data: &[u8]) -> bool {
+        match self {
+            Codec::H264 => nal_units(data).any(|nal| nal.first().is_some_and(|header| header & 0x1f == 5)),
+            // RFC 6386 9.1: the inverse of the first bit of the frame tag.
+            Codec::Vp8 => data.first().is_some_and(|tag| tag & 1 == 0),
+            Codec::Vp9 => vp9_is_keyframe(data),
+            Codec::Opus => true,
+        }
+    }
+}
+
+/// NAL units in length-prefixed (AVC) format, as the depacketizer is asked to produce.
+fn nal_units(mut data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let length = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+        let nal = data.get(4..4 + length)?;
+        data = &data[4 + length..];
+        Some(nal)
+    })
+}
+
+/// VP9 bitstream 6.2: frame_type in the uncompressed header.
+fn vp9_is_keyframe(data: &[u8]) -> bool {
+    let header = match data.first() {
+        Some(header) if header >> 6 == 2 => *header,
+        _ => return false,
+    };
+    let profile = (header >> 5 & 1) | (header >> 3 & 2);
+    // Bit holding show_existing_frame, which profile 3 precedes with a reserved bit.
+    let show_existing_frame = if profile == 3 { 2 } else { 3 };
+    header >> show_existing_frame & 1 == 0 && header >> (show_existing_frame - 1) & 1 == 0
+}
+
+/// AVCDecoderConfigurationRecord (ISO 14496-15 5.2.4.1) for one SPS and PPS.
+fn avc_config(sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let mut config = vec![1, sps[1], sps[2], sps[3], 0xff, 0xe1];
+    config.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    config.extend_from_slice(sps);
+    config.push(1);
+    config.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    config.extend_from_slice(pps);
+    config
+}
+
+/// RFC 7845 5.1 identification header, which Matroska keeps as the codec private data.
+fn opus_head(channels: u16, sample_rate: u32) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, channels as u8]);
+    head.extend_from_slice(&0u16.to_le_bytes());
+    head.extend_from_slice(&sample_rate.to_le_bytes());
+    head.extend_from_slice(&[0, 0, 0]);
+    head
+}
+
+/// Maps a track's RTP timestamps onto the wall clock, anchored at its first sample.
+struct RtpClock {
+    rate: u32,
+    last: u32,
+    ticks: i64,
+    anchor: SystemTime,
+}
+
+impl RtpClock {
+    fn new(rate: u32, timestamp: u32, anchor: SystemTime) -> Self {
+        RtpClock { rate: rate.max(1), last: timestamp, ticks: 0, anchor }
+    }
+
+    fn time(&mut self, timestamp: u32) -> SystemTime {
+        // Timestamps wrap around, and may step back a little for reordered frames.
+        self.ticks += timestamp.wrapping_sub(self.last) as i32 as i64;
+        self.last = timestamp;
+        let offset = Duration::from_nanos(self.ticks.unsigned_abs() * 1_000_000_000 / self.rate as u64);
+        if self.ticks >= 0 { self.anchor + offset } else { self.anchor - offset }
+    }
+}
+
+enum Event {
+    Track { index: usize, codec: Codec, sample_rate: u32, channels: u16 },
+    Frame { index: usize, time: SystemTime, data: Bytes },
+    /// A track that cannot be recorded, so the others are not kept waiting for it.
+    Skipped,
+}
+
+/// Reads one received track, sending its frames to the session to be muxed.
+async fn read_track(index: usize, remote: Arc<TrackRemote>, events: mpsc::UnboundedSender<Event>) {
+    let parameters = remote.codec().await;
+    let codec = match Codec::from_mime_type(&parameters.capability.mime_type) {
+        Some(codec) => codec,
+        None => {
+            log::warn!("Not recording {} track, the codec is not supported", parameters.capability.mime_type);
+            let _ = events.send(Event::Skipped);
+            return;
+        }
+    };
+    let (sample_rate, channels) = (parameters.capability.clock_rate, parameters.capability.channels.max(1));
+    if events.send(Event::Track { index, codec, sample_rate, channels }).is_err() {
+        return;
+    }
+    match codec {
+        Codec::H264 => {
+            let mut depacketizer = H264Packet::default();
+            depacketizer.is_avc = true;
+            read_samples(index, &remote, depacketizer, sample_rate, &events).await
+        }
+        Codec::Vp8 => read_samples(index, &remote, Vp8Packet::default(), sample_rate, &events).await,
+        Codec::Vp9 => read_samples(index, &remote, Vp9Packet::default(), sample_rate, &events).await,
+        Codec::Opus => read_samples(index, &remote, OpusPacket, sample_rate, &events).await,
+    }
+}
+
+async fn read_samples<T: Depacketizer>(index: usize, remote: &TrackRemote, depacketizer: T, sample_rate: u32, events: &mpsc::UnboundedSender<Event>) {
+    let mut builder = SampleBuilder::new(MAX_LATE, depacketizer, sample_rate);
+    let mut clock = None;
+    while let Ok((packet, _)) = remote.read_rtp().await {
+        builder.push(packet);
+        while let Some(sample) = builder.pop() {
+            let clock = clock.get_or_insert_with(|| RtpClock::new(sample_rate, sample.packet_timestamp, sample.timestamp));
+            let frame = Event::Frame { index, time: clock.time(sample.packet_timestamp), data: sample.data };
+            if events.send(frame).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// A segment file being written: fragmented MP4 for H.264, which browsers
+/// only play from MP4, and Matroska or WebM for everything else.
+enum Muxer {
+    Mkv(mkv::Writer<BufWriter<File>>),
+    Mp4(mp4::Writer<BufWriter<File>>),
+}
+
+impl Muxer {
+    fn write(&mut self, track: u64, timecode: u64, keyframe: bool, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Muxer::Mkv(writer) => writer.write(track, timecode, keyframe, data),
+            Muxer::Mp4(writer) => writer.write(track, timecode, keyframe, data),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<BufWriter<File>> {
+        match self {
+            Muxer::Mkv(writer) => writer.finish(),
+            Muxer::Mp4(writer) => writer.finish(),
+        }
+    }
+}
+
+struct Segment {
+    writer: Muxer,
+    path: PathBuf,
+    start: SystemTime,
+}
+
+/// Cuts a camera's frames into segment files. A segment starts at a keyframe
+/// of the video track, once every track the camera sends is known.
+struct Segmenter {
+    /// The camera's directory, and its name there.
+    dir: PathBuf,
+    camera: String,
+    index: Arc<dyn Storage>,
+    length: Duration,
+    video_size: Option<(u32, u32)>,
+    expected: Option<usize>,
+    /// Tracks in the order they arrived, which is also their order in the files.
+    tracks: Vec<(usize, Codec, Media)>,
+    skipped: usize,
+    segment: Option<Segment>,
+}
+
+impl Segmenter {
+    /// Notes a segment in the index. Should that fail, it is indexed when the server next starts.
+    fn note(&self, path: &Path, size: u64) {
+        let noted = store::segment(&self.camera, path, size).map(|recording| self.index.put_recording(&recording));
+        if let Some(Err(err)) = noted {
+            log::warn!("Failed indexing {}: {}", path.display(), err);
+        }
+    }
+
+    fn track(&mut self, index: usize, codec: Codec, sample_rate: u32, channels: u16) {
+        let media = match codec {
+            Codec::Opus => Media::Audio { sample_rate, channels },
+            _ => Media::Video { size: self.video_size },
+        };
+        self.tracks.push((index, codec, media));
+    }
+
+    /// The track whose keyframes start segments: the video, if there is any.
+    fn key_track(&self) -> usize {
+        self.tracks.iter().position(|(_, _, media)| matches!(media, Media::Video { .. })).unwrap_or(0) + 1
+    }
+
+    fn frame(&mut self, index: usize, time: SystemTime, data: &[u8]) -> Result<()> {
+        let number = match self.tracks.iter().position(|(track, ..)| *track == index) {
+            Some(position) => position + 1,
+            None => return Ok(()),
+        };
+        let keyframe = self.tracks[number - 1].1.is_keyframe(data);
+
+        if number == self.key_track() && keyframe {
+            let elapsed = self.segment.as_ref().map(|segment| time.duration_since(segment.start).unwrap_or_default());
+            if elapsed.is_some_and(|elapsed| elapsed >= self.length) {
+                self.finish()?;
+            }
+            if self.segment.is_none() && self.expected.is_some_and(|expected| self.tracks.len() + self.skipped >= expected) {
+                self.open(time, data)?;
+            }
+        }
+
+        if let Some(segment) = &mut self.segment {
+            let timecode = time.duration_since(segment.start).unwrap_or_default().as_millis() as u64;
+            segment.writer.write(number as u64, timecode, keyframe, data)
+                .with_context(|| format!("Failed writing {}", segment.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Starts a segment at `keyframe`, which for H.264 must carry the parameter sets.
+    fn open(&mut self, start: SystemTime, keyframe: &[u8]) -> Result<()> {
+        let key_track = self.key_track();
+        let mut tracks = Vec::new();
+        for (number, (_, codec, media)) in (1..).zip(&self.tracks) {
+            let codec_private = match (codec, media) {
+                (Codec::Opus, Media::Audio { sample_rate, channels }) => Some(opus_head(*channels, *sample_rate)),
+                (Codec::H264, _) if number == key_track => {
+                    let sps = nal_units(keyframe).find(|nal| nal.len() > 3 && nal[0] & 0x1f == 7);
+                    let pps = nal_units(keyframe).find(|nal| !nal.is_empty() && nal[0] & 0x1f == 8);
+                    match (sps, pps) {
+                        (Some(sps), Some(pps)) => Some(avc_config(sps, pps)),
+                        _ => {
+                            log::debug!("Waiting for a keyframe with parameter sets to start recording");
+                            return Ok(());
+                        }
+                    }
+                }
+                _ => None,
+            };
+            tracks.push(Track { media: media.clone(), codec_id: codec.codec_id(), codec_private });
+        }
+
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("Failed creating {}", self.dir.display()))?;
+        let extension = if mp4::supports(&tracks) { "mp4" } else { mkv::extension(&tracks) };
+        let path = store::segment_path(&self.dir, start, extension);
+        let file = BufWriter::new(File::create(&path).with_context(|| format!("Failed creating {}", path.display()))?);
+        let writer = match extension {
+            "mp4" => Muxer::Mp4(mp4::Writer::new(file, &tracks, start)?),
+            _ => Muxer::Mkv(mkv::Writer::new(file, &tracks, start)?),
+        };
+        log::info!("Recording to {}", path.display());
+        self.note(&path, 0);
+        self.segment = Some(Segment { writer, path, start });
+        Ok(())
+    }
+
+    /// Closes the current segment, indexing it for seeking and renaming it to
+    /// record when it ended.
+    fn finish(&mut self) -> Result<()> {
+        if let Some(Segment { writer, path, .. }) = self.segment.take() {
+            writer.finish().with_context(|| format!("Failed writing {}", path.display()))?;
+            let finished = store::finished_path(&path, SystemTime::now());
+            std::fs::rename(&path, &finished).with_context(|| format!("Failed renaming {}", path.display()))?;
+            if let Some(unfinished) = store::segment(&self.camera, &path, 0) {
+                if let Err(err) = self.index.remove_recording(&unfinished.file) {
+                    log::warn!("Failed indexing {}: {}", finished.display(), err);
+                }
+            }
+            self.note(&finished, std::fs::metadata(&finished).map(|metadata| metadata.len()).unwrap_or(0));
+        }
+        Ok(())
+    }
+}
+
+/// Records `camera` until it hangs up or the recorder stops.
+pub async fn run(
+    camera: common::Camera,
+    recorder: Uuid,
+    api: &API,
+    peers: &Arc<Registry>,
+    settings: &Settings,
+    index: Arc<dyn Storage>,
+    mut signals: mpsc::UnboundedReceiver<common::Signal>,
+) -> Result<()> {
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut next_index = 0;
+    let receiver = Receiver::offer(api, peers, recorder, camera.id, 1, Box::new(move |remote: Option<Arc<TrackRemote>>, _| {
+        let events = events_tx.clone();
+        let index = next_index;
+        next_index += 1;
+        Box::pin(async move {
+            if let Some(remote) = remote {
+                tokio1::spawn(read_track(index, remote, events));
+            }
+        })
+    })).await?;
+
+    let main = camera.descriptor.stream(common::MAIN_STREAM);
+    let dir = store::camera_dir(&camera.descriptor.name);
+    let mut segmenter = Segmenter {
+        dir: settings.dir.join(&dir),
+        camera: dir,
+        index,
+        length: settings.segment,
+        video_size: main.map(|stream| (stream.width, stream.height)).filter(|&(width, height)| width > 0 && height > 0),
+        expected: None,
+        tracks: Vec::new(),
+        skipped: 0,
+        segment: None,
+    };
+    let result = loop {
+        tokio1::select! {
+            signal = signals.recv() => match signal {
+                Some(common::Signal::Hangup) | None => break Ok(()),
+                Some(signal) => match receiver.apply(signal).await {
+                    Ok(Some(expected)) => segmenter.expected = Some(expected),
+                    Ok(None) => {}
+                    Err(err) => break Err(err),
+                },
+            },
+            Some(event) = events.recv() => {
+                let handled = match event {
+                    Event::Track { index, codec, sample_rate, channels } => {
+                        segmenter.track(index, codec, sample_rate, channels);
+                        Ok(())
+                    }
+                    Event::Frame { index, time, data } => segmenter.frame(index, time, &data),
+                    Event::Skipped => {
+                        segmenter.skipped += 1;
+                        Ok(())
+                    }
+                };
+                if let Err(err) = handled {
+                    break Err(err);
+                }
+            }
+        }
+    };
+    receiver.close().await;
+    segmenter.finish()?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_keyframes() {
+        let idr = [&[0, 0, 0, 2, 0x67, 0x42][..], &[0, 0, 0, 2, 0x68, 0xce], &[0, 0, 0, 2, 0x65, 0x88]].concat();
+        assert!(Codec::H264.is_keyframe(&idr));
+        assert!(!Codec::H264.is_keyframe(&[0, 0, 0, 2, 0x41, 0x9a]));
+        assert!(Codec::Vp8.is_keyframe(&[0x10]));
+        assert!(!Codec::Vp8.is_keyframe(&[0x11]));
+        // Profile 0 key and inter frames, then a profile 3 keyframe.
+        assert!(Codec::Vp9.is_keyframe(&[0x80]));
+        assert!(!Codec::Vp9.is_keyframe(&[0x84]));
+        assert!(Codec::Vp9.is_keyframe(&[0xb0]));
+    }
+
+    #[test]
+    fn rtp_clock_unwraps_timestamps() {
+        let anchor = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let mut clock = RtpClock::new(90000, u32::MAX - 44999, anchor);
+        assert_eq!(clock.time(45000), anchor + Duration::from_secs(1));
+        assert_eq!(clock.time(0), anchor + Duration::from_millis(500));
+        assert_eq!(clock.time(u32::MAX - 89999), anchor - Duration::from_millis(500));
+    }
+}
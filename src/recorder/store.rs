@@ -0,0 +1,326 @@
+//! Segment files on disk, and the retention policy that removes them.
+//!
+//! Each camera records into its own directory. A segment being written is
+//! named after its start time, `<start>.<ext>`, and renamed to
+//! `<start>-<end>.<ext>` once finished, with both times in milliseconds
+//! since the Unix epoch. Segments are indexed in the server's [`Storage`] as
+//! they are written and removed, and the index is brought back in line with
+//! the directories when the server starts. Events that happened while
+//! recording are kept beside the segments in `events.jsonl`, one JSON object
+//! per line.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::storage::{Storage, StorageError};
+
+const EVENTS_FILE: &str = "events.jsonl";
+/// Segments less than this far apart are shown as one span, hiding the
+/// moment between one segment closing and the next opening.
+const SPAN_GAP: u64 = 2000;
+
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// Segments that ended longer ago than this are removed.
+    pub max_age: Option<Duration>,
+    /// Once all recordings together exceed this many bytes, the oldest segments are removed.
+    pub quota: Option<u64>,
+}
+
+pub fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Directory name for a camera, keeping it to characters safe in any file system and URL.
+pub fn camera_dir(name: &str) -> String {
+    let dir = name.trim().chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect::<String>();
+    if dir.is_empty() { "camera".to_string() } else { dir }
+}
+
+pub fn segment_path(dir: &Path, start: SystemTime, extension: &str) -> PathBuf {
+    dir.join(format!("{}.{}", millis(start), extension))
+}
+
+/// Name a segment is given once it is complete.
+pub fn finished_path(path: &Path, end: SystemTime) -> PathBuf {
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    path.with_file_name(format!("{}-{}.{}", stem, millis(end), extension))
+}
+
+fn index_error(err: StorageError) -> io::Error {
+    io::Error::other(err)
+}
+
+/// Start and end times from a segment's file name.
+fn parse_name(name: &str) -> Option<(u64, Option<u64>)> {
+    let (stem, extension) = name.rsplit_once('.')?;
+    if !matches!(extension, "webm" | "mkv" | "mp4") {
+        return None;
+    }
+    match stem.split_once('-') {
+        Some((start, end)) => Some((start.parse().ok()?, Some(end.parse().ok()?))),
+        None => Some((stem.parse().ok()?, None)),
+    }
+}
+
+/// The segment at `path` in `camera`'s directory as the index has it, if it is named like one.
+pub fn segment(camera: &str, path: &Path, size: u64) -> Option<common::Recording> {
+    let name = path.file_name()?.to_str()?;
+    let (start, end) = parse_name(name)?;
+    Some(common::Recording { camera: camera.to_string(), file: format!("{}/{}", camera, name), start, end, size })
+}
+
+/// Segments in one camera's directory, in no particular order.
+fn list_camera(dir: &Path, camera: &str, recordings: &mut Vec<common::Recording>) -> io::Result<()> {
+    for file in std::fs::read_dir(dir)? {
+        let file = file?;
+        if let Some(recording) = segment(camera, &file.path(), file.metadata()?.len()) {
+            recordings.push(recording);
+        }
+    }
+    Ok(())
+}
+
+/// Every segment under `root`, oldest first.
+fn list(root: &Path) -> io::Result<Vec<common::Recording>> {
+    let mut recordings = Vec::new();
+    let cameras = match std::fs::read_dir(root) {
+        Ok(cameras) => cameras,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(recordings),
+        Err(err) => return Err(err),
+    };
+    for camera in cameras {
+        let camera = camera?;
+        if !camera.file_type()?.is_dir() {
+            continue;
+        }
+        list_camera(&camera.path(), &camera.file_name().to_string_lossy(), &mut recordings)?;
+    }
+    recordings.sort_by(|a, b| (a.start, &a.camera).cmp(&(b.start, &b.camera)));
+    Ok(recordings)
+}
+
+/// Indexes the segments under `root` the index is missing, such as those
+/// recorded before it existed, and forgets those whose files are gone.
+pub fn reindex(root: &Path, index: &dyn Storage) -> io::Result<()> {
+    let on_disk = list(root)?;
+    let indexed = index.recordings(None, 0, u64::MAX).map_err(index_error)?;
+    for recording in &on_disk {
+        if !indexed.contains(recording) {
+            index.put_recording(recording).map_err(index_error)?;
+        }
+    }
+    for recording in &indexed {
+        if !on_disk.iter().any(|segment| segment.file == recording.file) {
+            index.remove_recording(&recording.file).map_err(index_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// Events recorded for a camera, skipping lines that can't be read.
+fn events(dir: &Path) -> io::Result<Vec<common::RecordingEvent>> {
+    let text = match std::fs::read_to_string(dir.join(EVENTS_FILE)) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut events = text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect::<Vec<common::RecordingEvent>>();
+    events.sort_by_key(|event| event.time);
+    Ok(events)
+}
+
+/// Notes an event in a camera's recording.
+pub fn append_event(dir: &Path, event: &common::RecordingEvent) -> io::Result<()> {
+    use std::io::Write;
+    std::fs::create_dir_all(dir)?;
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(dir.join(EVENTS_FILE))?;
+    let line = serde_json::to_string(event).map_err(io::Error::from)?;
+    writeln!(file, "{}", line)
+}
+
+/// Drops events from before `oldest`, leaving the file alone if there are none.
+fn prune_events(dir: &Path, oldest: u64) -> io::Result<()> {
+    let events = events(dir)?;
+    if events.first().is_none_or(|event| event.time >= oldest) {
+        return Ok(());
+    }
+    let kept = events.iter()
+        .filter(|event| event.time >= oldest)
+        .map(|event| serde_json::to_string(event).map(|line| line + "\n"))
+        .collect::<Result<String, _>>()
+        .map_err(io::Error::from)?;
+    std::fs::write(dir.join(EVENTS_FILE), kept)
+}
+
+/// What was recorded of `camera` between `from` and `to`, or `None` if it has no recordings.
+/// Segments still being written are taken to run until `now`.
+pub fn timeline(root: &Path, index: &dyn Storage, camera: &str, from: Option<u64>, to: Option<u64>, now: SystemTime) -> io::Result<Option<common::Timeline>> {
+    // Anything camera_dir would change could reach outside the recordings.
+    if camera_dir(camera) != camera {
+        return Ok(None);
+    }
+    let dir = root.join(camera);
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    let (from, to, now) = (from.unwrap_or(0), to.unwrap_or(u64::MAX), millis(now));
+
+    let segments = index.recordings(Some(camera), from, to).map_err(index_error)?;
+
+    let mut spans: Vec<common::Span> = Vec::new();
+    for segment in &segments {
+        let end = segment.end.unwrap_or(now).max(segment.start);
+        match spans.last_mut() {
+            Some(span) if segment.start <= span.end + SPAN_GAP => span.end = span.end.max(end),
+            _ => spans.push(common::Span { start: segment.start, end }),
+        }
+    }
+
+    let mut events = events(&dir)?;
+    events.retain(|event| (from..to).contains(&event.time));
+    Ok(Some(common::Timeline { camera: camera.to_string(), spans, segments, events }))
+}
+
+/// Removes finished segments the policy no longer keeps, returning their paths.
+/// Segments still being written are never removed, though they count towards the quota.
+/// Events older than the age limit are dropped too.
+pub fn enforce(root: &Path, index: &dyn Storage, retention: &Retention, now: SystemTime) -> io::Result<Vec<PathBuf>> {
+    let mut recordings = index.recordings(None, 0, u64::MAX).map_err(index_error)?;
+    // The index only learns a segment's size once it is finished.
+    for recording in recordings.iter_mut().filter(|recording| recording.end.is_none()) {
+        recording.size = std::fs::metadata(root.join(&recording.file)).map(|metadata| metadata.len()).unwrap_or(0);
+    }
+    let mut total = recordings.iter().map(|recording| recording.size).sum::<u64>();
+    let oldest_kept = retention.max_age.and_then(|max_age| now.checked_sub(max_age)).map(millis);
+    let mut removed = Vec::new();
+    for recording in &recordings {
+        let end = match recording.end {
+            Some(end) => end,
+            None => continue,
+        };
+        let expired = oldest_kept.is_some_and(|oldest_kept| end < oldest_kept);
+        let over_quota = retention.quota.is_some_and(|quota| total > quota);
+        if !expired && !over_quota {
+            continue;
+        }
+        let path = root.join(&recording.file);
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        index.remove_recording(&recording.file).map_err(index_error)?;
+        total -= recording.size;
+        removed.push(path);
+    }
+    if let Some(oldest_kept) = oldest_kept {
+        for camera in std::fs::read_dir(root)? {
+            let camera = camera?;
+            if camera.file_type()?.is_dir() {
+                prune_events(&camera.path(), oldest_kept)?;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn recordings() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("rstream-recordings-{}", uuid::Uuid::new_v4()));
+        for (camera, name, size) in [("Porch", "1000-2000.webm", 10), ("Porch", "2000-3000.webm", 10), ("Yard", "1500-2500.mkv", 10), ("Porch", "3000.webm", 10)] {
+            std::fs::create_dir_all(root.join(camera)).unwrap();
+            std::fs::write(root.join(camera).join(name), vec![0; size]).unwrap();
+        }
+        std::fs::write(root.join("Porch").join("notes.txt"), b"ignored").unwrap();
+        std::fs::write(root.join("Porch").join(EVENTS_FILE), "{\"time\":2500,\"kind\":\"motion\"}\nnot json\n{\"time\":1200,\"kind\":\"motion\"}\n").unwrap();
+        root
+    }
+
+    #[test]
+    fn names_segments() {
+        let start = UNIX_EPOCH + Duration::from_millis(1000);
+        let path = segment_path(Path::new("rec/Back_door"), start, "webm");
+        assert_eq!(path, Path::new("rec/Back_door/1000.webm"));
+        assert_eq!(finished_path(&path, start + Duration::from_secs(1)), Path::new("rec/Back_door/1000-2000.webm"));
+        assert_eq!(camera_dir("Back door/1"), "Back_door_1");
+    }
+
+    /// The segments under `root`, indexed.
+    fn index(root: &Path) -> MemoryStorage {
+        let index = MemoryStorage::default();
+        reindex(root, &index).unwrap();
+        index
+    }
+
+    fn files(index: &dyn Storage) -> Vec<String> {
+        index.recordings(None, 0, u64::MAX).unwrap().into_iter().map(|recording| recording.file).collect()
+    }
+
+    #[test]
+    fn indexes_segments_on_disk() {
+        let root = recordings();
+        let index = index(&root);
+        assert_eq!(files(&index), vec!["Porch/1000-2000.webm", "Yard/1500-2500.mkv", "Porch/2000-3000.webm", "Porch/3000.webm"]);
+        assert_eq!(index.recordings(Some("Porch"), 3000, 4000).unwrap()[0].end, None);
+
+        // Segments removed behind the server's back are forgotten, and finished ones updated.
+        std::fs::remove_file(root.join("Yard/1500-2500.mkv")).unwrap();
+        std::fs::rename(root.join("Porch/3000.webm"), root.join("Porch/3000-4000.webm")).unwrap();
+        reindex(&root, &index).unwrap();
+        assert_eq!(files(&index), vec!["Porch/1000-2000.webm", "Porch/2000-3000.webm", "Porch/3000-4000.webm"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn builds_timelines() {
+        let root = recordings();
+        let index = index(&root);
+        let now = UNIX_EPOCH + Duration::from_millis(3500);
+        let timeline = |camera, from, to| timeline(&root, &index, camera, from, to, now);
+
+        let all = timeline("Porch", None, None).unwrap().unwrap();
+        assert_eq!(all.spans, vec![common::Span { start: 1000, end: 3500 }]);
+        assert_eq!(all.segments.len(), 3);
+        assert_eq!(all.events.iter().map(|event| event.time).collect::<Vec<_>>(), vec![1200, 2500]);
+        assert_eq!(all.segment_at(3200).map(|segment| segment.file.as_str()), Some("Porch/3000.webm"));
+
+        let later = timeline("Porch", Some(2000), Some(3000)).unwrap().unwrap();
+        assert_eq!(later.segments.iter().map(|segment| segment.file.as_str()).collect::<Vec<_>>(), vec!["Porch/2000-3000.webm"]);
+        assert_eq!(later.events.len(), 1);
+
+        append_event(&root.join("Porch"), &common::RecordingEvent { time: 3100, kind: "motion".to_string() }).unwrap();
+        assert_eq!(timeline("Porch", Some(3000), None).unwrap().unwrap().events.len(), 1);
+
+        assert_eq!(timeline("Garage", None, None).unwrap(), None);
+        assert_eq!(timeline("../Porch", None, None).unwrap(), None);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn removes_old_segments_then_oldest_over_quota() {
+        let root = recordings();
+        let index = index(&root);
+        let now = UNIX_EPOCH + Duration::from_millis(4000);
+
+        let by_age = Retention { max_age: Some(Duration::from_millis(1500)), quota: None };
+        assert_eq!(enforce(&root, &index, &by_age, now).unwrap(), vec![root.join("Porch/1000-2000.webm")]);
+        assert_eq!(events(&root.join("Porch")).unwrap(), vec![common::RecordingEvent { time: 2500, kind: "motion".to_string() }]);
+
+        let by_size = Retention { max_age: None, quota: Some(15) };
+        assert_eq!(enforce(&root, &index, &by_size, now).unwrap(), vec![root.join("Yard/1500-2500.mkv"), root.join("Porch/2000-3000.webm")]);
+        assert_eq!(list(&root).unwrap().len(), 1);
+        assert_eq!(files(&index), vec!["Porch/3000.webm"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use uuid::Uuid;
+
+use crate::PeerMsg;
+use crate::queue::{OutboundQueue, QueueError};
+use crate::snapshot::Snapshot;
+
+const DEFAULT_SHARDS: usize = 64;
+/// Signalling errors kept for the admin pages.
+const RECENT_ERRORS: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RegistryError {
+    #[error("peer {0} is already registered")]
+    AlreadyRegistered(Uuid),
+    #[error("unknown recipient {0}")]
+    UnknownPeer(Uuid),
+    #[error("camera {camera} has no {stream:?} stream")]
+    UnknownStream { camera: Uuid, stream: String },
+    #[error("not allowed to watch camera {0}")]
+    NotGranted(Uuid),
+    #[error("not allowed to stream as camera {0:?}")]
+    MayNotStream(String),
+    #[error("peer {0} has already registered as a camera")]
+    AlreadyCamera(Uuid),
+    #[error("a camera named {0:?} is already connected")]
+    NameTaken(String),
+    #[error(transparent)]
+    Queue(#[from] QueueError),
+}
+
+/// A connected peer as seen by the rest of the server.
+pub struct Peer {
+    pub id: Uuid,
+    pub queue: OutboundQueue,
+    pub connected_at: Instant,
+    /// Address the peer connected from, when known.
+    pub remote: Option<IpAddr>,
+    /// API user the peer connected as, if it gave a token.
+    pub user: Option<String>,
+    partners: Mutex<HashSet<Uuid>>,
+    camera: Mutex<Option<common::CameraDescriptor>>,
+    snapshot: Mutex<Option<Snapshot>>,
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Peer {
+    pub fn new(id: Uuid, queue_capacity: usize) -> Self {
+        Peer {
+            id,
+            queue: OutboundQueue::new(queue_capacity),
+            connected_at: Instant::now(),
+            remote: None,
+            user: None,
+            partners: Mutex::new(HashSet::new()),
+            camera: Mutex::new(None),
+            snapshot: Mutex::new(None),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+        }
+    }
+
+    /// Set once the peer has registered as a camera.
+    pub fn camera(&self) -> Option<common::CameraDescriptor> {
+        self.camera.lock().unwrap().clone()
+    }
+
+    pub fn set_camera(&self, descriptor: common::CameraDescriptor) {
+        *self.camera.lock().unwrap() = Some(descriptor);
+    }
+
+    /// The latest preview still, for cameras that send them.
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    pub fn set_snapshot(&self, snapshot: Snapshot) {
+        *self.snapshot.lock().unwrap() = Some(snapshot);
+    }
+
+    pub fn count_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes received from the peer so far.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent to the peer so far.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Peers this peer has exchanged signals with.
+    pub fn partners(&self) -> Vec<Uuid> {
+        self.partners.lock().unwrap().iter().copied().collect()
+    }
+}
+
+type Shard = RwLock<HashMap<Uuid, Arc<Peer>>>;
+
+/// Registry of connected peers.
+///
+/// Peers are spread over a fixed number of independently locked shards so that
+/// routing a message is an O(1) lookup touching a single shard, and no lock is
+/// ever held across an await point.
+pub struct Registry {
+    shards: Box<[Shard]>,
+    errors: Mutex<VecDeque<common::PeerError>>,
+    /// Held while a camera's name is checked and claimed, so two peers can't both take it.
+    naming: Mutex<()>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::with_shards(DEFAULT_SHARDS)
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        Registry {
+            shards: (0..shards.max(1)).map(|_| RwLock::new(HashMap::new())).collect(),
+            errors: Mutex::new(VecDeque::with_capacity(RECENT_ERRORS)),
+            naming: Mutex::new(()),
+        }
+    }
+
+    fn shard(&self, id: &Uuid) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    pub fn register(&self, peer: Arc<Peer>) -> Result<(), RegistryError> {
+        match self.shard(&peer.id).write().unwrap().entry(peer.id) {
+            Entry::Occupied(_) => Err(RegistryError::AlreadyRegistered(peer.id)),
+            Entry::Vacant(entry) => {
+                entry.insert(peer);
+                Ok(())
+            }
+        }
+    }
+
+    /// Makes a peer the camera `descriptor` describes, once per connection and
+    /// as long as no other connected camera has its name.
+    pub fn register_camera(&self, id: &Uuid, descriptor: common::CameraDescriptor) -> Result<(), RegistryError> {
+        let peer = self.get(id).ok_or(RegistryError::UnknownPeer(*id))?;
+        let _naming = self.naming.lock().unwrap();
+        if peer.camera().is_some() {
+            return Err(RegistryError::AlreadyCamera(*id));
+        }
+        if self.cameras().iter().any(|camera| camera.descriptor.name == descriptor.name) {
+            return Err(RegistryError::NameTaken(descriptor.name));
+        }
+        peer.set_camera(descriptor);
+        Ok(())
+    }
+
+    /// Removes the peer, closes its queue and sends a `Hangup` on its behalf to
+    /// every partner it was signalling with.
+    pub fn unregister(&self, id: &Uuid) -> Option<Arc<Peer>> {
+        let peer = self.shard(id).write().unwrap().remove(id)?;
+        peer.queue.close();
+
+        let partners = peer.partners.lock().unwrap().drain().collect::<Vec<_>>();
+        for partner in partners {
+            if let Some(partner) = self.get(&partner) {
+                partner.partners.lock().unwrap().remove(id);
+                let hangup = PeerMsg { signal: common::Signal::Hangup, sender: *id, recipient: partner.id };
+                if let Err(err) = partner.queue.push(hangup) {
+                    log::debug!("Could not notify {} of {} leaving: {}", partner.id, id, err);
+                }
+            }
+        }
+        Some(peer)
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Arc<Peer>> {
+        self.shard(id).read().unwrap().get(id).cloned()
+    }
+
+    /// Queues a message for its recipient without waiting on the recipient.
+    pub fn send(&self, msg: PeerMsg) -> Result<(), RegistryError> {
+        let peer = self.get(&msg.recipient).ok_or(RegistryError::UnknownPeer(msg.recipient))?;
+        peer.queue.push(msg)?;
+        Ok(())
+    }
+
+    /// Queues a message for `via` instead of its recipient, for peers such as
+    /// the SFU that answer on another peer's behalf.
+    pub fn forward(&self, msg: PeerMsg, via: &Uuid) -> Result<(), RegistryError> {
+        let peer = self.get(via).ok_or(RegistryError::UnknownPeer(*via))?;
+        peer.queue.push(msg)?;
+        Ok(())
+    }
+
+    /// Records that two peers are in a session, so each is told when the other leaves.
+    pub fn pair(&self, a: &Uuid, b: &Uuid) {
+        if let (Some(peer_a), Some(peer_b)) = (self.get(a), self.get(b)) {
+            peer_a.partners.lock().unwrap().insert(*b);
+            peer_b.partners.lock().unwrap().insert(*a);
+        }
+    }
+
+    pub fn unpair(&self, a: &Uuid, b: &Uuid) {
+        if let Some(peer) = self.get(a) {
+            peer.partners.lock().unwrap().remove(b);
+        }
+        if let Some(peer) = self.get(b) {
+            peer.partners.lock().unwrap().remove(a);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Snapshot of every connected peer. Each shard is locked in turn, so this is
+    /// not an atomic view of the whole registry.
+    pub fn peers(&self) -> Vec<Arc<Peer>> {
+        self.shards.iter()
+            .flat_map(|shard| shard.read().unwrap().values().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Checks that `camera` is a registered camera offering `stream`.
+    pub fn check_stream(&self, camera: &Uuid, stream: &str) -> Result<(), RegistryError> {
+        let peer = self.get(camera).ok_or(RegistryError::UnknownPeer(*camera))?;
+        match peer.camera() {
+            Some(descriptor) if descriptor.stream(stream).is_some() => Ok(()),
+            _ => Err(RegistryError::UnknownStream { camera: *camera, stream: stream.to_string() }),
+        }
+    }
+
+    pub fn is_camera(&self, id: &Uuid) -> bool {
+        self.get(id).is_some_and(|peer| peer.camera().is_some())
+    }
+
+    pub fn cameras(&self) -> Vec<common::Camera> {
+        self.peers()
+            .into_iter()
+            .filter_map(|peer| peer.camera().map(|descriptor| common::Camera { id: peer.id, descriptor }))
+            .collect()
+    }
+
+    pub fn ids(&self) -> Vec<Uuid> {
+        self.shards.iter()
+            .flat_map(|shard| shard.read().unwrap().keys().copied().collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Remembers a message from `peer` that couldn't be handled, forgetting
+    /// the oldest once there are more than [`RECENT_ERRORS`].
+    pub fn report_error(&self, peer: Uuid, message: String) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(common::PeerError { time, peer, message });
+    }
+
+    /// Recent signalling errors, oldest first.
+    pub fn errors(&self) -> Vec<common::PeerError> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::CloseReason;
+
+    fn msg(recipient: Uuid) -> PeerMsg {
+        PeerMsg {
+            signal: common::Signal::Offer { sdp: String::new() },
+            sender: Uuid::nil(),
+            recipient,
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_to_registered_peers() {
+        let registry = Registry::with_shards(4);
+        let peer = Arc::new(Peer::new(Uuid::new_v4(), 4));
+        registry.register(peer.clone()).unwrap();
+
+        assert_eq!(registry.register(peer.clone()), Err(RegistryError::AlreadyRegistered(peer.id)));
+        registry.send(msg(peer.id)).unwrap();
+        assert_eq!(peer.queue.pop().await.unwrap().recipient, peer.id);
+
+        let unknown = Uuid::new_v4();
+        assert_eq!(registry.send(msg(unknown)), Err(RegistryError::UnknownPeer(unknown)));
+    }
+
+    #[tokio::test]
+    async fn unregister_closes_queue() {
+        let registry = Registry::new();
+        let peer = Arc::new(Peer::new(Uuid::new_v4(), 4));
+        registry.register(peer.clone()).unwrap();
+
+        assert!(registry.unregister(&peer.id).is_some());
+        assert!(registry.is_empty());
+        assert_eq!(peer.queue.pop().await.unwrap_err(), CloseReason::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn unregister_hangs_up_partners() {
+        let registry = Registry::new();
+        let camera = Arc::new(Peer::new(Uuid::new_v4(), 4));
+        let viewer = Arc::new(Peer::new(Uuid::new_v4(), 4));
+        registry.register(camera.clone()).unwrap();
+        registry.register(viewer.clone()).unwrap();
+        registry.pair(&viewer.id, &camera.id);
+
+        registry.unregister(&camera.id);
+
+        let msg = viewer.queue.pop().await.unwrap();
+        assert!(matches!(msg.signal, common::Signal::Hangup));
+        assert_eq!(msg.sender, camera.id);
+        assert!(viewer.partners().is_empty());
+    }
+
+    #[test]
+    fn gives_each_camera_name_to_one_peer() {
+        let registry = Registry::new();
+        let (porch, impostor) = (Arc::new(Peer::new(Uuid::new_v4(), 4)), Arc::new(Peer::new(Uuid::new_v4(), 4)));
+        registry.register(porch.clone()).unwrap();
+        registry.register(impostor.clone()).unwrap();
+        let named = |name: &str| common::CameraDescriptor { name: name.to_string(), streams: Vec::new() };
+
+        registry.register_camera(&porch.id, named("Porch")).unwrap();
+        assert_eq!(registry.register_camera(&porch.id, named("Yard")), Err(RegistryError::AlreadyCamera(porch.id)));
+        assert_eq!(registry.register_camera(&impostor.id, named("Porch")), Err(RegistryError::NameTaken("Porch".to_string())));
+        assert_eq!(porch.camera().unwrap().name, "Porch");
+
+        // The name is free again once its camera disconnects.
+        registry.unregister(&porch.id);
+        registry.register_camera(&impostor.id, named("Porch")).unwrap();
+    }
+
+    #[test]
+    fn checks_camera_streams() {
+        let registry = Registry::new();
+        let camera = Arc::new(Peer::new(Uuid::new_v4(), 4));
+        let viewer = Arc::new(Peer::new(Uuid::new_v4(), 4));
+        registry.register(camera.clone()).unwrap();
+        registry.register(viewer.clone()).unwrap();
+        let stream = common::StreamDescriptor { id: common::SUB_STREAM.to_string(), codec: "H264".to_string(), width: 320, height: 240, fps: 15 };
+        camera.set_camera(common::CameraDescriptor { name: "Porch".to_string(), streams: vec![stream] });
+
+        assert_eq!(registry.check_stream(&camera.id, common::SUB_STREAM), Ok(()));
+        assert!(matches!(registry.check_stream(&camera.id, common::MAIN_STREAM), Err(RegistryError::UnknownStream { .. })));
+        assert!(matches!(registry.check_stream(&viewer.id, common::SUB_STREAM), Err(RegistryError::UnknownStream { .. })));
+    }
+
+    #[test]
+    fn keeps_recent_errors() {
+        let registry = Registry::new();
+        let peer = Uuid::new_v4();
+        for n in 0..RECENT_ERRORS + 5 {
+            registry.report_error(peer, n.to_string());
+        }
+        let errors = registry.errors();
+        assert_eq!(errors.len(), RECENT_ERRORS);
+        assert_eq!(errors[0].message, "5");
+        assert_eq!(errors.last().unwrap().peer, peer);
+    }
+}
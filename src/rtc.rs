@@ -0,0 +1,175 @@
+//! WebRTC plumbing shared by the peers the server runs itself, such as the SFU.
+//!
+//! webrtc needs tokio 1 while the server runs on tokio 0.2, so each of these
+//! peers drives its sessions from a runtime on a thread of its own. They take
+//! part in signalling through the registry like any connected peer.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use uuid::Uuid;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::{APIBuilder, API};
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::interceptor::registry::Registry as Interceptors;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::{OnTrackHdlrFn, RTCPeerConnection};
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType};
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+
+use crate::PeerMsg;
+use crate::registry::{Peer, Registry};
+
+/// The codecs cameras send: webrtc's defaults plus H.265, under the same
+/// payload type the camera client registers it with.
+pub fn api() -> Result<API> {
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs()?;
+    let capability = RTCRtpCodecCapability { mime_type: "video/H265".to_owned(), clock_rate: 90000, ..Default::default() };
+    media_engine.register_codec(RTCRtpCodecParameters { capability, payload_type: 118, ..Default::default() }, RTPCodecType::Video)?;
+    let registry = register_default_interceptors(Interceptors::new(), &mut media_engine)?;
+    Ok(APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_interceptor_registry(registry)
+        .build())
+}
+
+/// Registers `peer` and runs `task` on a tokio 1 runtime in a thread named
+/// `name`, unregistering the peer once the task returns.
+pub fn spawn<F>(name: &str, peers: Arc<Registry>, peer: Arc<Peer>, task: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let runtime = tokio1::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name(name)
+        .build()
+        .with_context(|| format!("Failed starting {} runtime", name))?;
+    peers.register(peer.clone())?;
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            runtime.block_on(task);
+            peers.unregister(&peer.id);
+        })
+        .with_context(|| format!("Failed starting {} thread", name))?;
+    Ok(())
+}
+
+/// Signals our ICE candidates to `recipient` as they are gathered, sent as if from `sender`.
+pub fn send_candidates(pc: &RTCPeerConnection, peers: Arc<Registry>, sender: Uuid, recipient: Uuid) {
+    pc.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+        let peers = peers.clone();
+        Box::pin(async move {
+            if let Some(candidate) = candidate.and_then(|candidate| candidate.to_json().ok()) {
+                let candidate = common::IceCandidate {
+                    candidate: candidate.candidate,
+                    sdp_mid: candidate.sdp_mid,
+                    sdp_m_line_index: candidate.sdp_mline_index,
+                };
+                let _ = peers.send(PeerMsg { signal: common::Signal::NewIceCandidate { candidate }, sender, recipient });
+            }
+        })
+    }));
+}
+
+pub fn candidate_init(candidate: common::IceCandidate) -> RTCIceCandidateInit {
+    RTCIceCandidateInit {
+        candidate: candidate.candidate,
+        sdp_mid: candidate.sdp_mid,
+        sdp_mline_index: candidate.sdp_m_line_index,
+        username_fragment: None,
+    }
+}
+
+/// A receive-only session with a camera, offered by one of the server's peers.
+pub struct Receiver {
+    pc: RTCPeerConnection,
+    camera: Uuid,
+}
+
+impl Receiver {
+    /// Offers to receive audio and `video` video tracks from `camera`, handing
+    /// each track it sends to `on_track`. Cameras send one stream per video
+    /// section offered, the main stream first.
+    pub async fn offer(api: &API, peers: &Arc<Registry>, sender: Uuid, camera: Uuid, video: usize, on_track: OnTrackHdlrFn) -> Result<Self> {
+        let receiver = Receiver { pc: api.new_peer_connection(RTCConfiguration::default()).await?, camera };
+        if let Err(err) = receiver.negotiate(peers, sender, video, on_track).await {
+            receiver.close().await;
+            return Err(err);
+        }
+        Ok(receiver)
+    }
+
+    async fn negotiate(&self, peers: &Arc<Registry>, sender: Uuid, video: usize, on_track: OnTrackHdlrFn) -> Result<()> {
+        send_candidates(&self.pc, peers.clone(), sender, self.camera);
+        self.pc.on_track(on_track);
+        let kinds = std::iter::repeat_n(RTPCodecType::Video, video.max(1)).chain([RTPCodecType::Audio]);
+        for kind in kinds {
+            let init = RTCRtpTransceiverInit { direction: RTCRtpTransceiverDirection::Recvonly, send_encodings: Vec::new() };
+            self.pc.add_transceiver_from_kind(kind, &[init]).await?;
+        }
+        let offer = self.pc.create_offer(None).await?;
+        self.pc.set_local_description(offer.clone()).await?;
+        peers.send(PeerMsg { signal: common::Signal::Offer { sdp: offer.sdp }, sender, recipient: self.camera })?;
+        Ok(())
+    }
+
+    /// Applies a signal from the camera. Once it answers, returns how many
+    /// tracks it is going to send.
+    pub async fn apply(&self, signal: common::Signal) -> Result<Option<usize>> {
+        match signal {
+            common::Signal::Answer { sdp } => {
+                let expected = sending_sections(&sdp)?;
+                anyhow::ensure!(expected > 0, "camera answered without any tracks");
+                self.pc.set_remote_description(RTCSessionDescription::answer(sdp)?).await?;
+                Ok(Some(expected))
+            }
+            common::Signal::NewIceCandidate { candidate } => {
+                self.pc.add_ice_candidate(candidate_init(candidate)).await?;
+                Ok(None)
+            }
+            signal => {
+                log::debug!("Ignoring {:?} from camera {}", signal, self.camera);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn pc(&self) -> &RTCPeerConnection {
+        &self.pc
+    }
+
+    pub async fn close(&self) {
+        if let Err(err) = self.pc.close().await {
+            log::warn!("Failed closing session with camera {}: {}", self.camera, err);
+        }
+    }
+}
+
+/// Number of audio and video sections in an answer that the camera will send on.
+fn sending_sections(answer: &str) -> Result<usize> {
+    let description = common::sdp::SessionDescription::parse(answer)?;
+    Ok(description.media.iter()
+        .filter(|section| matches!(section.kind.as_str(), "audio" | "video") && section.port != "0")
+        .filter(|section| section.has_attribute("sendonly") || section.has_attribute("sendrecv"))
+        .count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_sections_the_camera_sends() {
+        let answer = "v=0\r\no=- 1 1 IN IP4 0.0.0.0\r\ns=-\r\nt=0 0\r\n\
+            m=video 9 UDP/TLS/RTP/SAVPF 102\r\na=rtpmap:102 H264/90000\r\na=sendonly\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\na=inactive\r\n\
+            m=audio 0 UDP/TLS/RTP/SAVPF 0\r\na=sendonly\r\n";
+        assert_eq!(sending_sections(answer).unwrap(), 1);
+    }
+}
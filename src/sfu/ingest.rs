@@ -0,0 +1,174 @@
+//! The SFU's session with a camera, receiving each of its streams once for
+//! every viewer.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio1::sync::{mpsc, watch};
+use uuid::Uuid;
+use webrtc::api::API;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::TrackLocalWriter;
+use webrtc::track::track_remote::TrackRemote;
+use webrtc::util::MarshalSize;
+
+use super::Ended;
+use crate::PeerMsg;
+use crate::registry::Registry;
+use crate::rtc::Receiver;
+
+/// Keyframe requests from viewers are passed on to the camera at most this
+/// often for each stream.
+const KEYFRAME_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A track the camera sends, forwarded to whichever viewers are bound to it.
+#[derive(Clone)]
+pub struct Forwarded {
+    pub kind: RTPCodecType,
+    /// For video, the camera stream it carries, from the `video-<stream>`
+    /// track id cameras use.
+    pub stream: Option<String>,
+    /// The camera's SSRC, which keyframe requests are addressed to.
+    pub ssrc: u32,
+    pub track: Arc<TrackLocalStaticRTP>,
+}
+
+/// What viewers share of an ingest: the forwarded tracks, once every track the
+/// camera answered with has arrived, and a way to ask for a keyframe.
+#[derive(Clone)]
+pub struct Feed {
+    pub tracks: watch::Receiver<Option<Vec<Forwarded>>>,
+    keyframes: mpsc::UnboundedSender<u32>,
+}
+
+impl Feed {
+    /// Asks the camera for a keyframe on the track it sends as `ssrc`.
+    pub fn request_keyframe(&self, ssrc: u32) {
+        let _ = self.keyframes.send(ssrc);
+    }
+}
+
+pub struct Ingest {
+    signals: mpsc::UnboundedSender<common::Signal>,
+    feed: Feed,
+}
+
+impl Ingest {
+    /// Offers to receive from `camera` and forwards whatever it sends.
+    pub fn start(camera: Uuid, sfu: Uuid, api: Arc<API>, peers: Arc<Registry>, ended: mpsc::UnboundedSender<Ended>) -> Self {
+        let (signals, rx) = mpsc::unbounded_channel();
+        let (tracks_tx, tracks) = watch::channel(None);
+        let (keyframes, keyframes_rx) = mpsc::unbounded_channel();
+        let feed = Feed { tracks, keyframes };
+        tokio1::spawn(async move {
+            if let Err(err) = run(camera, sfu, &api, &peers, rx, tracks_tx, keyframes_rx).await {
+                log::warn!("SFU session with camera {} failed: {:#}", camera, err);
+            }
+            let _ = peers.send(PeerMsg { signal: common::Signal::Hangup, sender: sfu, recipient: camera });
+            let _ = ended.send(Ended::Ingest(camera));
+        });
+        Ingest { signals, feed }
+    }
+
+    pub fn feed(&self) -> Feed {
+        self.feed.clone()
+    }
+
+    pub fn signal(&self, signal: common::Signal) {
+        let _ = self.signals.send(signal);
+    }
+
+    pub fn close(&self) {
+        self.signal(common::Signal::Hangup);
+    }
+}
+
+/// Tracks received so far, and how many the camera said it would send.
+#[derive(Default)]
+struct Received {
+    expected: Option<usize>,
+    tracks: Vec<Forwarded>,
+}
+
+async fn run(
+    camera: Uuid,
+    sfu: Uuid,
+    api: &API,
+    peers: &Arc<Registry>,
+    mut signals: mpsc::UnboundedReceiver<common::Signal>,
+    tracks: watch::Sender<Option<Vec<Forwarded>>>,
+    mut keyframes: mpsc::UnboundedReceiver<u32>,
+) -> Result<()> {
+    let received = Arc::new(Mutex::new(Received::default()));
+    let tracks = Arc::new(tracks);
+
+    // A video section for every stream, so viewers can be switched between
+    // them without the camera's uplink changing.
+    let streams = peers.get(&camera).and_then(|peer| peer.camera()).map_or(1, |descriptor| descriptor.streams.len());
+    let track_state = (received.clone(), tracks.clone(), peers.get(&camera));
+    let receiver = Receiver::offer(api, peers, sfu, camera, streams, Box::new(move |remote: Option<Arc<TrackRemote>>, _| {
+        let (received, tracks, peer) = track_state.clone();
+        Box::pin(async move {
+            if let Some(remote) = remote {
+                let id = remote.id().await;
+                let local = Arc::new(TrackLocalStaticRTP::new(remote.codec().await.capability, id.clone(), format!("rstream-{}", camera)));
+                let stream = (remote.kind() == RTPCodecType::Video).then(|| id.strip_prefix("video-").unwrap_or(&id).to_string());
+                log::info!("SFU receiving {} {} from camera {}", local.codec().mime_type, stream.as_deref().unwrap_or("audio"), camera);
+                {
+                    let mut received = received.lock().unwrap();
+                    received.tracks.push(Forwarded { kind: remote.kind(), stream, ssrc: remote.ssrc(), track: local.clone() });
+                    publish(&received, &tracks);
+                }
+                tokio1::spawn(async move {
+                    while let Ok((packet, _)) = remote.read_rtp().await {
+                        if let Some(peer) = &peer {
+                            peer.count_received(packet.marshal_size());
+                        }
+                        if let Err(err) = local.write_rtp(&packet).await {
+                            log::debug!("Failed forwarding RTP from camera {}: {}", camera, err);
+                        }
+                    }
+                });
+            }
+        })
+    })).await?;
+
+    let mut last_keyframes = HashMap::<u32, Instant>::new();
+    let result = loop {
+        tokio1::select! {
+            signal = signals.recv() => match signal {
+                Some(common::Signal::Hangup) | None => break Ok(()),
+                Some(signal) => match receiver.apply(signal).await {
+                    Ok(Some(expected)) => {
+                        let mut received = received.lock().unwrap();
+                        received.expected = Some(expected);
+                        publish(&received, &tracks);
+                    }
+                    Ok(None) => {}
+                    Err(err) => break Err(err),
+                },
+            },
+            Some(media_ssrc) = keyframes.recv() => {
+                if last_keyframes.get(&media_ssrc).is_none_or(|last| last.elapsed() >= KEYFRAME_INTERVAL) {
+                    last_keyframes.insert(media_ssrc, Instant::now());
+                    if let Err(err) = receiver.pc().write_rtcp(&[Box::new(PictureLossIndication { sender_ssrc: 0, media_ssrc })]).await {
+                        log::debug!("Failed requesting a keyframe from camera {}: {}", camera, err);
+                    }
+                }
+            }
+        }
+    };
+    receiver.close().await;
+    result
+}
+
+/// Makes the tracks available to viewers once all of them have arrived.
+fn publish(received: &Received, tracks: &watch::Sender<Option<Vec<Forwarded>>>) {
+    if received.expected.is_some_and(|expected| received.tracks.len() >= expected) && tracks.borrow().is_none() {
+        let _ = tracks.send(Some(received.tracks.clone()));
+    }
+}
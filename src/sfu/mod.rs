@@ -0,0 +1,242 @@
+//! Optional selective forwarding unit.
+//!
+//! Instead of every viewer opening its own WebRTC session to a camera, the
+//! server opens one session per camera and forwards the RTP it receives to
+//! each viewer, so a camera's uplink carries one copy of its stream however
+//! many people are watching.
+//!
+//! The SFU takes part in signalling as an ordinary peer with its own queue.
+//! Signals that viewers address to a camera are routed to it, and it answers
+//! them on the camera's behalf. It receives every stream a camera offers, and
+//! forwards each viewer the one its `Watch` or `Constrain` picks.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio1::sync::mpsc;
+use uuid::Uuid;
+use webrtc::api::API;
+
+use crate::PeerMsg;
+use crate::queue::CloseReason;
+use crate::registry::{Peer, Registry};
+use crate::rtc;
+
+mod ingest;
+mod viewer;
+
+use ingest::Ingest;
+
+/// The SFU is sent every signal for every camera, so it buffers far more than a viewer.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// Handle to a running SFU.
+pub struct Sfu {
+    id: Uuid,
+}
+
+impl Sfu {
+    /// Registers the SFU as a peer and starts forwarding on a thread of its own.
+    pub fn start(peers: Arc<Registry>) -> Result<Self> {
+        let peer = Arc::new(Peer::new(Uuid::new_v4(), QUEUE_CAPACITY));
+        let id = peer.id;
+        let router = Router::new(id, peers.clone(), rtc::api()?);
+        rtc::spawn("sfu", peers, peer.clone(), async move { router.run(&peer).await })?;
+        log::info!("SFU running as peer {}", id);
+        Ok(Sfu { id })
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Where a signal from `sender` to `recipient` should be queued: signals
+    /// for cameras go to the SFU, unless the SFU is the one sending them.
+    pub fn route(&self, peers: &Registry, sender: Uuid, recipient: Uuid) -> Uuid {
+        if sender != self.id && peers.is_camera(&recipient) {
+            self.id
+        } else {
+            recipient
+        }
+    }
+}
+
+/// Sessions that finished on their own, so the router can clean up after them.
+#[derive(Debug)]
+enum Ended {
+    Ingest(Uuid),
+    Viewer { viewer: Uuid, camera: Uuid },
+}
+
+/// Dispatches signals from the SFU's queue to per-session tasks.
+struct Router {
+    id: Uuid,
+    peers: Arc<Registry>,
+    api: Arc<API>,
+    ingests: HashMap<Uuid, Ingest>,
+    /// Signals for each viewer's session with a camera, keyed by viewer then camera.
+    viewers: HashMap<(Uuid, Uuid), mpsc::UnboundedSender<common::Signal>>,
+    /// The stream each viewer last asked each camera for, applied when its
+    /// offer arrives, as viewers may choose before calling.
+    selections: HashMap<(Uuid, Uuid), common::Signal>,
+    ended_tx: mpsc::UnboundedSender<Ended>,
+    ended: mpsc::UnboundedReceiver<Ended>,
+}
+
+impl Router {
+    fn new(id: Uuid, peers: Arc<Registry>, api: API) -> Self {
+        let (ended_tx, ended) = mpsc::unbounded_channel();
+        Router { id, peers, api: Arc::new(api), ingests: HashMap::new(), viewers: HashMap::new(), selections: HashMap::new(), ended_tx, ended }
+    }
+
+    async fn run(mut self, peer: &Peer) {
+        loop {
+            tokio1::select! {
+                msg = peer.queue.pop() => match msg {
+                    Ok(msg) => self.dispatch(msg),
+                    Err(CloseReason::SlowConsumer) => {
+                        log::error!("SFU fell behind on signalling ({:?}), stopping", peer.queue.stats());
+                        break;
+                    }
+                    Err(CloseReason::Disconnected) => break,
+                },
+                Some(ended) = self.ended.recv() => self.ended(ended),
+            }
+        }
+        for ingest in self.ingests.values() {
+            ingest.close();
+        }
+    }
+
+    fn dispatch(&mut self, msg: PeerMsg) {
+        let PeerMsg { signal, sender, recipient } = msg;
+        if recipient == self.id {
+            if let Some(ingest) = self.ingests.get(&sender) {
+                ingest.signal(signal);
+            } else if let common::Signal::Hangup = signal {
+                // A viewer disconnected, ending every session it had.
+                let cameras = self.viewers.keys().filter(|(viewer, _)| *viewer == sender).map(|(_, camera)| *camera).collect::<Vec<_>>();
+                for camera in cameras {
+                    self.hang_up_viewer(sender, camera);
+                }
+                self.selections.retain(|(viewer, _), _| *viewer != sender);
+            }
+            return;
+        }
+
+        match signal {
+            common::Signal::Offer { sdp } => {
+                // A new offer replaces the viewer's session but keeps the camera's.
+                if let Some(old) = self.viewers.remove(&(sender, recipient)) {
+                    let _ = old.send(common::Signal::Hangup);
+                }
+                let (signals, rx) = mpsc::unbounded_channel();
+                let _ = signals.send(common::Signal::Offer { sdp });
+                let feed = self.ingest(recipient).feed();
+                let selection = self.selections.get(&(sender, recipient)).cloned();
+                self.viewers.insert((sender, recipient), signals);
+                let (api, peers, ended) = (self.api.clone(), self.peers.clone(), self.ended_tx.clone());
+                tokio1::spawn(async move {
+                    if let Err(err) = viewer::run(sender, recipient, &api, &peers, feed, selection, rx).await {
+                        log::warn!("SFU session between {} and {} failed: {:#}", sender, recipient, err);
+                        let hangup = PeerMsg { signal: common::Signal::Hangup, sender: recipient, recipient: sender };
+                        let _ = peers.send(hangup);
+                    }
+                    let _ = ended.send(Ended::Viewer { viewer: sender, camera: recipient });
+                });
+            }
+            common::Signal::Hangup => {
+                self.selections.remove(&(sender, recipient));
+                self.hang_up_viewer(sender, recipient);
+            }
+            selection @ (common::Signal::Watch { .. } | common::Signal::Constrain { .. }) => {
+                if let Some(signals) = self.viewers.get(&(sender, recipient)) {
+                    let _ = signals.send(selection.clone());
+                }
+                self.selections.insert((sender, recipient), selection);
+            }
+            signal => {
+                if let Some(signals) = self.viewers.get(&(sender, recipient)) {
+                    let _ = signals.send(signal);
+                }
+            }
+        }
+    }
+
+    /// The session receiving `camera`, started on first use.
+    fn ingest(&mut self, camera: Uuid) -> &Ingest {
+        let (id, api, peers, ended) = (self.id, &self.api, &self.peers, &self.ended_tx);
+        self.ingests.entry(camera).or_insert_with(|| {
+            log::info!("SFU receiving camera {}", camera);
+            peers.pair(&id, &camera);
+            Ingest::start(camera, id, api.clone(), peers.clone(), ended.clone())
+        })
+    }
+
+    fn hang_up_viewer(&mut self, viewer: Uuid, camera: Uuid) {
+        if let Some(signals) = self.viewers.remove(&(viewer, camera)) {
+            let _ = signals.send(common::Signal::Hangup);
+        }
+        self.release(camera);
+    }
+
+    /// Stops receiving a camera nobody is watching any more.
+    fn release(&mut self, camera: Uuid) {
+        if self.viewers.keys().any(|(_, watched)| *watched == camera) {
+            return;
+        }
+        if let Some(ingest) = self.ingests.remove(&camera) {
+            log::info!("SFU no longer receiving camera {}", camera);
+            ingest.close();
+            self.peers.unpair(&self.id, &camera);
+        }
+    }
+
+    fn ended(&mut self, ended: Ended) {
+        match ended {
+            Ended::Viewer { viewer, camera } => {
+                // The session may already have been replaced by a newer offer.
+                if self.viewers.get(&(viewer, camera)).is_some_and(mpsc::UnboundedSender::is_closed) {
+                    self.viewers.remove(&(viewer, camera));
+                    self.release(camera);
+                }
+            }
+            Ended::Ingest(camera) => {
+                if self.ingests.remove(&camera).is_none() {
+                    return;
+                }
+                self.peers.unpair(&self.id, &camera);
+                let viewers = self.viewers.keys().filter(|(_, watched)| *watched == camera).map(|(viewer, _)| *viewer).collect::<Vec<_>>();
+                for viewer in viewers {
+                    self.viewers.remove(&(viewer, camera));
+                    let hangup = PeerMsg { signal: common::Signal::Hangup, sender: camera, recipient: viewer };
+                    if let Err(err) = self.peers.send(hangup) {
+                        log::debug!("Could not tell {} that camera {} is gone: {}", viewer, camera, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_signals_for_cameras_through_the_sfu() {
+        let peers = Registry::new();
+        let camera = Arc::new(Peer::new(Uuid::new_v4(), 4));
+        let viewer = Arc::new(Peer::new(Uuid::new_v4(), 4));
+        peers.register(camera.clone()).unwrap();
+        peers.register(viewer.clone()).unwrap();
+        camera.set_camera(common::CameraDescriptor { name: "Porch".to_string(), streams: Vec::new() });
+        let sfu = Sfu { id: Uuid::new_v4() };
+
+        assert_eq!(sfu.route(&peers, viewer.id, camera.id), sfu.id);
+        assert_eq!(sfu.route(&peers, sfu.id, camera.id), camera.id);
+        assert_eq!(sfu.route(&peers, camera.id, viewer.id), viewer.id);
+        assert_eq!(sfu.route(&peers, camera.id, sfu.id), sfu.id);
+    }
+}
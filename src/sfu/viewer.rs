@@ -0,0 +1,249 @@
+//! The SFU's session with one viewer of one camera, answering for the camera.
+//!
+//! The viewer is sent one of the camera's streams, chosen as the camera would
+//! choose it, and switched to another when it sends `Watch` or `Constrain`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio1::sync::mpsc;
+use uuid::Uuid;
+use webrtc::api::API;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::track::track_local::TrackLocal;
+
+use super::ingest::{Feed, Forwarded};
+use crate::PeerMsg;
+use crate::registry::Registry;
+use crate::rtc;
+
+/// How long a viewer waits for the camera to start sending before giving up.
+const FEED_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Runs a session from the viewer's offer, which `signals` starts with. The
+/// viewer's last `Watch` or `Constrain` before it, if any, is `selection`.
+pub async fn run(
+    viewer: Uuid,
+    camera: Uuid,
+    api: &API,
+    peers: &Arc<Registry>,
+    feed: Feed,
+    selection: Option<common::Signal>,
+    mut signals: mpsc::UnboundedReceiver<common::Signal>,
+) -> Result<()> {
+    let offer = match signals.recv().await {
+        Some(common::Signal::Offer { sdp }) => sdp,
+        _ => return Ok(()),
+    };
+    let tracks = tokio1::time::timeout(FEED_TIMEOUT, wait_for_tracks(feed.clone()))
+        .await
+        .with_context(|| format!("camera {} did not start sending", camera))??;
+
+    let pc = api.new_peer_connection(RTCConfiguration::default()).await?;
+    let session = Session { viewer, camera, peers, feed: &feed, tracks: &tracks };
+    let result = session.serve(&pc, offer, selection, signals).await;
+    pc.close().await?;
+    result
+}
+
+async fn wait_for_tracks(mut feed: Feed) -> Result<Vec<Forwarded>> {
+    loop {
+        if let Some(tracks) = feed.tracks.borrow().clone() {
+            return Ok(tracks);
+        }
+        feed.tracks.changed().await.context("camera session ended")?;
+    }
+}
+
+/// The video sent to the viewer: its sender, the codec negotiated for it and
+/// the camera SSRC of the stream it is bound to.
+struct Video {
+    sender: Arc<RTCRtpSender>,
+    codec: String,
+    ssrc: Arc<AtomicU32>,
+    stream: Option<String>,
+}
+
+struct Session<'a> {
+    viewer: Uuid,
+    camera: Uuid,
+    peers: &'a Arc<Registry>,
+    feed: &'a Feed,
+    tracks: &'a [Forwarded],
+}
+
+impl Session<'_> {
+    async fn serve(
+        &self,
+        pc: &RTCPeerConnection,
+        offer: String,
+        selection: Option<common::Signal>,
+        mut signals: mpsc::UnboundedReceiver<common::Signal>,
+    ) -> Result<()> {
+        let (viewer, camera) = (self.viewer, self.camera);
+        rtc::send_candidates(pc, self.peers.clone(), camera, viewer);
+
+        let offered = offered_codecs(&offer)?;
+        pc.set_remote_description(RTCSessionDescription::offer(offer)?).await?;
+        let offered = |track: &Forwarded| {
+            let codec = codec_name(track);
+            let ok = offered.iter().any(|offered| offered.eq_ignore_ascii_case(&codec));
+            if !ok {
+                log::debug!("Viewer {} cannot receive {}", viewer, codec);
+            }
+            ok
+        };
+
+        let descriptor = self.peers.get(&camera).and_then(|peer| peer.camera());
+        let videos = self.tracks.iter().filter(|track| track.kind == RTPCodecType::Video).filter(|track| offered(track)).collect::<Vec<_>>();
+        let first = selection.as_ref()
+            .and_then(|selection| select(&videos, descriptor.as_ref(), None, selection))
+            .or_else(|| videos.iter().copied().find(|track| track.stream.as_deref() == Some(common::MAIN_STREAM)))
+            .or_else(|| videos.first().copied());
+        let mut video = None;
+        for track in self.tracks.iter().filter(|track| track.kind != RTPCodecType::Video).filter(|track| offered(track)).chain(first) {
+            let sender = pc.add_track(track.track.clone() as Arc<dyn TrackLocal + Send + Sync>).await?;
+            let ssrc = Arc::new(AtomicU32::new(track.ssrc));
+            self.forward_rtcp(sender.clone(), ssrc.clone());
+            if track.kind == RTPCodecType::Video {
+                video = Some(Video { sender, codec: codec_name(track), ssrc, stream: track.stream.clone() });
+            }
+        }
+        if videos.is_empty() {
+            log::info!("Viewer {} cannot receive any of camera {}'s video", viewer, camera);
+        }
+
+        let answer = pc.create_answer(None).await?;
+        pc.set_local_description(answer.clone()).await?;
+        self.peers.send(PeerMsg { signal: common::Signal::Answer { sdp: answer.sdp }, sender: camera, recipient: viewer })?;
+        log::info!("SFU forwarding camera {} to viewer {}", camera, viewer);
+        // A new viewer can only start decoding from a keyframe.
+        if let Some(video) = &video {
+            self.feed.request_keyframe(video.ssrc.load(Ordering::Relaxed));
+        }
+
+        while let Some(signal) = signals.recv().await {
+            match signal {
+                common::Signal::NewIceCandidate { candidate } => {
+                    pc.add_ice_candidate(rtc::candidate_init(candidate)).await?;
+                }
+                common::Signal::Hangup => break,
+                selection @ (common::Signal::Watch { .. } | common::Signal::Constrain { .. }) => {
+                    if let Some(video) = &mut video {
+                        self.switch(video, descriptor.as_ref(), &selection).await;
+                    }
+                }
+                signal => log::debug!("Ignoring {:?} from viewer {}", signal, viewer),
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes keyframe requests on to the camera for whichever stream the
+    /// sender is bound to; reading RTCP also keeps interceptors such as NACK working.
+    fn forward_rtcp(&self, sender: Arc<RTCRtpSender>, ssrc: Arc<AtomicU32>) {
+        let feed = self.feed.clone();
+        tokio1::spawn(async move {
+            while let Ok((packets, _)) = sender.read_rtcp().await {
+                let wants_keyframe = packets.iter().any(|packet| {
+                    packet.as_any().is::<PictureLossIndication>() || packet.as_any().is::<FullIntraRequest>()
+                });
+                if wants_keyframe {
+                    feed.request_keyframe(ssrc.load(Ordering::Relaxed));
+                }
+            }
+        });
+    }
+
+    /// Binds the viewer's video to the stream `selection` picks, if that is
+    /// another stream in the negotiated codec.
+    async fn switch(&self, video: &mut Video, descriptor: Option<&common::CameraDescriptor>, selection: &common::Signal) {
+        let videos = self.tracks.iter().filter(|track| track.kind == RTPCodecType::Video).collect::<Vec<_>>();
+        let track = match select(&videos, descriptor, Some(&video.codec), selection) {
+            Some(track) if track.stream != video.stream => track,
+            Some(_) => return,
+            None => return log::debug!("Viewer {} asked camera {} for a stream it can't receive: {:?}", self.viewer, self.camera, selection),
+        };
+        if let Err(err) = video.sender.replace_track(Some(track.track.clone() as Arc<dyn TrackLocal + Send + Sync>)).await {
+            return log::warn!("Cannot switch viewer {} to another stream: {}", self.viewer, err);
+        }
+        log::info!("Viewer {} watching the {} stream of camera {}", self.viewer, track.stream.as_deref().unwrap_or("video"), self.camera);
+        video.ssrc.store(track.ssrc, Ordering::Relaxed);
+        video.stream = track.stream.clone();
+        self.feed.request_keyframe(track.ssrc);
+    }
+}
+
+/// Encoding name of a track, such as `H264`.
+fn codec_name(track: &Forwarded) -> String {
+    let mime_type = track.track.codec().mime_type;
+    mime_type.split_once('/').map_or(mime_type.as_str(), |(_, name)| name).to_string()
+}
+
+/// The video a `Watch` or `Constrain` from the viewer picks, in `codec` once
+/// one is negotiated. Sizes come from the camera's descriptor, and before
+/// negotiation its main stream's codec is assumed as the camera does.
+fn select<'a>(videos: &[&'a Forwarded], descriptor: Option<&common::CameraDescriptor>, codec: Option<&str>, selection: &common::Signal) -> Option<&'a Forwarded> {
+    let stream = match selection {
+        common::Signal::Watch { stream } => stream.clone(),
+        common::Signal::Constrain { max_width, max_height } => {
+            let descriptor = descriptor?;
+            let codec = codec.or_else(|| descriptor.streams.first().map(|stream| stream.codec.as_str()))?;
+            descriptor.fitting(codec, *max_width, *max_height)?.id.clone()
+        }
+        _ => return None,
+    };
+    videos.iter().copied().find(|track| {
+        track.stream.as_deref() == Some(stream.as_str()) && codec.is_none_or(|codec| codec_name(track).eq_ignore_ascii_case(codec))
+    })
+}
+
+/// Encoding names of every format in an SDP offer.
+fn offered_codecs(offer: &str) -> Result<Vec<String>> {
+    let description = common::sdp::SessionDescription::parse(offer)?;
+    Ok(description.media.iter()
+        .flat_map(|section| section.formats.iter().filter_map(move |format| section.codec(format)))
+        .map(str::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+    use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+
+    fn video(stream: &str, codec: &str, ssrc: u32) -> Forwarded {
+        let capability = RTCRtpCodecCapability { mime_type: format!("video/{}", codec), clock_rate: 90000, ..Default::default() };
+        let track = Arc::new(TrackLocalStaticRTP::new(capability, format!("video-{}", stream), "rstream".to_string()));
+        Forwarded { kind: RTPCodecType::Video, stream: Some(stream.to_string()), ssrc, track }
+    }
+
+    #[test]
+    fn selects_streams_like_the_camera() {
+        let stream = |id: &str, codec: &str, width, height| common::StreamDescriptor { id: id.to_string(), codec: codec.to_string(), width, height, fps: 15 };
+        let descriptor = common::CameraDescriptor {
+            name: "Porch".to_string(),
+            streams: vec![stream("main", "H264", 1280, 720), stream("sub", "H264", 640, 360), stream("other", "VP8", 320, 180)],
+        };
+        let tracks = [video("main", "H264", 1), video("sub", "H264", 2), video("other", "VP8", 3)];
+        let videos = tracks.iter().collect::<Vec<_>>();
+        let ssrc = |codec, selection| select(&videos, Some(&descriptor), codec, &selection).map(|track| track.ssrc);
+
+        assert_eq!(ssrc(None, common::Signal::Watch { stream: "sub".to_string() }), Some(2));
+        assert_eq!(ssrc(None, common::Signal::Constrain { max_width: 800, max_height: 600 }), Some(2));
+        assert_eq!(ssrc(Some("H264"), common::Signal::Constrain { max_width: 1920, max_height: 1080 }), Some(1));
+        assert_eq!(ssrc(Some("VP8"), common::Signal::Constrain { max_width: 1920, max_height: 1080 }), Some(3));
+        // Switching codecs would need renegotiating.
+        assert_eq!(ssrc(Some("H264"), common::Signal::Watch { stream: "other".to_string() }), None);
+        assert_eq!(select(&videos, None, None, &common::Signal::Constrain { max_width: 800, max_height: 600 }).map(|track| track.ssrc), None);
+    }
+}
@@ -0,0 +1,82 @@
+//! Preview stills cameras send in, served over HTTP with validators so
+//! browsers only download a thumbnail again once it has changed.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+
+/// How long browsers may show a still without asking whether there is a newer one.
+const MAX_AGE: u32 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("snapshot is not base64")]
+    NotBase64,
+    #[error("snapshot is not a JPEG")]
+    NotJpeg,
+}
+
+/// The latest still from a camera.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub jpeg: Bytes,
+    pub taken: SystemTime,
+}
+
+impl Snapshot {
+    /// Decodes a still sent as base64, checking it at least starts like a JPEG.
+    pub fn decode(jpeg: &str, taken: SystemTime) -> Result<Self, SnapshotError> {
+        let jpeg = STANDARD.decode(jpeg).map_err(|_| SnapshotError::NotBase64)?;
+        if !jpeg.starts_with(&[0xff, 0xd8, 0xff]) {
+            return Err(SnapshotError::NotJpeg);
+        }
+        Ok(Snapshot { jpeg: jpeg.into(), taken })
+    }
+
+    fn etag(&self) -> String {
+        let millis = self.taken.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        format!("\"{:x}-{:x}\"", millis, self.jpeg.len())
+    }
+
+    /// The still, or 304 Not Modified when `if_none_match` names this one.
+    pub fn reply(&self, if_none_match: Option<&str>) -> Response<Body> {
+        let etag = self.etag();
+        let builder = Response::builder()
+            .header(header::ETAG, &etag)
+            .header(header::LAST_MODIFIED, httpdate::fmt_http_date(self.taken))
+            .header(header::CACHE_CONTROL, format!("private, max-age={}", MAX_AGE));
+        let fresh = if_none_match.is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"));
+        let response = if fresh {
+            builder.status(StatusCode::NOT_MODIFIED).body(Body::empty())
+        } else {
+            builder.header(header::CONTENT_TYPE, "image/jpeg").body(Body::from(self.jpeg.clone()))
+        };
+        response.expect("snapshot headers are valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_stills_with_validators() {
+        let taken = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        assert_eq!(Snapshot::decode("not base64!", taken).unwrap_err(), SnapshotError::NotBase64);
+        assert_eq!(Snapshot::decode(&STANDARD.encode(b"GIF89a"), taken).unwrap_err(), SnapshotError::NotJpeg);
+
+        let snapshot = Snapshot::decode(&STANDARD.encode([0xff, 0xd8, 0xff, 0xe0, 0, 0]), taken).unwrap();
+        let response = snapshot.reply(None);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        assert_eq!(response.headers()[header::LAST_MODIFIED], "Tue, 14 Nov 2023 22:13:20 GMT");
+        let etag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+
+        assert_eq!(snapshot.reply(Some(&format!("\"other\", {}", etag))).status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(snapshot.reply(Some("\"other\"")).status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use super::{Result, Storage, StorageError, StoredCamera, StoredUser};
+
+/// Storage that lasts as long as the process, for tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    users: Mutex<BTreeMap<String, StoredUser>>,
+    cameras: Mutex<BTreeMap<String, StoredCamera>>,
+    /// In the order they were added.
+    events: Mutex<Vec<common::Event>>,
+    recordings: Mutex<BTreeMap<String, common::Recording>>,
+}
+
+fn sorted(cameras: &[String]) -> Vec<String> {
+    let mut cameras = cameras.to_vec();
+    cameras.sort();
+    cameras.dedup();
+    cameras
+}
+
+impl Storage for MemoryStorage {
+    fn add_user(&self, user: &StoredUser) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        if users.contains_key(&user.user.name) {
+            return Err(StorageError::UserExists(user.user.name.clone()));
+        }
+        let mut user = user.clone();
+        user.user.cameras = sorted(&user.user.cameras);
+        users.insert(user.user.name.clone(), user);
+        Ok(())
+    }
+
+    fn user(&self, name: &str) -> Result<Option<StoredUser>> {
+        Ok(self.users.lock().unwrap().get(name).cloned())
+    }
+
+    fn users(&self) -> Result<Vec<StoredUser>> {
+        Ok(self.users.lock().unwrap().values().cloned().collect())
+    }
+
+    fn remove_user(&self, name: &str) -> Result<bool> {
+        Ok(self.users.lock().unwrap().remove(name).is_some())
+    }
+
+    fn set_grants(&self, name: &str, cameras: &[String]) -> Result<()> {
+        let mut users = self.users.lock().unwrap();
+        let user = users.get_mut(name).ok_or_else(|| StorageError::UnknownUser(name.to_string()))?;
+        user.user.cameras = sorted(cameras);
+        Ok(())
+    }
+
+    fn register_camera(&self, descriptor: &common::CameraDescriptor, time: u64) -> Result<()> {
+        let mut cameras = self.cameras.lock().unwrap();
+        let camera = cameras.entry(descriptor.name.clone()).or_insert_with(|| StoredCamera {
+            descriptor: descriptor.clone(),
+            first_seen: time,
+            last_seen: time,
+        });
+        camera.descriptor = descriptor.clone();
+        camera.last_seen = time;
+        Ok(())
+    }
+
+    fn cameras(&self) -> Result<Vec<StoredCamera>> {
+        Ok(self.cameras.lock().unwrap().values().cloned().collect())
+    }
+
+    fn add_event(&self, event: &common::Event) -> Result<()> {
+        self.events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+
+    fn events(&self, since: Option<u64>, kind: Option<&str>, limit: usize) -> Result<Vec<common::Event>> {
+        let events = self.events.lock().unwrap();
+        let mut matching = events
+            .iter()
+            .rev()
+            .filter(|event| since.is_none_or(|since| event.time > since))
+            .filter(|event| kind.is_none_or(|kind| event.kind.name() == kind))
+            .take(limit)
+            .cloned()
+            .collect::<Vec<_>>();
+        matching.reverse();
+        Ok(matching)
+    }
+
+    fn put_recording(&self, recording: &common::Recording) -> Result<()> {
+        self.recordings.lock().unwrap().insert(recording.file.clone(), recording.clone());
+        Ok(())
+    }
+
+    fn recordings(&self, camera: Option<&str>, from: u64, to: u64) -> Result<Vec<common::Recording>> {
+        let mut overlapping = self
+            .recordings
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|recording| camera.is_none_or(|camera| recording.camera == camera))
+            .filter(|recording| recording.start < to && recording.end.is_none_or(|end| end > from))
+            .cloned()
+            .collect::<Vec<_>>();
+        overlapping.sort_by(|a, b| (a.start, &a.camera).cmp(&(b.start, &b.camera)));
+        Ok(overlapping)
+    }
+
+    fn remove_recording(&self, file: &str) -> Result<bool> {
+        Ok(self.recordings.lock().unwrap().remove(file).is_some())
+    }
+}
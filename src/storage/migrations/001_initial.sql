@@ -0,0 +1,46 @@
+-- Accounts and the cameras they may watch, cameras that have registered,
+-- published events and recorded segments.
+
+CREATE TABLE users (
+    name TEXT PRIMARY KEY NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('admin', 'viewer')),
+    -- pbkdf2-sha256$<rounds>$<salt>$<hash>
+    password TEXT NOT NULL
+);
+
+CREATE TABLE grants (
+    user TEXT NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+    -- A camera name, or * for every camera.
+    camera TEXT NOT NULL,
+    PRIMARY KEY (user, camera)
+);
+
+CREATE TABLE cameras (
+    name TEXT PRIMARY KEY NOT NULL,
+    -- The streams it last registered, as JSON.
+    streams TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+);
+
+CREATE TABLE events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    time INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    -- The whole event as JSON, as the API returns it.
+    event TEXT NOT NULL
+);
+
+CREATE INDEX events_time ON events (time);
+
+CREATE TABLE recordings (
+    -- Relative to the recordings directory.
+    file TEXT PRIMARY KEY NOT NULL,
+    camera TEXT NOT NULL,
+    start INTEGER NOT NULL,
+    -- Unset while the segment is being written.
+    end INTEGER,
+    size INTEGER NOT NULL
+);
+
+CREATE INDEX recordings_camera_start ON recordings (camera, start);
@@ -0,0 +1,16 @@
+-- Users may have the camera role. SQLite can't change a CHECK constraint, so
+-- the table is rebuilt; foreign keys are off while migrations run, which keeps
+-- the grants pointing at it.
+
+CREATE TABLE users_new (
+    name TEXT PRIMARY KEY NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('admin', 'viewer', 'camera')),
+    -- pbkdf2-sha256$<rounds>$<salt>$<hash>
+    password TEXT NOT NULL
+);
+
+INSERT INTO users_new (name, role, password) SELECT name, role, password FROM users;
+
+DROP TABLE users;
+
+ALTER TABLE users_new RENAME TO users;
@@ -0,0 +1,181 @@
+//! What the server keeps across restarts: users and their grants, cameras
+//! that have registered, events and recorded segments.
+//!
+//! [`Storage`] is what the rest of the server uses. It runs on
+//! [`SqliteStorage`], whose schema is built by [`MIGRATIONS`], while tests
+//! can use [`MemoryStorage`].
+
+use crate::auth::User;
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+/// SQL bringing the database from each schema version to the next, applied
+/// in order from its `user_version`. Released migrations are never edited.
+pub const MIGRATIONS: &[&str] = &[
+    include_str!("migrations/001_initial.sql"),
+    include_str!("migrations/002_camera_role.sql"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StorageError {
+    #[error("no user named {0:?}")]
+    UnknownUser(String),
+    #[error("a user named {0:?} already exists")]
+    UserExists(String),
+    #[error("storage failed: {0}")]
+    Backend(String),
+}
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+/// A user as stored, with their password hash. The user's cameras are its
+/// grants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredUser {
+    pub user: User,
+    pub password: String,
+}
+
+/// A camera that has registered at some point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredCamera {
+    pub descriptor: common::CameraDescriptor,
+    /// Milliseconds since the Unix epoch.
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+pub trait Storage: Send + Sync {
+    /// Adds a user along with their grants.
+    fn add_user(&self, user: &StoredUser) -> Result<()>;
+    fn user(&self, name: &str) -> Result<Option<StoredUser>>;
+    /// Every user, by name.
+    fn users(&self) -> Result<Vec<StoredUser>>;
+    /// Removes a user and their grants, returning whether there was one.
+    fn remove_user(&self, name: &str) -> Result<bool>;
+    /// Replaces the cameras a user may watch.
+    fn set_grants(&self, name: &str, cameras: &[String]) -> Result<()>;
+
+    /// Records a camera registering at `time`, updating its streams.
+    fn register_camera(&self, descriptor: &common::CameraDescriptor, time: u64) -> Result<()>;
+    /// Every camera that has registered, by name.
+    fn cameras(&self) -> Result<Vec<StoredCamera>>;
+
+    fn add_event(&self, event: &common::Event) -> Result<()>;
+    /// Up to `limit` of the latest events after `since`, of the given type if
+    /// any, oldest first.
+    fn events(&self, since: Option<u64>, kind: Option<&str>, limit: usize) -> Result<Vec<common::Event>>;
+
+    /// Adds a segment, or updates it once it is finished.
+    fn put_recording(&self, recording: &common::Recording) -> Result<()>;
+    /// Segments overlapping `from..to`, of one camera or all of them, oldest
+    /// first. Unfinished segments reach up to now.
+    fn recordings(&self, camera: Option<&str>, from: u64, to: u64) -> Result<Vec<common::Recording>>;
+    /// Forgets a segment, once its file is removed.
+    fn remove_recording(&self, file: &str) -> Result<bool>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+
+    /// What every backend must do, so each can run it against itself.
+    fn check(storage: &dyn Storage) {
+        let alice = StoredUser {
+            user: User { name: "alice".to_string(), role: Role::Viewer, cameras: vec!["Porch".to_string()] },
+            password: "pbkdf2-sha256$1$salt$hash".to_string(),
+        };
+        storage.add_user(&alice).unwrap();
+        assert_eq!(storage.add_user(&alice), Err(StorageError::UserExists("alice".to_string())));
+        storage.set_grants("alice", &["Yard".to_string(), "Porch".to_string()]).unwrap();
+        assert_eq!(storage.user("alice").unwrap().unwrap().user.cameras, vec!["Porch".to_string(), "Yard".to_string()]);
+        assert_eq!(storage.set_grants("bob", &[]), Err(StorageError::UnknownUser("bob".to_string())));
+        assert_eq!(storage.users().unwrap().len(), 1);
+        assert!(storage.remove_user("alice").unwrap());
+        assert!(!storage.remove_user("alice").unwrap());
+        assert_eq!(storage.user("alice").unwrap(), None);
+
+        let porch = common::CameraDescriptor { name: "Porch".to_string(), streams: Vec::new() };
+        storage.register_camera(&porch, 1000).unwrap();
+        storage.register_camera(&porch, 2000).unwrap();
+        assert_eq!(storage.cameras().unwrap(), vec![StoredCamera { descriptor: porch, first_seen: 1000, last_seen: 2000 }]);
+
+        for (time, active) in [(1000, true), (2000, false), (3000, true)] {
+            let kind = common::EventKind::Motion { camera: uuid::Uuid::nil(), name: "Porch".to_string(), active };
+            storage.add_event(&common::Event { time, kind }).unwrap();
+        }
+        storage.add_event(&common::Event { time: 4000, kind: common::EventKind::AuthFailure { remote: None, reason: "wrong".to_string() } }).unwrap();
+        let times = |events: Vec<common::Event>| events.iter().map(|event| event.time).collect::<Vec<_>>();
+        assert_eq!(times(storage.events(Some(1000), None, 10).unwrap()), vec![2000, 3000, 4000]);
+        assert_eq!(times(storage.events(None, Some("motion"), 2).unwrap()), vec![2000, 3000]);
+
+        let segment = |file: &str, start, end| common::Recording { camera: "Porch".to_string(), file: file.to_string(), start, end, size: 10 };
+        storage.put_recording(&segment("porch/1.webm", 1000, None)).unwrap();
+        storage.put_recording(&segment("porch/1.webm", 1000, Some(2000))).unwrap();
+        storage.put_recording(&segment("porch/2.webm", 2000, None)).unwrap();
+        storage.put_recording(&common::Recording { camera: "Yard".to_string(), ..segment("yard/1.webm", 1000, None) }).unwrap();
+        let files = |recordings: Vec<common::Recording>| recordings.into_iter().map(|recording| recording.file).collect::<Vec<_>>();
+        assert_eq!(files(storage.recordings(Some("Porch"), 0, 1500).unwrap()), vec!["porch/1.webm"]);
+        // Unfinished segments reach up to now.
+        assert_eq!(files(storage.recordings(Some("Porch"), 2500, 3000).unwrap()), vec!["porch/2.webm"]);
+        assert_eq!(files(storage.recordings(None, 0, u64::MAX).unwrap()), vec!["porch/1.webm", "yard/1.webm", "porch/2.webm"]);
+        assert!(storage.remove_recording("porch/1.webm").unwrap());
+        assert!(!storage.remove_recording("porch/1.webm").unwrap());
+        assert_eq!(files(storage.recordings(Some("Porch"), 0, 3000).unwrap()), vec!["porch/2.webm"]);
+    }
+
+    #[test]
+    fn memory_storage() {
+        check(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn sqlite_storage() {
+        check(&SqliteStorage::in_memory().unwrap());
+    }
+
+    #[test]
+    fn keeps_grants_through_migrations() {
+        let path = std::env::temp_dir().join(format!("rstream-{}.db", uuid::Uuid::new_v4()));
+        let db = rusqlite::Connection::open(&path).unwrap();
+        db.execute_batch(MIGRATIONS[0]).unwrap();
+        db.execute_batch("PRAGMA user_version = 1; INSERT INTO users VALUES ('sam', 'viewer', 'hash'); INSERT INTO grants VALUES ('sam', 'Porch');").unwrap();
+        drop(db);
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.user("sam").unwrap().unwrap().user.cameras, vec!["Porch".to_string()]);
+        let camera = StoredUser { user: User { name: "porch".to_string(), role: Role::Camera, cameras: vec!["Porch".to_string()] }, password: "hash".to_string() };
+        storage.add_user(&camera).unwrap();
+        // Foreign keys are back on, so removing a user still takes their grants along.
+        storage.remove_user("sam").unwrap();
+        drop(storage);
+        let db = rusqlite::Connection::open(&path).unwrap();
+        assert_eq!(db.query_row("SELECT count(*) FROM grants WHERE user = 'sam'", [], |row| row.get::<_, i64>(0)).unwrap(), 0);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn migrates_databases_once() {
+        let path = std::env::temp_dir().join(format!("rstream-{}.db", uuid::Uuid::new_v4()));
+        let version = |path: &std::path::Path| rusqlite::Connection::open(path).unwrap().query_row("PRAGMA user_version", [], |row| row.get::<_, usize>(0)).unwrap();
+
+        let porch = common::CameraDescriptor { name: "Porch".to_string(), streams: Vec::new() };
+        SqliteStorage::open(&path).unwrap().register_camera(&porch, 1000).unwrap();
+        assert_eq!(version(&path), MIGRATIONS.len());
+        // Opening it again keeps what is there.
+        assert_eq!(SqliteStorage::open(&path).unwrap().cameras().unwrap().len(), 1);
+
+        rusqlite::Connection::open(&path).unwrap().pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        assert!(matches!(SqliteStorage::open(&path), Err(StorageError::Backend(_))));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
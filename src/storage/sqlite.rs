@@ -0,0 +1,216 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{Result, Storage, StorageError, StoredCamera, StoredUser, MIGRATIONS};
+use crate::auth::{Role, User};
+
+/// Storage in a SQLite database, which is created and migrated on opening.
+pub struct SqliteStorage {
+    db: Mutex<Connection>,
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(err: serde_json::Error) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+/// Applies the migrations the database hasn't had yet, each in a transaction
+/// with the `user_version` it brings the schema to. Foreign keys must be off,
+/// so migrations can rebuild tables others refer to; they are checked before
+/// each migration commits instead.
+fn migrate(db: &mut Connection) -> Result<()> {
+    let version = db.query_row("PRAGMA user_version", [], |row| row.get::<_, usize>(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(StorageError::Backend(format!("the database's schema version {} is newer than this server's {}", version, MIGRATIONS.len())));
+    }
+    for (applied, migration) in (version + 1..).zip(&MIGRATIONS[version..]) {
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)?;
+        if tx.prepare("PRAGMA foreign_key_check")?.exists([])? {
+            return Err(StorageError::Backend(format!("migration to schema version {} broke foreign keys", applied)));
+        }
+        tx.pragma_update(None, "user_version", applied)?;
+        tx.commit()?;
+        log::info!("Migrated database to schema version {}", applied);
+    }
+    Ok(())
+}
+
+fn role(name: &str) -> rusqlite::Result<Role> {
+    match name {
+        "admin" => Ok(Role::Admin),
+        "viewer" => Ok(Role::Viewer),
+        "camera" => Ok(Role::Camera),
+        _ => Err(rusqlite::Error::InvalidColumnType(1, "role".to_string(), rusqlite::types::Type::Text)),
+    }
+}
+
+/// Times go in signed columns; nothing is recorded that far off anyway.
+fn clamp(time: u64) -> i64 {
+    time.min(i64::MAX as u64) as i64
+}
+
+fn recording(row: &rusqlite::Row) -> rusqlite::Result<common::Recording> {
+    Ok(common::Recording { file: row.get(0)?, camera: row.get(1)?, start: row.get(2)?, end: row.get(3)?, size: row.get(4)? })
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if there is none yet.
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Connection::open(path)?;
+        // Readers don't wait for writers, and commits don't wait for the disk.
+        db.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        db.pragma_update(None, "synchronous", "NORMAL")?;
+        SqliteStorage::new(db)
+    }
+
+    /// A database that only lasts as long as the storage.
+    pub fn in_memory() -> Result<Self> {
+        SqliteStorage::new(Connection::open_in_memory()?)
+    }
+
+    fn new(mut db: Connection) -> Result<Self> {
+        db.pragma_update(None, "foreign_keys", false)?;
+        migrate(&mut db)?;
+        db.pragma_update(None, "foreign_keys", true)?;
+        Ok(SqliteStorage { db: Mutex::new(db) })
+    }
+
+    fn grants(db: &Connection, name: &str) -> Result<Vec<String>> {
+        let mut query = db.prepare_cached("SELECT camera FROM grants WHERE user = ?1 ORDER BY camera")?;
+        let cameras = query.query_map([name], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+        Ok(cameras)
+    }
+
+    fn users_where(db: &Connection, name: Option<&str>) -> Result<Vec<StoredUser>> {
+        let mut query = db.prepare_cached("SELECT name, role, password FROM users WHERE ?1 IS NULL OR name = ?1 ORDER BY name")?;
+        let rows = query.query_map([name], |row| Ok((row.get::<_, String>(0)?, role(&row.get::<_, String>(1)?)?, row.get::<_, String>(2)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(name, role, password)| {
+                let cameras = SqliteStorage::grants(db, &name)?;
+                Ok(StoredUser { user: User { name, role, cameras }, password })
+            })
+            .collect()
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn add_user(&self, user: &StoredUser) -> Result<()> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        let added = tx.execute(
+            "INSERT INTO users (name, role, password) VALUES (?1, ?2, ?3) ON CONFLICT (name) DO NOTHING",
+            params![user.user.name, user.user.role.to_string(), user.password],
+        )?;
+        if added == 0 {
+            return Err(StorageError::UserExists(user.user.name.clone()));
+        }
+        for camera in &user.user.cameras {
+            tx.execute("INSERT OR IGNORE INTO grants (user, camera) VALUES (?1, ?2)", params![user.user.name, camera])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn user(&self, name: &str) -> Result<Option<StoredUser>> {
+        Ok(SqliteStorage::users_where(&self.db.lock().unwrap(), Some(name))?.pop())
+    }
+
+    fn users(&self) -> Result<Vec<StoredUser>> {
+        SqliteStorage::users_where(&self.db.lock().unwrap(), None)
+    }
+
+    fn remove_user(&self, name: &str) -> Result<bool> {
+        Ok(self.db.lock().unwrap().execute("DELETE FROM users WHERE name = ?1", [name])? > 0)
+    }
+
+    fn set_grants(&self, name: &str, cameras: &[String]) -> Result<()> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        if tx.query_row("SELECT 1 FROM users WHERE name = ?1", [name], |_| Ok(())).optional()?.is_none() {
+            return Err(StorageError::UnknownUser(name.to_string()));
+        }
+        tx.execute("DELETE FROM grants WHERE user = ?1", [name])?;
+        for camera in cameras {
+            tx.execute("INSERT OR IGNORE INTO grants (user, camera) VALUES (?1, ?2)", params![name, camera])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn register_camera(&self, descriptor: &common::CameraDescriptor, time: u64) -> Result<()> {
+        let streams = serde_json::to_string(&descriptor.streams)?;
+        self.db.lock().unwrap().execute(
+            "INSERT INTO cameras (name, streams, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT (name) DO UPDATE SET streams = excluded.streams, last_seen = excluded.last_seen",
+            params![descriptor.name, streams, clamp(time)],
+        )?;
+        Ok(())
+    }
+
+    fn cameras(&self) -> Result<Vec<StoredCamera>> {
+        let db = self.db.lock().unwrap();
+        let mut query = db.prepare_cached("SELECT name, streams, first_seen, last_seen FROM cameras ORDER BY name")?;
+        let rows = query.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get(2)?, row.get(3)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(name, streams, first_seen, last_seen)| {
+                let descriptor = common::CameraDescriptor { name, streams: serde_json::from_str(&streams)? };
+                Ok(StoredCamera { descriptor, first_seen, last_seen })
+            })
+            .collect()
+    }
+
+    fn add_event(&self, event: &common::Event) -> Result<()> {
+        let json = serde_json::to_string(event)?;
+        self.db.lock().unwrap().execute(
+            "INSERT INTO events (time, type, event) VALUES (?1, ?2, ?3)",
+            params![clamp(event.time), event.kind.name(), json],
+        )?;
+        Ok(())
+    }
+
+    fn events(&self, since: Option<u64>, kind: Option<&str>, limit: usize) -> Result<Vec<common::Event>> {
+        let db = self.db.lock().unwrap();
+        let mut query = db.prepare_cached(
+            "SELECT event FROM events WHERE (?1 IS NULL OR time > ?1) AND (?2 IS NULL OR type = ?2) ORDER BY id DESC LIMIT ?3",
+        )?;
+        let rows = query.query_map(params![since.map(clamp), kind, clamp(limit as u64)], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.iter().rev().map(|json| Ok(serde_json::from_str(json)?)).collect()
+    }
+
+    fn put_recording(&self, recording: &common::Recording) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "INSERT INTO recordings (file, camera, start, \"end\", size) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (file) DO UPDATE SET camera = excluded.camera, start = excluded.start, \"end\" = excluded.\"end\", size = excluded.size",
+            params![recording.file, recording.camera, clamp(recording.start), recording.end.map(clamp), clamp(recording.size)],
+        )?;
+        Ok(())
+    }
+
+    fn recordings(&self, camera: Option<&str>, from: u64, to: u64) -> Result<Vec<common::Recording>> {
+        let db = self.db.lock().unwrap();
+        let mut query = db.prepare_cached(
+            "SELECT file, camera, start, \"end\", size FROM recordings
+             WHERE (?1 IS NULL OR camera = ?1) AND start < ?3 AND (\"end\" IS NULL OR \"end\" > ?2)
+             ORDER BY start, camera",
+        )?;
+        let recordings = query.query_map(params![camera, clamp(from), clamp(to)], recording)?.collect::<rusqlite::Result<_>>()?;
+        Ok(recordings)
+    }
+
+    fn remove_recording(&self, file: &str) -> Result<bool> {
+        Ok(self.db.lock().unwrap().execute("DELETE FROM recordings WHERE file = ?1", [file])? > 0)
+    }
+}
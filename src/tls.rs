@@ -0,0 +1,101 @@
+//! HTTPS for the server's routes.
+//!
+//! warp's own TLS server drops connections that fail the handshake without a
+//! trace, so this accepts connections and shakes hands itself, counting the
+//! failures, before handing each connection to the routes.
+
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use warp::hyper::service::Service;
+use warp::hyper::{Body, Request, Response};
+use warp::Filter;
+
+use crate::metrics::Metrics;
+
+/// Address a connection came from, as served by [`serve`].
+#[derive(Debug, Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+/// Address of the client, whether the connection was accepted by [`serve`]
+/// or by warp.
+pub fn remote() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::ext::optional::<RemoteAddr>()
+        .and(warp::addr::remote())
+        .map(|ours: Option<RemoteAddr>, warps: Option<SocketAddr>| ours.map(|RemoteAddr(addr)| addr).or(warps))
+}
+
+fn config(key: &Path, cert: &Path) -> Result<ServerConfig> {
+    let mut reader = BufReader::new(File::open(cert).with_context(|| format!("Failed opening {}", cert.display()))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .with_context(|| format!("Failed reading certificates from {}", cert.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let mut reader = BufReader::new(File::open(key).with_context(|| format!("Failed opening {}", key.display()))?);
+    let key = rustls_pemfile::read_all(&mut reader)
+        .with_context(|| format!("Failed reading {}", key.display()))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("No private key in {}", key.display()))?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or key")?;
+    // WebSockets need HTTP/1.1, and nothing here gains from HTTP/2.
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Serves HTTPS on `addr` until the listener fails.
+pub async fn serve<S>(service: S, addr: SocketAddr, key: &Path, cert: &Path, metrics: Arc<Metrics>) -> Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let acceptor = TlsAcceptor::from(Arc::new(config(key, cert)?));
+    let listener = tokio1::net::TcpListener::bind(addr).await.with_context(|| format!("Failed listening on {}", addr))?;
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::warn!("Failed accepting a connection: {}", err);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        let metrics = metrics.clone();
+        tokio1::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    metrics.count_tls_handshake_failure();
+                    log::debug!("TLS handshake with {} failed: {}", remote, err);
+                    return;
+                }
+            };
+            let service = warp::hyper::service::service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(RemoteAddr(remote));
+                service.clone().call(request)
+            });
+            let connection = warp::hyper::server::conn::Http::new().serve_connection(stream, service).with_upgrades();
+            if let Err(err) = connection.await {
+                log::debug!("Connection from {} failed: {}", remote, err);
+            }
+        });
+    }
+}